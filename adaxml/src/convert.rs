@@ -0,0 +1,391 @@
+use anyhow::{anyhow, Context};
+use crate::tag::{XmlString, XmlTag};
+
+/// Declares how a struct maps to an element, each field being one of
+/// - `child "name"`: a child element, `Option` when it may be missing and `Vec` for any number of them
+/// - `choice`: like `child` for an `xml_enum!`, whose variants give the element names
+/// - `attrib "name"`: an attribute, `Option` when it may be missing
+/// - `text`: the text of the element
+/// - `flag "name"`: a `bool` telling whether an empty child like `<chord/>` is there
+///
+/// Usage: xml_struct!( Time { beats: child "beats", beat_type: child "beat-type" } )
+#[macro_export]
+macro_rules! xml_struct {
+    ($type:ident { $($field:ident : $kind:ident $($name:literal)?),* $(,)? }) => {
+        impl $crate::convert::FromXmlTag for $type {
+            fn from_xml_tag(tag: &$crate::tag::XmlTag) -> ::anyhow::Result<Self> {
+                Ok($type { $($field: $crate::xml_struct!(@from tag, $kind $($name)?)),* })
+            }
+        }
+
+        impl $crate::convert::ToXmlTag for $type {
+            fn to_xml_tag(&self, name: &str) -> $crate::tag::XmlTag {
+                let mut tag = $crate::tag::XmlTag::new(name);
+                $($crate::xml_struct!(@to tag, self.$field, $kind $($name)?);)*
+                tag
+            }
+        }
+    };
+    (@from $tag:ident, child $name:literal) => {
+        $crate::convert::FromXmlChildren::from_xml_children($tag, $name)?
+    };
+    (@from $tag:ident, choice) => {
+        $crate::convert::FromXmlChildren::from_xml_children($tag, "")?
+    };
+    (@from $tag:ident, attrib $name:literal) => {
+        ::anyhow::Context::with_context(
+            $crate::convert::FromXmlText::from_xml_text($tag.get_attrib_value($name)),
+            || { format!("Can't parse attribute {} of <{}>", $name, $tag.name) }
+        )?
+    };
+    (@from $tag:ident, text) => {
+        ::anyhow::Context::with_context(
+            $crate::convert::FromXmlText::from_xml_text($tag.value.as_deref()),
+            || { format!("Can't parse the text of <{}>", $tag.name) }
+        )?
+    };
+    (@from $tag:ident, flag $name:literal) => {
+        $tag.does_child_exists($name)
+    };
+    (@to $tag:ident, $value:expr, child $name:literal) => {
+        $crate::convert::ToXmlChildren::to_xml_children(&$value, $name, &mut $tag)
+    };
+    (@to $tag:ident, $value:expr, choice) => {
+        $crate::convert::ToXmlChildren::to_xml_children(&$value, "", &mut $tag)
+    };
+    (@to $tag:ident, $value:expr, attrib $name:literal) => {
+        if let Some(text) = $crate::convert::ToXmlText::to_xml_text(&$value) {
+            $tag.add_attribute($name, text);
+        }
+    };
+    (@to $tag:ident, $value:expr, text) => {
+        $tag.value = $crate::convert::ToXmlText::to_xml_text(&$value)
+    };
+    (@to $tag:ident, $value:expr, flag $name:literal) => {
+        if $value {
+            $tag.add_child($name);
+        }
+    };
+}
+
+/// Declares an enum whose variants are told apart by the element name, each variant either
+/// holding the value read from that element or being a unit variant for an element without content.
+///
+/// Usage: xml_enum!( NoteContent { "pitch" => Pitch(WrittenPitch), "rest" => Rest } )
+#[macro_export]
+macro_rules! xml_enum {
+    ($type:ident { $($name:literal => $variant:ident $(($inner:ty))?),* $(,)? }) => {
+        impl $crate::convert::FromXmlTag for $type {
+            fn from_xml_tag(tag: &$crate::tag::XmlTag) -> ::anyhow::Result<Self> {
+                match tag.name.as_str() {
+                    $($name => Ok($crate::xml_enum!(@from tag, $variant $(($inner))?)),)*
+                    other => Err(::anyhow::anyhow!(
+                        "<{}> is none of {}", other, [$(concat!("<", $name, ">")),*].join(", ")
+                    ))
+                }
+            }
+
+            fn accepts(tag: &$crate::tag::XmlTag, _name: &str) -> bool {
+                tag.is_element() && [$($name),*].contains(&tag.name.as_str())
+            }
+        }
+
+        impl $crate::convert::ToXmlTag for $type {
+            fn to_xml_tag(&self, _name: &str) -> $crate::tag::XmlTag {
+                match self {
+                    $($crate::xml_enum!(@pattern $variant inner $(($inner))?) =>
+                        $crate::xml_enum!(@to inner, $name $(($inner))?),)*
+                }
+            }
+        }
+    };
+    (@from $tag:ident, $variant:ident ($inner:ty)) => {
+        Self::$variant(<$inner as $crate::convert::FromXmlTag>::from_xml_tag($tag)?)
+    };
+    (@from $tag:ident, $variant:ident) => {
+        Self::$variant
+    };
+    (@pattern $variant:ident $inner:ident ($_type:ty)) => {
+        Self::$variant($inner)
+    };
+    (@pattern $variant:ident $inner:ident) => {
+        Self::$variant
+    };
+    (@to $inner:ident, $name:literal ($_type:ty)) => {
+        $crate::convert::ToXmlTag::to_xml_tag($inner, $name)
+    };
+    (@to $inner:ident, $name:literal) => {
+        $crate::tag::XmlTag::new($name)
+    };
+}
+
+/// Implements `FromXmlValue` and `ToXmlValue` through `FromStr` and `ToString`, leading and trailing
+/// whitespace being ignored when reading
+#[macro_export]
+macro_rules! xml_value_from_str {
+    ($($type:ty),*) => {
+        $(
+            impl $crate::convert::FromXmlValue for $type {
+                fn from_xml_value(value: &str) -> ::anyhow::Result<Self> {
+                    value
+                        .trim()
+                        .parse::<$type>()
+                        .map_err(|err| { ::anyhow::anyhow!("{:?}: {}", value, err) })
+                }
+            }
+
+            impl $crate::convert::ToXmlValue for $type {
+                fn to_xml_value(&self) -> $crate::tag::XmlString {
+                    self.to_string()
+                }
+            }
+        )*
+    };
+}
+
+/// A value written as text, in an attribute or a simple element
+pub trait FromXmlValue: Sized {
+    fn from_xml_value(value: &str) -> anyhow::Result<Self>;
+}
+
+pub trait ToXmlValue {
+    fn to_xml_value(&self) -> XmlString;
+}
+
+/// A value read from an element; simple elements holding a `FromXmlValue` come for free
+pub trait FromXmlTag: Sized {
+    fn from_xml_tag(tag: &XmlTag) -> anyhow::Result<Self>;
+
+    /// Whether `tag` is where to read a field mapped to `name`, enums take the names of their variants
+    fn accepts(tag: &XmlTag, name: &str) -> bool {
+        tag.is_element() && tag.name == name
+    }
+}
+
+pub trait ToXmlTag {
+    /// The element is named after the field it comes from, enums name it after their variant
+    fn to_xml_tag(&self, name: &str) -> XmlTag;
+}
+
+xml_value_from_str!(bool, char, i8, i16, i32, i64, isize, u8, u16, u32, u64, usize, f32, f64);
+
+impl FromXmlValue for XmlString {
+    fn from_xml_value(value: &str) -> anyhow::Result<Self> {
+        Ok(value.to_string())
+    }
+}
+
+impl ToXmlValue for XmlString {
+    fn to_xml_value(&self) -> XmlString {
+        self.clone()
+    }
+}
+
+impl<T: FromXmlValue> FromXmlTag for T {
+    fn from_xml_tag(tag: &XmlTag) -> anyhow::Result<Self> {
+        T::from_xml_value(tag.value.as_deref().unwrap_or_default())
+    }
+}
+
+impl<T: ToXmlValue> ToXmlTag for T {
+    fn to_xml_tag(&self, name: &str) -> XmlTag {
+        let mut tag = XmlTag::new(name);
+        tag.value = Some(self.to_xml_value());
+        tag
+    }
+}
+
+/// Field mapped to children, used by `xml_struct!`: one child, `Option` or `Vec`
+pub trait FromXmlChildren: Sized {
+    fn from_xml_children(parent: &XmlTag, name: &str) -> anyhow::Result<Self>;
+}
+
+pub trait ToXmlChildren {
+    fn to_xml_children(&self, name: &str, parent: &mut XmlTag);
+}
+
+fn read_child<T: FromXmlTag>(parent: &XmlTag, child: &XmlTag) -> anyhow::Result<T> {
+    T::from_xml_tag(child)
+        .with_context(|| { format!("Can't parse <{}> in <{}>", child.name, parent.name) })
+}
+
+impl<T: FromXmlTag> FromXmlChildren for T {
+    fn from_xml_children(parent: &XmlTag, name: &str) -> anyhow::Result<Self> {
+        let child = parent.children
+            .iter()
+            .find(|child| { T::accepts(child, name) })
+            .ok_or_else(|| {
+                match name.is_empty() {
+                    true => anyhow!("Can't find a choice of element in <{}>", parent.name),
+                    false => anyhow!("Can't find <{}> in <{}>", name, parent.name)
+                }
+            })?;
+        read_child(parent, child)
+    }
+}
+
+impl<T: FromXmlTag> FromXmlChildren for Option<T> {
+    fn from_xml_children(parent: &XmlTag, name: &str) -> anyhow::Result<Self> {
+        parent.children
+            .iter()
+            .find(|child| { T::accepts(child, name) })
+            .map(|child| { read_child(parent, child) })
+            .transpose()
+    }
+}
+
+impl<T: FromXmlTag> FromXmlChildren for Vec<T> {
+    fn from_xml_children(parent: &XmlTag, name: &str) -> anyhow::Result<Self> {
+        parent.children
+            .iter()
+            .filter(|child| { T::accepts(child, name) })
+            .map(|child| { read_child(parent, child) })
+            .collect()
+    }
+}
+
+impl<T: ToXmlTag> ToXmlChildren for T {
+    fn to_xml_children(&self, name: &str, parent: &mut XmlTag) {
+        parent.children.push(self.to_xml_tag(name));
+    }
+}
+
+impl<T: ToXmlTag> ToXmlChildren for Option<T> {
+    fn to_xml_children(&self, name: &str, parent: &mut XmlTag) {
+        parent.children.extend(self.iter().map(|value| { value.to_xml_tag(name) }));
+    }
+}
+
+impl<T: ToXmlTag> ToXmlChildren for Vec<T> {
+    fn to_xml_children(&self, name: &str, parent: &mut XmlTag) {
+        parent.children.extend(self.iter().map(|value| { value.to_xml_tag(name) }));
+    }
+}
+
+/// Field mapped to an attribute or to the text, used by `xml_struct!`: required or `Option`
+pub trait FromXmlText: Sized {
+    fn from_xml_text(text: Option<&str>) -> anyhow::Result<Self>;
+}
+
+pub trait ToXmlText {
+    fn to_xml_text(&self) -> Option<XmlString>;
+}
+
+impl<T: FromXmlValue> FromXmlText for T {
+    fn from_xml_text(text: Option<&str>) -> anyhow::Result<Self> {
+        T::from_xml_value(text.ok_or_else(|| { anyhow!("missing") })?)
+    }
+}
+
+impl<T: FromXmlValue> FromXmlText for Option<T> {
+    fn from_xml_text(text: Option<&str>) -> anyhow::Result<Self> {
+        text.map(T::from_xml_value).transpose()
+    }
+}
+
+impl<T: ToXmlValue> ToXmlText for T {
+    fn to_xml_text(&self) -> Option<XmlString> {
+        Some(self.to_xml_value())
+    }
+}
+
+impl<T: ToXmlValue> ToXmlText for Option<T> {
+    fn to_xml_text(&self) -> Option<XmlString> {
+        self.as_ref().map(|value| { value.to_xml_value() })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::convert::{FromXmlTag, ToXmlTag};
+    use crate::tag::XmlTag;
+
+    #[derive(Debug, PartialEq)]
+    struct Pitch {
+        step: String,
+        alter: Option<f32>,
+        octave: i8
+    }
+    xml_struct!(Pitch { step: child "step", alter: child "alter", octave: child "octave" });
+
+    #[derive(Debug, PartialEq)]
+    enum NoteContent {
+        Pitch(Pitch),
+        Rest
+    }
+    xml_enum!(NoteContent { "pitch" => Pitch(Pitch), "rest" => Rest });
+
+    #[derive(Debug, PartialEq)]
+    struct Lyric {
+        number: Option<u8>,
+        text: String
+    }
+    xml_struct!(Lyric { number: attrib "number", text: child "text" });
+
+    #[derive(Debug, PartialEq)]
+    struct Note {
+        color: Option<String>,
+        chord: bool,
+        content: NoteContent,
+        duration: u32,
+        lyrics: Vec<Lyric>
+    }
+    xml_struct!(Note {
+        color: attrib "color",
+        chord: flag "chord",
+        content: choice,
+        duration: child "duration",
+        lyrics: child "lyric"
+    });
+
+    fn note_tag(source: &str) -> XmlTag {
+        XmlTag::from_read(source.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn structs_and_enums_round_trip() {
+        let tag = note_tag(r#"<note color="red"><chord/><pitch><step>C</step><octave>5</octave></pitch>
+            <duration>2</duration><lyric number="1"><text>la</text></lyric><lyric><text>li</text></lyric></note>"#);
+        let note = Note::from_xml_tag(&tag).unwrap();
+        assert_eq!(note, Note {
+            color: Some("red".to_string()),
+            chord: true,
+            content: NoteContent::Pitch(Pitch { step: "C".to_string(), alter: None, octave: 5 }),
+            duration: 2,
+            lyrics: vec![
+                Lyric { number: Some(1), text: "la".to_string() },
+                Lyric { number: None, text: "li".to_string() }
+            ]
+        });
+        let written = note.to_xml_tag("note");
+        assert_eq!(
+            written.children.iter().map(|child| { child.name.as_str() }).collect::<Vec<_>>(),
+            vec!["chord", "pitch", "duration", "lyric", "lyric"]
+        );
+        assert!(written.first_desc_with_name("alter").next().is_none());
+        assert_eq!(Note::from_xml_tag(&written).unwrap(), note);
+
+        let rest = Note::from_xml_tag(&note_tag("<note><rest/><duration>4</duration></note>")).unwrap();
+        assert_eq!(rest.content, NoteContent::Rest);
+        assert!(!rest.chord);
+        assert_eq!(rest.to_xml_tag("note").children[0].name, "rest");
+    }
+
+    #[test]
+    fn errors_name_the_element() {
+        let err = Note::from_xml_tag(&note_tag("<note><pitch><step>C</step><octave>high</octave></pitch><duration>1</duration></note>"))
+            .unwrap_err();
+        assert_eq!(
+            err.chain().map(|cause| { cause.to_string() }).collect::<Vec<_>>(),
+            vec!["Can't parse <pitch> in <note>", "Can't parse <octave> in <pitch>", "\"high\": invalid digit found in string"]
+        );
+        let err = Note::from_xml_tag(&note_tag("<note><rest/></note>")).unwrap_err();
+        assert_eq!(err.to_string(), "Can't find <duration> in <note>");
+        let err = Lyric::from_xml_tag(&note_tag("<lyric number=\"x\"><text/></lyric>")).unwrap_err();
+        assert_eq!(err.to_string(), "Can't parse attribute number of <lyric>");
+        assert!(NoteContent::from_xml_tag(&note_tag("<unpitched/>")).is_err());
+        let err = Note::from_xml_tag(&note_tag("<note><unpitched/><duration>1</duration></note>")).unwrap_err();
+        assert_eq!(err.to_string(), "Can't find a choice of element in <note>");
+        assert_eq!(u8::from_xml_tag(&note_tag("<staff> 3 </staff>")).unwrap(), 3);
+    }
+}
@@ -0,0 +1,504 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fmt::{Display, Formatter};
+use std::hash::{Hash, Hasher};
+use anyhow::anyhow;
+use crate::tag::{XmlAttrib, XmlNodeKind, XmlString, XmlTag};
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum XmlChangeKind {
+    Inserted(XmlTag),
+    Deleted(XmlTag),
+    /// Same subtree, now at another position among the children of its parent
+    Moved { from: usize, to: usize },
+    /// A namespace declaration counts as an `xmlns` or `xmlns:prefix` attribute
+    AttribChanged { name: XmlString, old: Option<XmlString>, new: Option<XmlString> },
+    ValueChanged { old: Option<XmlString>, new: Option<XmlString> }
+}
+
+/// One difference between two trees
+#[derive(Clone, Debug, PartialEq)]
+pub struct XmlChange {
+    /// Like the paths of parse errors, eg. `score-partwise/part[1]/measure[3]/note[2]`;
+    /// in the old tree for deletions, in the new tree otherwise
+    pub path: String,
+    pub kind: XmlChangeKind
+}
+
+impl Display for XmlChange {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match &self.kind {
+            XmlChangeKind::Inserted(_) => write!(f, "+ {}", self.path),
+            XmlChangeKind::Deleted(_) => write!(f, "- {}", self.path),
+            XmlChangeKind::Moved { from, to } => write!(f, "> {} moved from child {} to {}", self.path, from, to),
+            XmlChangeKind::AttribChanged { name, old, new } => write!(f, "~ {} @{}: {:?} -> {:?}", self.path, name, old, new),
+            XmlChangeKind::ValueChanged { old, new } => write!(f, "~ {}: {:?} -> {:?}", self.path, old, new)
+        }
+    }
+}
+
+/// Turns the tree it was computed from into the other one, see `XmlTag::diff`
+#[derive(Clone, Debug, PartialEq)]
+pub enum XmlPatch {
+    Replace(XmlTag),
+    /// Fields left None are kept as they are
+    Edit {
+        /// Qualified name of the element the patch is for
+        name: XmlString,
+        attribs: Option<Vec<XmlAttrib>>,
+        namespace_decls: Option<BTreeMap<XmlString, XmlString>>,
+        value: Option<Option<XmlString>>,
+        children: Option<Vec<XmlChildPatch>>
+    }
+}
+
+/// Where a child of the patched element comes from, in the new order
+#[derive(Clone, Debug, PartialEq)]
+pub enum XmlChildPatch {
+    Keep { old_index: usize, patch: Option<XmlPatch> },
+    Insert(XmlTag)
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct XmlDiff {
+    pub changes: Vec<XmlChange>,
+    /// None when the trees are the same
+    pub patch: Option<XmlPatch>
+}
+
+impl XmlDiff {
+    pub fn is_empty(&self) -> bool {
+        self.patch.is_none()
+    }
+}
+
+impl XmlPatch {
+    /// Patches `tag`, which has to be the tree the patch was computed from; `tag` is left unchanged on error
+    pub fn apply(&self, tag: &mut XmlTag) -> anyhow::Result<()> {
+        let mut patched = tag.clone();
+        self.apply_in_place(&mut patched)?;
+        *tag = patched;
+        Ok(())
+    }
+
+    fn apply_in_place(&self, tag: &mut XmlTag) -> anyhow::Result<()> {
+        let (name, attribs, namespace_decls, value, children) = match self {
+            XmlPatch::Replace(new) => {
+                *tag = new.clone();
+                return Ok(());
+            }
+            XmlPatch::Edit { name, attribs, namespace_decls, value, children } =>
+                (name, attribs, namespace_decls, value, children)
+        };
+        if label(tag) != *name {
+            return Err(anyhow!("Patch for <{}> applied to <{}>", name, label(tag)));
+        }
+        if let Some(attribs) = attribs {
+            tag.attribs = attribs.clone();
+        }
+        if let Some(namespace_decls) = namespace_decls {
+            tag.namespace_decls = namespace_decls.clone();
+        }
+        if let Some(value) = value {
+            tag.value = value.clone();
+        }
+        if let Some(children) = children {
+            let mut old_children: Vec<_> = std::mem::take(&mut tag.children)
+                .into_iter()
+                .map(Some)
+                .collect();
+            for child_patch in children {
+                match child_patch {
+                    XmlChildPatch::Keep { old_index, patch } => {
+                        let mut child = old_children
+                            .get_mut(*old_index)
+                            .and_then(Option::take)
+                            .ok_or_else(|| {
+                                anyhow!("Patch expects a child {} in <{}>, missing or taken twice", old_index, name)
+                            })?;
+                        if let Some(patch) = patch {
+                            patch.apply_in_place(&mut child)?;
+                        }
+                        tag.children.push(child);
+                    }
+                    XmlChildPatch::Insert(child) => tag.children.push(child.clone())
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl XmlTag {
+    /// What changed from this tree to `other`, and the patch doing it.
+    /// Children are first matched as unchanged subtrees in order, the others by name and attributes,
+    /// then by name, in order; unchanged subtrees found elsewhere among the siblings have moved.
+    pub fn diff(&self, other: &XmlTag) -> XmlDiff
+    {
+        let mut changes = Vec::new();
+        let path = label(self);
+        let patch = match match_key(self, true) == match_key(other, true) {
+            true => diff_tags(self, other, &path, &mut changes),
+            false => {
+                changes.push(XmlChange { path, kind: XmlChangeKind::Deleted(self.clone()) });
+                changes.push(XmlChange { path: label(other), kind: XmlChangeKind::Inserted(other.clone()) });
+                Some(XmlPatch::Replace(other.clone()))
+            }
+        };
+        XmlDiff { changes, patch }
+    }
+}
+
+/// Name in paths: the qualified name of an element, `text()`, `comment()`, ... for the other nodes
+fn label(tag: &XmlTag) -> XmlString {
+    match tag.kind {
+        XmlNodeKind::Element => tag.qualified_name(),
+        XmlNodeKind::Text | XmlNodeKind::CData => "text()".to_string(),
+        XmlNodeKind::Comment => "comment()".to_string(),
+        XmlNodeKind::ProcessingInstruction => format!("processing-instruction('{}')", tag.name)
+    }
+}
+
+/// What two children need in common to be paired: the label, and the attributes or content if `strict`
+fn match_key(tag: &XmlTag, strict: bool) -> (XmlNodeKind, XmlString, Option<XmlString>, Vec<(XmlString, &str)>) {
+    let namespace = tag.namespace.clone();
+    match (tag.is_element(), strict) {
+        (true, true) => (
+            tag.kind,
+            label(tag),
+            namespace,
+            tag.attribs.iter().map(|attrib| { (attrib.qualified_name(), attrib.value.as_str()) }).collect()
+        ),
+        (false, true) => (tag.kind, label(tag), tag.value.clone(), Vec::new()),
+        (_, false) => (tag.kind, label(tag), namespace, Vec::new())
+    }
+}
+
+fn subtree_hash(tag: &XmlTag) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    (tag.kind, label(tag), &tag.namespace, &tag.value, &tag.namespace_decls).hash(&mut hasher);
+    for attrib in tag.attribs.iter() {
+        (&attrib.name, &attrib.prefix, &attrib.namespace, &attrib.value).hash(&mut hasher);
+    }
+    for child in tag.children.iter() {
+        subtree_hash(child).hash(&mut hasher);
+    }
+    hasher.finish()
+}
+
+/// Paths of children, each numbered among the same labels before it
+fn child_paths(parent_path: &str, children: &[XmlTag]) -> Vec<String> {
+    let mut counts: HashMap<XmlString, usize> = HashMap::new();
+    children
+        .iter()
+        .map(|child| {
+            let name = label(child);
+            let count = counts.entry(name.clone()).or_insert(0);
+            *count += 1;
+            format!("{}/{}[{}]", parent_path, name, count)
+        })
+        .collect()
+}
+
+/// Longest common subsequence of two hash sequences, as index pairs in order
+fn common_subsequence(old: &[u64], new: &[u64]) -> Vec<(usize, usize)> {
+    let prefix = old.iter().zip(new.iter()).take_while(|(lhs, rhs)| { lhs == rhs }).count();
+    let suffix = old[prefix..].iter().rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(lhs, rhs)| { lhs == rhs })
+        .count();
+    let (old_mid, new_mid) = (&old[prefix..old.len() - suffix], &new[prefix..new.len() - suffix]);
+
+    // lengths[i][j]: longest common subsequence of old_mid[i..] and new_mid[j..]
+    let mut lengths = vec![vec![0u32; new_mid.len() + 1]; old_mid.len() + 1];
+    for i in (0..old_mid.len()).rev() {
+        for j in (0..new_mid.len()).rev() {
+            lengths[i][j] = match old_mid[i] == new_mid[j] {
+                true => lengths[i + 1][j + 1] + 1,
+                false => lengths[i + 1][j].max(lengths[i][j + 1])
+            };
+        }
+    }
+    let mut pairs: Vec<_> = (0..prefix).map(|idx| { (idx, idx) }).collect();
+    let (mut i, mut j) = (0, 0);
+    while i < old_mid.len() && j < new_mid.len() {
+        if old_mid[i] == new_mid[j] {
+            pairs.push((prefix + i, prefix + j));
+            i += 1;
+            j += 1;
+        } else if lengths[i + 1][j] >= lengths[i][j + 1] {
+            i += 1;
+        } else {
+            j += 1;
+        }
+    }
+    pairs.extend((0..suffix).map(|idx| { (old.len() - suffix + idx, new.len() - suffix + idx) }));
+    pairs
+}
+
+/// Pairs in order the children of the gaps between unchanged subtrees, by `match_key`
+fn pair_in_gap(old: &XmlTag, new: &XmlTag, old_range: (usize, usize), new_range: (usize, usize),
+               old_to_new: &mut [Option<usize>], new_to_old: &mut [Option<usize>])
+{
+    for strict in [true, false].iter() {
+        let mut next_old = old_range.0;
+        for (j, paired) in new_to_old.iter_mut().enumerate().take(new_range.1).skip(new_range.0) {
+            if paired.is_some() {
+                continue;
+            }
+            let key = match_key(&new.children[j], *strict);
+            let found = (next_old..old_range.1)
+                .find(|i| { old_to_new[*i].is_none() && match_key(&old.children[*i], *strict) == key });
+            if let Some(i) = found {
+                old_to_new[i] = Some(j);
+                *paired = Some(i);
+                next_old = i + 1;
+            }
+        }
+    }
+}
+
+/// Indices, in `old_indices`, of a longest increasing subsequence
+fn increasing_subsequence(old_indices: &[usize]) -> Vec<usize> {
+    // tails[k]: position of the smallest tail of an increasing run of length k + 1
+    let mut tails: Vec<usize> = Vec::new();
+    let mut previous = vec![None; old_indices.len()];
+    for (pos, value) in old_indices.iter().enumerate() {
+        let k = tails.partition_point(|tail| { old_indices[*tail] < *value });
+        previous[pos] = k.checked_sub(1).map(|prev| { tails[prev] });
+        match k == tails.len() {
+            true => tails.push(pos),
+            false => tails[k] = pos
+        }
+    }
+    let mut run = Vec::new();
+    let mut pos = tails.last().cloned();
+    while let Some(current) = pos {
+        run.push(current);
+        pos = previous[current];
+    }
+    run.reverse();
+    run
+}
+
+fn diff_tags(old: &XmlTag, new: &XmlTag, path: &str, changes: &mut Vec<XmlChange>) -> Option<XmlPatch>
+{
+    let mut attribs_changed = false;
+    for attrib in old.attribs.iter() {
+        let new_value = new.attribs
+            .iter()
+            .find(|new_attrib| { new_attrib.qualified_name() == attrib.qualified_name() })
+            .map(|new_attrib| { new_attrib.value.clone() });
+        if new_value.as_ref() != Some(&attrib.value) {
+            attribs_changed = true;
+            changes.push(XmlChange {
+                path: path.to_string(),
+                kind: XmlChangeKind::AttribChanged { name: attrib.qualified_name(), old: Some(attrib.value.clone()), new: new_value }
+            });
+        }
+    }
+    for attrib in new.attribs.iter() {
+        if !old.attribs.iter().any(|old_attrib| { old_attrib.qualified_name() == attrib.qualified_name() }) {
+            attribs_changed = true;
+            changes.push(XmlChange {
+                path: path.to_string(),
+                kind: XmlChangeKind::AttribChanged { name: attrib.qualified_name(), old: None, new: Some(attrib.value.clone()) }
+            });
+        }
+    }
+    // a reordering alone is no change, the patch still keeps the new order
+    let reordered = old.attribs.len() == new.attribs.len()
+        && old.attribs.iter().zip(new.attribs.iter()).any(|(lhs, rhs)| { lhs.qualified_name() != rhs.qualified_name() });
+
+    let prefixes: BTreeSet<_> = old.namespace_decls.keys().chain(new.namespace_decls.keys()).collect();
+    for prefix in prefixes {
+        let (old_uri, new_uri) = (old.namespace_decls.get(prefix), new.namespace_decls.get(prefix));
+        if old_uri != new_uri {
+            changes.push(XmlChange {
+                path: path.to_string(),
+                kind: XmlChangeKind::AttribChanged { name: xmlns_name(prefix), old: old_uri.cloned(), new: new_uri.cloned() }
+            });
+        }
+    }
+    let namespace_decls_changed = old.namespace_decls != new.namespace_decls;
+
+    let value_changed = old.value != new.value;
+    if value_changed {
+        changes.push(XmlChange {
+            path: path.to_string(),
+            kind: XmlChangeKind::ValueChanged { old: old.value.clone(), new: new.value.clone() }
+        });
+    }
+
+    let children = diff_children(old, new, path, changes);
+    if !attribs_changed && !reordered && !namespace_decls_changed && !value_changed && children.is_none() {
+        return None;
+    }
+    Some(XmlPatch::Edit {
+        name: label(new),
+        attribs: Some(new.attribs.clone()).filter(|_| { attribs_changed || reordered }),
+        namespace_decls: Some(new.namespace_decls.clone()).filter(|_| { namespace_decls_changed }),
+        value: Some(new.value.clone()).filter(|_| { value_changed }),
+        children
+    })
+}
+
+fn xmlns_name(prefix: &str) -> XmlString {
+    match prefix.is_empty() {
+        true => "xmlns".to_string(),
+        false => format!("xmlns:{}", prefix)
+    }
+}
+
+fn diff_children(old: &XmlTag, new: &XmlTag, path: &str, changes: &mut Vec<XmlChange>) -> Option<Vec<XmlChildPatch>>
+{
+    let old_hashes: Vec<_> = old.children.iter().map(subtree_hash).collect();
+    let new_hashes: Vec<_> = new.children.iter().map(subtree_hash).collect();
+    let mut old_to_new = vec![None; old.children.len()];
+    let mut new_to_old = vec![None; new.children.len()];
+
+    let anchors = common_subsequence(&old_hashes, &new_hashes);
+    for (i, j) in anchors.iter() {
+        old_to_new[*i] = Some(*j);
+        new_to_old[*j] = Some(*i);
+    }
+    let mut gap_start = (0, 0);
+    for (i, j) in anchors.iter().cloned().chain(std::iter::once((old.children.len(), new.children.len()))) {
+        pair_in_gap(old, new, (gap_start.0, i), (gap_start.1, j), &mut old_to_new, &mut new_to_old);
+        gap_start = (i + 1, j + 1);
+    }
+    // unchanged subtrees that went elsewhere
+    for j in 0..new.children.len() {
+        if new_to_old[j].is_none() {
+            if let Some(i) = (0..old.children.len()).find(|i| { old_to_new[*i].is_none() && old_hashes[*i] == new_hashes[j] }) {
+                old_to_new[i] = Some(j);
+                new_to_old[j] = Some(i);
+            }
+        }
+    }
+
+    let old_paths = child_paths(path, &old.children);
+    let new_paths = child_paths(path, &new.children);
+    for (i, paired) in old_to_new.iter().enumerate() {
+        if paired.is_none() {
+            changes.push(XmlChange { path: old_paths[i].clone(), kind: XmlChangeKind::Deleted(old.children[i].clone()) });
+        }
+    }
+    let kept: Vec<_> = new_to_old.iter().filter_map(|paired| { *paired }).collect();
+    let in_order: HashSet<_> = increasing_subsequence(&kept).into_iter().map(|pos| { kept[pos] }).collect();
+
+    let mut child_patches = Vec::with_capacity(new.children.len());
+    for (j, paired) in new_to_old.iter().enumerate() {
+        match paired {
+            Some(i) => {
+                if !in_order.contains(i) {
+                    changes.push(XmlChange { path: new_paths[j].clone(), kind: XmlChangeKind::Moved { from: *i, to: j } });
+                }
+                let patch = match old_hashes[*i] == new_hashes[j] {
+                    true => None,
+                    false => diff_tags(&old.children[*i], &new.children[j], &new_paths[j], changes)
+                };
+                child_patches.push(XmlChildPatch::Keep { old_index: *i, patch });
+            }
+            None => {
+                changes.push(XmlChange { path: new_paths[j].clone(), kind: XmlChangeKind::Inserted(new.children[j].clone()) });
+                child_patches.push(XmlChildPatch::Insert(new.children[j].clone()));
+            }
+        }
+    }
+    let unchanged = old.children.len() == new.children.len()
+        && child_patches.iter().enumerate().all(|(j, child_patch)| {
+            matches!(child_patch, XmlChildPatch::Keep { old_index, patch: None } if *old_index == j)
+        });
+    match unchanged {
+        true => None,
+        false => Some(child_patches)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::diff::{XmlChange, XmlChangeKind, XmlChildPatch, XmlPatch};
+    use crate::tag::XmlTag;
+
+    fn tree(source: &str) -> XmlTag {
+        XmlTag::from_read(source.as_bytes()).unwrap()
+    }
+
+    fn change_lines(changes: &[XmlChange]) -> Vec<String> {
+        changes.iter().map(|change| { change.to_string() }).collect()
+    }
+
+    #[test]
+    fn reports_and_patches_changes() {
+        let old = tree(r#"<part id="P1">
+            <measure number="1"><note><step>C</step></note><note><step>D</step></note></measure>
+            <measure number="2"><direction>f</direction><note><step>E</step></note></measure>
+            <measure number="3"><note color="red"><step>F</step></note></measure>
+            <measure number="4"/>
+          </part>"#);
+        let new = tree(r#"<part id="P1">
+            <measure number="1"><note><step>C</step></note><note><step>G</step></note></measure>
+            <measure number="2"><note><step>E</step></note><direction>f</direction></measure>
+            <measure number="3"><note color="blue" print-object="no"><step>F</step></note><barline/></measure>
+          </part>"#);
+
+        let diff = old.diff(&new);
+        assert_eq!(change_lines(&diff.changes), vec![
+            "- part/measure[4]",
+            "~ part/measure[1]/note[2]/step[1]: Some(\"D\") -> Some(\"G\")",
+            "> part/measure[2]/note[1] moved from child 1 to 0",
+            "~ part/measure[3]/note[1] @color: Some(\"red\") -> Some(\"blue\")",
+            "~ part/measure[3]/note[1] @print-object: None -> Some(\"no\")",
+            "+ part/measure[3]/barline[1]"
+        ]);
+
+        let mut patched = old.clone();
+        diff.patch.as_ref().unwrap().apply(&mut patched).unwrap();
+        assert_eq!(patched.to_canonical_string().unwrap(), new.to_canonical_string().unwrap());
+        assert!(new.diff(&patched).is_empty());
+        assert!(old.diff(&old).changes.is_empty());
+
+        let reverse = new.diff(&old);
+        let mut unpatched = new.clone();
+        reverse.patch.unwrap().apply(&mut unpatched).unwrap();
+        assert_eq!(unpatched.to_canonical_string().unwrap(), old.to_canonical_string().unwrap());
+    }
+
+    #[test]
+    fn processing_instructions_differ_by_target() {
+        let old = tree("<r><?a x?></r>");
+        let new = tree("<r><?b x?></r>");
+        let diff = old.diff(&new);
+        assert_eq!(change_lines(&diff.changes), vec![
+            "- r/processing-instruction('a')[1]",
+            "+ r/processing-instruction('b')[1]"
+        ]);
+        let mut patched = old.clone();
+        diff.patch.unwrap().apply(&mut patched).unwrap();
+        assert!(new.diff(&patched).is_empty());
+    }
+
+    #[test]
+    fn patch_only_fits_its_tree() {
+        let old = tree("<a><b/><c/></a>");
+        let diff = old.diff(&tree("<a><c/><d/></a>"));
+        assert_eq!(change_lines(&diff.changes), vec!["- a/b[1]", "+ a/d[1]"]);
+        assert!(matches!(
+            &diff.patch,
+            Some(XmlPatch::Edit { children: Some(children), .. })
+                if children[0] == XmlChildPatch::Keep { old_index: 1, patch: None }
+        ));
+
+        let mut other = tree("<a><c/></a>");
+        assert!(diff.patch.as_ref().unwrap().apply(&mut other).is_err());
+        assert_eq!(other.children.len(), 1);
+        assert!(diff.patch.unwrap().apply(&mut tree("<z/>")).is_err());
+
+        let renamed = old.diff(&tree("<z/>"));
+        assert!(matches!(renamed.changes[0].kind, XmlChangeKind::Deleted(_)));
+        let mut replaced = old.clone();
+        renamed.patch.unwrap().apply(&mut replaced).unwrap();
+        assert_eq!(replaced.name, "z");
+    }
+}
@@ -0,0 +1,208 @@
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
+use xml::common::Position;
+use xml::reader::XmlEvent;
+use crate::tag::XmlString;
+
+/// 1-based line and column in the source document
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct XmlPosition {
+    pub line: u64,
+    pub column: u64
+}
+
+impl Display for XmlPosition {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.line, self.column)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum XmlParseErrorKind {
+    /// An end tag that does not close the innermost open element
+    MismatchedEndTag { expected: XmlString, found: XmlString },
+    /// The document stops while `expected` is still open
+    UnexpectedEnd { expected: XmlString },
+    NoRootElement,
+    /// Any other malformation reported by xml-rs
+    Syntax(String),
+    Io(String)
+}
+
+/// Why and where reading a document failed
+#[derive(Clone, Debug, PartialEq)]
+pub struct XmlParseError {
+    pub kind: XmlParseErrorKind,
+    pub position: Option<XmlPosition>,
+    /// Innermost element open at the error, eg. `score-partwise/part[2]/measure[14]/note[3]`
+    pub path: String
+}
+
+impl XmlParseError {
+    pub(crate) fn new(kind: XmlParseErrorKind, position: Option<XmlPosition>, path: &ElementPath) -> Self {
+        XmlParseError { kind, position, path: path.to_string() }
+    }
+
+    /// Classifies an xml-rs error, naming the open element from `path` where xml-rs only has a message
+    pub(crate) fn from_reader_error(err: &xml::reader::Error, path: &ElementPath) -> Self {
+        let position = XmlPosition {
+            line: err.position().row + 1,
+            column: err.position().column + 1
+        };
+        let kind = match err.kind() {
+            xml::reader::ErrorKind::Io(io_err) => XmlParseErrorKind::Io(io_err.to_string()),
+            xml::reader::ErrorKind::UnexpectedEof => XmlParseErrorKind::UnexpectedEnd {
+                expected: path.innermost().unwrap_or_default().to_string()
+            },
+            _ => {
+                let msg = err.msg();
+                if let Some(found) = msg
+                    .strip_prefix("Unexpected closing tag: ")
+                    .and_then(|rest| { rest.split(", expected ").next() })
+                {
+                    XmlParseErrorKind::MismatchedEndTag {
+                        expected: path.innermost().unwrap_or_default().to_string(),
+                        found: found.to_string()
+                    }
+                } else if msg.starts_with("Unexpected end of stream") && path.innermost().is_some() {
+                    XmlParseErrorKind::UnexpectedEnd { expected: path.innermost().unwrap_or_default().to_string() }
+                } else if msg.starts_with("Unexpected end of stream") {
+                    XmlParseErrorKind::NoRootElement
+                } else {
+                    XmlParseErrorKind::Syntax(msg.to_string())
+                }
+            }
+        };
+        XmlParseError { kind, position: Some(position), path: path.to_string() }
+    }
+}
+
+impl Display for XmlParseError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if let Some(position) = self.position {
+            write!(f, "{} ", position)?;
+        }
+        if !self.path.is_empty() {
+            write!(f, "in {}: ", self.path)?;
+        }
+        match &self.kind {
+            XmlParseErrorKind::MismatchedEndTag { expected, found } => write!(f, "expected </{}>, found </{}>", expected, found),
+            XmlParseErrorKind::UnexpectedEnd { expected } => write!(f, "document ends before </{}>", expected),
+            XmlParseErrorKind::NoRootElement => write!(f, "no root element"),
+            XmlParseErrorKind::Syntax(msg) => write!(f, "{}", msg),
+            XmlParseErrorKind::Io(msg) => write!(f, "io error: {}", msg)
+        }
+    }
+}
+
+impl std::error::Error for XmlParseError {}
+
+/// Open elements, each numbered among the same-named siblings before it
+pub(crate) struct ElementPath {
+    steps: Vec<(XmlString, usize)>,
+    sibling_counts: Vec<BTreeMap<XmlString, usize>>
+}
+
+impl Default for ElementPath {
+    fn default() -> Self {
+        ElementPath { steps: Vec::new(), sibling_counts: vec![BTreeMap::new()] }
+    }
+}
+
+impl ElementPath {
+    /// Follows start and end elements, call it on every event read
+    pub(crate) fn update(&mut self, event: &XmlEvent) {
+        match event {
+            XmlEvent::StartElement { name, .. } => {
+                let name = match &name.prefix {
+                    Some(prefix) => format!("{}:{}", prefix, name.local_name),
+                    None => name.local_name.clone()
+                };
+                let count = self.sibling_counts
+                    .last_mut()
+                    .map(|counts| { counts.entry(name.clone()).or_insert(0) })
+                    .map(|count| { *count += 1; *count })
+                    .unwrap_or(1);
+                self.steps.push((name, count));
+                self.sibling_counts.push(BTreeMap::new());
+            }
+            XmlEvent::EndElement { .. } => {
+                self.steps.pop();
+                self.sibling_counts.pop();
+            }
+            _ => {}
+        }
+    }
+
+    pub(crate) fn innermost(&self) -> Option<&str> {
+        self.steps.last().map(|(name, _)| { name.as_str() })
+    }
+}
+
+impl Display for ElementPath {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        for (depth, (name, count)) in self.steps.iter().enumerate() {
+            match depth {
+                0 => write!(f, "{}", name)?, // the root is alone
+                _ => write!(f, "/{}[{}]", name, count)?
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::{XmlParseError, XmlParseErrorKind};
+    use crate::stream::XmlSubtreeStream;
+    use crate::tag::XmlTag;
+
+    fn parse_error(source: &str) -> XmlParseError {
+        XmlTag::from_read(source.as_bytes())
+            .err()
+            .and_then(|err| { err.downcast::<XmlParseError>().ok() })
+            .unwrap()
+    }
+
+    #[test]
+    fn errors_locate_the_element() {
+        let measures = "<measure/>".repeat(13);
+        let source = format!(
+            "<score-partwise>\n<part id=\"P1\"/>\n<part id=\"P2\">{}<measure><note/><note/><note><pitch></note></measure></part></score-partwise>",
+            measures
+        );
+        let err = parse_error(source.as_str());
+        assert_eq!(err.kind, XmlParseErrorKind::MismatchedEndTag { expected: "pitch".to_string(), found: "note".to_string() });
+        assert_eq!(err.path, "score-partwise/part[2]/measure[14]/note[3]/pitch[1]");
+        assert_eq!(err.position.map(|pos| { pos.line }), Some(3));
+
+        let err = parse_error("<a><b><c/>");
+        assert_eq!(err.kind, XmlParseErrorKind::UnexpectedEnd { expected: "b".to_string() });
+        assert_eq!(err.path, "a/b[1]");
+
+        assert_eq!(parse_error("").kind, XmlParseErrorKind::NoRootElement);
+        let err = parse_error("<a><b>&bogus;</b></a>");
+        assert!(matches!(err.kind, XmlParseErrorKind::Syntax(_)));
+        assert_eq!(err.path, "a/b[1]");
+        assert!(matches!(parse_error("<!-- unclosed").kind, XmlParseErrorKind::Syntax(_)));
+
+        let streamed_err = XmlSubtreeStream::from_read("<a><b/><b><c></b></a>".as_bytes(), &["b"])
+            .filter_map(|subtree| { subtree.err() })
+            .next()
+            .unwrap();
+        let streamed_err = streamed_err.downcast::<XmlParseError>().unwrap();
+        assert_eq!(streamed_err.path, "a/b[2]/c[1]");
+        assert_eq!(streamed_err.to_string(), "1:17 in a/b[2]/c[1]: expected </c>, found </b>");
+    }
+
+    #[test]
+    fn truncated_documents_never_panic() {
+        let source = std::fs::read("test/longduongs_tied.musicxml").unwrap();
+        let cuts = (0..200).chain((200..source.len() - 20).step_by(source.len() / 50));
+        for length in cuts {
+            assert!(XmlTag::from_read(&source[..length]).is_err());
+            let streamed: Vec<_> = XmlSubtreeStream::from_read(&source[..length], &["measure"]).collect();
+            assert!(streamed.last().unwrap().is_err());
+        }
+    }
+}
@@ -1,240 +1,571 @@
 pub use super::tag::*;
-use xml::reader::{XmlEvent::*, Events};
+use xml::reader::{XmlEvent, XmlEvent::*, Events};
 use core::iter::Peekable;
 use std::borrow::Cow;
-use std::io::{Read, Write};
-use std::fmt::{Debug, Display, Formatter};
+use std::io::{BufRead, BufReader, BufWriter, Cursor, ErrorKind, Read, Write};
+use std::fmt::{Debug, Formatter};
 use std::fs::File;
-use anyhow::Error;
+use anyhow::{Context, Error};
+use crate::error::{ElementPath, XmlParseError, XmlParseErrorKind, XmlPosition};
 use xml::writer::{EventWriter, EmitterConfig, XmlEvent as WriterXmlEvent};
 use xml::{ParserConfig};
-use xml::common::XmlVersion;
-use xml::namespace::Namespace;
+use xml::attribute::OwnedAttribute;
+use xml::name::OwnedName;
+use xml::namespace::{Namespace, NamespaceStack};
 
-#[derive(Debug)]
-enum AdaIoErr {
-    OpenFileErr,
-    ParseFileErr
+/// Configuration every reader of this crate parses with
+pub(crate) fn parser_config() -> ParserConfig
+{
+    // whitespace is sorted out by TreeBuilder, it matters in mixed content
+    ParserConfig::new()
+        .trim_whitespace(false)
+        .ignore_comments(false)
 }
 
-impl Display for AdaIoErr {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        f.write_fmt(
-            format_args!("{}",
-            match self {
-                Self::OpenFileErr => "OpenFileErr",
-                Self::ParseFileErr => "ParseFileErr"
-            })
-        )
+/// Order attributes are written in
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum XmlAttribOrder {
+    /// As they are in `XmlTag::attribs`
+    Preserve,
+    /// By namespace, then name
+    Sorted
+}
+
+/// How text is protected from being read as markup
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum XmlEscaping {
+    /// `<`, `&`, ... become entities
+    Entities,
+    /// Like `Entities`, except that text holding markup characters goes into a CDATA section
+    CData,
+    /// Written as is, for values known to be well formed already
+    Raw
+}
+
+/// How a tree is written
+#[derive(Clone, Debug, PartialEq)]
+pub struct XmlWriteOptions {
+    /// Written once per depth before each element; None puts the whole document on one line
+    pub indent: Option<String>,
+    pub attrib_order: XmlAttribOrder,
+    /// `<a/>` rather than `<a></a>` for elements without content
+    pub self_closing: bool,
+    pub escaping: XmlEscaping,
+    /// The xml declaration and the prolog of the `XmlMetaData`
+    pub declaration: bool,
+    pub comments: bool,
+    /// CDATA sections become escaped text
+    pub cdata_as_text: bool
+}
+
+impl Default for XmlWriteOptions {
+    fn default() -> Self {
+        Self::indented()
     }
 }
 
-impl std::error::Error for AdaIoErr {}
+impl XmlWriteOptions {
+    pub fn indented() -> Self {
+        XmlWriteOptions {
+            indent: Some("  ".to_string()),
+            attrib_order: XmlAttribOrder::Preserve,
+            self_closing: true,
+            escaping: XmlEscaping::Entities,
+            declaration: true,
+            comments: true,
+            cdata_as_text: false
+        }
+    }
 
-impl XmlTag
+    pub fn compact() -> Self {
+        XmlWriteOptions { indent: None, ..Self::indented() }
+    }
+
+    /// Close to Canonical XML without comments: no declaration nor DOCTYPE, sorted attributes,
+    /// explicit end tags, CDATA as text and no added whitespace, so equal trees give equal bytes
+    pub fn canonical() -> Self {
+        XmlWriteOptions {
+            indent: None,
+            attrib_order: XmlAttribOrder::Sorted,
+            self_closing: false,
+            escaping: XmlEscaping::Entities,
+            declaration: false,
+            comments: false,
+            cdata_as_text: true
+        }
+    }
+
+    /// Writer settings matching the options, as used by `XmlTag::to_writer_with_options`
+    pub fn emitter_config(&self) -> EmitterConfig {
+        let mut config = EmitterConfig::new()
+            .perform_indent(self.indent.is_some())
+            .normalize_empty_elements(self.self_closing)
+            .write_document_declaration(self.declaration)
+            .cdata_to_characters(self.cdata_as_text);
+        config.perform_escaping = self.escaping != XmlEscaping::Raw;
+        if let Some(indent) = self.indent.as_ref() {
+            config = config.indent_string(indent.clone());
+        }
+        config
+    }
+}
+
+/// Namespaces in scope before the root element
+pub(crate) fn base_namespace() -> Namespace
 {
-    pub fn from_reader<T: Read>(mut events: Peekable<Events<T>>)
-        -> anyhow::Result<XmlTag>
-    {
-        // TODO: Take care of StartDocument here
-        fn _recursive_build<T: Read>(events: &mut Peekable<Events<T>>)
-            -> Option<XmlTag>
-        {
-            /**
-            Working mechanism:
-            1. pull tag, prefill info to XmlTag.
-            2. peek next tag.
-                * if next tag closes the current one then return vec
-                * if next tag is something else, DON'T pull, push to vec the result of _recursive_build
-                on the next tag.
-                after doing this, peek again to see if we can close ourselves, if not then continue until
-                we can close ourself and return
-
-            Note: if _recursive_build returns Vec of length 1 containing Characters then incorporate that
-            into the value field of the parent's Tag for convenience
-             */
-            let mut current_tag: XmlTag = Default::default();
-            let mut children: Vec<_> = Vec::with_capacity(10);
-            let pull_result = events.next();
-            if pull_result.is_none() { return None; }
-
-            let current_event = pull_result.unwrap().unwrap();
-            match current_event {
-                StartElement {name, attributes, ..} => {
-                    current_tag = XmlTag {
-                        name: name.local_name.to_string(),
-                        value: None,
-                        attribs: {
-                            let mut xml_attribs = Vec::new();
-                            xml_attribs
-                            .extend(
-                                attributes
-                                .iter()
-                                .map(
-                                    |xml_attr| {
-                                        XmlAttrib {
-                                            name: xml_attr.name.local_name.to_string(),
-                                            value: xml_attr.value.to_string()
-                                        }
-                                    }
-                                )
-                            );
-                            xml_attribs
-                        },
-                        children: Vec::with_capacity(10)
-                    };
-                }
-                Characters(value) | CData(value) => {
-                    current_tag = XmlTag {
-                        value: {if value.is_empty() {None} else {Some(value)}},
-                        ..current_tag
-                    };
-                    return Some(current_tag);
+    NamespaceStack::default().squash()
+}
+
+/// Tag holding the name and attributes of a start element, without children yet.
+/// Only the namespaces not already bound the same way in `parent_scope` are recorded as declared here.
+pub(crate) fn tag_from_start(
+    name: &OwnedName,
+    attributes: &[OwnedAttribute],
+    scope: &Namespace,
+    parent_scope: &Namespace
+) -> XmlTag
+{
+    XmlTag {
+        kind: XmlNodeKind::Element,
+        name: name.local_name.to_string(),
+        prefix: name.prefix.clone(),
+        namespace: name.namespace.clone(),
+        namespace_decls: scope
+            .0
+            .iter()
+            .filter(|(prefix, uri)| { parent_scope.get(prefix.as_str()) != Some(uri.as_str()) })
+            .map(|(prefix, uri)| { (prefix.clone(), uri.clone()) })
+            .collect(),
+        value: None,
+        attribs: attributes
+            .iter()
+            .map(|xml_attr| {
+                XmlAttrib {
+                    name: xml_attr.name.local_name.to_string(),
+                    prefix: xml_attr.name.prefix.clone(),
+                    namespace: xml_attr.name.namespace.clone(),
+                    value: xml_attr.value.to_string()
                 }
-                EndElement {..} => {
-                    // Logical error because EndElement should be taken care of already when processing
-                    // the StartElement. In short, _recursive_build process EndElement instead of
-                    // calling itself to process this.
-                    unreachable!()
+            })
+            .collect(),
+        children: Vec::new()
+    }
+}
+
+/// Builds a tree from reader events, one event at a time.
+/// Text, CDATA, comments and processing instructions become child nodes, then `normalize_content`
+/// turns a lone text run into the element's value.
+pub(crate) struct TreeBuilder {
+    open_tags: Vec<XmlTag>,
+    scopes: Vec<Namespace>
+}
+
+impl Default for TreeBuilder {
+    fn default() -> Self {
+        TreeBuilder {
+            open_tags: Vec::new(),
+            scopes: vec![base_namespace()]
+        }
+    }
+}
+
+impl TreeBuilder {
+    /// Returns the outermost tag once its end element has been pushed
+    pub(crate) fn push(&mut self, event: XmlEvent) -> Option<XmlTag>
+    {
+        match event {
+            StartElement {name, attributes, namespace} => {
+                let tag = tag_from_start(&name, &attributes, &namespace, self.scopes.last().unwrap());
+                self.open_tags.push(tag);
+                self.scopes.push(namespace);
+            }
+            Characters(text) | Whitespace(text) => self.add_node(XmlTag::text_node(text)),
+            CData(text) => self.add_node(XmlTag::cdata_node(text)),
+            Comment(comment) => self.add_node(XmlTag::comment_node(comment)),
+            ProcessingInstruction {name, data} => self.add_node(XmlTag::processing_instruction_node(name, data)),
+            EndElement {..} => {
+                let mut closed_tag = self.open_tags.pop()?;
+                self.scopes.pop();
+                normalize_content(&mut closed_tag);
+                match self.open_tags.last_mut() {
+                    Some(parent) => parent.children.push(closed_tag),
+                    None => return Some(closed_tag)
                 }
-                StartDocument {..} => {
-                    return _recursive_build(events); // passthrough
+            }
+            _ => {}
+        }
+        None
+    }
+
+    // nodes outside the root element are left to the prolog
+    fn add_node(&mut self, node: XmlTag)
+    {
+        if let Some(current_tag) = self.open_tags.last_mut() {
+            current_tag.children.push(node);
+        }
+    }
+}
+
+/// Whitespace-only text between elements is dropped, unless the element has other text: then it is part of the mixed content.
+/// A single text run becomes the trimmed value, as with simple content like <divisions>4</divisions>.
+fn normalize_content(tag: &mut XmlTag)
+{
+    let has_text = tag.children
+        .iter()
+        .any(|child| {
+            child.kind == XmlNodeKind::CData
+            || (child.kind == XmlNodeKind::Text && child.value.as_ref().is_some_and(|text| { !text.trim().is_empty() }))
+        });
+    if !has_text {
+        // unless it is all there is, eg. <elision> </elision>
+        if tag.children.len() == 1 && tag.children[0].kind == XmlNodeKind::Text {
+            tag.value = tag.children.pop().and_then(|child| { child.value });
+        }
+        tag.children.retain(|child| { !child.is_text() });
+        return;
+    }
+
+    let value = tag.text_of_nodes().trim().to_string();
+    if tag.children.len() == 1 && tag.children[0].kind == XmlNodeKind::Text {
+        tag.children.clear();
+    }
+    tag.value = Some(value);
+}
+
+/// Reads the markup before the root element: xml-rs reports no DOCTYPE, and comments are ignored when parsing.
+/// The bytes consumed, DOCTYPE excepted, are returned along so the parser can be fed them again.
+fn read_prolog<R: BufRead>(source: &mut R) -> Result<(Vec<u8>, Vec<XmlPrologItem>), XmlParseError>
+{
+    fn next_byte<R: BufRead>(source: &mut R, consumed: &mut Vec<u8>) -> Result<u8, XmlParseError>
+    {
+        let mut byte = [0u8];
+        source.read_exact(&mut byte).map_err(|err| { prolog_error(err, consumed) })?;
+        consumed.push(byte[0]);
+        Ok(byte[0])
+    }
+
+    fn prolog_error(err: std::io::Error, consumed: &[u8]) -> XmlParseError
+    {
+        let kind = match err.kind() {
+            ErrorKind::UnexpectedEof => XmlParseErrorKind::Syntax("Document ends in its prolog".to_string()),
+            _ => XmlParseErrorKind::Io(err.to_string())
+        };
+        let line_start = consumed.iter().rposition(|byte| { *byte == b'\n' }).map_or(0, |idx| { idx + 1 });
+        let position = XmlPosition {
+            line: consumed.iter().filter(|byte| { **byte == b'\n' }).count() as u64 + 1,
+            column: (consumed.len() - line_start) as u64 + 1
+        };
+        XmlParseError::new(kind, Some(position), &ElementPath::default())
+    }
+
+    fn read_until<R: BufRead>(source: &mut R, consumed: &mut Vec<u8>, terminator: &[u8]) -> Result<String, XmlParseError>
+    {
+        let start = consumed.len();
+        while !consumed[start..].ends_with(terminator) {
+            next_byte(source, consumed)?;
+        }
+        Ok(String::from_utf8_lossy(&consumed[start..consumed.len() - terminator.len()]).to_string())
+    }
+
+    // up to the '>' closing the declaration, skipping quoted strings and the internal subset
+    fn read_doctype<R: BufRead>(source: &mut R, consumed: &mut Vec<u8>) -> Result<String, XmlParseError>
+    {
+        let start = consumed.len() - 3; // "<!D"
+        let mut quote = None;
+        let mut subset_depth = 0;
+        loop {
+            let byte = next_byte(source, consumed)?;
+            match (quote, byte) {
+                (Some(q), _) if q == byte => quote = None,
+                (Some(_), _) => {},
+                (None, b'"') | (None, b'\'') => quote = Some(byte),
+                (None, b'[') => subset_depth += 1,
+                (None, b']') => subset_depth -= 1,
+                (None, b'>') if subset_depth == 0 => break,
+                _ => {}
+            }
+        }
+        let doctype = String::from_utf8_lossy(&consumed[start..]).to_string();
+        // xml-rs trips on internal subsets, and has nothing to report of the DOCTYPE anyway
+        consumed.truncate(start);
+        Ok(doctype)
+    }
+
+    let mut consumed = Vec::new();
+    let mut prolog = Vec::new();
+    loop {
+        // only peek at what comes next, the root element must be left for the parser
+        let peeked = source.fill_buf().map_err(|err| { prolog_error(err, &consumed) })?;
+        if peeked.starts_with(&[0xEF, 0xBB, 0xBF]) {
+            consumed.extend_from_slice(&[0xEF, 0xBB, 0xBF]);
+            source.consume(3);
+            continue;
+        }
+        match peeked.first() {
+            Some(byte) if byte.is_ascii_whitespace() => {
+                consumed.push(*byte);
+                source.consume(1);
+                continue;
+            }
+            Some(b'<') => {},
+            _ => break
+        }
+
+        let opening = [next_byte(source, &mut consumed)?, next_byte(source, &mut consumed)?];
+        match opening[1] {
+            b'?' => {
+                let content = read_until(source, &mut consumed, b"?>")?;
+                let (name, data) = match content.find(char::is_whitespace) {
+                    Some(idx) => (content[..idx].to_string(), Some(content[idx..].trim().to_string())),
+                    None => (content, None)
+                };
+                // the xml declaration is reported by the parser
+                if name != "xml" {
+                    prolog.push(XmlPrologItem::ProcessingInstruction { name, data });
                 }
-                // These terminal tag can be ignored by returning None;
-                // However, the top level StartDocument must be passed through by returning recursive call.
-                EndDocument => { return None; }
-                ProcessingInstruction {..} | Comment(_) | Whitespace(_) => {
-                    return None;
+            }
+            b'!' => {
+                if next_byte(source, &mut consumed)? == b'-' {
+                    next_byte(source, &mut consumed)?;
+                    prolog.push(XmlPrologItem::Comment(read_until(source, &mut consumed, b"-->")?));
+                } else {
+                    prolog.push(XmlPrologItem::Doctype(read_doctype(source, &mut consumed)?));
                 }
-                _ => {unreachable!()}
             }
-            // Now we focus on getting all children tag
-            // Loop thru all child until the EndElement tag with our name is found
-            while
-                events.peek().is_some() &&
-                {
-                    let xml_event = events.peek().unwrap().as_ref().unwrap();
-                    match xml_event {
-                        EndElement{name} => {
-                            if current_tag.name == name.local_name.as_str() { false }
-                            else { false } // happens when tag closes immediately <tag></tag>
-                        }
-                        _ => true
-                    }
+            // start of the root element
+            _ => break
+        }
+    }
+    Ok((consumed, prolog))
+}
+
+impl XmlTag
+{
+    pub fn from_reader<T: Read>(events: Peekable<Events<T>>)
+        -> anyhow::Result<XmlTag>
+    {
+        // built with an explicit stack, so deep documents don't deepen the call stack
+        let mut builder = TreeBuilder::default();
+        let mut path = ElementPath::default();
+        for event in events {
+            let event = event.map_err(|err| { XmlParseError::from_reader_error(&err, &path) })?;
+            path.update(&event);
+            if let Some(root) = builder.push(event) {
+                return Ok(root);
+            }
+        }
+        Err(Error::from(XmlParseError::new(XmlParseErrorKind::NoRootElement, None, &path)))
+    }
+
+    pub fn from_path(path: &str) -> anyhow::Result<XmlTag>
+    {
+        let f = File::open(path).with_context(|| format!("Can't open {:?}", path))?;
+        XmlTag::from_read(f)
+    }
+
+    /// Parse a whole document from any byte source (file, zip entry, buffer, ...)
+    pub fn from_read<R: Read>(source: R) -> anyhow::Result<XmlTag>
+    {
+        Ok(XmlTag::from_read_with_meta(source)?.0)
+    }
+
+    pub fn from_path_with_meta(path: &str) -> anyhow::Result<(XmlTag, XmlMetaData)>
+    {
+        let f = File::open(path).with_context(|| format!("Can't open {:?}", path))?;
+        XmlTag::from_read_with_meta(f)
+    }
+
+    /// Parse a whole document, along with its xml declaration, DOCTYPE and the other markup before the root
+    pub fn from_read_with_meta<R: Read>(source: R) -> anyhow::Result<(XmlTag, XmlMetaData)>
+    {
+        let mut source = BufReader::new(source);
+        let (prolog_bytes, prolog) = read_prolog(&mut source)?;
+        let reader = parser_config().create_reader(Cursor::new(prolog_bytes).chain(source));
+
+        let mut meta = XmlMetaData { prolog, ..Default::default() };
+        let mut builder = TreeBuilder::default();
+        let mut path = ElementPath::default();
+        for event in reader {
+            let event = event.map_err(|err| { XmlParseError::from_reader_error(&err, &path) })?;
+            path.update(&event);
+            match event {
+                StartDocument {version, encoding, standalone} => {
+                    meta.version = version;
+                    meta.encoding = encoding;
+                    meta.standalone = standalone;
                 }
-            {
-                let build_res = _recursive_build(events);
-                // Ignore terminal ignorable tags (ie CData, Whitespace,... )
-                if let Some(built_child) = build_res
-                {
-                    if built_child.name.is_empty() && built_child.value.is_some() {
-                        current_tag.value = built_child.value;
-                    }
-                    else {
-                        children.push(built_child);
+                event => {
+                    if let Some(root) = builder.push(event) {
+                        return Ok((root, meta));
                     }
                 }
             }
-            if let Some(next_event) = events.peek() {
-                assert_eq!(
-                    current_tag.name,
-                    {
-                        match &next_event {
-                            Ok(EndElement {name}) => {name.local_name.as_str()},
-                            _ => unreachable!()
-                        }
-                    }
-                );
-                events.next();
-            } // removing EndElement(name)
-            current_tag.children = children;
-            return Some(current_tag);
         }
-        _recursive_build(&mut events).ok_or(Error::from(AdaIoErr::ParseFileErr))
+        Err(Error::from(XmlParseError::new(XmlParseErrorKind::NoRootElement, None, &path)))
     }
 
-    pub fn from_path(path: &str) -> anyhow::Result<XmlTag>
+    pub fn to_writer<W: Write>(&self, writer: &mut EventWriter<W>) -> anyhow::Result<()> {
+        self.to_writer_with_meta(&XmlMetaData::default(), writer)
+    }
+
+    /// Writes the xml declaration and prolog of `meta`, then the tree, as `writer` was set up
+    pub fn to_writer_with_meta<W: Write>(&self, meta: &XmlMetaData, writer: &mut EventWriter<W>)
+        -> anyhow::Result<()>
     {
-        let f = File::open(path)?;
-        let reader = ParserConfig::new()
-            .trim_whitespace(true)
-            .ignore_comments(true)
-            .create_reader(f);
-        let tree = XmlTag::from_reader(reader.into_iter().peekable());
-        tree
+        self.write_events(meta, &XmlWriteOptions::default(), writer)
+    }
+
+    /// Like `to_writer_with_meta`, through a writer made from `options`; `sink` is handed back
+    pub fn to_writer_with_options<W: Write>(&self, meta: &XmlMetaData, options: &XmlWriteOptions, sink: W)
+        -> anyhow::Result<W>
+    {
+        let mut writer = options.emitter_config().create_writer(sink);
+        self.write_events(meta, options, &mut writer)?;
+        Ok(writer.into_inner())
     }
 
-    pub fn to_writer<W: Write>(&self, writer: &mut EventWriter<W>) {
-        fn _recursive_write<W: Write>(me: &XmlTag, w: &mut EventWriter<W>) {
-            // TODO: quite stupid, please remove
-            let DUMMY_NAMESPACE: Namespace = xml::namespace::Namespace::empty();
+    fn write_events<W: Write>(&self, meta: &XmlMetaData, options: &XmlWriteOptions, writer: &mut EventWriter<W>)
+        -> anyhow::Result<()>
+    {
+        fn _recursive_write<W: Write>(me: &XmlTag, options: &XmlWriteOptions, w: &mut EventWriter<W>) -> anyhow::Result<()> {
+            let tag_name = xml::name::Name {
+                local_name: me.name.as_str(),
+                namespace: me.namespace.as_deref(),
+                prefix: me.prefix.as_deref()
+            };
+            let namespace_decls = Namespace(me.namespace_decls.clone());
+            let mut attributes: Vec<_> = me
+                .attribs
+                .iter()
+                .map(|attrib|{
+                    xml::attribute::Attribute {
+                        name: xml::name::Name {
+                            local_name: attrib.name.as_str(),
+                            namespace: attrib.namespace.as_deref(),
+                            prefix: attrib.prefix.as_deref()
+                        },
+                        value: attrib.value.as_ref()
+                    }
+                })
+                .collect();
+            if options.attrib_order == XmlAttribOrder::Sorted {
+                attributes.sort_by_key(|attrib| { (attrib.name.namespace, attrib.name.local_name) });
+            }
             let tag_begin = WriterXmlEvent::StartElement {
-                name: xml::name::Name {
-                    local_name: me.name.as_str(),
-                    namespace: None,
-                    prefix: None
-                },
-                attributes: {
-                    me
-                    .attribs
-                    .iter()
-                    .map(|attrib|{
-                        xml::attribute::Attribute {
-                            name: xml::name::Name::from(attrib.name.as_str()),
-                            value: attrib.value.as_ref()
-                        }
-                    })
-                    .collect()
-                },
-                namespace: Cow::Borrowed(&DUMMY_NAMESPACE)
+                name: tag_name,
+                attributes: Cow::Owned(attributes),
+                namespace: Cow::Owned(namespace_decls)
             };
-            w.write(tag_begin);
-            if me.value.is_some() {
-                let value_event = WriterXmlEvent::Characters(me.value.as_ref().unwrap());
-                w.write(value_event).unwrap();
+            w.write(tag_begin)?;
+            // the text nodes are written unless the value has been changed since
+            let text_in_nodes
+                = me.has_mixed_content()
+                && me.value.as_deref() == Some(me.text_of_nodes().trim());
+            if !text_in_nodes {
+                if let Some(value) = me.value.as_ref() {
+                    write_text(value, options, w)?;
+                }
             }
 
             for child in me.children.iter() {
-                _recursive_write(child, w)
+                match child.kind {
+                    XmlNodeKind::Element => _recursive_write(child, options, w)?,
+                    XmlNodeKind::Text | XmlNodeKind::CData if !text_in_nodes => {},
+                    XmlNodeKind::Text => write_text(child.value.as_deref().unwrap_or_default(), options, w)?,
+                    XmlNodeKind::CData => w.write(WriterXmlEvent::CData(child.value.as_deref().unwrap_or_default()))?,
+                    XmlNodeKind::Comment if !options.comments => {},
+                    XmlNodeKind::Comment => w.write(WriterXmlEvent::Comment(child.value.as_deref().unwrap_or_default()))?,
+                    XmlNodeKind::ProcessingInstruction => w.write(WriterXmlEvent::ProcessingInstruction {
+                        name: child.name.as_str(),
+                        data: child.value.as_deref()
+                    })?
+                }
             }
 
-            let tag_end = WriterXmlEvent::EndElement {
-                name: Some(xml::name::Name {
-                    local_name: me.name.as_str(),
-                    namespace: None,
-                    prefix: None
-                })
+            w.write(WriterXmlEvent::EndElement { name: Some(tag_name) })?;
+            Ok(())
+        }
+
+        fn write_text<W: Write>(text: &str, options: &XmlWriteOptions, w: &mut EventWriter<W>) -> anyhow::Result<()> {
+            let has_markup = text.contains(|c| { c == '<' || c == '&' || c == '>' });
+            match options.escaping {
+                XmlEscaping::CData if has_markup && !text.contains("]]>") => w.write(WriterXmlEvent::CData(text))?,
+                _ => w.write(WriterXmlEvent::Characters(text))?
+            }
+            Ok(())
+        }
+
+        if options.declaration {
+            let doc_begin: WriterXmlEvent = WriterXmlEvent::StartDocument {
+                version: meta.version,
+                encoding: Some(meta.encoding.as_str()),
+                standalone: meta.standalone
             };
-            w.write(tag_end);
+            writer.write(doc_begin)?;
+            for item in meta.prolog.iter() {
+                match item {
+                    // the writer has no event for it
+                    XmlPrologItem::Doctype(doctype) => write!(writer.inner_mut(), "\n{}", doctype)?,
+                    XmlPrologItem::ProcessingInstruction { name, data } => {
+                        writer.write(WriterXmlEvent::ProcessingInstruction { name, data: data.as_deref() })?
+                    }
+                    XmlPrologItem::Comment(_) if !options.comments => {},
+                    XmlPrologItem::Comment(comment) => writer.write(WriterXmlEvent::Comment(comment))?
+                }
+            }
         }
-        let doc_begin: WriterXmlEvent = WriterXmlEvent::StartDocument {
-            version: XmlVersion::Version10,
-            encoding: Some("UTF-8"),
-            standalone: None
-        };
-        writer.write(doc_begin);
-        _recursive_write(self, writer);
+        _recursive_write(self, options, writer)
     }
 
     pub fn to_path(&self, path: &str) -> anyhow::Result<()> {
-        let mut file = File::create(path)?;
-        let mut writer = EmitterConfig::new()
-            .perform_indent(true)
-            .create_writer(&mut file);
+        self.to_path_with_meta(&XmlMetaData::default(), path)
+    }
 
-        self.to_writer(&mut writer);
+    pub fn to_path_with_meta(&self, meta: &XmlMetaData, path: &str) -> anyhow::Result<()> {
+        self.to_path_with_options(meta, &XmlWriteOptions::default(), path)
+    }
+
+    pub fn to_path_with_options(&self, meta: &XmlMetaData, options: &XmlWriteOptions, path: &str) -> anyhow::Result<()> {
+        let file = File::create(path).with_context(|| format!("Can't create {:?}", path))?;
+        self.to_write_with_options(meta, options, BufWriter::new(file))
+            .with_context(|| format!("Can't write {:?}", path))
+    }
 
+    /// Write a whole indented document to any byte sink (file, zip entry, buffer, ...)
+    pub fn to_write<W: Write>(&self, sink: W) -> anyhow::Result<()> {
+        self.to_write_with_meta(&XmlMetaData::default(), sink)
+    }
+
+    pub fn to_write_with_meta<W: Write>(&self, meta: &XmlMetaData, sink: W) -> anyhow::Result<()> {
+        self.to_write_with_options(meta, &XmlWriteOptions::default(), sink)
+    }
+
+    /// The sink is flushed, so a failing buffered write is reported too
+    pub fn to_write_with_options<W: Write>(&self, meta: &XmlMetaData, options: &XmlWriteOptions, sink: W)
+        -> anyhow::Result<()>
+    {
+        self.to_writer_with_options(meta, options, sink)?.flush()?;
         Ok(())
     }
+
+    /// The canonical form, see `XmlWriteOptions::canonical`, for hashing and byte-stable diffs
+    pub fn to_canonical_string(&self) -> anyhow::Result<String> {
+        let mut bytes = Vec::new();
+        self.to_write_with_options(&XmlMetaData::default(), &XmlWriteOptions::canonical(), &mut bytes)?;
+        Ok(String::from_utf8(bytes)?)
+    }
 }
 
 impl Debug for XmlTag {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        static ATOMIC_INDENT: &'static str = "    ";
-        fn _recurse_write(me: &XmlTag, depth: i32, sink: &mut Formatter<'_>) {
+        static ATOMIC_INDENT: &str = "    ";
+        fn _recurse_write(me: &XmlTag, depth: i32, sink: &mut Formatter<'_>) -> std::fmt::Result {
             let indent =
             (0..depth).map(|_| {ATOMIC_INDENT})
             .fold(String::with_capacity(5), |r, s| r + s);
+            if !me.is_element() {
+                return sink.write_fmt(format_args!("{0}{1:?} {2} {3:?}\n", indent, me.kind, me.name, me.value));
+            }
 
             sink.write_fmt(format_args!(
                 "{0}+<{1} {2}> {3}\n",
@@ -242,47 +573,39 @@ impl Debug for XmlTag {
                 me.attribs.iter()
                     .map(|attr: &XmlAttrib| {format!("{}={}", attr.name, attr.value)})
                     .fold("".to_string(), |r, s| {r + s.as_str() + " "}),
-                {
-                    if me.value.is_some() {me.value.as_ref().unwrap()}
-                    else {""}
-                }
-            ));
+                me.value.as_deref().unwrap_or_default()
+            ))?;
             for tag in me.children.iter() {
-                _recurse_write(tag, depth + 1, sink);
+                _recurse_write(tag, depth + 1, sink)?;
             }
             sink.write_fmt(format_args!(
                 "{0}-</{1}> \n",
                 indent,
                 me.name
-            ));
+            ))
         }
-        _recurse_write(self, 0, f);
-        Ok(())
+        _recurse_write(self, 0, f)
     }
 }
 
 impl XmlTag {
     pub fn show_local_tag(&self) {
-        static ATOMIC_INDENT: &'static str = "    ";
+        static ATOMIC_INDENT: &str = "    ";
         let indent = ATOMIC_INDENT;
         println!("∧∧∧∧∧∧∧∧∧∧∧∧∧∧∧∧∧∧∧∧∧");
         println!(
-            "{0}+<{1} {2}> {3}\n",
-            "", self.name,
+            "+<{0} {1}> {2}\n",
+            self.name,
             self.attribs.iter()
             .map(|attr: &XmlAttrib| {format!("{}={}", attr.name, attr.value)})
             .fold("".to_string(), |r, s| {r + s.as_str() + " "}),
-            {
-                if self.value.is_some() {self.value.as_ref().unwrap()}
-                else {""}
-            }
+            self.value.as_deref().unwrap_or_default()
         );
         for tag in self.children.iter() {
             println!("{0} +{1}", indent, tag.name);
         }
         println!(
-            "{0}-</{1}>",
-            "",
+            "-</{0}>",
             self.name
         );
         println!("∨∨∨∨∨∨∨∨∨∨∨∨∨∨∨∨∨∨∨∨∨\n");
@@ -291,14 +614,15 @@ impl XmlTag {
 
 #[cfg(test)]
 mod tests {
-    use xml::{EventReader, ParserConfig};
-    use crate::tag::XmlTag;
+    use xml::ParserConfig;
+    use crate::io::{XmlAttribOrder, XmlEscaping, XmlWriteOptions};
+    use crate::tag::{XmlMetaData, XmlNodeKind, XmlPrologItem, XmlTag};
     use std::fs::File;
 
     #[test]
     fn exp(){
-        let mut f = File::open("test/template.musicxml").unwrap();
-        let mut reader = ParserConfig::new()
+        let f = File::open("test/template.musicxml").unwrap();
+        let reader = ParserConfig::new()
         .trim_whitespace(true)
         .ignore_comments(true)
         .create_reader(f);
@@ -314,6 +638,142 @@ mod tests {
     fn ed() {
         let tree = XmlTag::from_path("test/template.musicxml").unwrap();
         println!("{:?}", tree);
-        tree.to_path("test/outtemplate.xml");
+        tree.to_path("test/outtemplate.xml").unwrap();
+    }
+
+    #[test]
+    fn prolog_and_namespaces_round_trip() {
+        let source = r#"<?xml version="1.0" encoding="UTF-8" standalone="no"?>
+<!-- exported -->
+<!DOCTYPE score-partwise PUBLIC "-//Recordare//DTD MusicXML 3.1 Partwise//EN" "http://www.musicxml.org/dtds/partwise.dtd" [ <!ENTITY a "b>"> ]>
+<?xml-stylesheet href="score.css"?>
+<score-partwise xmlns:xlink="http://www.w3.org/1999/xlink" version="3.1">
+  <credit><link xlink:href="http://example.com"/></credit>
+  <m:math xmlns:m="http://www.w3.org/1998/Math/MathML"><m:mi>x</m:mi></m:math>
+</score-partwise>"#;
+        let (tree, meta) = XmlTag::from_read_with_meta(source.as_bytes()).unwrap();
+        assert_eq!(meta.standalone, Some(false));
+        assert_eq!(meta.prolog, vec![
+            XmlPrologItem::Comment(" exported ".to_string()),
+            XmlPrologItem::Doctype(r#"<!DOCTYPE score-partwise PUBLIC "-//Recordare//DTD MusicXML 3.1 Partwise//EN" "http://www.musicxml.org/dtds/partwise.dtd" [ <!ENTITY a "b>"> ]>"#.to_string()),
+            XmlPrologItem::ProcessingInstruction { name: "xml-stylesheet".to_string(), data: Some(r#"href="score.css""#.to_string()) }
+        ]);
+        assert_eq!(tree.namespace_decls.get("xlink").map(|uri| { uri.as_str() }), Some("http://www.w3.org/1999/xlink"));
+        let link = &tree.children[0].children[0];
+        assert_eq!(link.attribs[0].qualified_name(), "xlink:href");
+        assert_eq!(link.attribs[0].namespace.as_deref(), Some("http://www.w3.org/1999/xlink"));
+        assert!(link.namespace_decls.is_empty());
+        let math = &tree.children[1];
+        assert_eq!(math.qualified_name(), "m:math");
+        assert_eq!(math.children[0].namespace.as_deref(), Some("http://www.w3.org/1998/Math/MathML"));
+
+        let mut written = Vec::new();
+        tree.to_write_with_meta(&meta, &mut written).unwrap();
+        let written = String::from_utf8(written).unwrap();
+        assert_eq!(written.matches("xmlns:xlink=").count(), 1);
+        assert_eq!(written.matches("xmlns:m=").count(), 1);
+
+        let (reread, reread_meta) = XmlTag::from_read_with_meta(written.as_bytes()).unwrap();
+        assert_eq!(reread_meta.prolog, meta.prolog);
+        assert_eq!(reread_meta.standalone, meta.standalone);
+        assert_eq!(reread.children[0].children[0].attribs[0].qualified_name(), "xlink:href");
+        assert_eq!(reread.children[1].children[0].qualified_name(), "m:mi");
+        assert_eq!(reread.children[1].children[0].value.as_deref(), Some("x"));
+    }
+
+    #[test]
+    fn mixed_content_round_trip() {
+        let source = "<lyric><syllabic>single</syllabic><text>a</text><elision> </elision><text>b</text></lyric>\
+            <credit-words>Allegro <b>con</b> brio<!-- tempo --><![CDATA[ <ma non troppo> ]]></credit-words>";
+        let wrapped = format!("<credit>{}</credit>", source);
+        let tree = XmlTag::from_read(wrapped.as_bytes()).unwrap();
+
+        let lyric = &tree.children[0];
+        assert!(!lyric.has_mixed_content());
+        assert_eq!(lyric.get_child_value("elision").map(|v| { v.as_str() }), Some(" "));
+        let texts: Vec<_> = lyric.element_children().map(|child| { child.name.as_str() }).collect();
+        assert_eq!(texts, vec!["syllabic", "text", "elision", "text"]);
+
+        let words = &tree.children[1];
+        assert!(words.has_mixed_content());
+        assert_eq!(words.value.as_deref(), Some("Allegro  brio <ma non troppo>"));
+        let kinds: Vec<_> = words.children.iter().map(|child| { child.kind }).collect();
+        assert_eq!(kinds, vec![
+            XmlNodeKind::Text, XmlNodeKind::Element, XmlNodeKind::Text, XmlNodeKind::Comment, XmlNodeKind::CData
+        ]);
+        assert_eq!(words.element_children().count(), 1);
+        assert_eq!(words.get_child_value("b").map(|v| { v.as_str() }), Some("con"));
+
+        let mut written = Vec::new();
+        tree.to_write(&mut written).unwrap();
+        let written = String::from_utf8(written).unwrap();
+        assert!(written.contains("<credit-words>Allegro <b>con</b> brio<!-- tempo --><![CDATA[ <ma non troppo> ]]></credit-words>"));
+        assert!(written.contains("<elision> </elision>"));
+
+        // a changed value replaces the text nodes
+        let mut changed = words.clone();
+        changed.set_value("Presto");
+        let mut written = Vec::new();
+        changed.to_write(&mut written).unwrap();
+        let written = String::from_utf8(written).unwrap();
+        assert!(written.contains("Presto"));
+        assert!(!written.contains("Allegro"));
+        assert!(written.contains("<b>con</b>"));
+    }
+
+    fn written(tag: &XmlTag, options: &XmlWriteOptions) -> String {
+        String::from_utf8(tag.to_writer_with_options(&XmlMetaData::default(), options, Vec::new()).unwrap()).unwrap()
+    }
+
+    /// Accepts `room` bytes, then fails
+    struct FullSink {
+        room: usize
+    }
+
+    impl std::io::Write for FullSink {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            if buf.len() > self.room {
+                return Err(std::io::Error::other("disk full"));
+            }
+            self.room -= buf.len();
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn writer_options() {
+        let source = "<a z=\"1\" b=\"2\"><!-- note --><c/><d><![CDATA[x < y]]></d><e>1 &amp; 2</e></a>";
+        let tag = XmlTag::from_read(source.as_bytes()).unwrap();
+
+        let compact = XmlWriteOptions { declaration: false, ..XmlWriteOptions::compact() };
+        assert_eq!(
+            written(&tag, &compact),
+            "<a z=\"1\" b=\"2\"><!-- note --><c /><d><![CDATA[x < y]]></d><e>1 &amp; 2</e></a>"
+        );
+        let indented = written(&tag, &XmlWriteOptions::default());
+        assert!(indented.starts_with("<?xml version=\"1.0\" encoding=\"UTF-8\"?>"));
+        assert!(indented.contains("\n  <c />"));
+
+        let sorted = XmlWriteOptions { attrib_order: XmlAttribOrder::Sorted, self_closing: false, ..compact.clone() };
+        assert!(written(&tag, &sorted).starts_with("<a b=\"2\" z=\"1\"><!-- note --><c></c>"));
+        let cdata = XmlWriteOptions { escaping: XmlEscaping::CData, ..compact.clone() };
+        assert!(written(&tag, &cdata).contains("<e><![CDATA[1 & 2]]></e>"));
+        let raw = XmlWriteOptions { escaping: XmlEscaping::Raw, ..compact };
+        assert!(written(&tag, &raw).contains("<e>1 & 2</e>"));
+
+        // the same content spelled differently gives the same bytes
+        let canonical = "<a b=\"2\" z=\"1\"><c></c><d>x &lt; y</d><e>1 &amp; 2</e></a>";
+        assert_eq!(tag.to_canonical_string().unwrap(), canonical);
+        let respelled = "<?xml version='1.0'?>\n<a b='2'  z='1'>\n  <c></c>\n  <d>x &lt; y</d>\n  <e><![CDATA[1 & 2]]></e>\n</a>";
+        assert_eq!(XmlTag::from_read(respelled.as_bytes()).unwrap().to_canonical_string().unwrap(), canonical);
+
+        for room in [0, 10, 50].iter() {
+            assert!(tag.to_write(FullSink { room: *room }).is_err());
+        }
+        assert!(tag.to_path("test/no-such-dir/out.xml").is_err());
+    }
+}
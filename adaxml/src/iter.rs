@@ -1,19 +1,7 @@
-use crate::tag::{XmlTag};
+use crate::tag::{XmlString, XmlTag};
 use std::collections::VecDeque;
 
-macro_rules! __cast_const_to_mut_ref {
-    ($ref:expr, $type:ty) => {
-        (&mut *(($ref as *const $type) as *mut $type))
-    };
-}
-
-macro_rules! __cast_mut_to_const_ref {
-    ($ref:expr, $type:ty) => {
-        (& *(($ref as *mut $type) as *const $type))
-    };
-}
-
-// BFS
+// BFS, elements only
 pub struct BfsXmlTagIter<'a> {
     queue: VecDeque<&'a XmlTag>
 }
@@ -33,14 +21,14 @@ impl<'a> Iterator for BfsXmlTagIter<'a> {
         if let Some(v) = self.queue.pop_front() {
             self
             .queue
-            .extend(v.children.iter());
+            .extend(v.element_children());
 
             Some(v)
         } else { None }
     }
 }
 
-// DFS
+// DFS, elements only
 pub struct DfsXmlTagIter<'a> {
     stack: Vec<&'a XmlTag>
 }
@@ -65,6 +53,7 @@ impl<'a> Iterator for DfsXmlTagIter<'a> {
                 v
                 .children
                 .iter()
+                .filter(|child| { child.is_element() })
                 .rev()
             );
 
@@ -73,11 +62,64 @@ impl<'a> Iterator for DfsXmlTagIter<'a> {
     }
 }
 
+/// Where `XmlTag::visit_mut` is in the tree
+pub struct XmlVisitContext<'p> {
+    /// Names of the enclosing elements, outermost first
+    pub ancestors: &'p [XmlString],
+    /// Position among the parent's children, text and comment nodes included; None for the element visited first
+    pub index: Option<usize>
+}
+
+impl<'p> XmlVisitContext<'p> {
+    pub fn parent_name(&self) -> Option<&str> {
+        self.ancestors.last().map(|name| { name.as_str() })
+    }
+}
+
+/// What `XmlTag::visit_mut` does after visiting an element
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum XmlVisit {
+    Continue,
+    /// Go on with the next sibling, leaving out the children of this element
+    SkipChildren,
+    Stop
+}
+
+impl XmlTag {
+    /// Visits this element and then its descendant elements depth first, in document order.
+    /// The visitor may edit the element it gets, children included, before they are visited.
+    pub fn visit_mut<F>(&mut self, mut visitor: F) -> XmlVisit
+        where F: FnMut(&mut XmlTag, &XmlVisitContext) -> XmlVisit
+    {
+        let mut ancestors = Vec::new();
+        visit_mut_helper(self, None, &mut ancestors, &mut visitor)
+    }
+}
+
+fn visit_mut_helper<F>(tag: &mut XmlTag, index: Option<usize>, ancestors: &mut Vec<XmlString>, visitor: &mut F)
+    -> XmlVisit
+    where F: FnMut(&mut XmlTag, &XmlVisitContext) -> XmlVisit
+{
+    match visitor(tag, &XmlVisitContext { ancestors, index }) {
+        XmlVisit::Continue => {}
+        XmlVisit::SkipChildren => return XmlVisit::Continue,
+        XmlVisit::Stop => return XmlVisit::Stop
+    }
+    ancestors.push(tag.name.clone());
+    for (idx, child) in tag.children.iter_mut().enumerate() {
+        if child.is_element() && visit_mut_helper(child, Some(idx), ancestors, visitor) == XmlVisit::Stop {
+            ancestors.pop();
+            return XmlVisit::Stop;
+        }
+    }
+    ancestors.pop();
+    XmlVisit::Continue
+}
+
 #[cfg(test)]
 mod tests {
     use std::borrow::Borrow;
     use crate::tag::XmlTag;
-    use crate::io;
     use crate::iter::{*};
 
     #[test]
@@ -95,4 +137,40 @@ mod tests {
             leaf.show_local_tag();
         }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn visit_and_edit_in_place() {
+        let mut tree = XmlTag::from_path("test/template.musicxml").unwrap();
+        let prints = tree.all_desc_with_name("print").count();
+        assert_eq!(tree.remove_desc_where(|tag| { tag.name == "print" || tag.name == "credit" }), prints + 1);
+        assert!(tree.get_desc_with_name("print").is_none());
+        assert!(tree.get_desc_with_name("credit-words").is_none());
+
+        let mut paths = Vec::new();
+        let outcome = tree.visit_mut(|tag, context| {
+            if tag.name == "defaults" {
+                return XmlVisit::SkipChildren;
+            }
+            if tag.name == "note" {
+                tag.add_attribute_with_type("visited", context.index.unwrap());
+                paths.push(format!("{}/{}", context.ancestors.join("/"), tag.name));
+                return XmlVisit::Stop;
+            }
+            XmlVisit::Continue
+        });
+        assert_eq!(outcome, XmlVisit::Stop);
+        assert_eq!(paths, vec!["score-partwise/part/measure/note"]);
+        assert_eq!(tree.all_desc_with_name("note").filter(|note| { note.get_attrib_value("visited").is_some() }).count(), 1);
+
+        let mut measure = XmlTag::new("measure");
+        measure.add_child("attributes");
+        measure.add_child("note");
+        measure.insert_before(|child| { child.name == "note" }, XmlTag::new("direction")).unwrap();
+        measure.insert_after(|child| { child.name == "note" }, XmlTag::new("barline")).unwrap();
+        assert!(measure.insert_after(|child| { child.name == "harmony" }, XmlTag::new("sound")).is_err());
+        measure.children[0].replace_with(XmlTag::new("print"));
+        measure.retain_children(|child| { child.name != "barline" });
+        let names: Vec<_> = measure.children.iter().map(|child| { child.name.as_str() }).collect();
+        assert_eq!(names, vec!["print", "direction", "note"]);
+    }
+}
@@ -1,4 +1,9 @@
 pub mod tag;
 pub mod io;
 pub mod iter;
+pub mod stream;
+pub mod error;
+pub mod query;
+pub mod convert;
+pub mod diff;
 
@@ -0,0 +1,439 @@
+use std::collections::HashSet;
+use std::iter::Peekable;
+use std::str::{CharIndices, FromStr};
+use anyhow::bail;
+use crate::iter::DfsXmlTagIter;
+use crate::tag::{XmlString, XmlTag};
+
+/// Where a step looks for its elements, relative to each element the previous step selected
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Axis {
+    Child,
+    /// `//`, children of the element and of all its descendants
+    Descendant,
+    /// A leading `//`, the context element itself and all its descendants
+    DescendantOrSelf,
+    /// `.` or a leading `/`
+    SelfNode
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct Comparison {
+    negated: bool,
+    literal: XmlString
+}
+
+impl Comparison {
+    fn holds(&self, value: &str) -> bool {
+        (value == self.literal) != self.negated
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Predicate {
+    /// 1-based
+    Position(usize),
+    Last,
+    Attrib { name: XmlString, comparison: Option<Comparison> },
+    Child { name: XmlString, comparison: Option<Comparison> },
+    Text(Comparison)
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct Step {
+    axis: Axis,
+    /// None for `*`
+    name: Option<XmlString>,
+    predicates: Vec<Predicate>
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Target {
+    Elements,
+    Text,
+    Attrib(XmlString)
+}
+
+/// Compiled path query over an `XmlTag`, a small subset of XPath 1.0:
+/// - `part/measure` children, `part//note` descendants, `*` any element, `.` the context element
+/// - a leading `/` matches the context element as the root, a leading `//` searches it and all its descendants
+/// - predicates `[@color]`, `[@number='12']`, `[chord]`, `[step='C']`, `[text()='up']`, `[2]`, `[last()]`,
+///   with `!=` for inequality; several predicates all have to hold
+/// - a last step `text()` or `@name` selects values instead of elements
+///
+/// Like XPath, positions count the matches among the children of each element.
+#[derive(Clone, Debug, PartialEq)]
+pub struct XmlPath {
+    steps: Vec<Step>,
+    target: Target
+}
+
+fn name_matches(tag: &XmlTag, name: &str) -> bool {
+    match name.contains(':') {
+        true => tag.qualified_name() == name,
+        false => tag.name == name
+    }
+}
+
+impl Predicate {
+    fn holds(&self, tag: &XmlTag) -> bool {
+        match self {
+            Predicate::Attrib { name, comparison } => tag.attribs
+                .iter()
+                .filter(|attrib| { attrib.name == *name || attrib.qualified_name() == *name })
+                .any(|attrib| { comparison.as_ref().is_none_or(|cmp| { cmp.holds(&attrib.value) }) }),
+            Predicate::Child { name, comparison } => tag
+                .element_children()
+                .filter(|child| { name_matches(child, name) })
+                .any(|child| {
+                    comparison.as_ref().is_none_or(|cmp| { cmp.holds(child.value.as_deref().unwrap_or_default()) })
+                }),
+            Predicate::Text(comparison) => comparison.holds(tag.value.as_deref().unwrap_or_default()),
+            Predicate::Position(_) | Predicate::Last => unreachable!("positions depend on the siblings")
+        }
+    }
+}
+
+impl Step {
+    /// Keeps the candidates passing the name test and every predicate, in order
+    fn filter<'a>(&self, candidates: impl Iterator<Item=&'a XmlTag>) -> Vec<&'a XmlTag> {
+        let mut matches: Vec<_> = candidates
+            .filter(|tag| { tag.is_element() })
+            .filter(|tag| { self.name.as_ref().is_none_or(|name| { name_matches(tag, name) }) })
+            .collect();
+        for predicate in &self.predicates {
+            matches = match predicate {
+                Predicate::Position(position) => matches.get(position - 1).into_iter().cloned().collect(),
+                Predicate::Last => matches.last().into_iter().cloned().collect(),
+                _ => matches.into_iter().filter(|tag| { predicate.holds(tag) }).collect()
+            };
+        }
+        matches
+    }
+
+    fn apply<'a>(&self, context: &'a XmlTag) -> Vec<&'a XmlTag> {
+        match self.axis {
+            Axis::Child => self.filter(context.children.iter()),
+            Axis::Descendant => DfsXmlTagIter::from(context)
+                .flat_map(|tag| { self.filter(tag.children.iter()) })
+                .collect(),
+            Axis::DescendantOrSelf => self
+                .filter(std::iter::once(context))
+                .into_iter()
+                .chain(DfsXmlTagIter::from(context).flat_map(|tag| { self.filter(tag.children.iter()) }))
+                .collect(),
+            Axis::SelfNode => self.filter(std::iter::once(context))
+        }
+    }
+}
+
+impl XmlPath {
+    /// Elements the path leads to from `context`, in document order for a single step, each one once.
+    /// A path ending in `text()` or `@name` gives the elements holding the values.
+    pub fn select<'a>(&self, context: &'a XmlTag) -> impl Iterator<Item=&'a XmlTag>
+    {
+        self.steps
+            .iter()
+            .fold(vec![context], |tags, step| {
+                let mut seen = HashSet::new();
+                tags.into_iter()
+                    .flat_map(|tag| { step.apply(tag) })
+                    .filter(|tag| { seen.insert(*tag as *const XmlTag) })
+                    .collect()
+            })
+            .into_iter()
+    }
+
+    /// Values the path leads to: the text runs for `text()`, attribute values for `@name`,
+    /// otherwise the value of each selected element that has one
+    pub fn select_values<'a>(&self, context: &'a XmlTag) -> impl Iterator<Item=&'a str>
+    {
+        let values: Vec<&'a str> = match &self.target {
+            Target::Elements => self
+                .select(context)
+                .filter_map(|tag| { tag.value.as_deref() })
+                .collect(),
+            Target::Text => self
+                .select(context)
+                .flat_map(|tag| -> Vec<&'a str> {
+                    match tag.has_mixed_content() {
+                        true => tag.children
+                            .iter()
+                            .filter(|child| { child.is_text() })
+                            .filter_map(|child| { child.value.as_deref() })
+                            .collect(),
+                        false => tag.value.as_deref().into_iter().collect()
+                    }
+                })
+                .collect(),
+            Target::Attrib(name) => self
+                .select(context)
+                .flat_map(|tag| { tag.attribs.iter() })
+                .filter(|attrib| { attrib.name == *name || attrib.qualified_name() == *name })
+                .map(|attrib| { attrib.value.as_str() })
+                .collect()
+        };
+        values.into_iter()
+    }
+}
+
+/// Hand written scanner over the query, positions in errors are byte offsets
+struct PathParser<'s> {
+    source: &'s str,
+    chars: Peekable<CharIndices<'s>>
+}
+
+impl<'s> PathParser<'s> {
+    fn position(&mut self) -> usize {
+        self.chars.peek().map_or(self.source.len(), |(position, _)| { *position })
+    }
+
+    fn eat(&mut self, expected: char) -> bool {
+        self.chars.next_if(|(_, c)| { *c == expected }).is_some()
+    }
+
+    fn expect(&mut self, expected: char) -> anyhow::Result<()> {
+        if !self.eat(expected) {
+            self.unexpected(&format!("{:?}", expected))?;
+        }
+        Ok(())
+    }
+
+    fn unexpected(&mut self, expected: &str) -> anyhow::Result<()> {
+        let position = self.position();
+        match self.chars.peek() {
+            Some((_, found)) => bail!("Expected {} at {} in query {:?}, found {:?}", expected, position, self.source, found),
+            None => bail!("Expected {} at the end of query {:?}", expected, self.source)
+        }
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.chars.next_if(|(_, c)| { c.is_whitespace() }).is_some() {}
+    }
+
+    fn name(&mut self) -> anyhow::Result<XmlString> {
+        let mut name = XmlString::new();
+        while let Some((_, c)) = self.chars.next_if(|(_, c)| { c.is_alphanumeric() || "-_.:".contains(*c) }) {
+            name.push(c);
+        }
+        if name.is_empty() {
+            self.unexpected("a name")?;
+        }
+        Ok(name)
+    }
+
+    /// `text()` and `last()`, after their name
+    fn call_parentheses(&mut self, name: &str) -> anyhow::Result<bool> {
+        if (name == "text" || name == "last") && self.eat('(') {
+            self.expect(')')?;
+            return Ok(true);
+        }
+        Ok(false)
+    }
+
+    fn literal(&mut self) -> anyhow::Result<XmlString> {
+        self.skip_whitespace();
+        match self.chars.peek().map(|(_, c)| { *c }) {
+            Some(quote) if quote == '\'' || quote == '"' => {
+                self.chars.next();
+                let mut literal = XmlString::new();
+                loop {
+                    match self.chars.next() {
+                        Some((_, c)) if c == quote => return Ok(literal),
+                        Some((_, c)) => literal.push(c),
+                        None => bail!("Unterminated string in query {:?}", self.source)
+                    }
+                }
+            }
+            _ => self.name()
+        }
+    }
+
+    fn comparison(&mut self) -> anyhow::Result<Option<Comparison>> {
+        self.skip_whitespace();
+        let negated = self.eat('!');
+        if negated {
+            self.expect('=')?;
+        } else if !self.eat('=') {
+            return Ok(None);
+        }
+        Ok(Some(Comparison { negated, literal: self.literal()? }))
+    }
+
+    fn predicate(&mut self) -> anyhow::Result<Predicate> {
+        self.skip_whitespace();
+        let predicate = match self.chars.peek().map(|(_, c)| { *c }) {
+            Some(digit) if digit.is_ascii_digit() => {
+                let position = self.name()?;
+                match position.parse::<usize>() {
+                    Ok(position) if position > 0 => Predicate::Position(position),
+                    _ => bail!("Invalid position {:?} in query {:?}, they start at 1", position, self.source)
+                }
+            }
+            Some('@') => {
+                self.chars.next();
+                Predicate::Attrib { name: self.name()?, comparison: self.comparison()? }
+            }
+            _ => {
+                let name = self.name()?;
+                match (name.as_str(), self.call_parentheses(&name)?) {
+                    ("last", true) => Predicate::Last,
+                    // `[text()]` alone asks for any text
+                    ("text", true) => Predicate::Text(self.comparison()?.unwrap_or(Comparison {
+                        negated: true,
+                        literal: XmlString::new()
+                    })),
+                    _ => Predicate::Child { name, comparison: self.comparison()? }
+                }
+            }
+        };
+        self.skip_whitespace();
+        self.expect(']')?;
+        Ok(predicate)
+    }
+
+    fn parse(mut self) -> anyhow::Result<XmlPath> {
+        let mut steps = Vec::new();
+        let mut axis = match (self.eat('/'), self.eat('/')) {
+            (true, true) => Axis::DescendantOrSelf,
+            (true, false) => Axis::SelfNode,
+            _ => Axis::Child
+        };
+        loop {
+            if self.eat('@') {
+                let name = self.name()?;
+                return self.finish(steps, Target::Attrib(name));
+            }
+            let name = match self.eat('*') {
+                true => None,
+                false => Some(self.name()?)
+            };
+            if name.as_deref() == Some("text") && self.call_parentheses("text")? {
+                return self.finish(steps, Target::Text);
+            }
+            let step = match name.as_deref() {
+                Some(".") => Step { axis: Axis::SelfNode, name: None, predicates: Vec::new() },
+                _ => Step { axis, name, predicates: Vec::new() }
+            };
+            steps.push(step);
+            while self.eat('[') {
+                let predicate = self.predicate()?;
+                steps.last_mut().unwrap().predicates.push(predicate);
+            }
+            if self.chars.peek().is_none() {
+                return Ok(XmlPath { steps, target: Target::Elements });
+            }
+            self.expect('/')?;
+            axis = match self.eat('/') {
+                true => Axis::Descendant,
+                false => Axis::Child
+            };
+        }
+    }
+
+    fn finish(&mut self, mut steps: Vec<Step>, target: Target) -> anyhow::Result<XmlPath> {
+        if self.chars.peek().is_some() {
+            self.unexpected("the end of the query after text() or an attribute")?;
+        }
+        // a bare `text()` or `@name` reads the context element
+        if steps.is_empty() {
+            steps.push(Step { axis: Axis::SelfNode, name: None, predicates: Vec::new() });
+        }
+        Ok(XmlPath { steps, target })
+    }
+}
+
+impl FromStr for XmlPath {
+    type Err = anyhow::Error;
+
+    fn from_str(source: &str) -> Result<Self, Self::Err> {
+        PathParser { source, chars: source.char_indices().peekable() }.parse()
+    }
+}
+
+impl XmlTag {
+    /// Elements matching an `XmlPath` query from this element, eg. `part/measure[@number='12']/note[chord]`
+    pub fn select(&self, path: &str) -> anyhow::Result<impl Iterator<Item=&XmlTag>>
+    {
+        Ok(path.parse::<XmlPath>()?.select(self))
+    }
+
+    /// Values matching an `XmlPath` query from this element, eg. `part[@id='P1']//note/pitch/step/text()`
+    pub fn select_values(&self, path: &str) -> anyhow::Result<impl Iterator<Item=&str>>
+    {
+        Ok(path.parse::<XmlPath>()?.select_values(self))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::query::XmlPath;
+    use crate::tag::XmlTag;
+
+    fn select_names(tag: &XmlTag, path: &str) -> Vec<String> {
+        tag.select(path)
+            .unwrap()
+            .map(|tag| {
+                match tag.get_attrib_value("id") {
+                    Some(id) => format!("{}#{}", tag.name, id),
+                    None => tag.name.clone()
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn axes_predicates_and_positions() {
+        let source = r##"<score-partwise>
+            <part id="P1">
+              <measure number="11"><note id="a"><pitch><step>C</step></pitch></note></measure>
+              <measure number="12">
+                <note id="b" color="#FF0000"><pitch><step>D</step></pitch></note>
+                <note id="c"><chord/><pitch><step>F</step></pitch></note>
+                <direction><note id="d"/></direction>
+              </measure>
+            </part>
+            <part id="P2"><measure number="12"><note id="e"><rest/></note><note id="f"/></measure></part>
+          </score-partwise>"##;
+        let score = XmlTag::from_read(source.as_bytes()).unwrap();
+
+        assert_eq!(select_names(&score, "part/measure[@number='12']/note[chord]"), vec!["note#c"]);
+        assert_eq!(select_names(&score, "part[@id='P1']//note"), vec!["note#a", "note#b", "note#c", "note#d"]);
+        assert_eq!(select_names(&score, "//note[@color]"), vec!["note#b"]);
+        assert_eq!(select_names(&score, "//measure/note[1]"), vec!["note#a", "note#b", "note#e"]);
+        assert_eq!(select_names(&score, "//measure/note[last()]"), vec!["note#a", "note#c", "note#f"]);
+        assert_eq!(select_names(&score, "part[2]/*/*[@id!='e']"), vec!["note#f"]);
+        assert_eq!(select_names(&score, "//note[not-there]"), Vec::<String>::new());
+        assert_eq!(select_names(&score, "/score-partwise/part"), vec!["part#P1", "part#P2"]);
+        assert_eq!(select_names(&score, "/part"), Vec::<String>::new());
+        assert_eq!(select_names(&score, "//score-partwise"), vec!["score-partwise"]);
+        assert_eq!(select_names(&score, ".//note[pitch][2]"), vec!["note#c"]);
+
+        let steps: Vec<_> = score.select_values("//note/pitch/step/text()").unwrap().collect();
+        assert_eq!(steps, vec!["C", "D", "F"]);
+        let numbers: Vec<_> = score.select_values("part/measure/@number").unwrap().collect();
+        assert_eq!(numbers, vec!["11", "12", "12"]);
+        assert_eq!(score.select_values("//step[text()='F']").unwrap().collect::<Vec<_>>(), vec!["F"]);
+
+        // a compiled path is reusable on any element
+        let path: XmlPath = "note[2]/@id".parse().unwrap();
+        let ids: Vec<_> = score
+            .all_desc_with_name("measure")
+            .flat_map(|measure| { path.select_values(measure) })
+            .collect();
+        assert_eq!(ids, vec!["c", "f"]);
+    }
+
+    #[test]
+    fn mixed_text_and_bad_queries() {
+        let tag = XmlTag::from_read("<credit><words>Sonata <b>in</b> A</words></credit>".as_bytes()).unwrap();
+        let runs: Vec<_> = tag.select_values("words/text()").unwrap().collect();
+        assert_eq!(runs, vec!["Sonata ", " A"]);
+
+        for bad in ["", "a/", "a[", "a[0]", "a[@x='1", "a/@x/b", "a]", "a[@x=='1']"].iter() {
+            assert!(bad.parse::<XmlPath>().is_err(), "{:?} should not parse", bad);
+        }
+        let err = "part/measure[@number=]".parse::<XmlPath>().unwrap_err();
+        assert_eq!(err.to_string(), "Expected a name at 21 in query \"part/measure[@number=]\", found ']'");
+    }
+}
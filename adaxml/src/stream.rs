@@ -0,0 +1,156 @@
+use std::fs::File;
+use std::io::Read;
+use anyhow::Context;
+use xml::EventReader;
+use xml::reader::{XmlEvent, XmlEvent::*};
+use crate::error::{ElementPath, XmlParseError};
+use crate::io::{base_namespace, parser_config, tag_from_start, TreeBuilder};
+use crate::tag::*;
+
+/// One complete element out of a stream, with the elements enclosing it
+#[derive(Clone, Debug)]
+pub struct XmlSubtree {
+    /// Outermost first; name and attributes only, their children are never built
+    pub ancestors: Vec<XmlTag>,
+    pub tag: XmlTag
+}
+
+impl XmlSubtree {
+    /// Innermost enclosing element with that name, eg. the <part> of a <measure>
+    pub fn ancestor_with_name(&self, name: &str) -> Option<&XmlTag>
+    {
+        self.ancestors
+            .iter()
+            .rev()
+            .find(|tag| { tag.name == name })
+    }
+}
+
+/// Pull-based reader yielding the subtree of each element with one of the chosen names, in document order.
+/// Only the subtree being yielded is held in memory, everything outside those elements is skipped.
+/// A chosen element nested in another chosen element stays part of the outer subtree.
+pub struct XmlSubtreeStream<R: Read> {
+    events: EventReader<R>,
+    names: Vec<XmlString>,
+    ancestors: Vec<XmlTag>,
+    path: ElementPath,
+    finished: bool
+}
+
+impl<R: Read> XmlSubtreeStream<R> {
+    pub fn from_read(source: R, names: &[&str]) -> Self
+    {
+        XmlSubtreeStream {
+            events: parser_config().create_reader(source),
+            names: names
+                .iter()
+                .map(|name| { name.to_string() })
+                .collect(),
+            ancestors: Vec::new(),
+            path: ElementPath::default(),
+            finished: false
+        }
+    }
+
+    fn next_event(&mut self) -> Result<XmlEvent, XmlParseError>
+    {
+        let event = self.events
+            .next()
+            .map_err(|err| { XmlParseError::from_reader_error(&err, &self.path) })?;
+        self.path.update(&event);
+        Ok(event)
+    }
+
+    /// Namespaces in scope at the start are all declared on the subtree's root, so it stands on its own
+    fn build_subtree(&mut self, start_event: XmlEvent) -> anyhow::Result<XmlSubtree>
+    {
+        let mut builder = TreeBuilder::default();
+        builder.push(start_event);
+        loop {
+            if let Some(tag) = builder.push(self.next_event()?) {
+                return Ok(XmlSubtree { ancestors: self.ancestors.clone(), tag });
+            }
+        }
+    }
+}
+
+impl XmlSubtreeStream<File> {
+    pub fn from_path(path: &str, names: &[&str]) -> anyhow::Result<Self>
+    {
+        let f = File::open(path).with_context(|| format!("Can't open {:?}", path))?;
+        Ok(XmlSubtreeStream::from_read(f, names))
+    }
+}
+
+impl<R: Read> Iterator for XmlSubtreeStream<R> {
+    type Item = anyhow::Result<XmlSubtree>;
+
+    fn next(&mut self) -> Option<Self::Item>
+    {
+        while !self.finished {
+            let event = match self.next_event() {
+                Ok(event) => event,
+                Err(err) => {
+                    self.finished = true;
+                    return Some(Err(anyhow::Error::from(err)));
+                }
+            };
+            match event {
+                StartElement {name, attributes, namespace} => {
+                    if self.names.contains(&name.local_name) {
+                        let subtree = self.build_subtree(StartElement { name, attributes, namespace });
+                        self.finished = subtree.is_err();
+                        return Some(subtree);
+                    }
+                    self.ancestors.push(tag_from_start(&name, &attributes, &namespace, &base_namespace()));
+                }
+                EndElement {..} => { self.ancestors.pop(); }
+                EndDocument => { self.finished = true; }
+                _ => {}
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::stream::XmlSubtreeStream;
+    use crate::tag::XmlTag;
+
+    #[test]
+    fn measures_one_at_a_time() {
+        let tree = XmlTag::from_path("test/longduongs_tied.musicxml").unwrap();
+        let measures: Vec<_> = XmlSubtreeStream::from_path("test/longduongs_tied.musicxml", &["measure"])
+            .unwrap()
+            .map(|subtree| { subtree.unwrap() })
+            .collect();
+
+        let dom_measures: Vec<_> = tree.all_desc_with_name("measure").collect();
+        assert_eq!(measures.len(), dom_measures.len());
+        for (streamed, dom) in measures.iter().zip(dom_measures) {
+            assert_eq!(streamed.tag.get_attrib_value("number"), dom.get_attrib_value("number"));
+            assert_eq!(streamed.tag.all_desc_with_name("note").count(), dom.all_desc_with_name("note").count());
+            assert_eq!(
+                streamed.ancestors.iter().map(|tag| { tag.name.as_str() }).collect::<Vec<_>>(),
+                vec!["score-partwise", "part"]
+            );
+            assert!(streamed.ancestors[1].children.is_empty());
+        }
+        assert_eq!(measures[0].ancestor_with_name("part").unwrap().get_attrib_value("id"), Some("P1"));
+    }
+
+    #[test]
+    fn several_names_and_errors() {
+        let source = "<a><b x=\"1\"><c>text</c></b><c/><b><b/></b></a>";
+        let names: Vec<_> = XmlSubtreeStream::from_read(source.as_bytes(), &["b", "c"])
+            .map(|subtree| { subtree.unwrap() })
+            .map(|subtree| { (subtree.tag.name, subtree.tag.children.len(), subtree.ancestors.len()) })
+            .collect();
+        assert_eq!(names, vec![("b".to_string(), 1, 1), ("c".to_string(), 0, 1), ("b".to_string(), 1, 1)]);
+
+        let mut truncated = XmlSubtreeStream::from_read("<a><b><c></c>".as_bytes(), &["b"]);
+        assert!(truncated.next().unwrap().is_err());
+        assert!(truncated.next().is_none());
+    }
+}
@@ -1,3 +1,4 @@
+use std::collections::BTreeMap;
 use std::iter::Iterator;
 use xml::common::XmlVersion;
 use chrono::{Date, Utc};
@@ -30,22 +31,55 @@ macro_rules! drill {
 
 pub type XmlString = String;
 
+/// Markup before the root element that is not part of the tree
+#[derive(Clone, Debug, PartialEq)]
+pub enum XmlPrologItem {
+    /// The whole declaration, eg. `<!DOCTYPE score-partwise PUBLIC "..." "...">`
+    Doctype(XmlString),
+    ProcessingInstruction { name: XmlString, data: Option<XmlString> },
+    Comment(XmlString)
+}
+
 #[derive(Clone, Debug)]
 pub struct XmlMetaData {
     pub version: XmlVersion,
     pub encoding: String,
+    pub standalone: Option<bool>,
+    /// In document order, the xml declaration excluded
+    pub prolog: Vec<XmlPrologItem>,
     pub date: Date<Utc>
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct XmlAttrib {
-    pub name: XmlString,
+    pub name: XmlString, // local name
+    pub prefix: Option<XmlString>,
+    pub namespace: Option<XmlString>,
     pub value: XmlString
 }
 
-#[derive(Clone)]
+/// What a node of the tree is. Only elements have a name; the others keep their content in `value`
+/// (a processing instruction keeps its target in `name`) and never have children.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum XmlNodeKind {
+    Element,
+    Text,
+    CData,
+    Comment,
+    ProcessingInstruction
+}
+
+/// An element, or one of the text, CDATA, comment and processing instruction nodes among its children.
+/// The text of an element is in `value`: as is for simple content, while mixed content also keeps
+/// its text nodes in order among the children and `value` holds their trimmed concatenation.
+#[derive(Clone, PartialEq)]
 pub struct XmlTag {
-    pub name: XmlString,
+    pub kind: XmlNodeKind,
+    pub name: XmlString, // local name
+    pub prefix: Option<XmlString>,
+    pub namespace: Option<XmlString>,
+    /// Namespaces declared on this tag, prefix -> uri; the default namespace has prefix ""
+    pub namespace_decls: BTreeMap<XmlString, XmlString>,
     pub value: Option<XmlString>,
     pub attribs: Vec<XmlAttrib>,
     pub children: Vec<XmlTag>
@@ -54,7 +88,11 @@ pub struct XmlTag {
 impl Default for XmlTag {
     fn default() -> Self {
         XmlTag {
+            kind: XmlNodeKind::Element,
             name: XmlString::new(),
+            prefix: None,
+            namespace: None,
+            namespace_decls: BTreeMap::new(),
             value: None,
             attribs: Vec::with_capacity(5),
             children: Vec::with_capacity(50)
@@ -66,54 +104,164 @@ impl Default for XmlMetaData {
     fn default() -> Self {
         XmlMetaData {
             version: XmlVersion::Version10,
-            encoding: "".to_string(),
+            encoding: "UTF-8".to_string(),
+            standalone: None,
+            prolog: Vec::new(),
             date: Utc::now().date()
         }
     }
 }
 
+impl XmlMetaData {
+    pub fn doctype(&self) -> Option<&XmlString>
+    {
+        self.prolog
+            .iter()
+            .find_map(|item| {
+                match item {
+                    XmlPrologItem::Doctype(doctype) => Some(doctype),
+                    _ => None
+                }
+            })
+    }
+}
+
+impl XmlAttrib {
+    pub fn new<S: Into<XmlString>, V: Into<XmlString>>(name: S, value: V) -> Self {
+        XmlAttrib {
+            name: name.into(),
+            prefix: None,
+            namespace: None,
+            value: value.into()
+        }
+    }
+
+    /// Name as written, eg. `xlink:href`
+    pub fn qualified_name(&self) -> XmlString {
+        qualified_name(&self.prefix, &self.name)
+    }
+}
+
+fn qualified_name(prefix: &Option<XmlString>, name: &XmlString) -> XmlString {
+    match prefix {
+        Some(prefix) => format!("{}:{}", prefix, name),
+        None => name.clone()
+    }
+}
+
 impl XmlTag
 {
+    pub fn new<S: Into<XmlString>>(name: S) -> Self {
+        XmlTag {
+            name: name.into(),
+            ..Default::default()
+        }
+    }
+
+    fn node<S: Into<XmlString>>(kind: XmlNodeKind, content: S) -> Self {
+        XmlTag {
+            kind,
+            value: Some(content.into()),
+            ..Default::default()
+        }
+    }
+
+    pub fn text_node<S: Into<XmlString>>(text: S) -> Self {
+        XmlTag::node(XmlNodeKind::Text, text)
+    }
+
+    pub fn cdata_node<S: Into<XmlString>>(text: S) -> Self {
+        XmlTag::node(XmlNodeKind::CData, text)
+    }
+
+    pub fn comment_node<S: Into<XmlString>>(comment: S) -> Self {
+        XmlTag::node(XmlNodeKind::Comment, comment)
+    }
+
+    pub fn processing_instruction_node<S: Into<XmlString>>(target: S, data: Option<XmlString>) -> Self {
+        XmlTag {
+            kind: XmlNodeKind::ProcessingInstruction,
+            name: target.into(),
+            value: data,
+            ..Default::default()
+        }
+    }
+
+    /// Name as written, eg. `xlink:href` or `score-partwise`
+    pub fn qualified_name(&self) -> XmlString {
+        qualified_name(&self.prefix, &self.name)
+    }
+
+    pub fn is_element(&self) -> bool {
+        self.kind == XmlNodeKind::Element
+    }
+
+    pub fn is_text(&self) -> bool {
+        self.kind == XmlNodeKind::Text || self.kind == XmlNodeKind::CData
+    }
+
+    /// Children that are elements, leaving out text, comments, ...
+    pub fn element_children(&self) -> impl Iterator<Item=&XmlTag> {
+        self.children
+            .iter()
+            .filter(|child| { child.is_element() })
+    }
+
+    /// Whether text nodes are interleaved with the children, see `XmlTag`
+    pub fn has_mixed_content(&self) -> bool {
+        self.children
+            .iter()
+            .any(|child| { child.is_text() })
+    }
+
+    /// Text and CDATA children concatenated in order, untrimmed
+    pub fn text_of_nodes(&self) -> XmlString {
+        self.children
+            .iter()
+            .filter(|child| { child.is_text() })
+            .filter_map(|child| { child.value.as_ref() })
+            .fold(XmlString::new(), |text, run| { text + run })
+    }
+
     // Public functions
 
     // Search
-    pub fn all_desc_with_name(&self, name: &'static str)
-        -> impl Iterator<Item=&XmlTag>
+    pub fn all_desc_with_name<'a>(&'a self, name: &'a str)
+        -> impl Iterator<Item=&'a XmlTag>
     {
         BfsXmlTagIter::from(self)
         .filter(move |tag| {tag.name == name})
 
     }
 
-    pub fn first_desc_with_name(&self, name: &'static str)
-        -> impl Iterator<Item=&XmlTag>
+    pub fn first_desc_with_name<'a>(&'a self, name: &'a str)
+        -> impl Iterator<Item=&'a XmlTag>
     {
         self.all_desc_with_name(name).take(1)
     }
 
-    pub fn all_child_with_name(&self, name: &'static str)
-        -> impl Iterator<Item=&XmlTag>
+    pub fn all_child_with_name<'a>(&'a self, name: &'a str)
+        -> impl Iterator<Item=&'a XmlTag>
     {
         self.children.iter()
         .filter(move |child| {child.name == name})
     }
 
-    pub fn first_child_with_name(&self, name: &'static str)
+    pub fn first_child_with_name(&self, name: &str)
         -> Option<&XmlTag>
     {
-        self.all_child_with_name(name)
-        .next()
+        self.get_child_with_name(name)
     }
 
-    pub fn all_attribs_with_name(&self, name: &'static str)
-        -> impl Iterator<Item=&XmlAttrib>
+    pub fn all_attribs_with_name<'a>(&'a self, name: &'a str)
+        -> impl Iterator<Item=&'a XmlAttrib>
     {
         self.attribs.iter()
         .filter(move |attr| {attr.name == name})
     }
 
-    pub fn all_child_with_attrib(&self, name: &'static str, value: &'static str)
-        -> impl Iterator<Item=&XmlTag>
+    pub fn all_child_with_attrib<'a>(&'a self, name: &'a str, value: &'a str)
+        -> impl Iterator<Item=&'a XmlTag>
     {
         self.children.iter()
         .filter(
@@ -124,36 +272,36 @@ impl XmlTag
         )
     }
 
-    pub fn get_attrib_value(&self, name: &'static str)
+    pub fn get_attrib_value(&self, name: &str)
         -> Option<&str>
     {
-        self.all_attribs_with_name(name).next()
+        self.attribs.iter()
+        .find(|attr| {attr.name == name})
         .map(|attr| {attr.value.as_str()})
     }
 
-    pub fn get_attrib_value_as<T: FromStr>(&self, name: &'static str)
+    pub fn get_attrib_value_as<T: FromStr>(&self, name: &str)
         -> Option<T>
     {
         self.get_attrib_value(name)
-        .map(|val| {val.parse().ok()})
-        .flatten()
+        .and_then(|val| {val.parse().ok()})
     }
 
-    pub fn get_child_with_attrib(&self, name: &'static str, value: &'static str)
+    pub fn get_child_with_attrib(&self, name: &str, value: &str)
         -> Option<&XmlTag>
     {
-        self.all_child_with_attrib(name, value).next()
+        self.children.iter()
+        .find(|ch| { ch.attribs.iter().any(|attr| {attr.name == name && attr.value == value}) })
     }
 
-    pub fn get_child_with_name(&self, name: &'static str)
+    pub fn get_child_with_name(&self, name: &str)
         -> Option<&XmlTag>
     {
         self.children.iter()
-        .filter(move |child| {child.name == name})
-        .next()
+        .find(|child| {child.name == name})
     }
 
-    pub fn get_child_value(&self, name: &'static str)
+    pub fn get_child_value(&self, name: &str)
         -> Option<&XmlString>
     {
         self.get_child_with_name(name)?
@@ -161,70 +309,120 @@ impl XmlTag
         .as_ref()
     }
 
-    pub fn get_child_value_as<T:FromStr>(&self, name: &'static str)
+    pub fn get_child_value_as<T:FromStr>(&self, name: &str)
         -> Option<T>
     {
         self.get_child_value(name)
-        .map(|c| {c.parse().ok()})
-        .flatten()
+        .and_then(|c| {c.parse().ok()})
     }
 
-    pub fn get_desc_with_name(&self, name: &'static str)
+    pub fn get_desc_with_name(&self, name: &str)
         -> Option<&XmlTag>
     {
-        self.all_desc_with_name(name).next()
+        BfsXmlTagIter::from(self)
+        .find(|tag| {tag.name == name})
     }
 
-    pub fn does_child_exists(&self, name: &'static str) -> bool
+    pub fn does_child_exists(&self, name: &str) -> bool
     {
-        self.get_child_with_name("name").is_some()
+        self.get_child_with_name(name).is_some()
     }
 
-    /// Builder methods
+    // Builder methods
 
-    pub fn add_attribute(&mut self, name: XmlString, value: XmlString) -> &mut Self {
-        self.attribs.push(
-            XmlAttrib {
-                name,
-                value
-            }
-        );
+    pub fn add_attribute<S: Into<XmlString>, V: Into<XmlString>>(&mut self, name: S, value: V) -> &mut Self {
+        self.attribs.push(XmlAttrib::new(name, value));
         self
     }
 
-    pub fn add_attribute_with_type<T: ToString>(&mut self, name: XmlString, value: T)
+    pub fn add_attribute_with_type<S: Into<XmlString>, T: ToString>(&mut self, name: S, value: T)
         -> &mut Self
     {
         self.add_attribute(name, value.to_string());
         self
     }
 
-    pub fn add_child(&mut self, name:  XmlString) -> &mut XmlTag {
-        self.children.push(
-            XmlTag {
-                name,
-                value: None,
-                attribs: vec![],
-                children: vec![]
-            }
-        );
+    pub fn set_value<T: ToString>(&mut self, value: T) -> &mut Self {
+        self.value = Some(value.to_string());
+        self
+    }
+
+    pub fn add_child<S: Into<XmlString>>(&mut self, name: S) -> &mut XmlTag {
+        self.children.push(XmlTag::new(name));
         self.children.last_mut().unwrap()
     }
+
+    pub fn add_child_with_value<S: Into<XmlString>, T: ToString>(&mut self, name: S, value: T)
+        -> &mut XmlTag
+    {
+        self.add_child(name).set_value(value)
+    }
+
+    // Editing
+
+    /// Keeps the children, of any kind, for which `keep` is true
+    pub fn retain_children<F: FnMut(&XmlTag) -> bool>(&mut self, keep: F) -> &mut Self {
+        self.children.retain(keep);
+        self
+    }
+
+    /// Puts `other` in place of this node and returns the node it replaced
+    pub fn replace_with(&mut self, other: XmlTag) -> XmlTag {
+        std::mem::replace(self, other)
+    }
+
+    /// Inserts `child` before the first child matching `at`, or gives it back when none does
+    pub fn insert_before<F: FnMut(&XmlTag) -> bool>(&mut self, at: F, child: XmlTag)
+        -> Result<&mut XmlTag, Box<XmlTag>>
+    {
+        match self.children.iter().position(at) {
+            Some(idx) => {
+                self.children.insert(idx, child);
+                Ok(&mut self.children[idx])
+            }
+            None => Err(Box::new(child))
+        }
+    }
+
+    /// Inserts `child` after the first child matching `at`, or gives it back when none does
+    pub fn insert_after<F: FnMut(&XmlTag) -> bool>(&mut self, at: F, child: XmlTag)
+        -> Result<&mut XmlTag, Box<XmlTag>>
+    {
+        match self.children.iter().position(at) {
+            Some(idx) => {
+                self.children.insert(idx + 1, child);
+                Ok(&mut self.children[idx + 1])
+            }
+            None => Err(Box::new(child))
+        }
+    }
+
+    /// Removes every descendant element for which `remove` is true, along with its subtree,
+    /// and returns how many were removed. This element itself is never removed.
+    pub fn remove_desc_where<F: FnMut(&XmlTag) -> bool>(&mut self, mut remove: F) -> usize {
+        let mut removed = 0;
+        self.visit_mut(|tag, _| {
+            let count = tag.children.len();
+            tag.children.retain(|child| { !(child.is_element() && remove(child)) });
+            removed += count - tag.children.len();
+            XmlVisit::Continue
+        });
+        removed
+    }
 }
 
 
 #[cfg(test)]
 mod tests {
     use crate::tag::XmlTag;
-    use std::rc::Rc;
 
     #[test]
     fn load_tree() {
-        let mut tree = XmlTag::from_path("test/template.musicxml".into()).unwrap();
+        let tree = XmlTag::from_path("test/template.musicxml").unwrap();
         for child in tree.all_child_with_name("identification") {
             println!("{:?} -- {:?}", child, child.get_attrib_value("id").unwrap_or("NOTHING"));
         }
-        let mut subtree = tree.first_desc_with_name("identification").last().unwrap();
+        let subtree = tree.first_desc_with_name("identification").last().unwrap();
         let mut mutsubtree = subtree.to_owned();
         mutsubtree.name = "studp".to_string();
         mutsubtree.value = Some("Long".to_string());
@@ -1,6 +1,11 @@
+use std::collections::BTreeMap;
 use fraction::{GenericFraction, Ratio};
+use smallvec::SmallVec;
 use crate::interval::PInterval;
 use super::interval;
+use crate::clef::Clef;
+use crate::measure::measure_length_from_time_sig;
+use crate::voice::StaffNumberType;
 
 pub type KeySignature = i8;
 pub type BeatDivision = i32;
@@ -8,7 +13,71 @@ pub type BeatDivision = i32;
 pub type TimeSigComponent = u8;
 pub type TimeSig = Ratio<TimeSigComponent>;
 
-pub type ClefType = i8;
+pub type ClefType = Clef;
 pub type Offset = Ratio<BeatDivision>;
 pub type Duration = Offset;
-pub type MPInterval = PInterval<Offset>;
\ No newline at end of file
+pub type MPInterval = PInterval<Offset>;
+
+
+/// Clefs of the staves below the first one, eg. the bass clef of a piano part
+pub type LowerStaffClefs = SmallVec<[ClefType; 1]>;
+
+/// Key, meter and clefs in effect for a measure
+#[derive(Clone, Debug)]
+pub struct MeasureAttributes {
+    pub key_sig: KeySignature,
+    pub clef_sign: ClefType, // of the first staff
+    pub time_sig: TimeSig,
+    pub lower_staff_clefs: LowerStaffClefs,
+}
+
+impl MeasureAttributes {
+    pub fn new(key_sig: KeySignature, clef_sign: ClefType, time_sig: TimeSig) -> Self {
+        Self { key_sig, clef_sign, time_sig, lower_staff_clefs: LowerStaffClefs::new() }
+    }
+
+    pub fn measure_length(&self) -> Duration {
+        measure_length_from_time_sig(self.time_sig)
+    }
+
+    /// A quarter note in 4/4, a half note in 2/2, an eighth note in 3/8, a dotted quarter note in 6/8
+    pub fn beat_length(&self) -> Duration {
+        let (beats, beat_type) = (*self.time_sig.numer() as i32, *self.time_sig.denom() as i32);
+        let note_length = Duration::new(4, beat_type);
+        // compound meters are felt in dotted notes
+        if beats > 3 && beats % 3 == 0 { note_length * 3 } else { note_length }
+    }
+
+    pub fn staves(&self) -> StaffNumberType {
+        1 + self.lower_staff_clefs.len() as StaffNumberType
+    }
+
+    /// Staves are numbered from 1
+    pub fn clef_of_staff(&self, staff: StaffNumberType) -> Option<ClefType> {
+        match staff {
+            0 => None,
+            1 => Some(self.clef_sign),
+            _ => self.lower_staff_clefs.get(staff as usize - 2).cloned()
+        }
+    }
+}
+
+// time signatures are compared as written, 2/2 is not 4/4
+impl PartialEq for MeasureAttributes {
+    fn eq(&self, other: &Self) -> bool {
+        self.key_sig == other.key_sig
+        && self.clef_sign == other.clef_sign
+        && self.lower_staff_clefs == other.lower_staff_clefs
+        && self.time_sig.numer() == other.time_sig.numer()
+        && self.time_sig.denom() == other.time_sig.denom()
+    }
+}
+
+/// Attributes taking effect at a given offset, each entry holds every attribute, changed or not
+pub type AttributeChanges = BTreeMap<Offset, MeasureAttributes>;
+
+/// Start and length of measures shorter than their time signature, eg. a pickup or a bar closing a repeat
+pub type ImplicitMeasures = BTreeMap<Offset, Duration>;
+
+/// Quarter notes per minute from a given offset on, as in <sound tempo>
+pub type TempoMarks = BTreeMap<Offset, f64>;
@@ -0,0 +1,80 @@
+use std::fmt::{Debug, Formatter};
+use std::str::FromStr;
+use anyhow::anyhow;
+
+pub type ClefLine = i8;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum ClefSign {
+    G,
+    F,
+    C,
+    Percussion,
+    Tab,
+    Jianpu,
+    None
+}
+
+impl FromStr for ClefSign {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "G" => Ok(Self::G),
+            "F" => Ok(Self::F),
+            "C" => Ok(Self::C),
+            "percussion" => Ok(Self::Percussion),
+            "TAB" => Ok(Self::Tab),
+            "jianpu" => Ok(Self::Jianpu),
+            "none" => Ok(Self::None),
+            _ => Err(anyhow!("Unknown clef sign {:?}", s))
+        }
+    }
+}
+
+impl Into<&str> for ClefSign {
+    fn into(self) -> &'static str {
+        match self {
+            Self::G => "G",
+            Self::F => "F",
+            Self::C => "C",
+            Self::Percussion => "percussion",
+            Self::Tab => "TAB",
+            Self::Jianpu => "jianpu",
+            Self::None => "none",
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Clef {
+    pub sign: ClefSign,
+    pub line: Option<ClefLine>
+}
+
+impl Clef {
+    pub fn new(sign: ClefSign, line: Option<ClefLine>) -> Self {
+        Self { sign, line }
+    }
+
+    pub fn treble() -> Self { Self::new(ClefSign::G, Some(2)) }
+    pub fn bass() -> Self { Self::new(ClefSign::F, Some(4)) }
+}
+
+impl Default for Clef {
+    fn default() -> Self {
+        Self::treble()
+    }
+}
+
+impl Debug for Clef {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(
+            format_args!(
+                "{}{}",
+                <ClefSign as Into<&str>>::into(self.sign),
+                self.line.map_or("".to_string(), |l| { l.to_string() })
+            )
+        )
+    }
+}
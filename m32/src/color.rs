@@ -1,4 +1,6 @@
+use std::convert::TryFrom;
 use std::fmt::{Debug, Formatter};
+use anyhow::anyhow;
 
 #[derive(Clone)]
 pub struct Color {
@@ -8,25 +10,35 @@ pub struct Color {
 }
 
 impl Color {
-    pub fn from_hex_rgb(hex_str: &str) -> Self {
-        let mut processed = hex_str.strip_prefix("#").unwrap();
+    /// "#RRGGBB" or "#AARRGGBB", the alpha is dropped
+    pub fn from_hex_rgb(hex_str: &str) -> anyhow::Result<Self> {
+        let mut processed = hex_str
+            .strip_prefix("#")
+            .filter(|hex| { hex.chars().all(|c| { c.is_ascii_hexdigit() }) })
+            .ok_or(anyhow!("{:?} is not a hex color", hex_str))?;
         // strip alpha if present
         if processed.len() == 8 { processed = &processed[2..] }
-        assert_eq!(processed.len(), 6);
-        Color {
-            red: u16::from_str_radix(&processed[0..2], 16).unwrap(),
-            green: u16::from_str_radix(&processed[2..4], 16).unwrap(),
-            blue: u16::from_str_radix(&processed[4..6], 16).unwrap(),
+        if processed.len() != 6 {
+            return Err(anyhow!("{:?} is not a hex color", hex_str));
         }
+        Ok(
+            Color {
+                red: u16::from_str_radix(&processed[0..2], 16)?,
+                green: u16::from_str_radix(&processed[2..4], 16)?,
+                blue: u16::from_str_radix(&processed[4..6], 16)?,
+            }
+        )
     }
 
     pub fn to_hex(&self) -> String {
-        format!("#{:X}{:X}{:X}", self.red, self.green, self.blue)
+        format!("#{:02X}{:02X}{:02X}", self.red, self.green, self.blue)
     }
 }
 
-impl From<&str> for Color {
-    fn from(hex: &str) -> Self {
+impl TryFrom<&str> for Color {
+    type Error = anyhow::Error;
+
+    fn try_from(hex: &str) -> Result<Self, Self::Error> {
         Color::from_hex_rgb(hex)
     }
 }
@@ -39,6 +51,7 @@ impl Into<String> for Color {
 
 impl Debug for Color {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.to_hex().as_str())
     }
 }
 
@@ -48,7 +61,7 @@ mod tests {
 
     #[test]
     fn test1() {
-        let color1 = Color::from_hex_rgb("#32a852");
+        let color1 = Color::from_hex_rgb("#32a852").unwrap();
         assert!(
             color1.red == 50&&
             color1.green == 168&&
@@ -61,7 +74,7 @@ mod tests {
 
     #[test]
     fn test2() {
-        let color1 = Color::from_hex_rgb("#3532A852");
+        let color1 = Color::from_hex_rgb("#3532A852").unwrap();
         assert!(
             color1.red == 50&&
             color1.green == 168&&
@@ -71,4 +84,11 @@ mod tests {
         let hex1 = Color::to_hex(&color1);
         assert_eq!(hex1.to_lowercase(), "#32a852".to_lowercase())
     }
+
+    #[test]
+    fn malformed_hex_is_an_error() {
+        for hex in ["32a852", "#32a85", "#32a8zz", "#ü2a852"].iter() {
+            assert!(Color::from_hex_rgb(hex).is_err());
+        }
+    }
 }
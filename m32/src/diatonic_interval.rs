@@ -0,0 +1,222 @@
+use std::fmt::{Display, Formatter};
+use std::ops::Neg;
+use std::str::FromStr;
+use anyhow::anyhow;
+use crate::attribs::KeySignature;
+use crate::pitch::{PitchClass, PsType};
+
+/// Semitones of the major (or perfect) simple intervals, from unison to seventh
+static MAJOR_OR_PERFECT_SEMITONES: [PitchClass; 7] = [0, 2, 4, 5, 7, 9, 11];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IntervalQuality {
+    Diminished(u8), // how many times, 2 for doubly diminished
+    Minor,
+    Perfect,
+    Major,
+    Augmented(u8)
+}
+
+/// Interval between two spelled pitches: a number of letter steps (generic size)
+/// and a number of semitones (chromatic size). Descending intervals are negative in both.
+/// C to E is (2, 4), a major third; C to Fb is (3, 4), a diminished fourth.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DiatonicInterval {
+    pub steps: i8,
+    pub semitones: PitchClass
+}
+
+impl DiatonicInterval {
+    pub fn new(steps: i8, semitones: PitchClass) -> Self {
+        Self { steps, semitones }
+    }
+
+    /// `generic` counts like musicians do: 1 for unison, 3 for a third, -5 for a fifth down
+    pub fn from_quality(quality: IntervalQuality, generic: i8) -> anyhow::Result<Self> {
+        if generic == 0 { return Err(anyhow!("Interval size starts at 1")); }
+        let steps = generic.abs() - 1;
+        let reference = Self::reference_semitones(steps);
+        let semitones = match (quality, Self::is_perfect_type(steps)) {
+            (IntervalQuality::Perfect, true) | (IntervalQuality::Major, false) => reference,
+            (IntervalQuality::Minor, false) => reference - 1,
+            (IntervalQuality::Augmented(n), _) => reference + n as PitchClass,
+            (IntervalQuality::Diminished(n), true) => reference - n as PitchClass,
+            (IntervalQuality::Diminished(n), false) => reference - 1 - n as PitchClass,
+            (quality, _) => return Err(anyhow!("No {:?} interval of size {}", quality, generic.abs()))
+        };
+        Ok(
+            if generic < 0 { Self::new(-steps, -semitones) }
+            else { Self::new(steps, semitones) }
+        )
+    }
+
+    /// Spelling with the fewest fifths, ie. 6 semitones is an augmented fourth, 1 a minor second
+    pub fn from_semitones(semitones: PsType) -> Self {
+        let fifths = (7 * semitones as i32 + 5).rem_euclid(12) - 5;
+        let octaves = (semitones as i32 - 7 * fifths).div_euclid(12);
+        Self::new((4 * fifths + 7 * octaves) as i8, semitones)
+    }
+
+    /// Position on the circle of fifths, the amount a key signature moves by when transposed,
+    /// eg. 2 for a major second, -5 for a minor second, 0 for any number of octaves
+    pub fn fifths(&self) -> KeySignature {
+        (7 * self.semitones as i32 - 12 * self.steps as i32) as KeySignature
+    }
+
+    pub fn is_descending(&self) -> bool {
+        self.steps < 0 || (self.steps == 0 && self.semitones < 0)
+    }
+
+    /// Musician's count, signed by direction
+    pub fn generic(&self) -> i8 {
+        let size = self.steps.abs() + 1;
+        if self.is_descending() { -size } else { size }
+    }
+
+    pub fn quality(&self) -> IntervalQuality {
+        let (steps, semitones)
+            = if self.is_descending() { (-self.steps, -self.semitones) }
+            else { (self.steps, self.semitones) };
+        let diff = semitones - Self::reference_semitones(steps);
+        match (Self::is_perfect_type(steps), diff) {
+            (true, 0) => IntervalQuality::Perfect,
+            (false, 0) => IntervalQuality::Major,
+            (false, -1) => IntervalQuality::Minor,
+            (_, d) if d > 0 => IntervalQuality::Augmented(d as u8),
+            (true, d) => IntervalQuality::Diminished((-d) as u8),
+            (false, d) => IntervalQuality::Diminished((-d - 1) as u8),
+        }
+    }
+
+    /// Same interval within an octave, keeping the direction
+    pub fn simple(&self) -> Self {
+        let octaves = (self.steps.abs() / 7) * self.steps.signum();
+        Self::new(self.steps - 7 * octaves, self.semitones - 12 * octaves)
+    }
+
+    fn reference_semitones(steps: i8) -> PitchClass {
+        MAJOR_OR_PERFECT_SEMITONES[(steps % 7) as usize] + 12 * (steps / 7)
+    }
+
+    // unisons, fourths, fifths and their compounds
+    fn is_perfect_type(steps: i8) -> bool {
+        matches!(steps % 7, 0 | 3 | 4)
+    }
+}
+
+impl Neg for DiatonicInterval {
+    type Output = Self;
+
+    fn neg(self) -> Self::Output {
+        Self::new(-self.steps, -self.semitones)
+    }
+}
+
+/// Parses names like "M3", "m6", "P5", "d5", "A4", "dd7", "M9" and "-P4" for descending ones
+impl FromStr for DiatonicInterval {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (descending, name) = match s.strip_prefix('-') {
+            Some(rest) => (true, rest),
+            None => (false, s)
+        };
+        let size_idx = name
+            .find(|c: char| { c.is_ascii_digit() })
+            .ok_or(anyhow!("Interval {:?} has no size", s))?;
+        let (quality_str, size_str) = name.split_at(size_idx);
+        let quality = match quality_str {
+            "P" => IntervalQuality::Perfect,
+            "M" => IntervalQuality::Major,
+            "m" => IntervalQuality::Minor,
+            q if !q.is_empty() && q.chars().all(|c| { c == 'd' }) => IntervalQuality::Diminished(q.len() as u8),
+            q if !q.is_empty() && q.chars().all(|c| { c == 'A' }) => IntervalQuality::Augmented(q.len() as u8),
+            _ => return Err(anyhow!("Unknown interval quality {:?}", quality_str))
+        };
+        let size: i8 = size_str.parse().map_err(|_| { anyhow!("Can't parse interval size {:?}", size_str) })?;
+        Self::from_quality(quality, if descending { -size } else { size })
+    }
+}
+
+impl Display for DiatonicInterval {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let quality = match self.quality() {
+            IntervalQuality::Perfect => "P".to_string(),
+            IntervalQuality::Major => "M".to_string(),
+            IntervalQuality::Minor => "m".to_string(),
+            IntervalQuality::Diminished(n) => "d".repeat(n as usize),
+            IntervalQuality::Augmented(n) => "A".repeat(n as usize),
+        };
+        f.write_fmt(format_args!(
+            "{}{}{}",
+            if self.is_descending() { "-" } else { "" },
+            quality,
+            self.generic().abs()
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::diatonic_interval::*;
+    use crate::pitch::{Alter, DiatonicStep, Pitch};
+
+    #[test]
+    fn interval_names() {
+        for name in ["P1", "M3", "m6", "P5", "d5", "A4", "dd7", "M9", "-P4", "-m2", "A1", "P8"].iter() {
+            let interval: DiatonicInterval = name.parse().unwrap();
+            assert_eq!(interval.to_string(), *name);
+        }
+        assert_eq!("d5".parse::<DiatonicInterval>().unwrap(), DiatonicInterval::new(4, 6));
+        assert_eq!("-M3".parse::<DiatonicInterval>().unwrap(), DiatonicInterval::new(-2, -4));
+        assert!("M5".parse::<DiatonicInterval>().is_err());
+        assert!("P3".parse::<DiatonicInterval>().is_err());
+
+        assert_eq!(DiatonicInterval::from_semitones(6).to_string(), "A4");
+        assert_eq!(DiatonicInterval::from_semitones(-1).to_string(), "-m2");
+        assert_eq!(DiatonicInterval::from_semitones(14).to_string(), "M9");
+        assert_eq!("M2".parse::<DiatonicInterval>().unwrap().fifths(), 2);
+        assert_eq!("-m3".parse::<DiatonicInterval>().unwrap().fifths(), 3);
+        assert_eq!("P8".parse::<DiatonicInterval>().unwrap().fifths(), 0);
+    }
+
+    #[test]
+    fn transpose_keeps_letter_names() {
+        let mut c_sharp = Pitch::new(DiatonicStep::C, Some(4), Alter::Sharp);
+        c_sharp.transpose_by_interval(&"M3".parse().unwrap());
+        assert_eq!((c_sharp.step, c_sharp.alter, c_sharp.octave, c_sharp.ps), (DiatonicStep::E, Alter::Sharp, Some(4), 65));
+
+        let mut f_sharp = Pitch::new(DiatonicStep::F, Some(4), Alter::Sharp);
+        f_sharp.transpose_by_interval(&"A6".parse().unwrap());
+        assert_eq!((f_sharp.step, f_sharp.alter, f_sharp.octave), (DiatonicStep::D, Alter::DoubleSharp, Some(5)));
+
+        let mut b = Pitch::new(DiatonicStep::B, Some(3), Alter::No);
+        b.transpose_by_interval(&"-P4".parse().unwrap());
+        assert_eq!((b.step, b.alter, b.octave, b.ps), (DiatonicStep::F, Alter::Sharp, Some(3), 54));
+
+        let mut quarter_sharp = Pitch::new(DiatonicStep::G, Some(4), Alter::Microtonal(0.5));
+        quarter_sharp.transpose_by_interval(&"P5".parse().unwrap());
+        assert_eq!((quarter_sharp.step, quarter_sharp.alter), (DiatonicStep::D, Alter::Microtonal(0.5)));
+
+        let e = Pitch::new(DiatonicStep::E, Some(4), Alter::No);
+        let b_flat = Pitch::new(DiatonicStep::B, Some(4), Alter::Flat);
+        assert_eq!(e.interval_to(&b_flat).to_string(), "d5");
+        assert_eq!(b_flat.interval_to(&e).to_string(), "-d5");
+    }
+
+    #[test]
+    fn respell_in_key() {
+        // D major: sharps, F# rather than Gb
+        let spelled = |ps, key| { let p = Pitch::from_ps_in_key(ps, key); (p.step, p.alter, p.octave) };
+        assert_eq!(spelled(66, 2), (DiatonicStep::F, Alter::Sharp, Some(4)));
+        assert_eq!(spelled(61, 2), (DiatonicStep::C, Alter::Sharp, Some(4)));
+        // F major: Bb and Db
+        assert_eq!(spelled(70, -1), (DiatonicStep::B, Alter::Flat, Some(4)));
+        assert_eq!(spelled(61, -1), (DiatonicStep::D, Alter::Flat, Some(4)));
+        // C# major: E# and B#, which belongs to the octave below
+        assert_eq!(spelled(65, 7), (DiatonicStep::E, Alter::Sharp, Some(4)));
+        assert_eq!(spelled(60, 7), (DiatonicStep::B, Alter::Sharp, Some(3)));
+        // Cb major: Cb, which belongs to the octave above
+        assert_eq!(spelled(59, -7), (DiatonicStep::C, Alter::Flat, Some(4)));
+    }
+}
@@ -3,8 +3,8 @@ use smallvec::{SmallVec, smallvec};
 use super::attribs::Duration;
 use fraction::*;
 
-#[derive(Copy, Clone)]
-enum DurationName {
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub(crate) enum DurationName {
     Unspecified,
     Maxima,
     Longa,
@@ -31,7 +31,7 @@ impl Into<&str> for DurationName {
             DurationName::Whole => "whole",
             DurationName::Half => "half",
             DurationName::Quarter => "quarter",
-            DurationName::Eighth => "eight",
+            DurationName::Eighth => "eighth",
             DurationName::Dur16th => "16th",
             DurationName::Dur32nd => "32nd",
             DurationName::Dur64th => "64th",
@@ -75,20 +75,18 @@ impl From<Duration> for DurationName {
     }
 }
 
-mod duration_utils {
+pub(crate) mod duration_utils {
     use smallvec::SmallVec;
     use crate::attribs::{BeatDivision, Duration};
     use std::ops::Bound::*;
     use fraction::Ratio;
     use crate::duration::{DURATION_TO_DURATION_NAME, DurationName};
 
-    pub fn maximal_extractable_primitive_duration(duration: &Duration) -> Duration {
+    pub fn maximal_extractable_primitive_duration(duration: &Duration) -> Option<Duration> {
         DURATION_TO_DURATION_NAME
             .range((Unbounded, Included(duration)))
             .last()
-            .expect("Cannot find maximal extractable primitive duration")
-            .0
-            .clone()
+            .map(|(dur, _)| { dur.clone() })
     }
 
     // Decompose a duration so that it is representable in conventional duration type
@@ -99,8 +97,7 @@ mod duration_utils {
 
         // basically division
         let mut remainder = duration.clone();
-        let mut max_extractable_primitive = maximal_extractable_primitive_duration(&remainder);
-        while remainder >= max_extractable_primitive {
+        while let Some(max_extractable_primitive) = maximal_extractable_primitive_duration(&remainder) {
             remainder -= max_extractable_primitive;
             if !decomposition.is_empty() {
                 let mut last_component
@@ -115,8 +112,6 @@ mod duration_utils {
                 else { decomposition.push((max_extractable_primitive, 0)); }
             }
             else { decomposition.push((max_extractable_primitive, 0)); }
-
-            max_extractable_primitive = maximal_extractable_primitive_duration(&remainder);
         }
         if remainder != Duration::from_integer(0) {
             return Result::Err(anyhow::anyhow!("Duration not representable in primitives"));
@@ -124,13 +119,11 @@ mod duration_utils {
         return Ok(decomposition);
     }
 
-    pub fn compute_dotted_length(duration: Duration, mut dots: u8) -> Duration
+    pub fn compute_dotted_length(duration: Duration, dots: u8) -> Duration
     {
-        assert!(dots >= 0);
         let mut res = Duration::from_integer(0);
-        while dots >= 0 {
-            res += (duration / (2 as BeatDivision).pow(dots as u32));
-            dots -= 1;
+        for dot in 0..=dots {
+            res += duration / (2 as BeatDivision).pow(dot as u32);
         }
         res
     }
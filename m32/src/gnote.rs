@@ -1,3 +1,4 @@
+use crate::attribs::Offset;
 use crate::simple_note::SimpleNote;
 use super::{simple_note, tuplet};
 
@@ -11,8 +12,39 @@ macro_rules! either_gnote {
     )}
 }
 
-#[derive(Clone)]
+#[derive(Clone, Debug)]
 pub enum Gnote {
     SimpleNote(simple_note::SimpleNote),
     Tuplet(tuplet::Tuplet)
 }
+
+impl Gnote {
+    /// The note itself, or the notes of the tuplet with their intervals relative to it
+    pub fn simple_note_iter(&self) -> Box<dyn Iterator<Item=&SimpleNote> + '_> {
+        match self {
+            Gnote::SimpleNote(sn) => Box::new(std::iter::once(sn)),
+            Gnote::Tuplet(tup) => tup.simple_note_iter()
+        }
+    }
+
+    pub fn simple_note_mut_iter(&mut self) -> Box<dyn Iterator<Item=&mut SimpleNote> + '_> {
+        match self {
+            Gnote::SimpleNote(sn) => Box::new(std::iter::once(sn)),
+            Gnote::Tuplet(tup) => tup.simple_note_mut_iter()
+        }
+    }
+
+    /// Halves tied at `offset`, a tuplet keeps its ratio on both sides
+    pub fn split_at_offset(&self, offset: Offset) -> (Gnote, Gnote) {
+        match self {
+            Gnote::SimpleNote(sn) => {
+                let (mut first_half, mut second_half) = sn.split_at_offset(offset);
+                (Gnote::SimpleNote(first_half.remove(0)), Gnote::SimpleNote(second_half.remove(0)))
+            },
+            Gnote::Tuplet(tup) => {
+                let (first_half, second_half) = tup.split_at_offset(offset);
+                (Gnote::Tuplet(first_half), Gnote::Tuplet(second_half))
+            }
+        }
+    }
+}
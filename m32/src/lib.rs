@@ -10,14 +10,25 @@ mod attribs;
 mod interval;
 mod simple_note;
 mod pitch;
+mod diatonic_interval;
+mod transposition;
 mod duration;
 mod color;
+mod clef;
 mod lyric;
 mod tuplet;
 mod config;
 mod gnote;
 mod measure;
+mod voice;
 mod part;
 mod score;
 mod xml_import;
 mod xml_export;
+mod mxl;
+mod midi_export;
+mod midi_import;
+mod melbank;
+mod similarity;
+mod score_diff;
+mod tuplet_detection;
@@ -1,11 +1,49 @@
-#[derive(Clone)]
+use std::str::FromStr;
+use anyhow::anyhow;
+
+/// Where the syllable stands in its word, as MusicXML's <syllabic>
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Syllabic {
+    Single,
+    Begin,
+    Middle,
+    End,
+}
+
+impl FromStr for Syllabic {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "single" => Ok(Self::Single),
+            "begin" => Ok(Self::Begin),
+            "middle" => Ok(Self::Middle),
+            "end" => Ok(Self::End),
+            _ => Err(anyhow!("{:?} is not a syllabic type", s))
+        }
+    }
+}
+
+impl Into<&str> for Syllabic {
+    fn into(self) -> &'static str {
+        match self {
+            Self::Single => "single",
+            Self::Begin => "begin",
+            Self::Middle => "middle",
+            Self::End => "end",
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
 pub struct Lyric {
     pub number: u8,
+    pub syllabic: Option<Syllabic>,
     pub text: String
 }
 
 impl Lyric {
     pub fn new(number: u8, text: String) -> Self {
-        Self { number, text }
+        Self { number, syllabic: None, text }
     }
-}
\ No newline at end of file
+}
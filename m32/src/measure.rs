@@ -1,16 +1,20 @@
 use fraction::Ratio;
-use crate::attribs::{BeatDivision, Duration, MPInterval, Offset, TimeSig, TimeSigComponent};
+use crate::attribs::{BeatDivision, Duration, MeasureAttributes, MPInterval, Offset, TimeSig, TimeSigComponent};
 use crate::either_gnote;
 use crate::gnote::Gnote;
 use crate::simple_note::SimpleNote;
 use crate::tuplet::Tuplet;
+use crate::voice::{VoiceKey, VoiceMap};
 
 pub type MeasureNumberType = u32;
-#[derive(Clone)]
+#[derive(Clone, Debug)]
 pub struct Measure {
     pub interval: MPInterval,
-    pub gnotes: Vec<Gnote>,
+    pub gnotes: Vec<Gnote>, // primary voice
     pub measure_number: MeasureNumberType,
+    pub secondary_voices: VoiceMap,
+    pub attributes: MeasureAttributes, // in effect for this measure
+    pub implicit: bool, // MusicXML implicit="yes", eg. a pickup measure, not counted in numbering
 }
 
 pub fn measure_length_from_time_sig(ts: TimeSig)
@@ -27,16 +31,34 @@ impl Measure {
         offset: Offset,
         duration: Duration,
         measure_number: MeasureNumberType,
-        gnotes: Vec<Gnote>
+        gnotes: Vec<Gnote>,
+        attributes: MeasureAttributes
     ) -> Measure
     {
         Measure {
             interval: MPInterval::from_start_and_length(offset, duration),
             gnotes,
-            measure_number
+            measure_number,
+            secondary_voices: VoiceMap::new(),
+            attributes,
+            implicit: false
         }
     }
 
+    pub fn voice_gnotes(&self, voice: VoiceKey, primary_voice: VoiceKey)
+        -> Option<&Vec<Gnote>>
+    {
+        if voice == primary_voice { Some(&self.gnotes) }
+        else { self.secondary_voices.get(&voice) }
+    }
+
+    pub fn voice_gnotes_mut(&mut self, voice: VoiceKey, primary_voice: VoiceKey)
+        -> &mut Vec<Gnote>
+    {
+        if voice == primary_voice { &mut self.gnotes }
+        else { self.secondary_voices.entry(voice).or_insert_with(Vec::new) }
+    }
+
     pub fn get_elements_acc_duration(&self) -> Duration
     {
         self
@@ -57,15 +79,9 @@ impl Measure {
         .iter()
         .all(
             |gnote| {
-                match gnote {
-                    Gnote::SimpleNote(sn) => sn.is_rest(),
-                    Gnote::Tuplet(tup) => {
-                        tup
-                        .notes
-                        .iter()
-                        .all(|sn|{sn.is_rest()})
-                    }
-                }
+                gnote
+                .simple_note_iter()
+                .all(|sn|{sn.is_rest()})
             }
         )
     }
@@ -0,0 +1,566 @@
+//! Melody bank: every part of a directory tree of MusicXML/MXL files, indexed in SQLite.
+//! Files are laid out as <composer>/<work...>.musicxml, and a part is named after its path and
+//! number in the score, eg. mozart>sonata>no1>2. Parts are identified by their content hash,
+//! so the same melody found twice is only stored once.
+
+use std::path::{Path, PathBuf};
+use anyhow::{anyhow, Context};
+use adaxml::tag::XmlTag;
+use rusqlite::{Connection, OptionalExtension, Transaction, params, params_from_iter};
+use rusqlite::types::Value;
+use crate::attribs::{Duration, KeySignature, TimeSig};
+use crate::part::{FingerprintKind, Part};
+use crate::xml_import::{score_from_tag, score_tag_from_path};
+
+pub const MELODY_TABLE: &str = "MelodyBankTable";
+pub const PATH_TABLE: &str = "Path2Hashes";
+pub const MEL_NAME_SEPARATOR: &str = ">";
+const SCORE_EXTENSIONS: [&str; 3] = ["musicxml", "xml", "mxl"];
+
+// columns describing a part, added to banks created before they existed
+const METADATA_COLUMNS: [(&str, &str); 9] = [
+    ("Composer", "TEXT"),
+    ("Work", "TEXT"),
+    ("PartName", "TEXT"),
+    ("KeySig", "INTEGER"),
+    ("TimeSigBeats", "INTEGER"),
+    ("TimeSigBeatType", "INTEGER"),
+    ("Measures", "INTEGER"),
+    ("LengthQuarters", "REAL"),
+    ("Fingerprint", "TEXT"),
+];
+
+/// A part as stored in the bank
+#[derive(Clone, Debug, PartialEq)]
+pub struct MelBankEntry {
+    pub hash: String, // hex of Part::hash_note_and_rests
+    pub name: String,
+    pub container_path: String, // relative to the indexed directory
+    pub composer: Option<String>,
+    pub work: String,
+    pub part_name: String,
+    pub key_sig: KeySignature,
+    pub time_sig: TimeSig,
+    pub measures: u32, // implicit measures (pickups) not counted
+    pub length_quarters: f64,
+    pub fingerprint: String, // hex of the FingerprintKind::Normalized fingerprint
+}
+
+impl MelBankEntry {
+    /// Position of the part in its score, starting at 1, the last component of its name
+    pub fn part_number(&self) -> Option<usize>
+    {
+        self.name.rsplit(MEL_NAME_SEPARATOR).next()?.parse().ok()
+    }
+}
+
+/// Every criterion is optional, those given must all hold
+#[derive(Clone, Debug, Default)]
+pub struct MelBankQuery {
+    pub composer: Option<String>, // case insensitive
+    pub work: Option<String>,
+    pub part_name: Option<String>,
+    pub key_sig: Option<KeySignature>,
+    pub time_sig: Option<TimeSig>, // as written, 2/2 is not 4/4
+    pub min_measures: Option<u32>,
+    pub max_measures: Option<u32>,
+    pub fingerprint: Option<String>, // finds the same tune in another key, tempo or padding
+}
+
+#[derive(Debug, Default)]
+pub struct IndexReport {
+    pub indexed_files: usize,
+    pub added_parts: usize,
+    pub duplicate_parts: usize, // already in the bank under another name or path
+    pub failed_files: Vec<(PathBuf, String)>,
+}
+
+pub struct MelBank {
+    connection: Connection
+}
+
+impl MelBank {
+    /// Opens (or creates) a bank, eg. test/melBank/AIDA.sqlite
+    pub fn open(path: &str) -> anyhow::Result<Self>
+    {
+        let connection = Connection::open(path)
+            .with_context(|| format!("Can't open melody bank {:?}", path))?;
+        Self::from_connection(connection)
+    }
+
+    pub fn open_in_memory() -> anyhow::Result<Self>
+    {
+        Self::from_connection(Connection::open_in_memory().context("Can't create melody bank in memory")?)
+    }
+
+    fn from_connection(connection: Connection) -> anyhow::Result<Self>
+    {
+        let bank = Self { connection };
+        bank.create_schema().context("Can't set up melody bank tables")?;
+        Ok(bank)
+    }
+
+    fn create_schema(&self) -> rusqlite::Result<()>
+    {
+        self.connection.execute_batch(&format!(
+            "CREATE TABLE IF NOT EXISTS {melodies} (\
+                MelHash TEXT PRIMARY KEY ON CONFLICT FAIL NOT NULL ON CONFLICT FAIL, \
+                MelName TEXT NOT NULL ON CONFLICT FAIL UNIQUE ON CONFLICT FAIL, \
+                MelContainerPath TEXT, \
+                MelParentHash TEXT, \
+                FOREIGN KEY (MelParentHash) REFERENCES {melodies} (MelHash) on delete cascade on update restrict); \
+            CREATE TABLE IF NOT EXISTS {paths} (\
+                PATH TEXT NOT NULL ON CONFLICT FAIL, \
+                HASH TEXT NOT NULL ON CONFLICT FAIL, \
+                PRIMARY KEY (HASH, PATH));",
+            melodies = MELODY_TABLE,
+            paths = PATH_TABLE
+        ))?;
+
+        // banks created with a unique HASH kept a single path per hash
+        let paths_sql: String = self.connection.query_row(
+            "SELECT sql FROM sqlite_master WHERE type = 'table' AND name = ?1",
+            params![PATH_TABLE],
+            |row| { row.get(0) }
+        )?;
+        if !paths_sql.contains("PRIMARY KEY") {
+            self.connection.execute_batch(&format!(
+                "ALTER TABLE {paths} RENAME TO {paths}Old; \
+                CREATE TABLE {paths} (\
+                    PATH TEXT NOT NULL ON CONFLICT FAIL, \
+                    HASH TEXT NOT NULL ON CONFLICT FAIL, \
+                    PRIMARY KEY (HASH, PATH)); \
+                INSERT OR IGNORE INTO {paths} (PATH, HASH) SELECT PATH, HASH FROM {paths}Old WHERE HASH IS NOT NULL; \
+                DROP TABLE {paths}Old;",
+                paths = PATH_TABLE
+            ))?;
+        }
+
+        let existing_columns: Vec<String>
+            = self
+            .connection
+            .prepare(&format!("PRAGMA table_info({})", MELODY_TABLE))?
+            .query_map([], |row| { row.get::<_, String>(1) })?
+            .collect::<rusqlite::Result<_>>()?;
+        for (column, sql_type) in METADATA_COLUMNS.iter() {
+            if !existing_columns.iter().any(|existing| { existing == column }) {
+                self.connection.execute(
+                    &format!("ALTER TABLE {} ADD COLUMN {} {}", MELODY_TABLE, column, sql_type),
+                    []
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Indexes every score below `dir`, a file that can't be read is reported and skipped
+    pub fn index_directory(&mut self, dir: &str) -> anyhow::Result<IndexReport>
+    {
+        let root = Path::new(dir);
+        let mut score_paths = Vec::new();
+        collect_score_paths(root, &mut score_paths)
+            .with_context(|| format!("Can't list scores in {:?}", dir))?;
+        score_paths.sort();
+
+        let mut report = IndexReport::default();
+        for path in score_paths {
+            let relative_path = path.strip_prefix(root).unwrap();
+            let entries = match entries_of_file(&path, relative_path) {
+                Ok(entries) => entries,
+                Err(err) => {
+                    report.failed_files.push((path.clone(), format!("{:#}", err)));
+                    continue;
+                }
+            };
+            let transaction = self.connection.transaction()?;
+            // the parts a re-indexed file used to hold may no longer be found there
+            transaction
+                .execute(
+                    &format!("DELETE FROM {} WHERE PATH = ?1", PATH_TABLE),
+                    params![container_path_of(relative_path)]
+                )
+                .with_context(|| format!("Can't forget former parts of {:?}", relative_path))?;
+            for entry in entries.iter() {
+                if insert_entry_with(&transaction, entry).with_context(|| format!("Can't store part {:?}", entry.name))? {
+                    report.added_parts += 1;
+                } else {
+                    report.duplicate_parts += 1;
+                }
+            }
+            transaction.commit()?;
+            report.indexed_files += 1;
+        }
+        Ok(report)
+    }
+
+    /// Returns false if a part with the same hash already is in the bank.
+    /// A part of a bank made before the metadata columns existed gets them filled in.
+    pub fn insert_entry(&mut self, entry: &MelBankEntry) -> anyhow::Result<bool>
+    {
+        let transaction = self.connection.transaction()?;
+        let added = insert_entry_with(&transaction, entry)?;
+        transaction.commit()?;
+        Ok(added)
+    }
+
+    /// Parts matching every given criterion, by name. Parts without metadata are left out.
+    pub fn query(&self, query: &MelBankQuery) -> anyhow::Result<Vec<MelBankEntry>>
+    {
+        let mut conditions = vec!["KeySig IS NOT NULL".to_string()];
+        let mut values: Vec<Value> = Vec::new();
+        let mut add_condition = |condition: &str, value: Value| {
+            values.push(value);
+            conditions.push(format!("{} ?{}", condition, values.len()));
+        };
+        if let Some(composer) = query.composer.as_ref() {
+            add_condition("Composer COLLATE NOCASE =", Value::Text(composer.clone()));
+        }
+        if let Some(work) = query.work.as_ref() {
+            add_condition("Work =", Value::Text(work.clone()));
+        }
+        if let Some(part_name) = query.part_name.as_ref() {
+            add_condition("PartName =", Value::Text(part_name.clone()));
+        }
+        if let Some(key_sig) = query.key_sig {
+            add_condition("KeySig =", Value::Integer(key_sig as i64));
+        }
+        if let Some(time_sig) = query.time_sig {
+            add_condition("TimeSigBeats =", Value::Integer(*time_sig.numer() as i64));
+            add_condition("TimeSigBeatType =", Value::Integer(*time_sig.denom() as i64));
+        }
+        if let Some(min_measures) = query.min_measures {
+            add_condition("Measures >=", Value::Integer(min_measures as i64));
+        }
+        if let Some(max_measures) = query.max_measures {
+            add_condition("Measures <=", Value::Integer(max_measures as i64));
+        }
+        if let Some(fingerprint) = query.fingerprint.as_ref() {
+            add_condition("Fingerprint =", Value::Text(fingerprint.clone()));
+        }
+
+        let sql = format!(
+            "SELECT MelHash, MelName, MelContainerPath, Composer, Work, PartName, KeySig, TimeSigBeats, \
+            TimeSigBeatType, Measures, LengthQuarters, Fingerprint FROM {} WHERE {} ORDER BY MelName",
+            MELODY_TABLE,
+            conditions.join(" AND ")
+        );
+        let mut statement = self.connection.prepare(&sql).context("Can't prepare melody bank query")?;
+        let entries = statement
+            .query_map(params_from_iter(values.iter()), |row| {
+                Ok(MelBankEntry {
+                    hash: row.get(0)?,
+                    name: row.get(1)?,
+                    container_path: row.get::<_, Option<String>>(2)?.unwrap_or_default(),
+                    composer: row.get(3)?,
+                    work: row.get(4)?,
+                    part_name: row.get(5)?,
+                    key_sig: row.get(6)?,
+                    time_sig: TimeSig::new_raw(row.get(7)?, row.get(8)?),
+                    measures: row.get(9)?,
+                    length_quarters: row.get(10)?,
+                    fingerprint: row.get::<_, Option<String>>(11)?.unwrap_or_default(),
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()
+            .context("Can't read melody bank query results")?;
+        Ok(entries)
+    }
+
+    /// Other parts holding the same tune, whatever their key, tempo or surrounding rests
+    pub fn duplicates_of(&self, entry: &MelBankEntry) -> anyhow::Result<Vec<MelBankEntry>>
+    {
+        let query = MelBankQuery { fingerprint: Some(entry.fingerprint.clone()), ..Default::default() };
+        Ok(
+            self
+            .query(&query)?
+            .into_iter()
+            .filter(|other| { other.hash != entry.hash })
+            .collect()
+        )
+    }
+
+    /// Container paths (relative to the indexed directory) a hash was found in
+    pub fn paths_of_hash(&self, hash: &str) -> anyhow::Result<Vec<String>>
+    {
+        let mut statement
+            = self
+            .connection
+            .prepare(&format!("SELECT PATH FROM {} WHERE HASH = ?1 ORDER BY PATH", PATH_TABLE))?;
+        let paths = statement
+            .query_map(params![hash], |row| { row.get(0) })?
+            .collect::<rusqlite::Result<Vec<String>>>()?;
+        Ok(paths)
+    }
+}
+
+fn collect_score_paths(dir: &Path, score_paths: &mut Vec<PathBuf>) -> std::io::Result<()>
+{
+    for dir_entry in std::fs::read_dir(dir)? {
+        let path = dir_entry?.path();
+        if path.is_dir() {
+            collect_score_paths(&path, score_paths)?;
+        } else if path
+            .extension()
+            .and_then(|ext| { ext.to_str() })
+            .map_or(false, |ext| { SCORE_EXTENSIONS.contains(&ext) })
+        {
+            score_paths.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// One entry per part of the score at `path`, `relative_path` gives composer and work
+pub fn entries_of_file(path: &Path, relative_path: &Path) -> anyhow::Result<Vec<MelBankEntry>>
+{
+    let path_str = path.to_str().ok_or(anyhow!("Path {:?} is not valid unicode", path))?;
+    let score_tag = score_tag_from_path(path_str)?;
+    let score = score_from_tag(&score_tag).with_context(|| format!("Can't import {:?}", path))?;
+
+    // directories, then the file name without extension
+    let mut components: Vec<String>
+        = relative_path
+        .parent()
+        .into_iter()
+        .flat_map(|parent| { parent.components() })
+        .map(|component| { component.as_os_str().to_string_lossy().to_string() })
+        .collect();
+    components.push(
+        relative_path
+        .file_stem()
+        .ok_or(anyhow!("Path {:?} has no file name", path))?
+        .to_string_lossy()
+        .to_string()
+    );
+    let (composer, work)
+        = if components.len() > 1 { (Some(components[0].clone()), components[1..].join(MEL_NAME_SEPARATOR)) }
+        else { (composer_of_score_tag(&score_tag), components[0].clone()) };
+    let container_path = container_path_of(relative_path);
+
+    Ok(
+        score
+        .parts
+        .iter()
+        .enumerate()
+        .map(|(idx, part)| {
+            entry_of_part(
+                part,
+                format!("{}{}{}", components.join(MEL_NAME_SEPARATOR), MEL_NAME_SEPARATOR, idx + 1),
+                container_path.clone(),
+                composer.clone(),
+                work.clone()
+            )
+        })
+        .collect()
+    )
+}
+
+/// `MelBank::insert_entry` within a transaction the caller commits
+fn insert_entry_with(transaction: &Transaction, entry: &MelBankEntry) -> anyhow::Result<bool>
+{
+    let known_hash: Option<String>
+        = transaction
+        .query_row(
+            &format!("SELECT MelHash FROM {} WHERE MelHash = ?1", MELODY_TABLE),
+            params![entry.hash],
+            |row| { row.get(0) }
+        )
+        .optional()?;
+
+    let added = match known_hash {
+        Some(_) => {
+            transaction.execute(
+                &format!(
+                    "UPDATE {} SET Composer = ?2, Work = ?3, PartName = ?4, KeySig = ?5, TimeSigBeats = ?6, \
+                    TimeSigBeatType = ?7, Measures = ?8, LengthQuarters = ?9, Fingerprint = ?10 \
+                    WHERE MelHash = ?1 AND KeySig IS NULL",
+                    MELODY_TABLE
+                ),
+                params![
+                    entry.hash, entry.composer, entry.work, entry.part_name, entry.key_sig,
+                    entry.time_sig.numer(), entry.time_sig.denom(), entry.measures, entry.length_quarters,
+                    entry.fingerprint
+                ]
+            )?;
+            false
+        },
+        None => {
+            // a re-indexed file whose content changed replaces its former version
+            transaction.execute(&format!("DELETE FROM {} WHERE MelName = ?1", MELODY_TABLE), params![entry.name])?;
+            transaction.execute(
+                &format!(
+                    "INSERT INTO {} (MelHash, MelName, MelContainerPath, Composer, Work, PartName, KeySig, \
+                    TimeSigBeats, TimeSigBeatType, Measures, LengthQuarters, Fingerprint) \
+                    VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)",
+                    MELODY_TABLE
+                ),
+                params![
+                    entry.hash, entry.name, entry.container_path, entry.composer, entry.work, entry.part_name,
+                    entry.key_sig, entry.time_sig.numer(), entry.time_sig.denom(), entry.measures,
+                    entry.length_quarters, entry.fingerprint
+                ]
+            )?;
+            true
+        }
+    };
+    transaction.execute(
+        &format!("INSERT OR IGNORE INTO {} (PATH, HASH) VALUES (?1, ?2)", PATH_TABLE),
+        params![entry.container_path, entry.hash]
+    )?;
+    Ok(added)
+}
+
+// path components joined with '/' whatever the platform
+fn container_path_of(relative_path: &Path) -> String
+{
+    relative_path
+    .components()
+    .map(|component| { component.as_os_str().to_string_lossy().to_string() })
+    .collect::<Vec<_>>()
+    .join("/")
+}
+
+pub fn entry_of_part(part: &Part, name: String, container_path: String, composer: Option<String>, work: String)
+    -> MelBankEntry
+{
+    let mpart = part.to_measured();
+    let length: Duration = mpart.measures.iter().map(|measure| { measure.interval.length }).sum();
+    MelBankEntry {
+        hash: hex_of(part.hash_note_and_rests().as_slice()),
+        name,
+        container_path,
+        composer,
+        work,
+        part_name: part.name.clone(),
+        key_sig: part.key_sig,
+        time_sig: part.time_sig,
+        measures: mpart.measures.iter().filter(|measure| { !measure.implicit }).count() as u32,
+        length_quarters: *length.numer() as f64 / *length.denom() as f64,
+        fingerprint: hex_of(part.fingerprint(FingerprintKind::Normalized).as_slice()),
+    }
+}
+
+fn composer_of_score_tag(score_tag: &XmlTag) -> Option<String>
+{
+    score_tag
+    .select_values("identification/creator[@type='composer']")
+    .ok()?
+    .next()
+    .map(|composer| { composer.to_string() })
+}
+
+fn hex_of(bytes: &[u8]) -> String
+{
+    bytes.iter().map(|byte| { format!("{:02X}", byte) }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::attribs::TimeSig;
+    use crate::melbank::*;
+    use crate::xml_import::score_from_path;
+
+    const MELBANK_DIR: &str = "test/melBank";
+
+    #[test]
+    fn index_and_query() {
+        let mut bank = MelBank::open_in_memory().unwrap();
+        let report = bank.index_directory(MELBANK_DIR).unwrap();
+        assert!(report.failed_files.is_empty(), "{:?}", report.failed_files);
+        assert_eq!(report.indexed_files, 6);
+        assert!(report.added_parts > 0);
+
+        let all = bank.query(&MelBankQuery::default()).unwrap();
+        assert_eq!(all.len(), report.added_parts);
+        let sonata = all.iter().find(|entry| { entry.name == "mozart>sonata>no1>1" }).unwrap();
+        assert_eq!(sonata.composer.as_deref(), Some("mozart"));
+        assert_eq!(sonata.work, "sonata>no1");
+        assert_eq!(sonata.container_path, "mozart/sonata/no1.musicxml");
+        assert_eq!(sonata.hash.len(), 128);
+
+        // re-indexing only finds what is already there
+        let again = bank.index_directory(MELBANK_DIR).unwrap();
+        assert_eq!(again.added_parts, 0);
+        assert_eq!(bank.query(&MelBankQuery::default()).unwrap().len(), all.len());
+
+        // composers match case insensitively
+        let longduong = bank.query(&MelBankQuery { composer: Some("LongDuong".to_string()), ..Default::default() }).unwrap();
+        assert!(!longduong.is_empty());
+        assert!(longduong.iter().all(|entry| { entry.composer.as_deref() == Some("longduong") }));
+
+        let mozart_entries = all.iter().filter(|entry| { entry.composer.as_deref() == Some("mozart") });
+        for entry in mozart_entries {
+            let query = MelBankQuery {
+                composer: Some("mozart".to_string()),
+                time_sig: Some(entry.time_sig),
+                min_measures: Some(entry.measures),
+                ..Default::default()
+            };
+            assert!(bank.query(&query).unwrap().contains(entry));
+            let longer = MelBankQuery { min_measures: Some(entry.measures + 1), ..query };
+            assert!(!bank.query(&longer).unwrap().contains(entry));
+        }
+        // a transposed copy has its own hash but is found as a duplicate
+        let score = score_from_path("test/melBank/mozart/sonata/no1.musicxml").unwrap();
+        let transposed = entry_of_part(
+            &score.parts[0].transpose_by(3), "copyist>no1 in Eb>1".to_string(),
+            "copies/no1.musicxml".to_string(), Some("copyist".to_string()), "no1 in Eb".to_string()
+        );
+        assert!(bank.insert_entry(&transposed).unwrap());
+        assert!(bank.duplicates_of(&transposed).unwrap().iter().any(|entry| { entry.name == "mozart>sonata>no1>1" }));
+
+        let three_four = MelBankQuery { time_sig: Some(TimeSig::new_raw(3, 4)), ..Default::default() };
+        assert!(bank.query(&three_four).unwrap().iter().all(|entry| {
+            (entry.time_sig.numer(), entry.time_sig.denom()) == (&3, &4)
+        }));
+    }
+
+    #[test]
+    fn identical_files_keep_every_path() {
+        let dir = std::env::temp_dir().join("m32_melbank_identical");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("mozart")).unwrap();
+        for copy in ["first", "second"].iter() {
+            std::fs::copy(
+                format!("{}/mozart/sonata/no1.musicxml", MELBANK_DIR),
+                dir.join("mozart").join(format!("{}.musicxml", copy))
+            ).unwrap();
+        }
+        let mut bank = MelBank::open_in_memory().unwrap();
+        let report = bank.index_directory(dir.to_str().unwrap()).unwrap();
+        assert!(report.duplicate_parts > 0);
+        let entry = bank.query(&MelBankQuery::default()).unwrap().remove(0);
+        assert_eq!(bank.paths_of_hash(&entry.hash).unwrap(), ["mozart/first.musicxml", "mozart/second.musicxml"]);
+
+        // a copy that can't be read any more keeps what it was indexed with
+        std::fs::write(dir.join("mozart").join("second.musicxml"), "not a score").unwrap();
+        let report = bank.index_directory(dir.to_str().unwrap()).unwrap();
+        assert_eq!(report.failed_files.len(), 1);
+        assert_eq!(bank.paths_of_hash(&entry.hash).unwrap(), ["mozart/first.musicxml", "mozart/second.musicxml"]);
+
+        // once the second copy holds another score, re-indexing forgets the part there
+        std::fs::copy(
+            format!("{}/mozart/symps/no1.musicxml", MELBANK_DIR),
+            dir.join("mozart").join("second.musicxml")
+        ).unwrap();
+        bank.index_directory(dir.to_str().unwrap()).unwrap();
+        assert_eq!(bank.paths_of_hash(&entry.hash).unwrap(), ["mozart/first.musicxml"]);
+    }
+
+    #[test]
+    fn upgrades_existing_bank() {
+        // work on a copy, the bank under test/ stays as shipped
+        let bank_path = std::env::temp_dir().join("m32_melbank_upgrade.sqlite");
+        std::fs::copy(format!("{}/AIDA.sqlite", MELBANK_DIR), &bank_path).unwrap();
+        let mut bank = MelBank::open(bank_path.to_str().unwrap()).unwrap();
+        // parts of the shipped bank have no metadata yet
+        assert!(bank.query(&MelBankQuery::default()).unwrap().is_empty());
+
+        bank.index_directory(MELBANK_DIR).unwrap();
+        let entries = bank.query(&MelBankQuery::default()).unwrap();
+        assert!(!entries.is_empty());
+        for entry in entries.iter() {
+            assert!(!bank.paths_of_hash(&entry.hash).unwrap().is_empty());
+        }
+    }
+}
@@ -0,0 +1,343 @@
+//! Standard MIDI File (format 1) export.
+//! Track 0 is a conductor track holding the tempo changes, then one track per Part.
+
+use std::collections::HashMap;
+use anyhow::{anyhow, Context};
+use fraction::ToPrimitive;
+use midly::{Format, Header, MetaMessage, MidiMessage, Smf, Timing, Track, TrackEvent, TrackEventKind};
+use midly::num::{u15, u24, u28, u4, u7};
+use smallvec::SmallVec;
+use crate::attribs::{MeasureAttributes, Offset, TempoMarks};
+use crate::gnote::Gnote;
+use crate::part::Part;
+use crate::score::Score;
+use crate::simple_note::{SimpleNote, TieInfo};
+use crate::transposition::transpose_key;
+
+pub const TICKS_PER_QUARTER: u16 = 480;
+pub const DEFAULT_QUARTER_BPM: f64 = 120.0;
+pub const DEFAULT_VELOCITY: u8 = 80;
+const PERCUSSION_CHANNEL: u8 = 9;
+
+pub fn score_to_midi_path(score: &Score, path: &str) -> anyhow::Result<()>
+{
+    score_to_smf(score, DEFAULT_QUARTER_BPM)?
+        .save(path)
+        .with_context(|| format!("Can't write midi to {:?}", path))
+}
+
+/// `quarter_bpm` holds until the first tempo mark of the score
+pub fn score_to_smf(score: &Score, quarter_bpm: f64) -> anyhow::Result<Smf>
+{
+    let mut smf = Smf::new(Header::new(Format::Parallel, Timing::Metrical(u15::new(TICKS_PER_QUARTER))));
+    smf.tracks.reserve(score.parts.len() + 1);
+    smf.tracks.push(conductor_track(score, quarter_bpm)?);
+    for (idx, part) in score.parts.iter().enumerate() {
+        smf.tracks.push(
+            part_to_track(part, channel_of_part(idx))
+            .with_context(|| format!("Can't export part {:?} to midi", part.name))?
+        );
+    }
+    Ok(smf)
+}
+
+fn conductor_track(score: &Score, quarter_bpm: f64) -> anyhow::Result<Track>
+{
+    // tempo marks of every part, at a given offset the first part holding one wins
+    let mut tempo_marks = TempoMarks::new();
+    for part in score.parts.iter() {
+        for (offset, bpm) in part.tempo_marks.iter() {
+            tempo_marks.entry(*offset).or_insert(*bpm);
+        }
+    }
+    tempo_marks.entry(Offset::from_integer(0)).or_insert(quarter_bpm);
+
+    let mut track = vec![meta_event(0, MetaMessage::TrackName(score.title.as_bytes()))];
+    let mut last_tick = 0;
+    let mut previous_bpm = None;
+    for (offset, bpm) in tempo_marks {
+        if previous_bpm == Some(bpm) { continue; }
+        let tick = ticks_of(offset)?;
+        track.push(meta_event(tick - last_tick, MetaMessage::Tempo(micros_per_quarter(bpm)?)));
+        last_tick = tick;
+        previous_bpm = Some(bpm);
+    }
+    track.push(meta_event(0, MetaMessage::EndOfTrack));
+    Ok(track)
+}
+
+fn micros_per_quarter(quarter_bpm: f64) -> anyhow::Result<u24>
+{
+    if quarter_bpm <= 0.0 { return Err(anyhow!("Invalid tempo {}", quarter_bpm)); }
+    u24::try_from((60_000_000.0 / quarter_bpm).round() as u32)
+        .ok_or(anyhow!("Tempo {} is too slow for midi", quarter_bpm))
+}
+
+// channels wrap around the 16 available ones, leaving out the percussion channel
+fn channel_of_part(part_idx: usize) -> u4
+{
+    let channel = (part_idx % 15) as u8;
+    u4::new(if channel >= PERCUSSION_CHANNEL { channel + 1 } else { channel })
+}
+
+/// Sounding note, tied notes already merged
+struct MidiNote {
+    start: u32,
+    end: u32,
+    key: u7,
+    velocity: u7
+}
+
+pub fn part_to_track(part: &Part, channel: u4) -> anyhow::Result<Track>
+{
+    // (tick, order, event): metas first, then note offs, then note ons at the same tick
+    let mut timed_events: Vec<(u32, u8, TrackEventKind)> = Vec::new();
+    timed_events.push((0, 0, TrackEventKind::Meta(MetaMessage::TrackName(part.name.as_bytes()))));
+
+    // attribute changes are placed on the barline they take effect at
+    let mpart = part.to_measured();
+    let mut attribute_changes = vec![(Offset::from_integer(0), part.initial_attributes())];
+    attribute_changes.extend(mpart.attribute_changes().into_iter());
+    let mut previous: Option<MeasureAttributes> = None;
+    for (offset, attributes) in attribute_changes {
+        let tick = ticks_of(offset)?;
+        if previous.as_ref().map_or(true, |p| { p.time_sig.numer() != attributes.time_sig.numer() || p.time_sig.denom() != attributes.time_sig.denom() }) {
+            timed_events.push((tick, 0, TrackEventKind::Meta(time_signature_meta(&attributes)?)));
+        }
+        if previous.as_ref().map_or(true, |p| { p.key_sig != attributes.key_sig }) {
+            // the notes are written at sounding pitch, so is the key
+            let key_sig = part.transposition.as_ref().map_or(attributes.key_sig, |transposition| {
+                transpose_key(attributes.key_sig, transposition)
+            });
+            timed_events.push((tick, 0, TrackEventKind::Meta(MetaMessage::KeySignature(key_sig, false))));
+        }
+        previous = Some(attributes);
+    }
+
+    for note in midi_notes_of_part(part)? {
+        timed_events.push((note.end, 1, TrackEventKind::Midi {
+            channel,
+            message: MidiMessage::NoteOff { key: note.key, vel: u7::new(0) }
+        }));
+        timed_events.push((note.start, 2, TrackEventKind::Midi {
+            channel,
+            message: MidiMessage::NoteOn { key: note.key, vel: note.velocity }
+        }));
+    }
+    timed_events.sort_by_key(|(tick, order, _)| { (*tick, *order) });
+
+    let mut track = Vec::with_capacity(timed_events.len() + 1);
+    let mut last_tick = 0;
+    for (tick, _, kind) in timed_events {
+        track.push(TrackEvent { delta: u28::new(tick - last_tick), kind });
+        last_tick = tick;
+    }
+    track.push(meta_event(0, MetaMessage::EndOfTrack));
+    Ok(track)
+}
+
+/// Every pitch of every voice, a note tied to the previous one of the same voice and pitch extends it
+fn midi_notes_of_part(part: &Part) -> anyhow::Result<Vec<MidiNote>>
+{
+    // transposing instruments are played as they sound
+    let sounding_shift = part.transposition.map_or(0, |transposition| { transposition.semitones });
+    let mut notes: Vec<MidiNote> = Vec::new();
+    for voice in part.voice_keys() {
+        // pitch -> index in notes of the note waiting for its tie continuation
+        let mut open_ties: HashMap<i8, usize> = HashMap::new();
+        for (sn_start, sn) in timed_simple_notes(part.voice_gnotes(voice).unwrap()) {
+            let (start, end) = (ticks_of(sn_start)?, ticks_of(sn_start + sn.interval.length)?);
+            for pitch in sn.pitches.iter() {
+                let sounding_ps = pitch.ps as i32 + sounding_shift as i32;
+                let key = u7::try_from(sounding_ps as u8)
+                    .filter(|_| { (0..=127).contains(&sounding_ps) })
+                    .ok_or(anyhow!("Pitch {:?} out of midi range", pitch))?;
+
+                let tied_note_idx = open_ties
+                    .remove(&pitch.ps)
+                    .filter(|idx| { sn.tie_info.contains(TieInfo::TieEnd) && notes[*idx].end == start });
+                let note_idx = match tied_note_idx {
+                    Some(idx) => { notes[idx].end = end; idx },
+                    None => {
+                        notes.push(MidiNote { start, end, key, velocity: velocity_of(&sn) });
+                        notes.len() - 1
+                    }
+                };
+                if sn.tie_info.contains(TieInfo::TieStart) {
+                    open_ties.insert(pitch.ps, note_idx);
+                }
+            }
+        }
+    }
+    Ok(notes)
+}
+
+/// Simple notes with their start, tuplet members are laid out from the tuplet's own interval
+fn timed_simple_notes(gnotes: &[Gnote]) -> impl Iterator<Item=(Offset, SimpleNote)> + '_
+{
+    gnotes.iter().flat_map(|gnote| {
+        let notes = match gnote {
+            Gnote::SimpleNote(sn) => SmallVec::from_elem(sn.clone(), 1),
+            Gnote::Tuplet(tup) => tup.flatten()
+        };
+        notes.into_iter().map(|sn| { (sn.interval.start, sn) })
+    })
+}
+
+/// `SimpleNote.dynamic` is read as a loudness in [0, 1]
+fn velocity_of(sn: &SimpleNote) -> u7
+{
+    let velocity = sn
+        .dynamic
+        .map(|dynamic| { (dynamic.clamp(0.0, 1.0) * 127.0).round() as u8 })
+        .unwrap_or(DEFAULT_VELOCITY);
+    u7::new(velocity.max(1))
+}
+
+fn time_signature_meta(attributes: &MeasureAttributes) -> anyhow::Result<MetaMessage<'static>>
+{
+    let denom = *attributes.time_sig.denom();
+    if !denom.is_power_of_two() {
+        return Err(anyhow!("Time signature {}/{} can't be written to midi", attributes.time_sig.numer(), denom));
+    }
+    // 24 midi clocks per quarter, 8 thirty-second notes per quarter
+    let clocks_per_click = (24 * 4 / denom as u32) as u8;
+    Ok(MetaMessage::TimeSignature(*attributes.time_sig.numer(), denom.trailing_zeros() as u8, clocks_per_click, 8))
+}
+
+fn ticks_of(offset: Offset) -> anyhow::Result<u32>
+{
+    (offset * Offset::from_integer(TICKS_PER_QUARTER as i32))
+        .round()
+        .to_integer()
+        .to_u32()
+        .ok_or(anyhow!("Offset {} can't be placed on the midi timeline", offset))
+}
+
+fn meta_event(delta: u32, message: MetaMessage) -> TrackEvent
+{
+    TrackEvent { delta: u28::new(delta), kind: TrackEventKind::Meta(message) }
+}
+
+#[cfg(test)]
+mod tests {
+    use midly::{Format, MetaMessage, MidiMessage, Smf, TrackEventKind};
+    use crate::attribs::{Duration, Offset, TimeSig};
+    use crate::clef::Clef;
+    use crate::midi_export::*;
+    use crate::part::Part;
+    use crate::pitch::{Alter, DiatonicStep, Pitch};
+    use crate::score::Score;
+    use crate::simple_note::{SimpleNote, TieInfo};
+    use crate::xml_import::score_from_path;
+
+    fn note_ons<'a>(track: &'a Track) -> impl Iterator<Item=(u32, u8, u8)> + 'a {
+        let mut tick = 0;
+        track.iter().filter_map(move |event| {
+            tick += event.delta.as_int();
+            match event.kind {
+                TrackEventKind::Midi { message: MidiMessage::NoteOn { key, vel }, .. } => Some((tick, key.as_int(), vel.as_int())),
+                _ => None
+            }
+        })
+    }
+
+    #[test]
+    fn tied_notes_are_merged() {
+        let mut part = Part::new("Tied".to_string(), -2, Clef::treble(), TimeSig::new_raw(3, 4));
+        for tie_info in [TieInfo::TieStart, TieInfo::TieEnd, TieInfo::TieNeither].iter() {
+            let mut sn = SimpleNote::new(Offset::from_integer(0), Duration::from_integer(2), Vec::new(), None, *tie_info);
+            sn.pitches.insert(Pitch::new(DiatonicStep::C, Some(4), Alter::No));
+            sn.dynamic = Some(1.0);
+            part.append_simple_note(sn);
+        }
+        let mut score = Score::new("Tied");
+        score.parts.push(part);
+
+        let smf = score_to_smf(&score, DEFAULT_QUARTER_BPM).unwrap();
+        let mut bytes = Vec::new();
+        smf.write_std(&mut bytes).unwrap();
+        let reread = Smf::parse(&bytes).unwrap();
+        assert_eq!(reread.header.format, Format::Parallel);
+        assert_eq!(reread.tracks.len(), 2);
+
+        let track = &reread.tracks[1];
+        assert!(note_ons(track).eq([(0, 60, 127), (4 * TICKS_PER_QUARTER as u32, 60, 127)].iter().cloned()));
+        assert!(track.iter().any(|event| { event.kind == TrackEventKind::Meta(MetaMessage::TimeSignature(3, 2, 24, 8)) }));
+        assert!(track.iter().any(|event| { event.kind == TrackEventKind::Meta(MetaMessage::KeySignature(-2, false)) }));
+        assert!(reread.tracks[0].iter().any(|event| { event.kind == TrackEventKind::Meta(MetaMessage::Tempo(midly::num::u24::new(500_000))) }));
+    }
+
+    #[test]
+    fn transposing_part_key_sounds_with_the_notes() {
+        // B flat clarinet written in D major sounds in C major
+        let mut part = Part::new("Clarinet".to_string(), 2, Clef::treble(), TimeSig::new_raw(4, 4));
+        part.transposition = Some("-M2".parse().unwrap());
+        let mut sn = SimpleNote::new(Offset::from_integer(0), Duration::from_integer(4), Vec::new(), None, TieInfo::TieNeither);
+        sn.pitches.insert(Pitch::new(DiatonicStep::D, Some(4), Alter::No));
+        sn.dynamic = Some(1.0);
+        part.append_simple_note(sn);
+        let mut score = Score::new("Clarinet");
+        score.parts.push(part);
+
+        let smf = score_to_smf(&score, DEFAULT_QUARTER_BPM).unwrap();
+        let track = &smf.tracks[1];
+        assert!(note_ons(track).eq([(0, 60, 127)].iter().cloned()));
+        assert!(track.iter().any(|event| { event.kind == TrackEventKind::Meta(MetaMessage::KeySignature(0, false)) }));
+        assert!(!track.iter().any(|event| { event.kind == TrackEventKind::Meta(MetaMessage::KeySignature(2, false)) }));
+    }
+
+    #[test]
+    fn tempo_changes_are_written() {
+        let mut score = Score::new("Tempo");
+        for (name, marks) in [("Upper", [(0, 90.0), (4, 90.0), (6, 60.0)]), ("Lower", [(0, 200.0), (5, 150.0), (6, 60.0)])].iter() {
+            let mut part = Part::new(name.to_string(), 0, Clef::treble(), TimeSig::new_raw(4, 4));
+            let mut sn = SimpleNote::new(Offset::from_integer(0), Duration::from_integer(8), Vec::new(), None, TieInfo::TieNeither);
+            sn.pitches.insert(Pitch::new(DiatonicStep::C, Some(4), Alter::No));
+            part.append_simple_note(sn);
+            part.tempo_marks.extend(marks.iter().map(|(quarters, bpm)| { (Offset::from_integer(*quarters), *bpm) }));
+            score.parts.push(part);
+        }
+
+        let smf = score_to_smf(&score, DEFAULT_QUARTER_BPM).unwrap();
+        let mut tick = 0;
+        let tempo_changes: Vec<(u32, u32)> = smf.tracks[0]
+            .iter()
+            .filter_map(|event| {
+                tick += event.delta.as_int();
+                match event.kind {
+                    TrackEventKind::Meta(MetaMessage::Tempo(micros)) => Some((tick, micros.as_int())),
+                    _ => None
+                }
+            })
+            .collect();
+        // the upper part wins at offset 0, a repeated tempo is left out
+        let quarter = TICKS_PER_QUARTER as u32;
+        assert_eq!(tempo_changes, vec![(0, 666_667), (5 * quarter, 400_000), (6 * quarter, 1_000_000)]);
+
+        score.parts.iter_mut().for_each(|part| { part.tempo_marks.clear(); });
+        score.parts[0].tempo_marks.insert(Offset::from_integer(1), 0.0);
+        assert!(score_to_smf(&score, DEFAULT_QUARTER_BPM).is_err());
+    }
+
+    #[test]
+    fn export_score() {
+        let score = score_from_path("test/longduongs_tied.musicxml").unwrap();
+        let out_path = std::env::temp_dir().join("m32_export_score.mid");
+        score_to_midi_path(&score, out_path.to_str().unwrap()).unwrap();
+
+        let bytes = std::fs::read(&out_path).unwrap();
+        let smf = Smf::parse(&bytes).unwrap();
+        assert_eq!(smf.tracks.len(), score.parts.len() + 1);
+        for (part, track) in score.parts.iter().zip(smf.tracks.iter().skip(1)) {
+            let sounding_notes: usize = part
+                .voice_keys()
+                .into_iter()
+                .flat_map(|voice| { part.voice_simple_note_iter(voice) })
+                .filter(|sn| { !sn.tie_info.contains(TieInfo::TieEnd) })
+                .map(|sn| { sn.pitches.len() })
+                .sum();
+            assert_eq!(note_ons(track).count(), sounding_notes);
+        }
+    }
+}
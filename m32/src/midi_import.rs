@@ -0,0 +1,454 @@
+//! Standard MIDI File (format 0 and 1) import.
+//! Every (track, channel) holding notes becomes a Part; notes sounding together become chords.
+//! Track 0 is taken as a conductor track when it holds no notes.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+use anyhow::{anyhow, Context};
+use midly::{MetaMessage, MidiMessage, Smf, Timing, TrackEventKind};
+use crate::attribs::{BeatDivision, Duration, KeySignature, MeasureAttributes, Offset, TimeSig};
+use crate::clef::Clef;
+use crate::gnote::Gnote;
+use crate::part::Part;
+use crate::pitch::{Pitch, PsType};
+use crate::score::Score;
+use crate::simple_note::{SimpleNote, TieInfo};
+use crate::tuplet_detection::{detect_tuplets_in_gnotes, notatable_pieces};
+
+/// Onsets and ends snap to the nearest multiple of one of the grid units (in quarter notes).
+/// On equal distance the unit listed first wins.
+#[derive(Clone, Debug)]
+pub struct Quantization {
+    pub grid: Vec<Offset>
+}
+
+impl Default for Quantization {
+    // sixteenth notes and eighth note triplets
+    fn default() -> Self {
+        Self { grid: vec![Offset::new(1, 4), Offset::new(1, 3)] }
+    }
+}
+
+impl Quantization {
+    pub fn new(grid: Vec<Offset>) -> anyhow::Result<Self> {
+        if grid.is_empty() || grid.iter().any(|unit| { *unit <= Offset::from_integer(0) }) {
+            return Err(anyhow!("Quantization grid units must be positive"));
+        }
+        Ok(Self { grid })
+    }
+
+    pub fn quantize(&self, offset: Offset) -> Offset {
+        self.grid
+            .iter()
+            .map(|unit| { (offset / unit).round() * unit })
+            .min_by_key(|snapped| { if *snapped > offset { *snapped - offset } else { offset - *snapped } })
+            .unwrap_or(offset)
+    }
+
+    pub fn smallest_unit(&self) -> Duration {
+        self.grid.iter().min().cloned().unwrap_or(Duration::new(1, 4))
+    }
+}
+
+pub fn score_from_midi_path(path: &str, quantization: &Quantization) -> anyhow::Result<Score>
+{
+    let bytes = std::fs::read(path).with_context(|| format!("Can't read {:?}", path))?;
+    score_from_midi_bytes(&bytes, quantization)
+}
+
+pub fn score_from_midi_bytes(bytes: &[u8], quantization: &Quantization) -> anyhow::Result<Score>
+{
+    let smf = Smf::parse(bytes).context("Can't parse midi file")?;
+    score_from_smf(&smf, quantization)
+}
+
+struct MidiNote {
+    start: u32,
+    end: u32,
+    key: PsType,
+    velocity: u8
+}
+
+#[derive(Default)]
+struct TrackContent {
+    name: Option<String>,
+    notes_by_channel: BTreeMap<u8, Vec<MidiNote>>,
+    time_sigs: Vec<(u32, TimeSig)>,
+    key_sigs: Vec<(u32, KeySignature)>
+}
+
+pub fn score_from_smf(smf: &Smf, quantization: &Quantization) -> anyhow::Result<Score>
+{
+    let ticks_per_quarter = match smf.header.timing {
+        Timing::Metrical(tpq) => tpq.as_int() as BeatDivision,
+        Timing::Timecode(..) => return Err(anyhow!("Timecode based midi files are not supported"))
+    };
+
+    let tracks: Vec<TrackContent> = smf.tracks
+        .iter()
+        .enumerate()
+        .map(|(track_idx, track)| {
+            track_content(track).with_context(|| { format!("Can't read track {}", track_idx + 1) })
+        })
+        .collect::<anyhow::Result<_>>()?;
+
+    // meter and key may live in a conductor track, they apply to every part
+    let mut time_sigs: Vec<(u32, TimeSig)> = tracks.iter().flat_map(|t| { t.time_sigs.iter().cloned() }).collect();
+    let mut key_sigs: Vec<(u32, KeySignature)> = tracks.iter().flat_map(|t| { t.key_sigs.iter().cloned() }).collect();
+    time_sigs.sort_by_key(|(tick, _)| { *tick });
+    key_sigs.sort_by_key(|(tick, _)| { *tick });
+
+    let mut score = Score::new(
+        tracks
+        .first()
+        .filter(|track| { track.notes_by_channel.is_empty() })
+        .and_then(|track| { track.name.clone() })
+        .unwrap_or("Untitled".to_string())
+        .as_str()
+    );
+
+    let to_offset = |tick: u32| { quantization.quantize(Offset::new(tick as BeatDivision, ticks_per_quarter)) };
+    for (track_idx, track) in tracks.iter().enumerate() {
+        // past the conductor track, a track without notes is still a (silent) part
+        if track_idx > 0 && track.notes_by_channel.is_empty() {
+            let name = track.name.clone().unwrap_or(format!("Track {}", track_idx + 1));
+            score.parts.push(part_from_midi_notes(name, &[], &time_sigs, &key_sigs, &to_offset, quantization)?);
+        }
+        for (channel, notes) in track.notes_by_channel.iter() {
+            let name = match (&track.name, track.notes_by_channel.len()) {
+                (Some(name), 1) => name.clone(),
+                (Some(name), _) => format!("{} ({})", name, channel + 1),
+                (None, _) => format!("Track {} ({})", track_idx + 1, channel + 1)
+            };
+            score.parts.push(
+                part_from_midi_notes(name, notes, &time_sigs, &key_sigs, &to_offset, quantization)
+                .with_context(|| format!("Can't import channel {} of track {}", channel + 1, track_idx + 1))?
+            );
+        }
+    }
+    Ok(score)
+}
+
+fn track_content(track: &[midly::TrackEvent]) -> anyhow::Result<TrackContent>
+{
+    let mut content = TrackContent::default();
+    // (channel, key) -> onsets still sounding, first in first out
+    let mut sounding: HashMap<(u8, u8), VecDeque<(u32, u8)>> = HashMap::new();
+    let mut tick = 0;
+    for event in track.iter() {
+        tick += event.delta.as_int();
+        match event.kind {
+            TrackEventKind::Midi { channel, message } => {
+                let channel = channel.as_int();
+                match message {
+                    MidiMessage::NoteOn { key, vel } if vel.as_int() > 0 => {
+                        sounding.entry((channel, key.as_int())).or_default().push_back((tick, vel.as_int()));
+                    },
+                    MidiMessage::NoteOn { key, .. } | MidiMessage::NoteOff { key, .. } => {
+                        if let Some((start, velocity)) = sounding.get_mut(&(channel, key.as_int())).and_then(|q| { q.pop_front() }) {
+                            content.notes_by_channel.entry(channel).or_default()
+                                .push(MidiNote { start, end: tick, key: key.as_int() as PsType, velocity });
+                        }
+                    },
+                    _ => {}
+                }
+            },
+            TrackEventKind::Meta(MetaMessage::TrackName(name)) => {
+                content.name = Some(String::from_utf8_lossy(name).to_string());
+            },
+            TrackEventKind::Meta(MetaMessage::TimeSignature(numer, denom_pow, _, _)) => {
+                // the denominator is a power of two that must fit in a TimeSig
+                if numer == 0 || denom_pow >= 8 {
+                    return Err(anyhow!("Time signature {}/2^{} at tick {} is invalid", numer, denom_pow, tick));
+                }
+                content.time_sigs.push((tick, TimeSig::new_raw(numer, 1 << denom_pow)));
+            },
+            TrackEventKind::Meta(MetaMessage::KeySignature(fifths, _)) => {
+                content.key_sigs.push((tick, fifths));
+            },
+            _ => {}
+        }
+    }
+    // notes never released end with the track
+    for ((channel, key), onsets) in sounding.into_iter() {
+        for (start, velocity) in onsets {
+            content.notes_by_channel.entry(channel).or_default()
+                .push(MidiNote { start, end: tick, key: key as PsType, velocity });
+        }
+    }
+    content.notes_by_channel.values_mut().for_each(|notes| { notes.sort_by_key(|n| { (n.start, n.key) }) });
+    Ok(content)
+}
+
+fn part_from_midi_notes(
+    name: String,
+    notes: &[MidiNote],
+    time_sigs: &[(u32, TimeSig)],
+    key_sigs: &[(u32, KeySignature)],
+    to_offset: &impl Fn(u32) -> Offset,
+    quantization: &Quantization
+) -> anyhow::Result<Part>
+{
+    let average_key = notes.iter().map(|n| { n.key as i32 }).sum::<i32>() / (notes.len().max(1) as i32);
+    let clef = if average_key < 60 { Clef::bass() } else { Clef::treble() };
+    let mut part = Part::new(
+        name,
+        key_sigs.first().map(|(_, key)| { *key }).unwrap_or(0),
+        clef,
+        time_sigs.first().map(|(_, ts)| { *ts }).unwrap_or(TimeSig::new_raw(4, 4))
+    );
+
+    // later meta events are attribute changes
+    let mut attribute_ticks: BTreeSet<u32> = BTreeSet::new();
+    attribute_ticks.extend(time_sigs.iter().skip(1).map(|(tick, _)| { *tick }));
+    attribute_ticks.extend(key_sigs.iter().skip(1).map(|(tick, _)| { *tick }));
+    for tick in attribute_ticks {
+        part.add_attribute_change(
+            to_offset(tick),
+            MeasureAttributes::new(
+                latest_at(key_sigs, tick).unwrap_or(part.key_sig),
+                clef,
+                latest_at(time_sigs, tick).unwrap_or(part.time_sig)
+            )
+        );
+    }
+
+    let quantized_notes: Vec<(Offset, Offset, PsType, u8)> = notes
+        .iter()
+        .map(|note| {
+            let start = to_offset(note.start);
+            let end = to_offset(note.end).max(start + quantization.smallest_unit());
+            (start, end, note.key, note.velocity)
+        })
+        .collect();
+
+    let segments = chordify(&quantized_notes);
+    let beats = part.beats_until(segments.last().map_or(Offset::from_integer(0), |segment| { segment.end }));
+    for gnote in segments_to_gnotes(&segments, &beats, |offset| { part.attributes_at(offset).key_sig })? {
+        part.append_gnote(gnote);
+    }
+    Ok(part)
+}
+
+fn latest_at<T: Copy>(changes: &[(u32, T)], tick: u32) -> Option<T>
+{
+    changes.iter().rev().find(|(t, _)| { *t <= tick }).map(|(_, value)| { *value })
+}
+
+/// Stretch of time during which the same pitches sound, no pitches is a rest
+struct Segment {
+    start: Offset,
+    end: Offset,
+    pitches: BTreeSet<PsType>,
+    velocity: u8
+}
+
+/// Cuts the timeline at every onset and release; notes sounding together become one chord.
+/// A pitch held while others change is struck again with the new chord.
+fn chordify(notes: &[(Offset, Offset, PsType, u8)]) -> Vec<Segment>
+{
+    let mut boundaries: BTreeSet<Offset> = BTreeSet::new();
+    boundaries.insert(Offset::from_integer(0));
+    notes.iter().for_each(|(start, end, _, _)| { boundaries.insert(*start); boundaries.insert(*end); });
+
+    let boundaries: Vec<Offset> = boundaries.into_iter().collect();
+    let mut segments: Vec<Segment> = Vec::new();
+    for window in boundaries.windows(2) {
+        let (start, end) = (window[0], window[1]);
+        let sounding: Vec<&(Offset, Offset, PsType, u8)> = notes
+            .iter()
+            .filter(|(n_start, n_end, _, _)| { *n_start <= start && start < *n_end })
+            .collect();
+        let pitches: BTreeSet<PsType> = sounding.iter().map(|(_, _, key, _)| { *key }).collect();
+        let has_onset = sounding.iter().any(|(n_start, _, _, _)| { *n_start == start });
+
+        match segments.last_mut() {
+            Some(last) if last.pitches == pitches && !has_onset => last.end = end,
+            _ => segments.push(Segment {
+                start,
+                end,
+                pitches,
+                velocity: sounding.iter().map(|(_, _, _, vel)| { *vel }).max().unwrap_or(0)
+            })
+        }
+    }
+    segments
+}
+
+/// Segments as plain notes spelled in the key at their onset, gathered into tuplets
+/// where their beat needs one, then tied over where a single value can't express their length.
+fn segments_to_gnotes(segments: &[Segment], beats: &[Offset], key_at: impl Fn(Offset) -> KeySignature)
+    -> anyhow::Result<Vec<Gnote>>
+{
+    let plain_notes: Vec<Gnote> = segments
+        .iter()
+        .map(|segment| { Gnote::SimpleNote(simple_note_of_segment(segment, key_at(segment.start))) })
+        .collect();
+
+    let mut gnotes = Vec::with_capacity(plain_notes.len());
+    for gnote in detect_tuplets_in_gnotes(&plain_notes, beats)? {
+        match gnote {
+            Gnote::SimpleNote(sn) => {
+                gnotes.extend(
+                    notatable_pieces(&sn, Offset::from_integer(1))
+                    .with_context(|| { format!("Can't notate quantized duration {}", sn.interval.length) })?
+                    .into_iter()
+                    .map(Gnote::SimpleNote)
+                );
+            },
+            tuplet => gnotes.push(tuplet)
+        }
+    }
+    Ok(gnotes)
+}
+
+fn simple_note_of_segment(segment: &Segment, key_sig: KeySignature) -> SimpleNote
+{
+    let mut sn = SimpleNote::new(segment.start, segment.end - segment.start, Vec::new(), None, TieInfo::TieNeither);
+    sn.pitches.extend(segment.pitches.iter().map(|ps| { Pitch::from_ps_in_key(*ps, key_sig) }));
+    if !segment.pitches.is_empty() {
+        sn.dynamic = Some(segment.velocity as f32 / 127.0);
+    }
+    sn
+}
+
+#[cfg(test)]
+mod tests {
+    use midly::{Format, Header, MetaMessage, MidiMessage, Smf, Timing, TrackEvent, TrackEventKind};
+    use midly::num::{u15, u28, u4, u7};
+    use crate::attribs::{Duration, Offset};
+    use crate::gnote::Gnote;
+    use crate::midi_import::*;
+    use crate::pitch::{Alter, DiatonicStep};
+    use crate::simple_note::TieInfo;
+
+    // (tick, key, on)
+    fn track_of(events: &[(u32, u8, bool)]) -> Vec<TrackEvent<'static>> {
+        let mut events = events.to_vec();
+        events.sort_by_key(|(tick, _, on)| { (*tick, *on) });
+        let mut track = vec![TrackEvent {
+            delta: u28::new(0),
+            kind: TrackEventKind::Meta(MetaMessage::TimeSignature(3, 2, 24, 8))
+        }];
+        let mut last_tick = 0;
+        for (tick, key, on) in events {
+            let message = if on { MidiMessage::NoteOn { key: u7::new(key), vel: u7::new(100) } }
+                else { MidiMessage::NoteOff { key: u7::new(key), vel: u7::new(0) } };
+            track.push(TrackEvent {
+                delta: u28::new(tick - last_tick),
+                kind: TrackEventKind::Midi { channel: u4::new(0), message }
+            });
+            last_tick = tick;
+        }
+        track.push(TrackEvent { delta: u28::new(0), kind: TrackEventKind::Meta(MetaMessage::EndOfTrack) });
+        track
+    }
+
+    #[test]
+    fn quantize_chords_triplets_and_ties() {
+        let track = track_of(&[
+            // C4 E4 chord, released a bit early
+            (0, 60, true), (3, 64, true), (470, 60, false), (468, 64, false),
+            // eighth note triplet, sloppy
+            (485, 67, true), (640, 67, false),
+            (645, 69, true), (795, 69, false),
+            (800, 71, true), (955, 71, false),
+            // five quarters
+            (962, 62, true), (3360, 62, false),
+        ]);
+        let mut smf = Smf::new(Header::new(Format::SingleTrack, Timing::Metrical(u15::new(480))));
+        smf.tracks.push(track);
+
+        let score = score_from_smf(&smf, &Quantization::default()).unwrap();
+        assert_eq!(score.parts.len(), 1);
+        let part = &score.parts[0];
+        assert_eq!((part.time_sig.numer(), part.time_sig.denom()), (&3, &4));
+        assert_eq!(part.gnotes.len(), 4);
+
+        match &part.gnotes[0] {
+            Gnote::SimpleNote(sn) => {
+                assert!(sn.pitches.iter().map(|pt| { pt.ps }).eq([60, 64].iter().cloned()));
+                assert_eq!(sn.interval.length, Duration::from_integer(1));
+            },
+            _ => panic!("expected a chord")
+        }
+        match &part.gnotes[1] {
+            Gnote::Tuplet(tup) => {
+                assert_eq!((tup.actual_number, tup.normal_number), (3, 2));
+                assert_eq!(tup.interval.start, Offset::from_integer(1));
+                assert!(tup.simple_note_iter().all(|sn| { sn.interval.length == Duration::new(1, 3) }));
+            },
+            _ => panic!("expected a triplet")
+        }
+        // five quarters can't be one value: whole tied to a quarter
+        match (&part.gnotes[2], &part.gnotes[3]) {
+            (Gnote::SimpleNote(first), Gnote::SimpleNote(second)) => {
+                assert_eq!(first.interval.length, Duration::from_integer(4));
+                assert_eq!(first.tie_info, TieInfo::TieStart);
+                assert_eq!(second.interval.start, Offset::from_integer(6));
+                assert_eq!(second.interval.length, Duration::from_integer(1));
+                assert_eq!(second.tie_info, TieInfo::TieEnd);
+            },
+            _ => panic!("expected tied notes")
+        }
+    }
+
+    fn smf_with_time_sig(numer: u8, denom_pow: u8) -> Smf<'static> {
+        let mut track = track_of(&[(0, 60, true), (480, 60, false)]);
+        track.insert(0, TrackEvent {
+            delta: u28::new(0),
+            kind: TrackEventKind::Meta(MetaMessage::TimeSignature(numer, denom_pow, 24, 8))
+        });
+        let mut smf = Smf::new(Header::new(Format::SingleTrack, Timing::Metrical(u15::new(480))));
+        smf.tracks.push(track);
+        smf
+    }
+
+    #[test]
+    fn empty_time_signature_is_an_error() {
+        assert!(score_from_smf(&smf_with_time_sig(0, 2), &Quantization::default()).is_err());
+    }
+
+    #[test]
+    fn oversized_time_signature_denominator_is_an_error() {
+        assert!(score_from_smf(&smf_with_time_sig(4, 8), &Quantization::default()).is_err());
+        assert!(score_from_smf(&smf_with_time_sig(4, 7), &Quantization::default()).is_ok());
+    }
+
+    #[test]
+    fn notes_are_spelled_in_the_key_in_effect() {
+        // E flat major, then D major once the first note is released
+        let mut track = track_of(&[(0, 63, true), (480, 63, false), (1440, 66, true), (1920, 66, false)]);
+        track.insert(1, TrackEvent { delta: u28::new(0), kind: TrackEventKind::Meta(MetaMessage::KeySignature(-3, false)) });
+        track.insert(4, TrackEvent { delta: u28::new(0), kind: TrackEventKind::Meta(MetaMessage::KeySignature(2, false)) });
+        let mut smf = Smf::new(Header::new(Format::SingleTrack, Timing::Metrical(u15::new(480))));
+        smf.tracks.push(track);
+
+        let score = score_from_smf(&smf, &Quantization::default()).unwrap();
+        let spellings: Vec<(DiatonicStep, Alter)> = score.parts[0]
+            .simple_note_iter()
+            .flat_map(|sn| { sn.pitches.iter().map(|pt| { (pt.step, pt.alter) }).collect::<Vec<_>>() })
+            .collect();
+        assert_eq!(spellings, vec![(DiatonicStep::E, Alter::Flat), (DiatonicStep::F, Alter::Sharp)]);
+    }
+
+    #[test]
+    fn round_trip_exported_midi() {
+        let score = crate::xml_import::score_from_path("test/longduongs_tied.musicxml").unwrap();
+        let out_path = std::env::temp_dir().join("m32_round_trip.mid");
+        crate::midi_export::score_to_midi_path(&score, out_path.to_str().unwrap()).unwrap();
+
+        // the score holds quintuplets and sextuplets besides sixteenths
+        let quantization = Quantization::new(vec![Offset::new(1, 4), Offset::new(1, 6), Offset::new(1, 10)]).unwrap();
+        let reimported = score_from_midi_path(out_path.to_str().unwrap(), &quantization).unwrap();
+        assert_eq!(reimported.parts.len(), score.parts.len());
+        for (part, reimported_part) in score.parts.iter().zip(reimported.parts.iter()) {
+            let pitches_of = |p: &Part| -> Vec<PsType> {
+                p.simple_note_iter()
+                .filter(|sn| { !sn.tie_info.contains(TieInfo::TieEnd) })
+                .flat_map(|sn| { sn.pitches.iter().map(|pt| { pt.ps }).collect::<Vec<_>>() })
+                .collect()
+            };
+            assert_eq!(pitches_of(part), pitches_of(reimported_part));
+        }
+    }
+}
@@ -0,0 +1,145 @@
+//! Compressed MusicXML (.mxl) container handling.
+//! The container is a zip holding META-INF/container.xml, which points to the score document.
+
+use std::fs::File;
+use std::io::{Read, Seek, Write};
+use anyhow::{anyhow, Context};
+use adaxml::tag::*;
+use crate::xml_export::musicxml_meta_data;
+use zip::CompressionMethod;
+use zip::write::FileOptions;
+
+pub const CONTAINER_PATH: &str = "META-INF/container.xml";
+pub const MUSICXML_MEDIA_TYPE: &str = "application/vnd.recordare.musicxml+xml";
+pub const MIMETYPE_PATH: &str = "mimetype";
+pub const MXL_MIMETYPE: &str = "application/vnd.recordare.musicxml";
+pub const DEFAULT_ROOTFILE_PATH: &str = "score.musicxml";
+
+pub fn score_tag_from_mxl_path(path: &str) -> anyhow::Result<XmlTag>
+{
+    let f = File::open(path).with_context(|| format!("Can't open {:?}", path))?;
+    score_tag_from_mxl(f)
+}
+
+pub fn score_tag_from_mxl<R: Read + Seek>(source: R) -> anyhow::Result<XmlTag>
+{
+    read_mxl_rootfile(source, |rootfile| { XmlTag::from_read(rootfile) })
+}
+
+/// Hands the score document to `read` as it is unzipped, eg. to stream it instead of building its tree
+pub fn read_mxl_rootfile<R: Read + Seek, T>(source: R, read: impl FnOnce(&mut dyn Read) -> anyhow::Result<T>)
+    -> anyhow::Result<T>
+{
+    let mut archive = zip::ZipArchive::new(source).context("Can't read mxl as zip archive")?;
+
+    let container_tag = {
+        let container_file = archive
+            .by_name(CONTAINER_PATH)
+            .with_context(|| format!("Can't find {} in mxl", CONTAINER_PATH))?;
+        XmlTag::from_read(container_file).context("Can't parse container.xml")?
+    };
+    let rootfile_path = rootfile_path_from_container(&container_tag)?;
+
+    let mut rootfile = archive
+        .by_name(rootfile_path.as_str())
+        .with_context(|| format!("Can't find rootfile {:?} in mxl", rootfile_path))?;
+    read(&mut rootfile).context("Can't parse mxl rootfile")
+}
+
+/// The first <rootfile> that is a MusicXML document; media-type defaults to MusicXML when absent
+pub fn rootfile_path_from_container(container_tag: &XmlTag) -> anyhow::Result<String>
+{
+    if container_tag.name != "container" {
+        return Err(anyhow!("<container> tag not found"));
+    }
+    container_tag
+        .get_child_with_name("rootfiles").context("Can't find <rootfiles>")?
+        .all_child_with_name("rootfile")
+        .filter(|rootfile| {
+            rootfile
+            .get_attrib_value("media-type")
+            .map_or(true, |media_type| { media_type == MUSICXML_MEDIA_TYPE })
+        })
+        .next()
+        .and_then(|rootfile| { rootfile.get_attrib_value("full-path") })
+        .map(|full_path| { full_path.to_string() })
+        .ok_or(anyhow!("No MusicXML <rootfile> in container"))
+}
+
+pub fn score_tag_to_mxl_path(score_tag: &XmlTag, path: &str) -> anyhow::Result<()>
+{
+    let f = File::create(path).with_context(|| format!("Can't create {:?}", path))?;
+    score_tag_to_mxl(score_tag, f)?;
+    Ok(())
+}
+
+/// Writes mimetype, META-INF/container.xml and the score document, in that order.
+/// mimetype must come first and stay uncompressed so the file type can be sniffed.
+pub fn score_tag_to_mxl<W: Write + Seek>(score_tag: &XmlTag, sink: W) -> anyhow::Result<W>
+{
+    let mut archive = zip::ZipWriter::new(sink);
+
+    archive.start_file(
+        MIMETYPE_PATH,
+        FileOptions::default().compression_method(CompressionMethod::Stored)
+    )?;
+    archive.write_all(MXL_MIMETYPE.as_bytes())?;
+
+    archive.start_file(CONTAINER_PATH, FileOptions::default())?;
+    container_tag(DEFAULT_ROOTFILE_PATH).to_write(&mut archive)?;
+
+    archive.start_file(DEFAULT_ROOTFILE_PATH, FileOptions::default())?;
+    score_tag.to_write_with_meta(&musicxml_meta_data(), &mut archive)?;
+
+    archive.finish().context("Can't finish mxl archive")
+}
+
+pub fn container_tag(rootfile_path: &str) -> XmlTag
+{
+    let mut container_tag = XmlTag::new("container");
+    container_tag
+        .add_child("rootfiles")
+        .add_child("rootfile")
+        .add_attribute("full-path", rootfile_path)
+        .add_attribute("media-type", MUSICXML_MEDIA_TYPE);
+    container_tag
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+    use std::io::Read;
+    use crate::mxl::*;
+
+    #[test]
+    fn read_container() {
+        let tag = score_tag_from_mxl_path("test/longduongs.mxl").unwrap();
+        assert_eq!(tag.name, "score-partwise");
+        assert!(tag.get_child_with_name("part").is_some());
+    }
+
+    #[test]
+    fn write_container() {
+        let tag = score_tag_from_mxl_path("test/longduongs.mxl").unwrap();
+        let out_path = std::env::temp_dir().join("m32_write_container.mxl");
+        score_tag_to_mxl_path(&tag, out_path.to_str().unwrap()).unwrap();
+
+        let mut archive = zip::ZipArchive::new(File::open(&out_path).unwrap()).unwrap();
+        {
+            let mut mimetype_file = archive.by_index(0).unwrap();
+            assert_eq!(mimetype_file.name(), MIMETYPE_PATH);
+            assert_eq!(mimetype_file.compression(), zip::CompressionMethod::Stored);
+            let mut mimetype = String::new();
+            mimetype_file.read_to_string(&mut mimetype).unwrap();
+            assert_eq!(mimetype, MXL_MIMETYPE);
+        }
+        assert!(archive.by_name(CONTAINER_PATH).is_ok());
+
+        let reread = score_tag_from_mxl_path(out_path.to_str().unwrap()).unwrap();
+        assert_eq!(reread.name, "score-partwise");
+        assert_eq!(
+            reread.all_desc_with_name("note").count(),
+            tag.all_desc_with_name("note").count()
+        );
+    }
+}
@@ -1,28 +1,67 @@
 use sha2::{Sha512, Digest};
 use std::borrow::{Borrow, BorrowMut};
-use std::cell::{Cell, RefCell};
 use std::collections::{BTreeSet, VecDeque};
 use std::iter;
-use std::iter::{empty, from_fn, FromIterator};
+use std::iter::{empty, FromIterator};
 use std::mem::size_of;
-use anyhow::anyhow;
+use anyhow::{anyhow, Context};
 use smallvec::SmallVec;
-use crate::config::config;
 use crate::{either_gnote};
 use crate::gnote::Gnote;
 use crate::simple_note;
-use crate::measure::{Measure, measure_length_from_time_sig, MeasureNumberType};
-use crate::pitch::PsType;
+use crate::measure::{Measure, MeasureNumberType};
+use crate::diatonic_interval::DiatonicInterval;
+use crate::pitch::{Pitch, PsType};
 use crate::simple_note::{SimpleNote, TieInfo};
+use crate::transposition::{resolve_interval, transpose_gnotes, transpose_key, TransposeOptions};
+use crate::tuplet_detection::{beats_of_measure, detect_tuplets_in_gnotes};
+use crate::voice::{DEFAULT_VOICE_KEY, pad_with_rest, rest_gnote, VoiceKey, VoiceMap};
 use super::attribs::*;
 
-#[derive(Clone)]
+// Feeds the in-memory bytes of a plain value to the hasher
+macro_rules! hash_type {
+    ($hasher:ident, $value:expr, $value_type:ty) => {{
+        $hasher.update(
+            std::mem::transmute::<$value_type, [u8; size_of::<$value_type>()/size_of::<u8>()]>
+            ($value)
+        );
+    }};
+}
+
+/// What a fingerprint of a note sequence is invariant to
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FingerprintKind {
+    Absolute, // pitches and offsets as they are, same as Part::hash_note_and_rests
+    PitchIntervals, // transposition
+    IoiRatios, // tempo, each note's inter-onset interval relative to the previous one's
+    IntervalsAndIoiRatios, // transposition and tempo
+    Normalized, // transposition and tempo, rests before the first and after the last note left out
+}
+
+impl FingerprintKind {
+    fn hashes_pitch_intervals(self) -> bool {
+        matches!(self, Self::PitchIntervals | Self::IntervalsAndIoiRatios | Self::Normalized)
+    }
+
+    fn hashes_ioi_ratios(self) -> bool {
+        matches!(self, Self::IoiRatios | Self::IntervalsAndIoiRatios | Self::Normalized)
+    }
+}
+
+#[derive(Clone, Debug)]
 pub struct Part {
     pub name: String,
     pub key_sig: KeySignature,
     pub clef_sign: ClefType,
     pub time_sig: TimeSig,
-    pub gnotes: Vec<Gnote>,
+    pub lower_staff_clefs: LowerStaffClefs, // empty for a single staff
+    pub gnotes: Vec<Gnote>, // primary voice
+    pub primary_voice: VoiceKey,
+    pub secondary_voices: VoiceMap,
+    pub attribute_changes: AttributeChanges, // key_sig, clef_sign, time_sig and lower_staff_clefs hold at offset 0
+    pub implicit_measures: ImplicitMeasures, // measures not following the time signature, eg. a pickup
+    pub tempo_marks: TempoMarks,
+    pub transposition: Option<DiatonicInterval>, // written to sounding, for transposing instruments
 }
 
 impl Part {
@@ -38,10 +77,48 @@ impl Part {
             key_sig,
             clef_sign,
             time_sig,
+            lower_staff_clefs: LowerStaffClefs::new(),
             gnotes: Vec::new(),
+            primary_voice: DEFAULT_VOICE_KEY,
+            secondary_voices: VoiceMap::new(),
+            attribute_changes: AttributeChanges::new(),
+            implicit_measures: ImplicitMeasures::new(),
+            tempo_marks: TempoMarks::new(),
+            transposition: None,
         }
     }
 
+    /// Length of the implicit measure at offset 0, if any
+    pub fn pickup_length(&self) -> Option<Duration>
+    {
+        self.implicit_measures.get(&Offset::from_integer(0)).copied()
+    }
+
+    pub fn initial_attributes(&self) -> MeasureAttributes
+    {
+        MeasureAttributes {
+            lower_staff_clefs: self.lower_staff_clefs.clone(),
+            ..MeasureAttributes::new(self.key_sig, self.clef_sign, self.time_sig)
+        }
+    }
+
+    /// Attributes of the latest change at or before `offset`
+    pub fn attributes_at(&self, offset: Offset) -> MeasureAttributes
+    {
+        self
+        .attribute_changes
+        .range(..=offset)
+        .next_back()
+        .map(|(_, attributes)| { attributes.clone() })
+        .unwrap_or(self.initial_attributes())
+    }
+
+    /// A time signature change only takes effect at the first barline at or after `offset`
+    pub fn add_attribute_change(&mut self, offset: Offset, attributes: MeasureAttributes)
+    {
+        self.attribute_changes.insert(offset, attributes);
+    }
+
     pub fn append_simple_note(&mut self, mut sn: SimpleNote) {
         sn.interval.set_start_keep_length(
             self.gnotes
@@ -74,142 +151,155 @@ impl Part {
             self.clef_sign,
             self.time_sig
         );
-        if self.gnotes.is_empty() { return measured_part; }
-
-        // Measure length in quarter notes of time signature a/b
-        // is given by a * (4 / b)
-        let measure_length = measure_length_from_time_sig(self.time_sig);
-        let est_number_of_measures =
-            1 +
-            (either_gnote!(self.gnotes.last().unwrap(), gn => gn.interval.end) / measure_length)
-                .to_integer();
-        measured_part.measures.reserve(est_number_of_measures as usize);
-        // initial empty measure
-        measured_part.measures.push(
-            Measure::new(
-                Offset::new(0, 1),
+        measured_part.lower_staff_clefs = self.lower_staff_clefs.clone();
+        measured_part.tempo_marks = self.tempo_marks.clone();
+        measured_part.primary_voice = self.primary_voice;
+        measured_part.transposition = self.transposition;
+        if self.gnotes.is_empty() && self.secondary_voices.is_empty() { return measured_part; }
+
+        let primary_measures = Self::split_gnotes_at_barlines(&self.gnotes, self.measure_windows());
+        let secondary_measures: Vec<(VoiceKey, Vec<Vec<Gnote>>)>
+            = self
+            .secondary_voices
+            .iter()
+            .map(|(voice, gnotes)| {
+                (*voice, Self::split_gnotes_at_barlines(gnotes, self.measure_windows()))
+            })
+            .collect();
+
+        let number_of_measures
+            = secondary_measures
+            .iter()
+            .map(|(_, measures)| { measures.len() })
+            .fold(primary_measures.len(), core::cmp::max);
+        measured_part.measures.reserve(number_of_measures);
+
+        let mut primary_measures = primary_measures.into_iter();
+        let mut measure_windows = self.measure_windows().peekable();
+        let mut measure_number: MeasureNumberType = 0;
+        for idx in 0..number_of_measures {
+            let (window, implicit) = measure_windows.next().unwrap();
+            let measure_length = window.length;
+            // implicit measures share the number of the previous one, a pickup is measure 0
+            if !implicit { measure_number += 1; }
+            let mut measure = Measure::new(
+                window.start,
                 measure_length,
-                0 as MeasureNumberType,
-                Vec::new()
-            )
+                measure_number,
+                primary_measures.next().unwrap_or_default(),
+                self.attributes_at(window.start)
+            );
+            measure.implicit = implicit;
+            // every voice fills the whole measure, trailing measures included
+            pad_with_rest(&mut measure.gnotes, measure_length);
+            for (voice, measures) in secondary_measures.iter() {
+                if let Some(voice_gnotes) = measures.get(idx) {
+                    let mut voice_gnotes = voice_gnotes.clone();
+                    pad_with_rest(&mut voice_gnotes, measure_length);
+                    measure.secondary_voices.insert(*voice, voice_gnotes);
+                }
+            }
+            measured_part.measures.push(measure);
+        }
+        // implicit measures after the last note, eg. an empty closing bar, are kept as well
+        while let Some((window, _)) = measure_windows.next_if(|(_, implicit)| { *implicit }) {
+            let mut measure = Measure::new(
+                window.start,
+                window.length,
+                measure_number,
+                Vec::new(),
+                self.attributes_at(window.start)
+            );
+            measure.implicit = true;
+            pad_with_rest(&mut measure.gnotes, window.length);
+            measured_part.measures.push(measure);
+        }
+        assert!(
+            measured_part
+            .measures
+            .iter()
+            .all( |mea| {mea.get_elements_acc_duration() == mea.interval.length} )
         );
 
-        // TODO: NOT SURE IF WORKS, MUST TEST
-        let mut _gnotes = RefCell::new(VecDeque::from_iter(self.gnotes.clone()));
-        while let Some(cur_gnote) = _gnotes.borrow_mut().pop_front()
-        {
-            let current_measure_window
-                = measured_part
-                .measures
-                .last()
-                .unwrap()
-                .interval;
+        measured_part
+    }
+
+    /// Every measure from offset 0 on, with whether it is implicit, endlessly.
+    /// Measure length in quarter notes of time signature a/b is given by a * (4 / b),
+    /// using the time signature in effect at the barline.
+    /// An implicit measure keeps its own length wherever it is, later barlines follow from it.
+    fn measure_windows(&self) -> impl Iterator<Item=(MPInterval, bool)> + '_
+    {
+        let mut start = Offset::from_integer(0);
+        // an empty implicit measure leaves the barline where it was, the next measure is a regular one
+        let mut implicit_done = false;
+        iter::from_fn(move || {
+            let window = match self.implicit_measures.get(&start) {
+                Some(length) if !implicit_done => (MPInterval::from_start_and_length(start, *length), true),
+                _ => (MPInterval::from_start_and_length(start, self.attributes_at(start).measure_length()), false)
+            };
+            implicit_done = window.0.end == start;
+            start = window.0.end;
+            Some(window)
+        })
+    }
+
+    /// Cuts a stream of gnotes (absolute offsets) at every barline, splitting gnotes that
+    /// straddle one. Returned gnotes are offset relative to the start of their measure.
+    fn split_gnotes_at_barlines(gnotes: &[Gnote], measure_windows: impl Iterator<Item=(MPInterval, bool)>)
+        -> Vec<Vec<Gnote>>
+    {
+        let mut measures: Vec<Vec<Gnote>> = Vec::new();
+        if gnotes.is_empty() { return measures; }
 
+        // initial empty measure
+        let mut measure_windows = measure_windows.map(|(window, _)| { window });
+        measures.push(Vec::new());
+        let mut current_measure_window = measure_windows.next().unwrap();
+
+        let mut _gnotes = VecDeque::from_iter(gnotes.iter().cloned());
+        while let Some(cur_gnote) = _gnotes.pop_front()
+        {
             if current_measure_window
                 .does_swallow(either_gnote!(&cur_gnote, gn => gn.interval).borrow())
             {
-                let shifted_gnote = cur_gnote;
-                either_gnote!(&shifted_gnote, gn => gn.interval)
-                    .displace_start_keep_length(-current_measure_window.start);
+                let mut shifted_gnote = cur_gnote;
+                either_gnote!(&mut shifted_gnote, gn => gn.interval
+                    .displace_start_keep_length(-current_measure_window.start));
 
-                measured_part
-                    .measures
-                    .last_mut()
-                    .unwrap()
-                    .gnotes
-                    .push(shifted_gnote);
+                measures.last_mut().unwrap().push(shifted_gnote);
             }
             else if current_measure_window.end
-                == either_gnote!(&cur_gnote, gn => gn.interval.start)
+                <= either_gnote!(&cur_gnote, gn => gn.interval.start)
             {
                 // introduce new measure
-                measured_part.measures.push(
-                    Measure::new(
-                        current_measure_window.end,
-                        measure_length,
-                        measured_part
-                            .measures
-                            .last()
-                            .unwrap()
-                            .measure_number + 1,
-                        Vec::new()
-                    )
-                );
-
-                let new_current_measure = measured_part.measures.last_mut().unwrap();
+                measures.push(Vec::new());
+                current_measure_window = measure_windows.next().unwrap();
 
-                let shifted_gnote = cur_gnote;
-                either_gnote!(&shifted_gnote, gn => gn.interval)
-                    .displace_start_keep_length(-new_current_measure.interval.start);
-
-                new_current_measure.gnotes.push(shifted_gnote);
+                // let the new measure decide whether it swallows or splits the gnote
+                _gnotes.push_front(cur_gnote);
             }
             else if current_measure_window
                 .does_overlap_with(either_gnote!(&cur_gnote, gn => &gn.interval))
             {
-                let (mut first_half, mut second_half)
-                    = either_gnote!(cur_gnote, gn => gn.split_at_offset(current_measure_window.end));
+                let (mut first_half, second_half)
+                    = cur_gnote.split_at_offset(current_measure_window.end);
 
-                // Shift offset
-                [&mut first_half, &mut second_half]
-                .iter_mut()
-                .flat_map(|half| {half.iter_mut()})
-                .for_each(
-                    |snote| {
-                        snote.interval.displace_start_keep_length(-current_measure_window.start)
-                    }
-                );
+                // Shift offset; second half stays absolute since it is fed back into the queue
+                either_gnote!(&mut first_half, gn => gn.interval
+                    .displace_start_keep_length(-current_measure_window.start));
 
-                measured_part
-                    .measures
-                    .last_mut()
-                    .unwrap()
-                    .gnotes
-                    .extend(
-                        first_half
-                        .into_iter()
-                        .map( |sn| { Gnote::from(sn) } )
-                    );
-
-                let current_measure_number
-                = measured_part
-                    .measures
-                    .last()
-                    .unwrap()
-                    .measure_number;
+                measures.last_mut().unwrap().push(first_half);
 
-                measured_part
-                    .measures
-                    .push(
-                        Measure::new(
-                            current_measure_window.end,
-                            measure_length,
-                            current_measure_number + 1,
-                            Vec::new()
-                        )
-                    );
+                measures.push(Vec::new());
+                current_measure_window = measure_windows.next().unwrap();
 
                 // surgery to retains invariant
-                {
-                    let mut mut_gnotes = _gnotes.borrow_mut();
-                    mut_gnotes.reserve(second_half.len());
-                    second_half
-                        .into_iter()
-                        .rev()
-                        .map(|sn| { Gnote::SimpleNote(sn) })
-                        .for_each(|gn| { mut_gnotes.push_front(gn) })
-                }
+                _gnotes.push_front(second_half);
             }
             else {unreachable!()}
         }
-        assert!(
-            measured_part
-            .measures
-            .iter()
-            .all( |mea| {mea.get_elements_acc_duration() == measure_length} )
-        );
-
-        measured_part
+        measures
     }
 
     fn fuse_tied_notes_in_range(note_range: &[simple_note::SimpleNote])
@@ -280,18 +370,33 @@ impl Part {
             self.clef_sign,
             self.time_sig
         );
-        part.gnotes.reserve(self.gnotes.len());
+        part.lower_staff_clefs = self.lower_staff_clefs.clone();
+        part.primary_voice = self.primary_voice;
+        part.attribute_changes = self.attribute_changes.clone();
+        part.implicit_measures = self.implicit_measures.clone();
+        part.tempo_marks = self.tempo_marks.clone();
+        part.transposition = self.transposition;
+        part.gnotes = Self::fuse_tied_notes_in_gnotes(&self.gnotes)?;
+        for (voice, gnotes) in self.secondary_voices.iter() {
+            part.secondary_voices.insert(*voice, Self::fuse_tied_notes_in_gnotes(gnotes)?);
+        }
+        Ok(part)
+    }
 
-        let mut _gnotes = RefCell::new(VecDeque::from(self.gnotes.clone()));
-        while !_gnotes.borrow().is_empty() {
-            match _gnotes.borrow_mut().pop_front().unwrap() {
+    fn fuse_tied_notes_in_gnotes(gnotes: &[Gnote]) -> anyhow::Result<Vec<Gnote>>
+    {
+        let mut fused = Vec::with_capacity(gnotes.len());
+
+        let mut _gnotes = VecDeque::from_iter(gnotes.iter().cloned());
+        while let Some(gnote) = _gnotes.pop_front() {
+            match gnote {
                 Gnote::SimpleNote(sn) => {
                     let mut tmp_snote_stream
                         = SmallVec::<[SimpleNote; 20]>::new();
                     tmp_snote_stream.push(sn);
-                    while let Some(Gnote::SimpleNote(new_sn)) = _gnotes.borrow().front() {
+                    while let Some(Gnote::SimpleNote(_)) = _gnotes.front() {
                         tmp_snote_stream.push(
-                            match _gnotes.borrow_mut().pop_front().unwrap() {
+                            match _gnotes.pop_front().unwrap() {
                                 Gnote::SimpleNote(sn) => {sn},
                                 _ => {panic!()}
                             }
@@ -300,8 +405,7 @@ impl Part {
 
                     let note_stream
                         = Self::fuse_tied_notes_in_range(tmp_snote_stream.as_slice())?;
-                    part
-                    .gnotes
+                    fused
                     .extend(
                         note_stream
                         .into_iter()
@@ -309,78 +413,172 @@ impl Part {
                     )
                 }
                 Gnote::Tuplet(mut tup) => {
-                    let note_stream
-                        = Self::fuse_tied_notes_in_range(tup.notes.as_slice())?;
-
-                    tup.notes.clear();
-                    tup
-                    .notes
-                    .extend(note_stream.into_iter())
+                    // ties are fused within a bracket, nested ones included
+                    tup.members = Self::fuse_tied_notes_in_gnotes(&tup.members)?;
+                    fused.push(Gnote::Tuplet(tup));
                 }
             }
         }
+        Ok(fused)
+    }
+
+    /// Plain notes off the binary grid of their beat gathered into tuplets, one bracket per beat.
+    /// Beats follow the time signature of every measure, counted from its barline.
+    pub fn detect_tuplets(&self) -> anyhow::Result<Self>
+    {
+        let end
+            = iter::once(&self.gnotes)
+            .chain(self.secondary_voices.values())
+            .filter_map(|gnotes| { gnotes.last() })
+            .map(|gnote| { either_gnote!(gnote, gn => gn.interval.end) })
+            .max()
+            .unwrap_or(Offset::from_integer(0));
+        let beats = self.beats_until(end);
+        let mut part = self.clone();
+        part.gnotes = detect_tuplets_in_gnotes(&self.gnotes, &beats)?;
+        for gnotes in part.secondary_voices.values_mut() {
+            *gnotes = detect_tuplets_in_gnotes(gnotes, &beats)?;
+        }
         Ok(part)
     }
 
+    /// Start of every beat of the measures starting before `end`, then the end of the last beat
+    pub fn beats_until(&self, end: Offset) -> Vec<Offset>
+    {
+        let mut beats = Vec::new();
+        let mut last_barline = Offset::from_integer(0);
+        for (idx, (window, implicit)) in self.measure_windows().take_while(|(window, _)| { window.start < end }).enumerate() {
+            let beat_length = self.attributes_at(window.start).beat_length();
+            beats.extend(beats_of_measure(window.start, window.length, beat_length, implicit && idx == 0));
+            last_barline = window.end;
+        }
+        beats.push(last_barline);
+        beats
+    }
+
+    /// Shifts every pitch by semitones, the key signature follows
     pub fn transpose_by(&self, displacement: PsType) -> Self
     {
+        self.transpose(&DiatonicInterval::from_semitones(displacement), &TransposeOptions::default())
+    }
+
+    /// Moves notes and key signatures by the interval, notes are respelled to fit the new keys
+    pub fn transpose(&self, interval: &DiatonicInterval, options: &TransposeOptions) -> Self
+    {
+        let interval = resolve_interval(interval, self.key_sig, options);
+        let key_sig_at = |offset: Offset| { self.attributes_at(offset).key_sig };
+
         let mut part = self.clone();
+        part.key_sig = transpose_key(self.key_sig, &interval);
         part
-        .simple_note_mut_iter()
-        .for_each(|sn| {
-            sn.pitches
-             = BTreeSet::from_iter(
-                sn.pitches
-                .iter()
-                .map(|pt| {
-                    let mut _pt = pt.clone();
-                    _pt.transpose(displacement);
-                    _pt
-                })
-            )
-        });
+        .attribute_changes
+        .values_mut()
+        .for_each(|attributes| { attributes.key_sig = transpose_key(attributes.key_sig, &interval) });
+        transpose_gnotes(&mut part.gnotes, &interval, &key_sig_at);
+        part
+        .secondary_voices
+        .values_mut()
+        .for_each(|gnotes| { transpose_gnotes(gnotes, &interval, &key_sig_at) });
+        part
+    }
+
+    /// Part at concert pitch, a transposing instrument's part is moved by its transposition
+    pub fn to_sounding(&self, options: &TransposeOptions) -> Self
+    {
+        match self.transposition {
+            Some(transposition) => {
+                let mut part = self.transpose(&transposition, options);
+                part.transposition = None;
+                part
+            },
+            None => self.clone()
+        }
+    }
+
+    /// Part written for an instrument sounding `transposition` away from the notes it reads
+    pub fn to_written(&self, transposition: DiatonicInterval, options: &TransposeOptions) -> Self
+    {
+        let mut part = self
+            .to_sounding(options)
+            .transpose(&-transposition, options);
+        part.transposition = Some(transposition);
         part
     }
 
     pub fn simple_note_mut_iter(&mut self)
         -> impl Iterator<Item=&mut simple_note::SimpleNote>
     {
-        let mut residue = VecDeque::<&mut simple_note::SimpleNote>::new();
-        residue.reserve(config::EXP_TUP_LEN);
-        let mut cur_gnote = self.gnotes.iter_mut();
-
-        from_fn(move || {
-            return
-            if residue.is_empty() {
-                let new_note = cur_gnote.next();
-                if new_note.is_some() {
-                    match new_note.unwrap() {
-                        Gnote::SimpleNote(ref mut sn) => Some(sn),
-                        Gnote::Tuplet(ref mut tup) => {
-                            tup
-                            .notes
-                            .iter_mut()
-                            .for_each(|sn| {residue.push_back(sn)});
-
-                            return residue.pop_front();
-                        }
-                    }
-                } else { None }
-            } else { residue.pop_front() }
-        })
+        self
+        .gnotes
+        .iter_mut()
+        .flat_map(|gn| { gn.simple_note_mut_iter() })
     }
 
-    // forgive me heavenly father for I have sinned
     pub fn simple_note_iter(&self)
         -> impl Iterator<Item=&simple_note::SimpleNote>
     {
-        // should be fine since we did not do any modification and the user of this can't either
-        unsafe {
-            let mut mut_self = &mut *( (self as *const Self) as *mut Self);
-            mut_self
-            .simple_note_mut_iter()
-            .map(|sn| { &*(sn as *const simple_note::SimpleNote)})
-        }
+        self
+        .gnotes
+        .iter()
+        .flat_map(|gn| { gn.simple_note_iter() })
+    }
+
+    pub fn all_voices_simple_note_mut_iter(&mut self)
+        -> impl Iterator<Item=&mut simple_note::SimpleNote>
+    {
+        self
+        .gnotes
+        .iter_mut()
+        .chain(
+            self
+            .secondary_voices
+            .values_mut()
+            .flat_map(|gnotes| { gnotes.iter_mut() })
+        )
+        .flat_map(|gn| { gn.simple_note_mut_iter() })
+    }
+
+    /// Primary voice first, then the others in staff and voice order
+    pub fn voice_keys(&self) -> Vec<VoiceKey>
+    {
+        iter::once(self.primary_voice)
+        .chain(self.secondary_voices.keys().cloned())
+        .collect()
+    }
+
+    pub fn voice_gnotes(&self, voice: VoiceKey) -> Option<&Vec<Gnote>>
+    {
+        if voice == self.primary_voice { Some(&self.gnotes) }
+        else { self.secondary_voices.get(&voice) }
+    }
+
+    /// Single voice copy of this part, with `voice` as its primary voice
+    pub fn voice_part(&self, voice: VoiceKey) -> Option<Part>
+    {
+        let mut part = Part::new(
+            self.name.clone(),
+            self.key_sig,
+            self.clef_sign,
+            self.time_sig
+        );
+        part.lower_staff_clefs = self.lower_staff_clefs.clone();
+        part.primary_voice = voice;
+        part.attribute_changes = self.attribute_changes.clone();
+        part.implicit_measures = self.implicit_measures.clone();
+        part.tempo_marks = self.tempo_marks.clone();
+        part.transposition = self.transposition;
+        part.gnotes = self.voice_gnotes(voice)?.clone();
+        Some(part)
+    }
+
+    pub fn voice_simple_note_iter(&self, voice: VoiceKey)
+        -> impl Iterator<Item=&simple_note::SimpleNote>
+    {
+        self
+        .voice_gnotes(voice)
+        .into_iter()
+        .flat_map(|gnotes| { gnotes.iter() })
+        .flat_map(|gn| { gn.simple_note_iter() })
     }
 
     pub fn hash_iter<'a, D: Digest + Default, SnIter: Iterator<Item=&'a SimpleNote>>
@@ -389,15 +587,6 @@ impl Part {
         let mut sha = D::default();
         unsafe {
             for sn in iter {
-                macro_rules! hash_type {
-                    ($hasher:ident, $value:expr, $value_type:ty) => {{
-                        $hasher.update(
-                            std::mem::transmute::<$value_type, [u8; size_of::<$value_type>()/size_of::<u8>()]>
-                            ($value)
-                        );
-                    }};
-                }
-
                 // Hash pitch
                 if sn.is_rest() {
                     sha.update("R");
@@ -419,6 +608,63 @@ impl Part {
         SmallVec::from(sha.finalize().as_slice())
     }
 
+    /// Hash of the notes as seen by `kind`, kinds other than Absolute never share a hash.
+    /// Notes follow each other, so a note's inter-onset interval is its length;
+    /// notes without length (eg. grace notes) are left out.
+    pub fn fingerprint_iter<'a, D: Digest + Default, SnIter: Iterator<Item=&'a SimpleNote>>
+        (iter: SnIter, kind: FingerprintKind) -> SmallVec<[u8; 128]>
+    {
+        if kind == FingerprintKind::Absolute { return Self::hash_iter::<D, SnIter>(iter); }
+
+        let mut notes: Vec<&SimpleNote>
+            = iter
+            .filter(|sn| { sn.interval.length > Duration::from_integer(0) })
+            .collect();
+        if kind == FingerprintKind::Normalized {
+            let first_note = notes.iter().position(|sn| { !sn.is_rest() }).unwrap_or(notes.len());
+            let last_note = notes.iter().rposition(|sn| { !sn.is_rest() }).map_or(first_note, |idx| { idx + 1 });
+            notes = notes[first_note..last_note].to_vec();
+        }
+
+        let mut sha = D::default();
+        sha.update(format!("{:?}", kind));
+        sha.update(Self::hash_iter::<D, _>(Self::normalized_notes(&notes, kind).iter()));
+        SmallVec::from(sha.finalize().as_slice())
+    }
+
+    // The notes with what `kind` is invariant to taken out, ready for `hash_iter`:
+    // pitches relative to the lowest one of the previous note (a first chord by its shape) or all alike,
+    // lengths relative to the previous one's or all alike, laid out one after the other
+    fn normalized_notes(notes: &[&SimpleNote], kind: FingerprintKind) -> Vec<SimpleNote>
+    {
+        let mut normalized = Vec::with_capacity(notes.len());
+        let mut previous_ps: Option<PsType> = None;
+        let mut previous_length: Option<Duration> = None;
+        let mut start = Offset::from_integer(0);
+        for sn in notes.iter() {
+            let length = match previous_length {
+                Some(previous) if kind.hashes_ioi_ratios() => sn.interval.length / previous,
+                _ => Duration::from_integer(1)
+            };
+            previous_length = Some(sn.interval.length);
+
+            let mut note = SimpleNote::new(start, length, Vec::new(), None, TieInfo::TieNeither);
+            if !sn.is_rest() {
+                if kind.hashes_pitch_intervals() {
+                    let lowest_ps = sn.pitches.iter().next().unwrap().ps;
+                    let reference_ps = previous_ps.unwrap_or(lowest_ps);
+                    note.pitches.extend(sn.pitches.iter().map(|pitch| { Pitch::from_ps(pitch.ps - reference_ps) }));
+                    previous_ps = Some(lowest_ps);
+                } else {
+                    note.pitches.insert(Pitch::from_ps(0));
+                }
+            }
+            start += length;
+            normalized.push(note);
+        }
+        normalized
+    }
+
     fn hash_iter_512<'a, SnIter: Iterator<Item=&'a SimpleNote>>
         (iter: SnIter)
         -> SmallVec<[u8; 128]>
@@ -429,16 +675,33 @@ impl Part {
     pub fn hash_note_and_rests(&self) -> SmallVec<[u8; 128]> {
         Self::hash_iter_512(self.simple_note_iter())
     }
+
+    pub fn hash_voice_note_and_rests(&self, voice: VoiceKey) -> Option<SmallVec<[u8; 128]>> {
+        self.voice_gnotes(voice)?;
+        Some(Self::hash_iter_512(self.voice_simple_note_iter(voice)))
+    }
+
+    pub fn fingerprint(&self, kind: FingerprintKind) -> SmallVec<[u8; 128]> {
+        Self::fingerprint_iter::<Sha512, _>(self.simple_note_iter(), kind)
+    }
+
+    pub fn voice_fingerprint(&self, voice: VoiceKey, kind: FingerprintKind) -> Option<SmallVec<[u8; 128]>> {
+        self.voice_gnotes(voice)?;
+        Some(Self::fingerprint_iter::<Sha512, _>(self.voice_simple_note_iter(voice), kind))
+    }
 }
 
+#[derive(Clone, Debug)]
 pub struct MeasuredPart {
     pub name: String,
     pub key_sig: KeySignature,
     pub clef_sign: ClefType,
     pub time_sig: TimeSig,
+    pub lower_staff_clefs: LowerStaffClefs, // empty for a single staff
     pub measures: Vec<Measure>,
-
-    pub measure_length: Duration, // used alot so compute it here
+    pub primary_voice: VoiceKey,
+    pub tempo_marks: TempoMarks,
+    pub transposition: Option<DiatonicInterval>, // written to sounding, for transposing instruments
 }
 
 impl MeasuredPart {
@@ -454,9 +717,130 @@ impl MeasuredPart {
             key_sig,
             clef_sign,
             time_sig,
+            lower_staff_clefs: LowerStaffClefs::new(),
             measures: Vec::new(),
-            measure_length: measure_length_from_time_sig(time_sig)
+            primary_voice: DEFAULT_VOICE_KEY,
+            tempo_marks: TempoMarks::new(),
+            transposition: None,
+        }
+    }
+
+    pub fn initial_attributes(&self) -> MeasureAttributes
+    {
+        MeasureAttributes {
+            lower_staff_clefs: self.lower_staff_clefs.clone(),
+            ..MeasureAttributes::new(self.key_sig, self.clef_sign, self.time_sig)
+        }
+    }
+
+    /// Attributes of the last measure, which new measures inherit
+    pub fn current_attributes(&self) -> MeasureAttributes
+    {
+        self
+        .measures
+        .last()
+        .map(|mea| { mea.attributes.clone() })
+        .unwrap_or(self.initial_attributes())
+    }
+
+    /// Offsets at which a measure's attributes differ from those of the previous measure
+    pub fn attribute_changes(&self) -> AttributeChanges
+    {
+        let mut changes = AttributeChanges::new();
+        let mut previous = self.initial_attributes();
+        for measure in self.measures.iter() {
+            if measure.attributes != previous {
+                changes.insert(measure.interval.start, measure.attributes.clone());
+                previous = measure.attributes.clone();
+            }
+        }
+        changes
+    }
+
+    /// Length of the leading implicit measure, if any
+    pub fn pickup_length(&self) -> Option<Duration>
+    {
+        self
+        .measures
+        .first()
+        .filter(|mea| { mea.implicit })
+        .map(|mea| { mea.interval.length })
+    }
+
+    /// Start and length of every implicit measure
+    pub fn implicit_measures(&self) -> ImplicitMeasures
+    {
+        self
+        .measures
+        .iter()
+        .filter(|mea| { mea.implicit })
+        .map(|mea| { (mea.interval.start, mea.interval.length) })
+        .collect()
+    }
+
+    /// Moves notes and key signatures by the interval, notes are respelled to fit the new keys
+    pub fn transpose(&self, interval: &DiatonicInterval, options: &TransposeOptions) -> Self
+    {
+        let interval = resolve_interval(interval, self.key_sig, options);
+        let mut mpart = self.clone();
+        mpart.key_sig = transpose_key(self.key_sig, &interval);
+        for measure in mpart.measures.iter_mut() {
+            // keys only change at barlines
+            let key_sig = measure.attributes.key_sig;
+            let key_sig_at = |_: Offset| { key_sig };
+            measure.attributes.key_sig = transpose_key(key_sig, &interval);
+            transpose_gnotes(&mut measure.gnotes, &interval, &key_sig_at);
+            measure
+            .secondary_voices
+            .values_mut()
+            .for_each(|gnotes| { transpose_gnotes(gnotes, &interval, &key_sig_at) });
         }
+        mpart
+    }
+
+    /// Part at concert pitch, a transposing instrument's part is moved by its transposition
+    pub fn to_sounding(&self, options: &TransposeOptions) -> Self
+    {
+        match self.transposition {
+            Some(transposition) => {
+                let mut mpart = self.transpose(&transposition, options);
+                mpart.transposition = None;
+                mpart
+            },
+            None => self.clone()
+        }
+    }
+
+    /// Part written for an instrument sounding `transposition` away from the notes it reads
+    pub fn to_written(&self, transposition: DiatonicInterval, options: &TransposeOptions) -> Self
+    {
+        let mut mpart = self
+            .to_sounding(options)
+            .transpose(&-transposition, options);
+        mpart.transposition = Some(transposition);
+        mpart
+    }
+
+    /// Like `Part::detect_tuplets`, measure by measure
+    pub fn detect_tuplets(&self) -> anyhow::Result<Self>
+    {
+        let mut mpart = self.clone();
+        for (idx, measure) in mpart.measures.iter_mut().enumerate() {
+            let length = measure.interval.length;
+            let mut beats = beats_of_measure(
+                Offset::from_integer(0),
+                length,
+                measure.attributes.beat_length(),
+                measure.implicit && idx == 0
+            );
+            beats.push(length);
+            let measure_number = measure.measure_number;
+            for gnotes in iter::once(&mut measure.gnotes).chain(measure.secondary_voices.values_mut()) {
+                *gnotes = detect_tuplets_in_gnotes(gnotes, &beats)
+                    .with_context(|| format!("Can't detect tuplets in measure {}", measure_number))?;
+            }
+        }
+        Ok(mpart)
     }
 
     pub fn flatten(&self) -> Part {
@@ -466,40 +850,96 @@ impl MeasuredPart {
             self.clef_sign,
             self.time_sig
         );
+        flat_part.lower_staff_clefs = self.lower_staff_clefs.clone();
+        flat_part.primary_voice = self.primary_voice;
+        flat_part.attribute_changes = self.attribute_changes();
+        flat_part.implicit_measures = self.implicit_measures();
+        flat_part.tempo_marks = self.tempo_marks.clone();
+        flat_part.transposition = self.transposition;
+        flat_part.gnotes = self.flatten_voice_gnotes(self.primary_voice);
+        for voice in self.voice_keys().into_iter().skip(1) {
+            flat_part.secondary_voices.insert(voice, self.flatten_voice_gnotes(voice));
+        }
+        flat_part
+    }
 
+    /// Flattens a single voice into a part having it as primary voice
+    pub fn flatten_voice(&self, voice: VoiceKey) -> Part {
+        let mut flat_part = Part::new(
+            self.name.clone(),
+            self.key_sig,
+            self.clef_sign,
+            self.time_sig
+        );
+        flat_part.lower_staff_clefs = self.lower_staff_clefs.clone();
+        flat_part.primary_voice = voice;
+        flat_part.attribute_changes = self.attribute_changes();
+        flat_part.implicit_measures = self.implicit_measures();
+        flat_part.tempo_marks = self.tempo_marks.clone();
+        flat_part.transposition = self.transposition;
+        flat_part.gnotes = self.flatten_voice_gnotes(voice);
+        flat_part
+    }
+
+    // Measures in which the voice is silent contribute a measure-long rest
+    fn flatten_voice_gnotes(&self, voice: VoiceKey) -> Vec<Gnote> {
+        let mut flat_gnotes = Vec::new();
         let mut acc_offset = Offset::from_integer(0);
         for measure in self.measures.iter() {
-            for gn in measure.gnotes.iter() {
-                let mut new_gnote = gn.clone();
-                match &mut new_gnote {
-                    Gnote::SimpleNote(sn) => sn.interval.set_start_keep_length(acc_offset),
-                    Gnote::Tuplet(tup) => tup.interval.set_start_keep_length(acc_offset)
+            match measure.voice_gnotes(voice, self.primary_voice) {
+                Some(gnotes) => {
+                    for gn in gnotes.iter() {
+                        let mut new_gnote = gn.clone();
+                        match &mut new_gnote {
+                            Gnote::SimpleNote(sn) => sn.interval.set_start_keep_length(acc_offset),
+                            Gnote::Tuplet(tup) => tup.interval.set_start_keep_length(acc_offset)
+                        }
+                        acc_offset += either_gnote!(&new_gnote, gn => gn.interval.length);
+                        flat_gnotes.push(new_gnote);
+                    }
+                }
+                None => {
+                    flat_gnotes.push(rest_gnote(acc_offset, measure.interval.length));
+                    acc_offset += measure.interval.length;
                 }
-                acc_offset += either_gnote!(&new_gnote, gn => gn.interval.length);
-                flat_part.gnotes.push(new_gnote);
             }
         }
-        flat_part
+        flat_gnotes
     }
 
-    pub fn append_gnote(&mut self, gnote: Gnote) -> anyhow::Result<()>
+    /// Primary voice first, then every other voice found in any measure
+    pub fn voice_keys(&self) -> Vec<VoiceKey>
     {
-        let measure_length = self.measure_length;
+        let secondary: BTreeSet<VoiceKey>
+            = self
+            .measures
+            .iter()
+            .flat_map(|measure| { measure.secondary_voices.keys().cloned() })
+            .filter(|voice| { *voice != self.primary_voice })
+            .collect();
+        iter::once(self.primary_voice)
+        .chain(secondary.into_iter())
+        .collect()
+    }
 
+    pub fn append_gnote(&mut self, gnote: Gnote) -> anyhow::Result<()>
+    {
         if self.measures.is_empty() {
+            let attributes = self.initial_attributes();
             self.measures.push(
                 Measure::new(
                 Offset::from_integer(0),
-                measure_length,
-                0 as MeasureNumberType,
-                Vec::new()
+                attributes.measure_length(),
+                1 as MeasureNumberType,
+                Vec::new(),
+                attributes
                 )
             );
         }
 
-        let mut _buffer = RefCell::new(VecDeque::with_capacity(5));
-        _buffer.borrow_mut().push_back(gnote);
-        while let Some(mut cur_gnote) = _buffer.borrow_mut().pop_front()
+        let mut _buffer = VecDeque::with_capacity(5);
+        _buffer.push_back(gnote);
+        while let Some(mut cur_gnote) = _buffer.pop_front()
         {
             let current_measure_window = self.measures.last().unwrap().interval;
 
@@ -538,33 +978,30 @@ impl MeasuredPart {
                     self.measures.push(
                         Measure::new(
                             current_measure_window.end,
-                            measure_length,
+                            current_measure.attributes.measure_length(),
                             current_measure.measure_number + 1,
-                            Vec::new()
+                            Vec::new(),
+                            current_measure.attributes.clone()
                         )
                     );
                 }
 
-                let new_current_measure = self.measures.last_mut().unwrap();
-
-                either_gnote!(&mut cur_gnote, gn => gn.interval)
-                    .displace_start_keep_length(-new_current_measure.interval.start);
-
-                new_current_measure.gnotes.push(cur_gnote);
+                // let the new measure decide whether it swallows or splits the gnote
+                _buffer.push_front(cur_gnote);
             }
             else if current_measure_window
                 .does_overlap_with(either_gnote!(&cur_gnote, gn => gn.interval).borrow())
             {
                 let (mut first_half, mut second_half)
-                    = either_gnote!(cur_gnote, gn => gn.split_at_offset(current_measure_window.end));
+                    = cur_gnote.split_at_offset(current_measure_window.end);
 
                 // Shift offset
                 [&mut first_half, &mut second_half]
                     .iter_mut()
-                    .flat_map(|half| {half.iter_mut()})
                     .for_each(
-                        |snote| {
-                            snote.interval.displace_start_keep_length(-current_measure_window.start)
+                        |half| {
+                            either_gnote!(half, gn => gn.interval
+                                .displace_start_keep_length(-current_measure_window.start))
                         }
                     );
 
@@ -573,63 +1010,143 @@ impl MeasuredPart {
                     .last_mut()
                     .unwrap()
                     .gnotes
-                    .extend(
-                        first_half
-                        .into_iter()
-                        .map( |sn| { Gnote::from(sn) } )
-                    );
+                    .push(first_half);
 
-                let current_measure_number
+                let (current_measure_number, current_attributes)
                 = self
                     .measures
                     .last()
-                    .unwrap()
-                    .measure_number;
+                    .map(|mea| { (mea.measure_number, mea.attributes.clone()) })
+                    .unwrap();
 
                 self
                     .measures
                     .push(
                         Measure::new(
                             current_measure_window.end,
-                            measure_length,
+                            current_attributes.measure_length(),
                             current_measure_number + 1,
-                            Vec::new()
+                            Vec::new(),
+                            current_attributes
                         )
                     );
 
                 // surgery to retains invariant
-                {
-                    let mut mut_gnotes = _buffer.borrow_mut();
-                    mut_gnotes.reserve(second_half.len());
-                    second_half
-                        .into_iter()
-                        .rev()
-                        .map(|sn| { Gnote::SimpleNote(sn) })
-                        .for_each(|gn| { mut_gnotes.push_front(gn) })
-                }
+                _buffer.push_front(second_half);
             }
         }
         assert!(
             self
             .measures
             .iter()
-            .all( |mea| {mea.get_elements_acc_duration() == measure_length} )
+            .all( |mea| {mea.get_elements_acc_duration() == mea.interval.length} )
         );
         Ok(())
     }
 
     pub fn append_empty_measure(&mut self) -> &mut Measure {
+        self.append_empty_measure_with_attributes(self.current_attributes())
+    }
+
+    pub fn append_empty_measure_with_attributes(&mut self, attributes: MeasureAttributes) -> &mut Measure {
         self.measures
         .push(
             Measure::new(
                 self.measures.last()
                 .map(|mea| { mea.interval.end })
                 .unwrap_or(Offset::from_integer(0)),
-                self.measure_length,
-                (self.measures.len() + 1) as MeasureNumberType,
-                Vec::new()
+                attributes.measure_length(),
+                self.measures.last()
+                .map(|mea| { mea.measure_number + 1 })
+                .unwrap_or(1),
+                Vec::new(),
+                attributes
             )
         );
         self.measures.last_mut().unwrap()
     }
-}
\ No newline at end of file
+
+    /// Appends a measure lasting `length` instead of a full measure,
+    /// numbered as the previous measure or 0 when it is a pickup
+    pub fn append_implicit_measure(&mut self, attributes: MeasureAttributes, length: Duration) -> &mut Measure {
+        let measure_number = self.measures.last().map_or(0, |mea| { mea.measure_number });
+        let measure = self.append_empty_measure_with_attributes(attributes);
+        measure.interval.set_length_keep_start(length);
+        measure.implicit = true;
+        measure.measure_number = measure_number;
+        measure
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::attribs::{Duration, Offset, TimeSig};
+    use crate::clef::Clef;
+    use crate::part::{FingerprintKind, Part};
+    use crate::pitch::Pitch;
+    use crate::simple_note::{SimpleNote, TieInfo};
+
+    // (midi pitch or rest, length in eighths)
+    fn melody(notes: &[(Option<i8>, i32)], length_scale: i32) -> Part {
+        let mut part = Part::new("Melody".to_string(), 0, Clef::treble(), TimeSig::new_raw(4, 4));
+        for (ps, eighths) in notes.iter() {
+            let mut sn = SimpleNote::new(
+                Offset::from_integer(0), Duration::new(*eighths * length_scale, 2), Vec::new(), None, TieInfo::TieNeither
+            );
+            sn.pitches.extend(ps.map(Pitch::from_ps));
+            part.append_simple_note(sn);
+        }
+        part
+    }
+
+    #[test]
+    fn fingerprint_invariance() {
+        let tune = [(Some(60), 2), (Some(62), 1), (Some(64), 1), (None, 2), (Some(67), 2)];
+        let original = melody(&tune, 1);
+        let transposed = original.transpose_by(5);
+        let slower = melody(&tune, 2);
+        let slower_transposed = slower.transpose_by(-3);
+        let mut padded_tune = vec![(None, 4)];
+        padded_tune.extend_from_slice(&tune);
+        padded_tune.push((None, 3));
+        let padded = melody(&padded_tune, 1).transpose_by(2);
+
+        let same = |lhs: &Part, rhs: &Part, kind| { lhs.fingerprint(kind) == rhs.fingerprint(kind) };
+        assert_eq!(original.fingerprint(FingerprintKind::Absolute), original.hash_note_and_rests());
+        assert!(!same(&original, &transposed, FingerprintKind::Absolute));
+        assert!(same(&original, &transposed, FingerprintKind::PitchIntervals));
+        assert!(same(&original, &slower, FingerprintKind::PitchIntervals)); // rhythm is ignored
+        assert!(same(&original, &slower, FingerprintKind::IoiRatios));
+        assert!(same(&original, &slower_transposed, FingerprintKind::IoiRatios));
+        assert!(same(&original, &slower_transposed, FingerprintKind::IntervalsAndIoiRatios));
+        assert!(!same(&original, &padded, FingerprintKind::IntervalsAndIoiRatios));
+        assert!(same(&original, &padded, FingerprintKind::Normalized));
+
+        // a different tune stays different
+        let other = melody(&[(Some(60), 2), (Some(63), 1), (Some(64), 1), (None, 2), (Some(67), 2)], 1);
+        assert!(!same(&original, &other, FingerprintKind::Normalized));
+        assert_ne!(original.fingerprint(FingerprintKind::IntervalsAndIoiRatios), original.fingerprint(FingerprintKind::Normalized));
+    }
+
+    #[test]
+    fn implicit_measures_keep_their_length() {
+        // pickup, two full measures around an empty implicit one, then a short bar closing a repeat
+        // and another empty implicit measure
+        let mut part = melody(&[(Some(67), 2), (Some(72), 6), (Some(71), 6), (Some(72), 4)], 1);
+        part.time_sig = TimeSig::new_raw(3, 4);
+        part.implicit_measures.insert(Offset::from_integer(0), Duration::from_integer(1));
+        part.implicit_measures.insert(Offset::from_integer(4), Duration::from_integer(0));
+        part.implicit_measures.insert(Offset::from_integer(7), Duration::from_integer(2));
+        part.implicit_measures.insert(Offset::from_integer(9), Duration::from_integer(0));
+
+        let mpart = part.to_measured();
+        assert!(
+            mpart.measures.iter()
+            .map(|mea| { (mea.measure_number, mea.interval.length, mea.implicit) })
+            .eq([(0, 1, true), (1, 3, false), (1, 0, true), (2, 3, false), (2, 2, true), (2, 0, true)].iter()
+                .map(|(number, length, implicit)| { (*number, Duration::from_integer(*length), *implicit) }))
+        );
+        assert_eq!(mpart.implicit_measures(), part.implicit_measures);
+        assert_eq!(mpart.flatten().to_measured().measures.len(), mpart.measures.len());
+    }
+}
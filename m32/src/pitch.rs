@@ -1,11 +1,15 @@
 use std::cmp::Ordering;
 use std::fmt::{Debug, Formatter};
+use std::str::FromStr;
+use anyhow::anyhow;
+use crate::attribs::KeySignature;
+use crate::diatonic_interval::DiatonicInterval;
 
 pub type PitchClass = i8;
 pub type Octave = i8;
 pub type PsType = PitchClass;
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub enum DiatonicStep {
     A = 9,
     B = 11,
@@ -32,17 +36,36 @@ impl From<i8> for DiatonicStep {
     }
 }
 
+impl DiatonicStep {
+    /// Position of the letter from C, ie. C = 0 ... B = 6
+    pub fn index(self) -> i8 {
+        DIATONIC_PC.binary_search(&(self as PitchClass)).unwrap() as i8
+    }
+
+    pub fn from_index(idx: i8) -> Self {
+        Self::from(DIATONIC_PC[idx.rem_euclid(7) as usize])
+    }
+}
+
 impl From<&str> for DiatonicStep {
     fn from(s: &str) -> Self {
+        s.parse().unwrap()
+    }
+}
+
+impl FromStr for DiatonicStep {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
         match s {
-            "A" => Self::A,
-            "B" => Self::B,
-            "C" => Self::C,
-            "D" => Self::D,
-            "E" => Self::E,
-            "F" => Self::F,
-            "G" => Self::G,
-            _ => {unreachable!()}
+            "A" => Ok(Self::A),
+            "B" => Ok(Self::B),
+            "C" => Ok(Self::C),
+            "D" => Ok(Self::D),
+            "E" => Ok(Self::E),
+            "F" => Ok(Self::F),
+            "G" => Ok(Self::G),
+            _ => Err(anyhow!("{:?} is not a step name", s))
         }
     }
 }
@@ -61,21 +84,54 @@ impl Into<&str> for DiatonicStep {
     }
 }
 
-#[derive(Clone, Copy)]
+/// Alteration of the diatonic step, as MusicXML's <alter> in semitones
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum Alter {
-    Flat = -1,
-    No = 0,
-    Sharp = 1
+    DoubleFlat,
+    Flat,
+    No,
+    Sharp,
+    DoubleSharp,
+    Microtonal(f32) // any other amount, eg. -0.5 for a quarter tone flat
 }
+
 impl From<i32> for Alter {
     fn from(num: i32) -> Self {
-        match num {
-            -1 => Alter::Flat,
-            0 => Alter::No,
-            1 => Alter::Sharp,
-            _ => panic!("Unexpected alter numerical value!")
+        Self::from_semitones(num as f32)
+    }
+}
+
+impl Alter {
+    pub fn from_semitones(semitones: f32) -> Self {
+        match semitones {
+            s if s == -2.0 => Alter::DoubleFlat,
+            s if s == -1.0 => Alter::Flat,
+            s if s == 0.0 => Alter::No,
+            s if s == 1.0 => Alter::Sharp,
+            s if s == 2.0 => Alter::DoubleSharp,
+            s => Alter::Microtonal(s)
+        }
+    }
+
+    pub fn semitones(&self) -> f32 {
+        match self {
+            Alter::DoubleFlat => -2.0,
+            Alter::Flat => -1.0,
+            Alter::No => 0.0,
+            Alter::Sharp => 1.0,
+            Alter::DoubleSharp => 2.0,
+            Alter::Microtonal(s) => *s
         }
     }
+
+    /// Nearest whole number of semitones, what `Pitch.ps` is computed with
+    pub fn chromatic_shift(&self) -> PitchClass {
+        self.semitones().round() as PitchClass
+    }
+
+    pub fn is_microtonal(&self) -> bool {
+        self.semitones().fract() != 0.0
+    }
 }
 
 #[derive(Clone)]
@@ -106,36 +162,114 @@ impl Ord for Pitch {
     }
 }
 
+// sharps are added in this order, flats in the reverse one
+static SHARP_ORDER: [DiatonicStep; 7] = [
+    DiatonicStep::F, DiatonicStep::C, DiatonicStep::G, DiatonicStep::D,
+    DiatonicStep::A, DiatonicStep::E, DiatonicStep::B
+];
+
+/// Alteration the key signature applies to a step, eg. +1 for F in D major
+pub fn key_alter_of_step(step: DiatonicStep, key_sig: KeySignature) -> PitchClass
+{
+    let accidentals = key_sig.unsigned_abs() as usize;
+    let count = (0..accidentals)
+        .filter(|idx| {
+            let order_idx = if key_sig > 0 { idx % 7 } else { 6 - idx % 7 };
+            SHARP_ORDER[order_idx] == step
+        })
+        .count() as PitchClass;
+    count * key_sig.signum()
+}
+
 impl Pitch {
     pub fn new(step: DiatonicStep, octave: Option<Octave>, alter: Alter) -> Self {
         Self {
             step,
             octave,
             alter,
-            ps: ((step as i8 + alter as i8) + (octave.unwrap_or(4) + 1) * 12) as PsType
+            ps: Self::natural_ps(step, octave.unwrap_or(4)) + alter.chromatic_shift()
         }
     }
+
+    /// Spelled as in C major: sharps for black keys
+    pub fn from_ps(ps: PsType) -> Self {
+        Self::from_ps_in_key(ps, 0)
+    }
+
+    pub fn from_ps_in_key(ps: PsType, key_sig: KeySignature) -> Self {
+        let mut pitch = Self::new(DiatonicStep::C, Some(-1), Alter::No);
+        pitch.update_ps(ps, key_sig);
+        pitch
+    }
+
+    pub fn natural_ps(step: DiatonicStep, octave: Octave) -> PsType {
+        (step as PitchClass) + (octave + 1) * 12
+    }
+
+    /// Shifts by semitones, spelling the result as in C major
     pub fn transpose(&mut self, half_steps: PsType) {
-        self.update_ps(self.ps + half_steps);
+        self.update_ps(self.ps + half_steps, 0);
     }
 
-    fn update_ps(&mut self, _ps: PsType) {
-        let pc: PitchClass = _ps % 12;
-        if DIATONIC_PC.binary_search(&pc).is_ok() {
-            self.step = DiatonicStep::from(pc);
-        }
-        else if pc == 1 || pc == 6 || pc == 8 {
-            self.alter = Alter::Sharp;
-            self.step = DiatonicStep::from(pc - 1);
-        }
-        else if pc == 10 || pc == 3 {
-            self.alter = Alter::Flat;
-            self.step = DiatonicStep::from(pc + 1);
-        }
-        else { unreachable!() }
+    /// Shifts by semitones, spelling the result to fit the key signature
+    pub fn transpose_in_key(&mut self, half_steps: PsType, key_sig: KeySignature) {
+        self.update_ps(self.ps + half_steps, key_sig);
+    }
+
+    /// Moves the letter name by the interval's steps, the alteration follows from its size,
+    /// so C# up a major third is E#, not F
+    pub fn transpose_by_interval(&mut self, interval: &DiatonicInterval) {
+        let octave = self.octave.unwrap_or(4);
+        let letter_idx = self.step.index() as i32 + interval.steps as i32;
+        let new_step = DiatonicStep::from_index(letter_idx.rem_euclid(7) as i8);
+        let new_octave = octave + letter_idx.div_euclid(7) as Octave;
+
+        let natural_shift = Self::natural_ps(new_step, new_octave) - Self::natural_ps(self.step, octave);
+        let new_alter = self.alter.semitones() + (interval.semitones - natural_shift) as f32;
+
+        self.step = new_step;
+        if self.octave.is_some() { self.octave = Some(new_octave); }
+        self.alter = Alter::from_semitones(new_alter);
+        self.ps = Self::natural_ps(new_step, new_octave) + self.alter.chromatic_shift();
+    }
+
+    /// Keeps the sounding pitch, picks the spelling that fits the key signature best.
+    /// Microtonal pitches are left as they are.
+    pub fn respell_in_key(&mut self, key_sig: KeySignature) {
+        if self.alter.is_microtonal() { return; }
+        self.update_ps(self.ps, key_sig);
+    }
+
+    /// Interval from self up (or down) to other
+    pub fn interval_to(&self, other: &Pitch) -> DiatonicInterval {
+        let steps
+            = (other.step.index() as i32 + 7 * other.octave.unwrap_or(4) as i32)
+            - (self.step.index() as i32 + 7 * self.octave.unwrap_or(4) as i32);
+        DiatonicInterval::new(steps as i8, other.ps - self.ps)
+    }
+
+    // A step spelled with its key signature alteration wins, then one altered in the key's
+    // direction (sharps in sharp keys), then the smallest alteration
+    fn update_ps(&mut self, _ps: PsType, key_sig: KeySignature) {
+        let pc: PitchClass = _ps.rem_euclid(12);
+        let (step, alter) = (0..7)
+            .map(DiatonicStep::from_index)
+            .filter_map(|step| {
+                let alter = (pc - step as PitchClass + 6).rem_euclid(12) - 6;
+                if alter.abs() <= 2 { Some((step, alter)) } else { None }
+            })
+            .min_by_key(|(step, alter)| {
+                let key_alter = key_alter_of_step(*step, key_sig);
+                let against_key
+                    = (key_sig >= 0 && *alter < key_alter) || (key_sig < 0 && *alter > key_alter);
+                ((alter - key_alter).abs() * 2 + against_key as PitchClass, alter.abs())
+            })
+            .unwrap();
 
+        self.step = step;
+        self.alter = Alter::from(alter as i32);
         if self.octave.is_some() {
-            self.octave.replace(((_ps as f32) / 12.0 - 1.0) as i8);
+            self.octave.replace((_ps - step as PitchClass - alter).div_euclid(12) - 1);
         }
         self.ps = _ps;
     }
@@ -145,13 +279,16 @@ impl Debug for Pitch {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         f.write_fmt(
             format_args!(
-                "{:?}{:?}{:?}",
+                "{}{}{}",
                 <DiatonicStep as Into<&str>>::into(self.step),
                 {
                     match self.alter {
-                        Alter::No => "",
-                        Alter::Flat => "b",
-                        Alter::Sharp => "#",
+                        Alter::DoubleFlat => "bb".to_string(),
+                        Alter::Flat => "b".to_string(),
+                        Alter::No => "".to_string(),
+                        Alter::Sharp => "#".to_string(),
+                        Alter::DoubleSharp => "x".to_string(),
+                        Alter::Microtonal(s) => format!("({:+})", s),
                     }
                 },
                 self.octave.map_or("".to_string(), |r| { r.to_string() })
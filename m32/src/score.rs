@@ -1,6 +1,9 @@
+use crate::diatonic_interval::DiatonicInterval;
 use crate::part::MeasuredPart;
+use crate::transposition::TransposeOptions;
 use super::part::{Part};
 
+#[derive(Debug)]
 pub struct Score {
     pub title: String,
     pub parts: Vec<Part>
@@ -33,6 +36,34 @@ impl Score {
         new_score
     }
 
+    /// Every part moved by the interval, each with its own key signature
+    pub fn transpose(&self, interval: &DiatonicInterval, options: &TransposeOptions) -> Self {
+        let mut new_score = Self::new(self.title.as_str());
+        new_score
+        .parts
+        .extend(
+            self
+            .parts
+            .iter()
+            .map(|part| { part.transpose(interval, options) })
+        );
+        new_score
+    }
+
+    /// Concert pitch score, transposing instruments' parts are written as they sound
+    pub fn to_sounding(&self, options: &TransposeOptions) -> Self {
+        let mut new_score = Self::new(self.title.as_str());
+        new_score
+        .parts
+        .extend(
+            self
+            .parts
+            .iter()
+            .map(|part| { part.to_sounding(options) })
+        );
+        new_score
+    }
+
     pub fn fuse_tied_notes(&self) -> anyhow::Result<Self> {
         let mut new_score = Self::new(self.title.as_str());
         new_score.parts.reserve(self.parts.len());
@@ -48,6 +79,7 @@ impl Score {
     }
 }
 
+#[derive(Debug)]
 pub struct MeasuredScore {
     pub title: String,
     pub measured_parts: Vec<MeasuredPart>
@@ -86,16 +118,38 @@ impl MeasuredScore {
         .iter()
         .for_each(
             |orig_part| {
+                // the crop starts with the attributes of its first measure
+                let attributes
+                    = orig_part
+                    .measures
+                    .get(start)
+                    .map(|mea| { mea.attributes.clone() })
+                    .unwrap_or(orig_part.initial_attributes());
                 let mut part_clone = MeasuredPart::new(
                     orig_part.name.clone(),
-                    orig_part.key_sig,
-                    orig_part.clef_sign,
-                    orig_part.time_sig
+                    attributes.key_sig,
+                    attributes.clef_sign,
+                    attributes.time_sig
                 );
+                part_clone.lower_staff_clefs = attributes.lower_staff_clefs.clone();
+                part_clone.primary_voice = orig_part.primary_voice;
+                part_clone.transposition = orig_part.transposition;
                 part_clone.measures.reserve(stop - start);
                 part_clone
                 .measures
-                .extend_from_slice(&orig_part.measures[start..stop])
+                .extend_from_slice(&orig_part.measures[start..stop]);
+                // the tempo in effect at the first measure is kept as well
+                if let (Some(first), Some(last)) = (part_clone.measures.first(), part_clone.measures.last()) {
+                    let (crop_start, crop_end) = (first.interval.start, last.interval.end);
+                    if let Some((_, bpm)) = orig_part.tempo_marks.range(..=crop_start).next_back() {
+                        part_clone.tempo_marks.insert(crop_start, *bpm);
+                    }
+                    part_clone.tempo_marks.extend(
+                        orig_part.tempo_marks.range(crop_start..crop_end)
+                        .map(|(offset, bpm)| { (*offset, *bpm) })
+                    );
+                }
+                crop.measured_parts.push(part_clone);
             }
         );
         crop
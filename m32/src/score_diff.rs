@@ -0,0 +1,497 @@
+//! Musical diff: parts are paired by name, measures by number and occurrence, and the notes of each
+//! voice of a measure are aligned with an edit distance on pitch and rhythm.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::{Display, Formatter};
+use crate::attribs::{Duration, Offset};
+use crate::gnote::Gnote;
+use crate::measure::{Measure, MeasureNumberType};
+use crate::part::MeasuredPart;
+use crate::pitch::Pitch;
+use crate::score::{MeasuredScore, Score};
+use crate::simple_note::{SimpleNote, TieInfo};
+use crate::voice::VoiceKey;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ScoreChangeKind {
+    PartAdded,
+    PartRemoved,
+    MeasureAdded,
+    MeasureRemoved,
+    NoteInserted { pitches: Vec<Pitch>, length: Duration },
+    NoteDeleted { pitches: Vec<Pitch>, length: Duration },
+    PitchChanged { old: Vec<Pitch>, new: Vec<Pitch> }, // empty for a rest
+    RhythmChanged { old_offset: Offset, old_length: Duration, new_offset: Offset, new_length: Duration },
+    TieChanged { old: TieInfo, new: TieInfo },
+    LyricChanged { number: u8, old: Option<String>, new: Option<String> },
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ScoreChange {
+    pub part: String,
+    pub measure_number: Option<MeasureNumberType>, // None for part level changes
+    pub voice: Option<VoiceKey>, // None for part and measure level changes
+    pub offset: Offset, // of the old note from the start of its measure, of the new one if inserted
+    pub kind: ScoreChangeKind,
+}
+
+fn pitches_name(pitches: &[Pitch]) -> String
+{
+    if pitches.is_empty() { return "rest".to_string(); }
+    pitches.iter().map(|pitch| { format!("{:?}", pitch) }).collect::<Vec<_>>().join(" ")
+}
+
+impl Display for ScoreChange {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.part)?;
+        if let Some(number) = self.measure_number { write!(f, " m.{}", number)?; }
+        if let Some((staff, voice)) = self.voice { write!(f, " st.{} v.{} @{}", staff, voice, self.offset)?; }
+        match &self.kind {
+            ScoreChangeKind::PartAdded => write!(f, ": part added"),
+            ScoreChangeKind::PartRemoved => write!(f, ": part removed"),
+            ScoreChangeKind::MeasureAdded => write!(f, ": measure added"),
+            ScoreChangeKind::MeasureRemoved => write!(f, ": measure removed"),
+            ScoreChangeKind::NoteInserted { pitches, length } =>
+                write!(f, ": + {} ({})", pitches_name(pitches), length),
+            ScoreChangeKind::NoteDeleted { pitches, length } =>
+                write!(f, ": - {} ({})", pitches_name(pitches), length),
+            ScoreChangeKind::PitchChanged { old, new } =>
+                write!(f, ": pitch {} -> {}", pitches_name(old), pitches_name(new)),
+            ScoreChangeKind::RhythmChanged { old_offset, old_length, new_offset, new_length } =>
+                write!(f, ": rhythm @{} ({}) -> @{} ({})", old_offset, old_length, new_offset, new_length),
+            ScoreChangeKind::TieChanged { old, new } =>
+                write!(f, ": tie {:?} -> {:?}", old, new),
+            ScoreChangeKind::LyricChanged { number, old, new } =>
+                write!(
+                    f, ": lyric {} {:?} -> {:?}",
+                    number, old.as_deref().unwrap_or(""), new.as_deref().unwrap_or("")
+                ),
+        }
+    }
+}
+
+// a note of a measure voice, tuplets unpacked
+struct DiffNote<'a> {
+    offset: Offset, // from the start of the measure
+    note: &'a SimpleNote,
+}
+
+impl<'a> DiffNote<'a> {
+    fn pitches(&self) -> Vec<Pitch> { self.note.pitches.iter().cloned().collect() }
+}
+
+// notes follow each other, offsets are accumulated from their lengths
+fn measure_voice_notes(gnotes: &[Gnote]) -> Vec<DiffNote<'_>>
+{
+    let mut notes = Vec::new();
+    let mut offset = Offset::from_integer(0);
+    for gn in gnotes.iter() {
+        for sn in gn.simple_note_iter() {
+            notes.push(DiffNote { offset, note: sn });
+            offset += sn.interval.length;
+        }
+    }
+    notes
+}
+
+// the pitch set compares ps only, a respelling is a change too
+fn same_pitches(lhs: &SimpleNote, rhs: &SimpleNote) -> bool
+{
+    lhs.pitches.len() == rhs.pitches.len()
+    && lhs.pitches
+        .iter()
+        .zip(rhs.pitches.iter())
+        .all(|(l, r)| { l.ps == r.ps && l.step == r.step })
+}
+
+fn same_rhythm(lhs: &DiffNote, rhs: &DiffNote) -> bool
+{
+    lhs.offset == rhs.offset && lhs.note.interval.length == rhs.note.interval.length
+}
+
+// a note whose pitch and rhythm both changed is still cheaper than deleting and inserting
+const PITCH_COST: f64 = 0.8;
+const RHYTHM_COST: f64 = 0.8;
+const INDEL_COST: f64 = 1.0;
+
+fn substitution_cost(lhs: &DiffNote, rhs: &DiffNote) -> f64
+{
+    let pitch_cost = if same_pitches(lhs.note, rhs.note) { 0.0 } else { PITCH_COST };
+    let rhythm_cost = if same_rhythm(lhs, rhs) { 0.0 } else { RHYTHM_COST };
+    pitch_cost + rhythm_cost
+}
+
+enum NoteAlignment {
+    Paired(usize, usize),
+    Deleted(usize),
+    Inserted(usize),
+}
+
+fn align_notes(old: &[DiffNote], new: &[DiffNote]) -> Vec<NoteAlignment>
+{
+    // costs[i][j]: turning old[i..] into new[j..]
+    let mut costs = vec![vec![0.0; new.len() + 1]; old.len() + 1];
+    for i in (0..=old.len()).rev() {
+        for j in (0..=new.len()).rev() {
+            costs[i][j] = if i == old.len() { (new.len() - j) as f64 * INDEL_COST }
+            else if j == new.len() { (old.len() - i) as f64 * INDEL_COST }
+            else {
+                (costs[i + 1][j + 1] + substitution_cost(&old[i], &new[j]))
+                    .min(costs[i + 1][j] + INDEL_COST)
+                    .min(costs[i][j + 1] + INDEL_COST)
+            };
+        }
+    }
+
+    let mut alignment = Vec::with_capacity(old.len().max(new.len()));
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len()
+            && costs[i][j] == costs[i + 1][j + 1] + substitution_cost(&old[i], &new[j]) {
+            alignment.push(NoteAlignment::Paired(i, j));
+            i += 1;
+            j += 1;
+        }
+        else if i < old.len() && (j == new.len() || costs[i][j] == costs[i + 1][j] + INDEL_COST) {
+            alignment.push(NoteAlignment::Deleted(i));
+            i += 1;
+        }
+        else {
+            alignment.push(NoteAlignment::Inserted(j));
+            j += 1;
+        }
+    }
+    alignment
+}
+
+fn lyrics_by_number(note: &SimpleNote) -> BTreeMap<u8, &str>
+{
+    note.lyrics.iter().map(|l| { (l.number, l.text.as_str()) }).collect()
+}
+
+fn note_changes(old: &DiffNote, new: &DiffNote) -> Vec<ScoreChangeKind>
+{
+    let mut kinds = Vec::new();
+    if !same_pitches(old.note, new.note) {
+        kinds.push(ScoreChangeKind::PitchChanged { old: old.pitches(), new: new.pitches() });
+    }
+    if !same_rhythm(old, new) {
+        kinds.push(ScoreChangeKind::RhythmChanged {
+            old_offset: old.offset,
+            old_length: old.note.interval.length,
+            new_offset: new.offset,
+            new_length: new.note.interval.length
+        });
+    }
+    if old.note.tie_info != new.note.tie_info {
+        kinds.push(ScoreChangeKind::TieChanged { old: old.note.tie_info, new: new.note.tie_info });
+    }
+    let old_lyrics = lyrics_by_number(old.note);
+    let new_lyrics = lyrics_by_number(new.note);
+    let numbers: BTreeSet<u8> = old_lyrics.keys().chain(new_lyrics.keys()).cloned().collect();
+    for number in numbers {
+        let (old_text, new_text) = (old_lyrics.get(&number), new_lyrics.get(&number));
+        if old_text != new_text {
+            kinds.push(ScoreChangeKind::LyricChanged {
+                number,
+                old: old_text.map(|t| { t.to_string() }),
+                new: new_text.map(|t| { t.to_string() })
+            });
+        }
+    }
+    kinds
+}
+
+fn diff_measures(
+    part_name: &str,
+    old_part: &MeasuredPart,
+    old: &Measure,
+    new_part: &MeasuredPart,
+    new: &Measure,
+    changes: &mut Vec<ScoreChange>
+)
+{
+    let voices: BTreeSet<VoiceKey> = [old_part.primary_voice, new_part.primary_voice]
+        .iter()
+        .cloned()
+        .chain(old.secondary_voices.keys().cloned())
+        .chain(new.secondary_voices.keys().cloned())
+        .collect();
+    for voice in voices {
+        let old_notes = old
+            .voice_gnotes(voice, old_part.primary_voice)
+            .map(|gnotes| { measure_voice_notes(gnotes) })
+            .unwrap_or_default();
+        let new_notes = new
+            .voice_gnotes(voice, new_part.primary_voice)
+            .map(|gnotes| { measure_voice_notes(gnotes) })
+            .unwrap_or_default();
+        let change = |offset: Offset, kind: ScoreChangeKind| {
+            ScoreChange {
+                part: part_name.to_string(),
+                measure_number: Some(old.measure_number),
+                voice: Some(voice),
+                offset,
+                kind
+            }
+        };
+        for step in align_notes(&old_notes, &new_notes) {
+            match step {
+                NoteAlignment::Paired(i, j) => {
+                    changes.extend(
+                        note_changes(&old_notes[i], &new_notes[j])
+                            .into_iter()
+                            .map(|kind| { change(old_notes[i].offset, kind) })
+                    );
+                }
+                NoteAlignment::Deleted(i) => {
+                    let dn = &old_notes[i];
+                    changes.push(change(
+                        dn.offset,
+                        ScoreChangeKind::NoteDeleted { pitches: dn.pitches(), length: dn.note.interval.length }
+                    ));
+                }
+                NoteAlignment::Inserted(j) => {
+                    let dn = &new_notes[j];
+                    changes.push(change(
+                        dn.offset,
+                        ScoreChangeKind::NoteInserted { pitches: dn.pitches(), length: dn.note.interval.length }
+                    ));
+                }
+            }
+        }
+    }
+}
+
+fn diff_parts(old: &MeasuredPart, new: &MeasuredPart, changes: &mut Vec<ScoreChange>)
+{
+    let old_measures = measures_by_occurrence(old);
+    let new_measures = measures_by_occurrence(new);
+    let keys: BTreeSet<(MeasureNumberType, usize)>
+        = old_measures.keys().chain(new_measures.keys()).cloned().collect();
+    for (number, occurrence) in keys {
+        let measure_change = |kind| {
+            ScoreChange {
+                part: old.name.clone(),
+                measure_number: Some(number),
+                voice: None,
+                offset: Offset::from_integer(0),
+                kind
+            }
+        };
+        match (old_measures.get(&(number, occurrence)), new_measures.get(&(number, occurrence))) {
+            (Some(old_measure), Some(new_measure)) =>
+                diff_measures(&old.name, old, old_measure, new, new_measure, changes),
+            (Some(_), None) => changes.push(measure_change(ScoreChangeKind::MeasureRemoved)),
+            (None, Some(_)) => changes.push(measure_change(ScoreChangeKind::MeasureAdded)),
+            (None, None) => unreachable!(),
+        }
+    }
+}
+
+/// Measures keyed by number and how many measures of that number came before,
+/// an implicit measure shares the number of the measure before it
+fn measures_by_occurrence(mpart: &MeasuredPart) -> BTreeMap<(MeasureNumberType, usize), &Measure>
+{
+    let mut occurrences: BTreeMap<MeasureNumberType, usize> = BTreeMap::new();
+    mpart.measures
+        .iter()
+        .map(|mea| {
+            let occurrence = occurrences.entry(mea.measure_number).or_insert(0);
+            *occurrence += 1;
+            ((mea.measure_number, *occurrence - 1), mea)
+        })
+        .collect()
+}
+
+fn part_change(name: &str, kind: ScoreChangeKind) -> ScoreChange
+{
+    ScoreChange {
+        part: name.to_string(),
+        measure_number: None,
+        voice: None,
+        offset: Offset::from_integer(0),
+        kind
+    }
+}
+
+/// Changes turning `old` into `new`, in part then measure order.
+/// Parts sharing a name are paired in their order of appearance.
+pub fn diff_measured_scores(old: &MeasuredScore, new: &MeasuredScore) -> Vec<ScoreChange>
+{
+    let mut changes = Vec::new();
+    let mut unpaired_new: Vec<Option<&MeasuredPart>> = new.measured_parts.iter().map(Some).collect();
+    for old_part in old.measured_parts.iter() {
+        let paired = unpaired_new
+            .iter_mut()
+            .find(|slot| { slot.map_or(false, |p| { p.name == old_part.name }) })
+            .and_then(|slot| { slot.take() });
+        match paired {
+            Some(new_part) => diff_parts(old_part, new_part, &mut changes),
+            None => changes.push(part_change(&old_part.name, ScoreChangeKind::PartRemoved)),
+        }
+    }
+    changes.extend(
+        unpaired_new
+            .into_iter()
+            .flatten()
+            .map(|new_part| { part_change(&new_part.name, ScoreChangeKind::PartAdded) })
+    );
+    changes
+}
+
+pub fn diff_scores(old: &Score, new: &Score) -> Vec<ScoreChange>
+{
+    diff_measured_scores(&old.to_measured(), &new.to_measured())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::attribs::TimeSig;
+    use crate::clef::Clef;
+    use crate::lyric::Lyric;
+    use crate::part::Part;
+    use crate::pitch::{Alter, DiatonicStep};
+    use crate::xml_import::measured_score_from_path;
+
+    fn first_notes_mut(mscore: &mut MeasuredScore, measure_idx: usize) -> &mut Vec<Gnote>
+    {
+        &mut mscore.measured_parts[0].measures[measure_idx].gnotes
+    }
+
+    fn simple_note_mut(gnotes: &mut Vec<Gnote>, idx: usize) -> &mut SimpleNote
+    {
+        match &mut gnotes[idx] {
+            Gnote::SimpleNote(sn) => sn,
+            Gnote::Tuplet(tup) => tup.simple_note_mut_iter().next().unwrap(),
+        }
+    }
+
+    #[test]
+    fn unchanged_score_has_no_changes() {
+        let mscore = measured_score_from_path("test/longduongs_tied.musicxml").unwrap();
+        assert!(diff_measured_scores(&mscore, &mscore).is_empty());
+        let score = mscore.flatten();
+        assert!(diff_scores(&score, &score).is_empty());
+    }
+
+    #[test]
+    fn reports_note_level_changes() {
+        let old = measured_score_from_path("test/longduongs_tied.musicxml").unwrap();
+        let mut new = measured_score_from_path("test/longduongs_tied.musicxml").unwrap();
+        let part_name = old.measured_parts[0].name.clone();
+
+        let first_number = old.measured_parts[0].measures[0].measure_number;
+        let second_number = old.measured_parts[0].measures[1].measure_number;
+        {
+            let sn = simple_note_mut(first_notes_mut(&mut new, 0), 0);
+            let mut pitch = sn.pitches.iter().next().cloned().unwrap_or_else(|| { Pitch::from_ps(60) });
+            pitch.transpose(2);
+            sn.pitches = [pitch].iter().cloned().collect();
+            sn.lyrics = vec![Lyric::new(1, "la".to_string())];
+        }
+        {
+            let gnotes = first_notes_mut(&mut new, 1);
+            gnotes.pop();
+        }
+        let removed_count = measure_voice_notes(&old.measured_parts[0].measures[1].gnotes).len()
+            - measure_voice_notes(&new.measured_parts[0].measures[1].gnotes).len();
+
+        let changes = diff_measured_scores(&old, &new);
+        assert!(changes.iter().all(|c| { c.part == part_name && c.voice.is_some() }));
+
+        let first: Vec<&ScoreChangeKind> = changes
+            .iter()
+            .filter(|c| { c.measure_number == Some(first_number) })
+            .map(|c| { &c.kind })
+            .collect();
+        assert!(first.iter().any(|k| { matches!(k, ScoreChangeKind::PitchChanged { .. }) }));
+        assert!(first.iter().any(|k| {
+            matches!(k, ScoreChangeKind::LyricChanged { number: 1, new: Some(text), .. } if text == "la")
+        }));
+        assert!(changes
+            .iter()
+            .filter(|c| { c.measure_number == Some(first_number) })
+            .all(|c| { c.offset == Offset::from_integer(0) }));
+
+        let second: Vec<&ScoreChangeKind> = changes
+            .iter()
+            .filter(|c| { c.measure_number == Some(second_number) })
+            .map(|c| { &c.kind })
+            .collect();
+        assert_eq!(second.len(), removed_count);
+        assert!(second.iter().all(|k| { matches!(k, ScoreChangeKind::NoteDeleted { .. }) }));
+        assert!(changes.iter().all(|c| { !c.to_string().is_empty() }));
+    }
+
+    #[test]
+    fn compares_implicit_measures_sharing_a_number() {
+        // 3/4, an implicit measure of two quarters between the first and second measure
+        let mut part = Part::new("Flute".to_string(), 0, Clef::treble(), TimeSig::new_raw(3, 4));
+        for ps in [60, 62, 64, 65, 67, 69, 71, 72].iter() {
+            let mut sn = SimpleNote::new(Offset::from_integer(0), Duration::from_integer(1), Vec::new(), None, TieInfo::TieNeither);
+            sn.pitches.insert(Pitch::from_ps(*ps));
+            part.append_simple_note(sn);
+        }
+        part.implicit_measures.insert(Offset::from_integer(3), Duration::from_integer(2));
+        let old = part.to_measured();
+        assert_eq!(old.measures[1].measure_number, old.measures[0].measure_number);
+
+        let mut new = old.clone();
+        simple_note_mut(&mut new.measures[1].gnotes, 1).pitches = [Pitch::from_ps(66)].iter().cloned().collect();
+        let mut changes = Vec::new();
+        diff_parts(&old, &new, &mut changes);
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].measure_number, Some(old.measures[1].measure_number));
+        assert_eq!(changes[0].offset, Offset::from_integer(1));
+        assert!(matches!(changes[0].kind, ScoreChangeKind::PitchChanged { .. }));
+    }
+
+    #[test]
+    fn changes_name_pitches_and_rests() {
+        let change = ScoreChange {
+            part: "Flute".to_string(),
+            measure_number: Some(3),
+            voice: Some((1, 1)),
+            offset: Offset::new(1, 2),
+            kind: ScoreChangeKind::PitchChanged {
+                old: vec![Pitch::new(DiatonicStep::C, Some(4), Alter::Sharp), Pitch::new(DiatonicStep::E, Some(4), Alter::No)],
+                new: Vec::new()
+            }
+        };
+        assert_eq!(change.to_string(), "Flute m.3 st.1 v.1 @1/2: pitch C#4 E4 -> rest");
+    }
+
+    #[test]
+    fn reports_rhythm_ties_measures_and_parts() {
+        let old = measured_score_from_path("test/longduongs_tied.musicxml").unwrap();
+        let mut new = measured_score_from_path("test/longduongs_tied.musicxml").unwrap();
+        let last_number = old.measured_parts[0].measures.last().unwrap().measure_number;
+        {
+            let gnotes = first_notes_mut(&mut new, 0);
+            let sn = simple_note_mut(gnotes, 0);
+            sn.interval.set_length_keep_start(sn.interval.length * Duration::new(1, 2));
+            sn.tie_info.toggle(TieInfo::TieStart);
+        }
+        new.measured_parts[0].measures.pop();
+        let mut renamed = new.measured_parts[0].clone();
+        renamed.name = "Descant".to_string();
+        new.measured_parts.push(renamed);
+
+        let kinds: Vec<ScoreChangeKind> = diff_measured_scores(&old, &new)
+            .into_iter()
+            .map(|c| { c.kind })
+            .collect();
+        assert!(kinds.iter().any(|k| { matches!(k, ScoreChangeKind::RhythmChanged { .. }) }));
+        assert!(kinds.iter().any(|k| { matches!(k, ScoreChangeKind::TieChanged { .. }) }));
+        assert!(kinds.contains(&ScoreChangeKind::MeasureRemoved));
+        assert_eq!(kinds.last(), Some(&ScoreChangeKind::PartAdded));
+
+        let removed = diff_measured_scores(&old, &new)
+            .into_iter()
+            .find(|c| { c.kind == ScoreChangeKind::MeasureRemoved })
+            .unwrap();
+        assert_eq!(removed.measure_number, Some(last_number));
+    }
+}
@@ -0,0 +1,335 @@
+//! Melodic similarity: weighted edit distance on (interval, duration) sequences,
+//! with an n-gram index over intervals to pick the parts worth aligning.
+
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+use anyhow::{anyhow, Context};
+use crate::attribs::{Duration, Offset};
+use crate::melbank::{MelBank, MelBankQuery};
+use crate::part::Part;
+use crate::pitch::PsType;
+use crate::simple_note::{SimpleNote, TieInfo};
+use crate::xml_import::score_from_path;
+
+pub const DEFAULT_NGRAM_LENGTH: usize = 3;
+
+/// A note of the melody (the top pitch of a chord), tied notes merged
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MelodicEvent {
+    pub interval: Option<PsType>, // semitones from the previous event, None for the first one
+    pub duration: Duration, // until the next event, following rests included
+    pub offset: Offset, // from the start of the sequence
+}
+
+/// Events of a monophonic note sequence, eg. `Part::simple_note_iter`.
+/// Notes follow each other, so offsets are accumulated from their lengths.
+pub fn melodic_events<'a>(notes: impl Iterator<Item=&'a SimpleNote>) -> Vec<MelodicEvent>
+{
+    let mut events: Vec<MelodicEvent> = Vec::new();
+    let mut previous_ps: Option<PsType> = None;
+    let mut offset = Offset::from_integer(0);
+    for sn in notes {
+        let top_ps = sn.pitches.iter().next_back().map(|pitch| { pitch.ps });
+        let continues_previous
+            = top_ps.is_none()
+            || (sn.tie_info.contains(TieInfo::TieEnd) && top_ps == previous_ps);
+        match events.last_mut() {
+            Some(last) if continues_previous => last.duration += sn.interval.length,
+            _ => {
+                // a rest before the first note is left out
+                if let Some(ps) = top_ps {
+                    events.push(MelodicEvent {
+                        interval: previous_ps.map(|previous| { ps - previous }),
+                        duration: sn.interval.length,
+                        offset
+                    });
+                }
+            }
+        }
+        previous_ps = top_ps.or(previous_ps);
+        offset += sn.interval.length;
+    }
+    events
+}
+
+pub fn melodic_events_of_part(part: &Part) -> Vec<MelodicEvent>
+{
+    melodic_events(part.simple_note_iter())
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct EditWeights {
+    pub insertion: f64, // an extra event in the target
+    pub deletion: f64, // an event of the query missing from the target
+    pub interval: f64, // per semitone of difference
+    pub duration: f64, // per doubling or halving of the duration
+}
+
+impl Default for EditWeights {
+    fn default() -> Self {
+        Self { insertion: 1.0, deletion: 1.0, interval: 0.5, duration: 0.5 }
+    }
+}
+
+impl EditWeights {
+    // never more than deleting one event and inserting the other
+    fn substitution(&self, lhs: &MelodicEvent, rhs: &MelodicEvent) -> f64
+    {
+        let interval_cost = match (lhs.interval, rhs.interval) {
+            (Some(l), Some(r)) => self.interval * (l as f64 - r as f64).abs(),
+            _ => 0.0 // the first event has nothing to be compared to
+        };
+        let zero = Duration::from_integer(0);
+        // a grace note has no duration, it is as far from any other note as can be
+        let duration_cost = if lhs.duration == zero || rhs.duration == zero {
+            if lhs.duration == rhs.duration { 0.0 } else { f64::INFINITY }
+        } else {
+            let ratio = lhs.duration / rhs.duration;
+            self.duration * (*ratio.numer() as f64 / *ratio.denom() as f64).log2().abs()
+        };
+        (interval_cost + duration_cost).min(self.insertion + self.deletion)
+    }
+}
+
+/// Cost of turning `lhs` into `rhs`
+pub fn edit_distance(lhs: &[MelodicEvent], rhs: &[MelodicEvent], weights: &EditWeights) -> f64
+{
+    let mut previous_row: Vec<f64> = (0..=rhs.len()).map(|j| { j as f64 * weights.insertion }).collect();
+    for (i, l) in lhs.iter().enumerate() {
+        let mut row = Vec::with_capacity(rhs.len() + 1);
+        row.push((i + 1) as f64 * weights.deletion);
+        for (j, r) in rhs.iter().enumerate() {
+            let cost = (previous_row[j] + weights.substitution(l, r))
+                .min(previous_row[j + 1] + weights.deletion)
+                .min(row[j] + weights.insertion);
+            row.push(cost);
+        }
+        previous_row = row;
+    }
+    previous_row[rhs.len()]
+}
+
+/// Best place of `query` inside `target`: the cheapest alignment of the whole query
+/// with any stretch of the target. Returns (distance, first, past the last target event).
+pub fn best_alignment(query: &[MelodicEvent], target: &[MelodicEvent], weights: &EditWeights)
+    -> Option<(f64, usize, usize)>
+{
+    if query.is_empty() || target.is_empty() { return None; }
+    // (cost, start in target) for the query prefix done so far, skipping target events before is free
+    let mut previous_row: Vec<(f64, usize)> = (0..=target.len()).map(|j| { (0.0, j) }).collect();
+    for (i, q) in query.iter().enumerate() {
+        let mut row: Vec<(f64, usize)> = Vec::with_capacity(target.len() + 1);
+        row.push(((i + 1) as f64 * weights.deletion, 0));
+        for (j, t) in target.iter().enumerate() {
+            let substituted = (previous_row[j].0 + weights.substitution(q, t), previous_row[j].1);
+            let deleted = (previous_row[j + 1].0 + weights.deletion, previous_row[j + 1].1);
+            let inserted = (row[j].0 + weights.insertion, row[j].1);
+            let best = [substituted, deleted, inserted]
+                .iter()
+                .cloned()
+                .min_by(|a, b| { a.0.total_cmp(&b.0) })
+                .unwrap();
+            row.push(best);
+        }
+        previous_row = row;
+    }
+    previous_row
+        .iter()
+        .enumerate()
+        .skip(1)
+        .min_by(|(_, a), (_, b)| { a.0.total_cmp(&b.0) })
+        .map(|(end, (cost, start))| { (*cost, *start, end.max(*start)) })
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct SimilarityMatch {
+    pub id: String,
+    pub distance: f64,
+    pub start: Offset, // of the matched stretch, from the start of the part
+    pub end: Offset,
+}
+
+struct IndexedMelody {
+    id: String,
+    events: Vec<MelodicEvent>,
+}
+
+/// Inverted index from interval n-grams to the melodies (and positions) holding them
+pub struct NgramIndex {
+    n: usize,
+    melodies: Vec<IndexedMelody>,
+    postings: HashMap<Vec<PsType>, Vec<(usize, usize)>>, // n-gram -> (melody, event index)
+}
+
+impl NgramIndex {
+    pub fn new(n: usize) -> anyhow::Result<Self>
+    {
+        if n == 0 { return Err(anyhow!("n-grams must hold at least one interval")); }
+        Ok(Self { n, melodies: Vec::new(), postings: HashMap::new() })
+    }
+
+    pub fn len(&self) -> usize { self.melodies.len() }
+    pub fn is_empty(&self) -> bool { self.melodies.is_empty() }
+
+    pub fn add_part(&mut self, id: String, part: &Part)
+    {
+        self.add_events(id, melodic_events_of_part(part));
+    }
+
+    pub fn add_events(&mut self, id: String, events: Vec<MelodicEvent>)
+    {
+        let melody_idx = self.melodies.len();
+        for (position, gram) in self.ngrams(&events) {
+            self.postings.entry(gram).or_insert_with(Vec::new).push((melody_idx, position));
+        }
+        self.melodies.push(IndexedMelody { id, events });
+    }
+
+    // n consecutive intervals, the first event has none
+    fn ngrams(&self, events: &[MelodicEvent]) -> Vec<(usize, Vec<PsType>)>
+    {
+        if events.len() <= self.n { return Vec::new(); }
+        (1..=events.len() - self.n)
+            .map(|position| {
+                (position, events[position..position + self.n].iter().map(|ev| { ev.interval.unwrap() }).collect())
+            })
+            .collect()
+    }
+
+    /// Melodies sharing n-grams with the query, most shared first.
+    /// A query too short to have an n-gram has every melody as candidate.
+    pub fn candidates(&self, query: &[MelodicEvent]) -> Vec<usize>
+    {
+        let grams = self.ngrams(query);
+        if grams.is_empty() { return (0..self.melodies.len()).collect(); }
+
+        let mut hits: BTreeMap<usize, usize> = BTreeMap::new();
+        for (_, gram) in grams {
+            for (melody_idx, _) in self.postings.get(&gram).into_iter().flatten() {
+                *hits.entry(*melody_idx).or_insert(0) += 1;
+            }
+        }
+        let mut candidates: Vec<(usize, usize)> = hits.into_iter().collect();
+        candidates.sort_by(|(l_idx, l_hits), (r_idx, r_hits)| { r_hits.cmp(l_hits).then(l_idx.cmp(r_idx)) });
+        candidates.into_iter().map(|(melody_idx, _)| { melody_idx }).collect()
+    }
+
+    /// The `k` closest melodies among the candidates, with where the query was found in them
+    pub fn top_k(&self, query: &[MelodicEvent], k: usize, weights: &EditWeights) -> Vec<SimilarityMatch>
+    {
+        let mut matches: Vec<SimilarityMatch>
+            = self
+            .candidates(query)
+            .into_iter()
+            .filter_map(|melody_idx| {
+                let melody = &self.melodies[melody_idx];
+                let (distance, first, past_last) = best_alignment(query, &melody.events, weights)?;
+                let last = &melody.events[past_last.max(first + 1) - 1];
+                Some(SimilarityMatch {
+                    id: melody.id.clone(),
+                    distance,
+                    start: melody.events[first.min(melody.events.len() - 1)].offset,
+                    end: last.offset + last.duration
+                })
+            })
+            .collect();
+        matches.sort_by(|l, r| { l.distance.total_cmp(&r.distance) });
+        matches.truncate(k);
+        matches
+    }
+
+    pub fn top_k_part(&self, snippet: &Part, k: usize, weights: &EditWeights) -> Vec<SimilarityMatch>
+    {
+        self.top_k(&melodic_events_of_part(snippet), k, weights)
+    }
+}
+
+/// Indexes every part of the bank by name, reading the scores from the directory the bank indexed
+pub fn index_melbank(bank: &MelBank, dir: &str, n: usize) -> anyhow::Result<NgramIndex>
+{
+    let mut index = NgramIndex::new(n)?;
+    let mut entries = bank.query(&MelBankQuery::default())?;
+    entries.sort_by(|l, r| { l.container_path.cmp(&r.container_path) });
+
+    let mut loaded: Option<(String, Vec<Part>)> = None;
+    for entry in entries {
+        if loaded.as_ref().map_or(true, |(path, _)| { *path != entry.container_path }) {
+            let score_path = Path::new(dir).join(&entry.container_path);
+            let score = score_from_path(score_path.to_str().ok_or(anyhow!("Path {:?} is not valid unicode", score_path))?)
+                .with_context(|| format!("Can't import {:?} of the melody bank", score_path))?;
+            loaded = Some((entry.container_path.clone(), score.parts));
+        }
+        let parts = &loaded.as_ref().unwrap().1;
+        let part = entry
+            .part_number()
+            .and_then(|number| { parts.get(number.checked_sub(1)?) })
+            .ok_or(anyhow!("Part {:?} not found in {:?}", entry.name, entry.container_path))?;
+        index.add_part(entry.name.clone(), part);
+    }
+    Ok(index)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::attribs::{Duration, Offset};
+    use crate::melbank::MelBank;
+    use crate::similarity::*;
+
+    fn events(intervals_and_eighths: &[(PsType, i32)]) -> Vec<MelodicEvent> {
+        let mut offset = Offset::from_integer(0);
+        intervals_and_eighths
+            .iter()
+            .enumerate()
+            .map(|(idx, (interval, eighths))| {
+                let duration = Duration::new(*eighths, 2);
+                let event = MelodicEvent { interval: if idx == 0 { None } else { Some(*interval) }, duration, offset };
+                offset += duration;
+                event
+            })
+            .collect()
+    }
+
+    #[test]
+    fn weighted_edit_distance() {
+        let weights = EditWeights::default();
+        let tune = events(&[(0, 2), (2, 1), (2, 1), (-4, 4)]);
+        assert_eq!(edit_distance(&tune, &tune, &weights), 0.0);
+        // one interval a semitone off
+        assert_eq!(edit_distance(&tune, &events(&[(0, 2), (2, 1), (1, 1), (-4, 4)]), &weights), 0.5);
+        // one note twice as long
+        assert_eq!(edit_distance(&tune, &events(&[(0, 2), (2, 2), (2, 1), (-4, 4)]), &weights), 0.5);
+        // a note left out, the next interval then spans both
+        assert_eq!(edit_distance(&tune, &events(&[(0, 2), (2, 1), (-2, 4)]), &weights), 1.0 + 0.5 * 2.0);
+        // grace notes have no duration
+        let grace = events(&[(0, 2), (2, 0), (2, 1), (-4, 4)]);
+        assert_eq!(edit_distance(&grace, &grace, &weights), 0.0);
+        assert_eq!(edit_distance(&tune, &grace, &weights), 2.0);
+
+        // the query is found inside a longer melody
+        let longer = events(&[(0, 4), (5, 4), (0, 2), (2, 1), (2, 1), (-4, 4), (7, 4)]);
+        let (distance, first, past_last) = best_alignment(&tune, &longer, &weights).unwrap();
+        assert_eq!((distance, first, past_last), (0.0, 2, 6));
+    }
+
+    #[test]
+    fn query_snippet_against_melbank() {
+        let mut bank = MelBank::open_in_memory().unwrap();
+        bank.index_directory("test/melBank").unwrap();
+        let index = index_melbank(&bank, "test/melBank", DEFAULT_NGRAM_LENGTH).unwrap();
+        assert!(!index.is_empty());
+
+        // a stretch of the sonata's first part, transposed
+        let sonata = score_from_path("test/melBank/mozart/sonata/no1.musicxml").unwrap();
+        let sonata_events = melodic_events_of_part(&sonata.parts[0].transpose_by(-5));
+        let snippet = &sonata_events[10..22];
+
+        let matches = index.top_k(snippet, 3, &EditWeights::default());
+        assert!(!matches.is_empty() && matches.len() <= 3);
+        let best = &matches[0];
+        assert_eq!(best.id, "mozart>sonata>no1>1");
+        assert_eq!(best.distance, 0.0);
+        assert_eq!(best.start, sonata_events[10].offset);
+        assert_eq!(best.end, sonata_events[21].offset + sonata_events[21].duration);
+        assert!(matches.windows(2).all(|pair| { pair[0].distance <= pair[1].distance }));
+    }
+}
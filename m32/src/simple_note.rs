@@ -94,7 +94,7 @@ impl Debug for SimpleNote {
                 "\tRest<[{:.2}, {:.2}) | length={:.2}>",
                 self.interval.start, self.interval.end,
                 self.interval.length
-            ));
+            ))?;
         }
         else if self.is_note() {
             f.write_fmt(format_args!(
@@ -102,8 +102,8 @@ impl Debug for SimpleNote {
                 self.interval.start, self.interval.end,
                 self.interval.length,
                 self.tie_info
-            ));
-            format_args!("| {:?}", self.pitches.iter().last().unwrap());
+            ))?;
+            f.write_fmt(format_args!(" | {:?}", self.pitches.iter().last().unwrap()))?;
         }
         else {
             f.write_fmt(format_args!(
@@ -111,12 +111,12 @@ impl Debug for SimpleNote {
                 self.interval.start, self.interval.end,
                 self.interval.length,
                 self.tie_info
-            ));
+            ))?;
             for pitch in &self.pitches {
-                f.write_fmt(format_args!("{:?} ", pitch));
+                f.write_fmt(format_args!(" {:?}", pitch))?;
             }
         }
-        todo!()
+        Ok(())
     }
 }
 
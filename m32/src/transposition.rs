@@ -0,0 +1,221 @@
+use crate::attribs::{KeySignature, Offset};
+use crate::diatonic_interval::DiatonicInterval;
+use crate::either_gnote;
+use crate::gnote::Gnote;
+use crate::pitch::Pitch;
+
+/// Largest number of sharps or flats a key signature is written with
+pub const MAX_KEY_ACCIDENTALS: KeySignature = 7;
+
+/// Which key to land in when the target has two spellings, eg. F# or Gb major
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KeySpelling {
+    FewestAccidentals, // ties keep the spelling of the given interval
+    Sharps,
+    Flats
+}
+
+/// What is done with the octave part of the interval
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OctaveHandling {
+    AsGiven,
+    Nearest, // up or down, whichever is at most a tritone away
+    Octaves(i8) // upwards within an octave, then shifted by that many octaves
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TransposeOptions {
+    pub key_spelling: KeySpelling,
+    pub octave_handling: OctaveHandling
+}
+
+impl Default for TransposeOptions {
+    fn default() -> Self {
+        Self {
+            key_spelling: KeySpelling::FewestAccidentals,
+            octave_handling: OctaveHandling::AsGiven
+        }
+    }
+}
+
+const OCTAVE: DiatonicInterval = DiatonicInterval { steps: 7, semitones: 12 };
+const DIMINISHED_SECOND: DiatonicInterval = DiatonicInterval { steps: 1, semitones: 0 };
+
+/// The interval notes of a part in `key_sig` are actually moved by, after the octave handling
+/// and the choice between enharmonic target keys (a diminished second apart) have been applied
+pub fn resolve_interval(interval: &DiatonicInterval, key_sig: KeySignature, options: &TransposeOptions)
+    -> DiatonicInterval
+{
+    let interval = match options.octave_handling {
+        OctaveHandling::AsGiven => *interval,
+        OctaveHandling::Nearest => {
+            let simple = interval.simple();
+            let other_way
+                = if simple.is_descending() { add(&simple, &OCTAVE, 1) }
+                else { add(&simple, &OCTAVE, -1) };
+            if other_way.semitones.abs() < simple.semitones.abs() { other_way } else { simple }
+        },
+        OctaveHandling::Octaves(octaves) => {
+            let simple = interval.simple();
+            let upwards = if simple.is_descending() { add(&simple, &OCTAVE, 1) } else { simple };
+            add(&upwards, &OCTAVE, octaves)
+        }
+    };
+
+    let target_key = key_sig as i32 + interval.fifths() as i32;
+    let candidates = [
+        (target_key, interval),
+        (target_key - 12, add(&interval, &DIMINISHED_SECOND, 1)),
+        (target_key + 12, add(&interval, &DIMINISHED_SECOND, -1))
+    ];
+    let writable = candidates
+        .iter()
+        .filter(|(key, _)| { key.abs() <= MAX_KEY_ACCIDENTALS as i32 });
+    let chosen = match options.key_spelling {
+        KeySpelling::FewestAccidentals => writable.min_by_key(|(key, _)| { key.abs() }),
+        KeySpelling::Sharps => writable.max_by_key(|(key, _)| { *key }),
+        KeySpelling::Flats => writable.min_by_key(|(key, _)| { *key }),
+    };
+    // there always is a writable key, they are 12 fifths apart
+    chosen.map(|(_, interval)| { *interval }).unwrap()
+}
+
+/// Key signature moved along the circle of fifths, brought back within seven accidentals if needed
+pub fn transpose_key(key_sig: KeySignature, interval: &DiatonicInterval) -> KeySignature
+{
+    let key = key_sig as i32 + interval.fifths() as i32;
+    let key
+        = if key.abs() <= MAX_KEY_ACCIDENTALS as i32 { key }
+        else { (key + 5).rem_euclid(12) - 5 };
+    key as KeySignature
+}
+
+/// Moves the pitch by the interval, notated in the key `key_sig` becomes.
+/// The spelling follows the interval (C# up a major third is E#) unless it needs more
+/// than a double accidental or the key had to be respelled, then it is spelled to fit the new key.
+pub fn transpose_pitch(pitch: &mut Pitch, interval: &DiatonicInterval, key_sig: KeySignature)
+{
+    let new_key = transpose_key(key_sig, interval);
+    let key_respelled = new_key as i32 != key_sig as i32 + interval.fifths() as i32;
+    pitch.transpose_by_interval(interval);
+    if key_respelled || pitch.alter.semitones().abs() > 2.0 {
+        pitch.respell_in_key(new_key);
+    }
+}
+
+/// Transposes every pitch of the gnotes, `key_sig_at` gives the key in effect at a gnote's start
+pub fn transpose_gnotes(gnotes: &mut [Gnote], interval: &DiatonicInterval, key_sig_at: &impl Fn(Offset) -> KeySignature)
+{
+    for gnote in gnotes.iter_mut() {
+        let key_sig = key_sig_at(either_gnote!(&gnote, gn => gn.interval.start));
+        for sn in gnote.simple_note_mut_iter() {
+            sn.pitches = sn
+                .pitches
+                .iter()
+                .map(|pitch| {
+                    let mut pitch = pitch.clone();
+                    transpose_pitch(&mut pitch, interval, key_sig);
+                    pitch
+                })
+                .collect();
+        }
+    }
+}
+
+fn add(lhs: &DiatonicInterval, rhs: &DiatonicInterval, times: i8) -> DiatonicInterval
+{
+    DiatonicInterval::new(lhs.steps + times * rhs.steps, lhs.semitones + times * rhs.semitones)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::attribs::{Duration, Offset, TimeSig};
+    use crate::clef::Clef;
+    use crate::diatonic_interval::DiatonicInterval;
+    use crate::part::Part;
+    use crate::pitch::{Alter, DiatonicStep, Pitch};
+    use crate::simple_note::{SimpleNote, TieInfo};
+    use crate::transposition::*;
+
+    fn interval(name: &str) -> DiatonicInterval {
+        name.parse().unwrap()
+    }
+
+    fn part_with_pitches(key_sig: KeySignature, pitches: &[Pitch]) -> Part {
+        let mut part = Part::new("Transposed".to_string(), key_sig, Clef::treble(), TimeSig::new_raw(4, 4));
+        for pitch in pitches.iter() {
+            let mut sn = SimpleNote::new(Offset::from_integer(0), Duration::from_integer(1), Vec::new(), None, TieInfo::TieNeither);
+            sn.pitches.insert(pitch.clone());
+            part.append_simple_note(sn);
+        }
+        part
+    }
+
+    fn spelled(part: &Part) -> Vec<(DiatonicStep, Alter, Option<i8>)> {
+        part
+        .simple_note_iter()
+        .flat_map(|sn| { sn.pitches.iter() })
+        .map(|pitch| { (pitch.step, pitch.alter, pitch.octave) })
+        .collect()
+    }
+
+    #[test]
+    fn enharmonic_target_key() {
+        let c_major = part_with_pitches(0, &[
+            Pitch::new(DiatonicStep::C, Some(4), Alter::No),
+            Pitch::new(DiatonicStep::F, Some(4), Alter::Sharp),
+        ]);
+        let sharps = TransposeOptions { key_spelling: KeySpelling::Sharps, ..Default::default() };
+        let flats = TransposeOptions { key_spelling: KeySpelling::Flats, ..Default::default() };
+
+        let f_sharp = c_major.transpose(&interval("A4"), &sharps);
+        assert_eq!(f_sharp.key_sig, 6);
+        assert_eq!(spelled(&f_sharp), vec![(DiatonicStep::F, Alter::Sharp, Some(4)), (DiatonicStep::B, Alter::Sharp, Some(4))]);
+
+        let g_flat = c_major.transpose(&interval("A4"), &flats);
+        assert_eq!(g_flat.key_sig, -6);
+        assert_eq!(spelled(&g_flat), vec![(DiatonicStep::G, Alter::Flat, Some(4)), (DiatonicStep::C, Alter::No, Some(5))]);
+
+        // G# major would need 8 sharps, Ab major is used whatever the interval's spelling
+        let e_major = part_with_pitches(4, &[Pitch::new(DiatonicStep::G, Some(4), Alter::Sharp)]);
+        let a_flat = e_major.transpose(&interval("M3"), &Default::default());
+        assert_eq!(a_flat.key_sig, -4);
+        assert_eq!(spelled(&a_flat), vec![(DiatonicStep::C, Alter::No, Some(5))]);
+        assert_eq!(a_flat.simple_note_iter().next().unwrap().pitches.iter().next().unwrap().ps, 72);
+    }
+
+    #[test]
+    fn octave_handling_and_semitones() {
+        let d_major = part_with_pitches(2, &[Pitch::new(DiatonicStep::D, Some(4), Alter::No)]);
+        let nearest = TransposeOptions { octave_handling: OctaveHandling::Nearest, ..Default::default() };
+        let octave_up = TransposeOptions { octave_handling: OctaveHandling::Octaves(1), ..Default::default() };
+
+        let down = d_major.transpose(&interval("P5"), &nearest);
+        assert_eq!(down.key_sig, 3);
+        assert_eq!(spelled(&down), vec![(DiatonicStep::A, Alter::No, Some(3))]);
+
+        let up = d_major.transpose(&interval("-m3"), &octave_up);
+        assert_eq!(up.key_sig, 5);
+        assert_eq!(spelled(&up), vec![(DiatonicStep::B, Alter::No, Some(5))]);
+
+        // semitone transposition follows the key signature too
+        let e_flat = d_major.transpose_by(1);
+        assert_eq!(e_flat.key_sig, -3);
+        assert_eq!(spelled(&e_flat), vec![(DiatonicStep::E, Alter::Flat, Some(4))]);
+    }
+
+    #[test]
+    fn transposing_instrument() {
+        // a Bb clarinet sounds a major second below its written pitch
+        let concert = part_with_pitches(-2, &[Pitch::new(DiatonicStep::B, Some(4), Alter::Flat)]);
+        let written = concert.to_written(interval("-M2"), &Default::default());
+        assert_eq!(written.key_sig, 0);
+        assert_eq!(written.transposition, Some(interval("-M2")));
+        assert_eq!(spelled(&written), vec![(DiatonicStep::C, Alter::No, Some(5))]);
+
+        let sounding = written.to_measured().to_sounding(&Default::default()).flatten();
+        assert_eq!(sounding.key_sig, -2);
+        assert_eq!(sounding.transposition, None);
+        assert_eq!(spelled(&sounding), vec![(DiatonicStep::B, Alter::Flat, Some(4))]);
+    }
+}
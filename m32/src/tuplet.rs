@@ -1,111 +1,204 @@
 use fraction::Ratio;
 use smallvec::SmallVec;
 use crate::attribs::{BeatDivision, Duration, MPInterval, Offset};
+use crate::either_gnote;
+use crate::gnote::Gnote;
 use crate::simple_note::{SimpleNote};
 use crate::config::*;
 
 pub type NormalNumType = u16;
 
-#[derive(Clone)]
+/// `actual_number` members in the time of `normal_number`, eg. 3:2 for triplets.
+/// Members are laid out one after the other and their intervals are relative to the start
+/// of the tuplet, so moving a tuplet only moves its own interval. They hold their sounding
+/// length, can differ in length, be rests or be tuplets themselves.
+#[derive(Clone, Debug)]
 pub struct Tuplet {
-    pub notes: Vec<SimpleNote>,
-    pub interval: MPInterval, // should not be changed directly
+    pub members: Vec<Gnote>,
+    pub interval: MPInterval, // should not be changed directly, its length is the members' sum
     pub actual_number: NormalNumType,
     pub normal_number: NormalNumType
 }
 
 impl Tuplet {
+    /// Members are laid out from 0 whatever their own start
     pub fn new(
         normal_number: NormalNumType,
         actual_number: NormalNumType,
-        interval: MPInterval,
-        simple_notes: Vec<SimpleNote>
+        start: Offset,
+        mut members: Vec<Gnote>
     ) -> Self {
+        let mut length = Duration::from_integer(0);
+        for member in members.iter_mut() {
+            either_gnote!(member, gn => gn.interval.set_start_keep_length(length));
+            length += either_gnote!(member, gn => gn.interval.length);
+        }
         Self {
-            notes: simple_notes,
-            interval,
+            members,
+            interval: MPInterval::from_start_and_length(start, length),
             actual_number,
             normal_number
         }
     }
 
+    /// Written length over sounding length of the members
+    pub fn ratio(&self) -> Ratio<BeatDivision> {
+        Ratio::new(self.actual_number as BeatDivision, self.normal_number as BeatDivision)
+    }
+
     pub fn displace_start_keep_length(&mut self, displacement: Duration) {
-        self.displace_start_keep_length(displacement);
-        self.notes.iter_mut()
-        .for_each(|nt|
-            {nt.interval.displace_start_keep_length(displacement)}
-        )
+        self.interval.displace_start_keep_length(displacement);
     }
 
     pub fn set_start_keep_length(&mut self, start: Offset) {
-        let displacement = start - self.interval.start;
-        self.set_start_keep_length(start);
-        self.notes.iter_mut()
-        .for_each(|nt| {nt.interval.displace_start_keep_length(displacement)})
+        self.interval.set_start_keep_length(start);
     }
 
+    /// Simple notes of the members in order, nested tuplets included
+    pub fn simple_note_iter(&self) -> Box<dyn Iterator<Item=&SimpleNote> + '_> {
+        Box::new(self.members.iter().flat_map(|gn| { gn.simple_note_iter() }))
+    }
+
+    pub fn simple_note_mut_iter(&mut self) -> Box<dyn Iterator<Item=&mut SimpleNote> + '_> {
+        Box::new(self.members.iter_mut().flat_map(|gn| { gn.simple_note_mut_iter() }))
+    }
+
+    /// Simple notes placed in the frame of the tuplet's own interval, each keeping its length
     pub fn flatten(&self) -> SmallVec<[SimpleNote; config::EXP_TUP_LEN]>
     {
-        // truncate right
-        let normal_note_duration =
-            self.interval.length / Offset::from_integer(self.normal_number as BeatDivision);
         let mut flat = SmallVec::<[SimpleNote; config::EXP_TUP_LEN]>::new();
-        flat.reserve(self.notes.len());
-
-        let mut last_offset = self.interval.start;
-        for note in self.notes.iter() {
-            let mut new_note = note.clone();
-            new_note.interval.set_start_keep_length(last_offset);
-            new_note.interval.set_length_keep_start(normal_note_duration);
-            last_offset += normal_note_duration;
+        self.flatten_into(self.interval.start, &mut flat);
+        flat
+    }
 
-            flat.push(new_note);
+    fn flatten_into(&self, start: Offset, flat: &mut SmallVec<[SimpleNote; config::EXP_TUP_LEN]>)
+    {
+        for member in self.members.iter() {
+            match member {
+                Gnote::SimpleNote(sn) => {
+                    let mut new_note = sn.clone();
+                    new_note.interval.displace_start_keep_length(start);
+                    flat.push(new_note);
+                },
+                Gnote::Tuplet(tup) => tup.flatten_into(start + tup.interval.start, flat)
+            }
         }
-        flat
     }
 
-    pub fn split_at_offset(&self, offset: Offset)
-        -> (SmallVec<[SimpleNote; config::EXP_TUP_LEN]>,
-            SmallVec<[SimpleNote; config::EXP_TUP_LEN]>)
+    /// Two tuplets of the same ratio, the member across `offset` is split into tied halves.
+    /// `offset` is in the frame of the tuplet's interval, eg. a barline.
+    pub fn split_at_offset(&self, offset: Offset) -> (Tuplet, Tuplet)
     {
+        assert!(self.interval.does_half_closed_contains_offset(offset));
+        assert_ne!(self.interval.start, offset);
         assert_eq!(
-            self.notes
+            self.members
                 .iter()
                 .fold(Duration::from(0),
-                      |length, note| {
-                            length + note.interval.length
+                      |length, member| {
+                            length + either_gnote!(member, gn => gn.interval.length)
                       }),
             self.interval.length,
             "tuplet components don't add up length-wise!"
         );
-        let mut flat_vec = self.flatten();
-
-        for (index, note) in
-            flat_vec
-            .iter()
-            .enumerate()
-            .filter(
-                |(index, note)|
-                {note.interval.does_half_closed_contains_offset(offset)})
-        {
-            // if already cut
-            if note.interval.start == offset {
-                let (first_half, second_half) = flat_vec.split_at(index);
-                return (first_half.into(), second_half.into());
+
+        let relative_offset = offset - self.interval.start;
+        let mut left = Vec::new();
+        let mut right = Vec::new();
+        for member in self.members.iter() {
+            let member_interval = either_gnote!(member, gn => gn.interval);
+            if member_interval.end <= relative_offset {
+                left.push(member.clone());
+            }
+            else if member_interval.start >= relative_offset {
+                right.push(member.clone());
+            }
+            else {
+                let (first_half, second_half) = member.split_at_offset(relative_offset);
+                left.push(first_half);
+                right.push(second_half);
             }
-            let crumbles = note.split_at_offset(offset);
-            let (first_half, second_half) = flat_vec.split_at(index);
-            let (mut first, mut second)
-                = (SmallVec::<[SimpleNote; 6]>::from(first_half),
-                   SmallVec::<[SimpleNote; 6]>::from(second_half));
-            first.pop();
-            first.extend(crumbles.0.into_iter());
-            crumbles.1
-            .into_iter()
-            .rev()
-            .for_each(|sn| {second.insert(0, sn)});
-            return (first, second);
         }
-        unreachable!()
+        (
+            Tuplet::new(self.normal_number, self.actual_number, self.interval.start, left),
+            Tuplet::new(self.normal_number, self.actual_number, offset, right)
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::attribs::{Duration, Offset};
+    use crate::gnote::Gnote;
+    use crate::pitch::{Pitch, PsType};
+    use crate::simple_note::{SimpleNote, TieInfo};
+    use crate::tuplet::Tuplet;
+
+    fn note(length: Duration, ps: Option<PsType>) -> Gnote {
+        let mut sn = SimpleNote::new(Offset::from_integer(0), length, Vec::new(), None, TieInfo::TieNeither);
+        sn.pitches.extend(ps.map(Pitch::from_ps));
+        Gnote::SimpleNote(sn)
     }
-}
\ No newline at end of file
+
+    // quarter + eighth rest under a triplet bracket, then a nested 5:4 on the last third
+    fn nested_tuplet() -> Tuplet {
+        let quintuplet = Tuplet::new(
+            4, 5, Offset::from_integer(0),
+            (0..5).map(|idx| { note(Duration::new(1, 15), Some(60 + idx)) }).collect()
+        );
+        Tuplet::new(2, 3, Offset::from_integer(4), vec![
+            note(Duration::new(2, 3), Some(67)),
+            note(Duration::new(1, 3), None),
+            note(Duration::new(2, 3), Some(69)),
+            Gnote::Tuplet(quintuplet),
+        ])
+    }
+
+    #[test]
+    fn members_keep_their_own_length() {
+        let mut tup = nested_tuplet();
+        assert_eq!(tup.interval.length, Duration::from_integer(2));
+        tup.set_start_keep_length(Offset::from_integer(8));
+        tup.displace_start_keep_length(Duration::from_integer(-1));
+
+        let flat = tup.flatten();
+        assert_eq!(flat.len(), 8);
+        assert_eq!(flat[0].interval.length, Duration::new(2, 3));
+        assert!(flat[1].is_rest());
+        assert_eq!(flat[3].interval.start, Offset::new(26, 3));
+        assert_eq!(flat.last().unwrap().interval.end, Offset::from_integer(9));
+        assert!(flat.windows(2).all(|pair| { pair[0].interval.end == pair[1].interval.start }));
+        assert_eq!(tup.simple_note_iter().count(), 8);
+    }
+
+    #[test]
+    fn split_keeps_the_ratio() {
+        let tup = nested_tuplet();
+        // inside the second quintuplet member
+        let (left, right) = tup.split_at_offset(Offset::new(17, 3) + Duration::new(1, 10));
+        assert_eq!((left.actual_number, left.normal_number), (3, 2));
+        assert_eq!((right.actual_number, right.normal_number), (3, 2));
+        assert_eq!(left.interval.length + right.interval.length, tup.interval.length);
+        assert_eq!(right.interval.start, left.interval.end);
+
+        match (left.members.last().unwrap(), right.members.first().unwrap()) {
+            (Gnote::Tuplet(left_inner), Gnote::Tuplet(right_inner)) => {
+                assert_eq!((left_inner.actual_number, right_inner.actual_number), (5, 5));
+                assert_eq!(left_inner.simple_note_iter().count(), 2);
+                assert_eq!(right_inner.simple_note_iter().count(), 4);
+                let (cut_left, cut_right) = (
+                    left_inner.simple_note_iter().last().unwrap(),
+                    right_inner.simple_note_iter().next().unwrap()
+                );
+                assert!(cut_left.tie_info.contains(TieInfo::TieStart));
+                assert!(cut_right.tie_info.contains(TieInfo::TieEnd));
+                assert_eq!(cut_left.interval.length + cut_right.interval.length, Duration::new(1, 15));
+            },
+            _ => panic!("the quintuplet should be split too")
+        }
+
+        // on a member boundary nothing is cut
+        let (left, right) = tup.split_at_offset(Offset::from_integer(5));
+        assert_eq!((left.members.len(), right.members.len()), (2, 2));
+    }
+}
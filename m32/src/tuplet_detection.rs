@@ -0,0 +1,320 @@
+//! Tuplet detection: plain notes whose edges fall off the binary grid of their beat
+//! are gathered into one tuplet per beat. Beats follow the time signature from every barline.
+
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use anyhow::{anyhow, Context};
+use fraction::Integer;
+use smallvec::SmallVec;
+use crate::attribs::{BeatDivision, Duration, Offset};
+use crate::duration::duration_utils::{compute_dotted_length, decompose_duration_into_primitives};
+use crate::either_gnote;
+use crate::gnote::Gnote;
+use crate::simple_note::{SimpleNote, TieInfo};
+use crate::tuplet::{NormalNumType, Tuplet};
+
+pub fn is_binary(offset: Offset) -> bool
+{
+    offset.denom().count_ones() == 1
+}
+
+// the odd factor of the denominator gives the tuplet, eg. 3 for triplets
+pub fn odd_part(offset: Offset) -> BeatDivision
+{
+    let denom = *offset.denom();
+    denom >> denom.trailing_zeros()
+}
+
+/// The simplest bracket for `actual` notes: in the time of the largest power of two below it, eg. 3:2, 5:4
+pub fn normal_number_of(actual: NormalNumType) -> NormalNumType
+{
+    1 << (15 - actual.leading_zeros())
+}
+
+/// Beat starts of a measure, each beat lasting `beat_length` from the barline.
+/// A pickup ends on a barline, so do its beats: the first one may be shorter.
+pub fn beats_of_measure(start: Offset, length: Duration, beat_length: Duration, is_pickup: bool) -> Vec<Offset>
+{
+    let end = start + length;
+    let mut beats = Vec::new();
+    if is_pickup {
+        let mut beat = end - beat_length;
+        while beat > start {
+            beats.push(beat);
+            beat -= beat_length;
+        }
+        if start < end { beats.push(start); }
+        beats.reverse();
+    } else {
+        let mut beat = start;
+        while beat < end {
+            beats.push(beat);
+            beat += beat_length;
+        }
+    }
+    beats
+}
+
+// index in `beats` of the beat holding the offset, none past the last beat
+fn beat_of(beats: &[Offset], offset: Offset) -> Option<usize>
+{
+    beats
+    .partition_point(|start| { *start <= offset })
+    .checked_sub(1)
+    .filter(|idx| { idx + 1 < beats.len() })
+}
+
+// beat index -> actual number of the tuplet filling it
+fn tuplet_beats(gnotes: &[Gnote], beats: &[Offset]) -> anyhow::Result<BTreeMap<usize, NormalNumType>>
+{
+    let mut tuplet_beats: BTreeMap<usize, BeatDivision> = BTreeMap::new();
+    for sn in gnotes.iter().filter_map(|gn| { if let Gnote::SimpleNote(sn) = gn { Some(sn) } else { None } }) {
+        for boundary in [sn.interval.start, sn.interval.end].iter() {
+            if let Some(beat) = beat_of(beats, *boundary) {
+                let from_beat = *boundary - beats[beat];
+                if !is_binary(from_beat) {
+                    let actual = tuplet_beats.entry(beat).or_insert(1);
+                    *actual = actual.lcm(&odd_part(from_beat));
+                }
+            }
+        }
+    }
+    // beats already holding a tuplet are left as they are
+    for tup in gnotes.iter().filter_map(|gn| { if let Gnote::Tuplet(tup) = gn { Some(tup) } else { None } }) {
+        tuplet_beats.retain(|beat, _| { beats[*beat + 1] <= tup.interval.start || tup.interval.end <= beats[*beat] });
+    }
+    tuplet_beats
+        .into_iter()
+        .map(|(beat, actual)| {
+            NormalNumType::try_from(actual)
+                .map(|actual| { (beat, actual) })
+                .map_err(|_| { anyhow!("Tuplet of {} notes in beat {} is too large", actual, beat) })
+        })
+        .collect()
+}
+
+// the note tied over at every cut strictly inside it, a rest is cut without ties
+fn cut_at(sn: &SimpleNote, cuts: &[Offset]) -> SmallVec<[SimpleNote; 4]>
+{
+    let mut pieces = SmallVec::<[SimpleNote; 4]>::new();
+    let mut rest = sn.clone();
+    for cut in cuts.iter().filter(|cut| { sn.interval.start < **cut && **cut < sn.interval.end }) {
+        let (mut left, right) = rest.split_at_offset(*cut);
+        let (left, right) = (left.remove(0), right.into_iter().next().unwrap());
+        pieces.push(left);
+        rest = right;
+    }
+    pieces.push(rest);
+    if sn.is_rest() {
+        pieces.iter_mut().for_each(|piece| { piece.tie_info = TieInfo::TieNeither; });
+    }
+    pieces
+}
+
+/// The note tied over so that every piece has a single notatable length
+/// once multiplied by `notated_ratio`, eg. 3/2 in a triplet
+pub fn notatable_pieces(sn: &SimpleNote, notated_ratio: Offset) -> anyhow::Result<SmallVec<[SimpleNote; 4]>>
+{
+    let components = decompose_duration_into_primitives(&(sn.interval.length * notated_ratio))
+        .with_context(|| { format!("Can't notate duration {}", sn.interval.length) })?;
+    let mut cuts = Vec::with_capacity(components.len());
+    let mut end = sn.interval.start;
+    for (primitive, dots) in components.iter() {
+        end += compute_dotted_length(*primitive, *dots) / notated_ratio;
+        cuts.push(end);
+    }
+    Ok(cut_at(sn, &cuts))
+}
+
+/// Plain notes of a voice grouped into tuplets where their beat needs one.
+/// `beats` holds the start of every beat in increasing order, then the end of the last one,
+/// in the frame of the gnote intervals; notes outside of it are left plain.
+pub fn detect_tuplets_in_gnotes(gnotes: &[Gnote], beats: &[Offset]) -> anyhow::Result<Vec<Gnote>>
+{
+    let tuplet_beats = tuplet_beats(gnotes, beats)?;
+    if tuplet_beats.is_empty() { return Ok(gnotes.to_vec()); }
+    let mut beat_edges: Vec<Offset> = tuplet_beats
+        .keys()
+        .flat_map(|beat| { [beats[*beat], beats[*beat + 1]] })
+        .collect();
+    // neighbouring tuplet beats share an edge
+    beat_edges.dedup();
+
+    let mut detected: Vec<Gnote> = Vec::with_capacity(gnotes.len());
+    // (beat, members) of the tuplet being gathered
+    let mut open_tuplet: Option<(usize, Vec<Gnote>)> = None;
+    let close = |open_tuplet: &mut Option<(usize, Vec<Gnote>)>, detected: &mut Vec<Gnote>| {
+        if let Some((beat, members)) = open_tuplet.take() {
+            let actual = tuplet_beats[&beat];
+            detected.push(Gnote::Tuplet(Tuplet::new(normal_number_of(actual), actual, beats[beat], members)));
+        }
+    };
+
+    for gnote in gnotes.iter() {
+        let sn = match gnote {
+            Gnote::SimpleNote(sn) => sn,
+            Gnote::Tuplet(_) => {
+                close(&mut open_tuplet, &mut detected);
+                detected.push(gnote.clone());
+                continue;
+            }
+        };
+        for piece in cut_at(sn, &beat_edges) {
+            let beat = beat_of(beats, piece.interval.start);
+            match beat.and_then(|beat| { tuplet_beats.get(&beat).map(|actual| { (beat, *actual) }) }) {
+                Some((beat, actual)) => {
+                    if open_tuplet.as_ref().map_or(false, |(open_beat, _)| { *open_beat != beat }) {
+                        close(&mut open_tuplet, &mut detected);
+                    }
+                    let normal = normal_number_of(actual);
+                    let members = notatable_pieces(&piece, Offset::new(actual as BeatDivision, normal as BeatDivision))
+                        .with_context(|| { format!("Can't fit a note in a {}:{} tuplet", actual, normal) })?;
+                    open_tuplet
+                    .get_or_insert_with(|| { (beat, Vec::new()) })
+                    .1
+                    .extend(members.into_iter().map(Gnote::SimpleNote));
+                },
+                None => {
+                    close(&mut open_tuplet, &mut detected);
+                    detected.push(Gnote::SimpleNote(piece));
+                }
+            }
+        }
+    }
+    close(&mut open_tuplet, &mut detected);
+
+    debug_assert!(
+        detected.iter().map(|gn| { either_gnote!(gn, g => g.interval.length) }).sum::<Duration>()
+        == gnotes.iter().map(|gn| { either_gnote!(gn, g => g.interval.length) }).sum::<Duration>()
+    );
+    Ok(detected)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::attribs::{Duration, Offset, TimeSig};
+    use crate::clef::Clef;
+    use crate::gnote::Gnote;
+    use crate::part::Part;
+    use crate::pitch::{Alter, DiatonicStep, Pitch};
+    use crate::score::Score;
+    use crate::simple_note::{SimpleNote, TieInfo};
+    use crate::tuplet_detection::normal_number_of;
+    use crate::xml_export::score_to_tag;
+
+    fn plain_note(length: Duration, is_rest: bool) -> SimpleNote {
+        let mut sn = SimpleNote::new(Offset::from_integer(0), length, Vec::new(), None, TieInfo::TieNeither);
+        if !is_rest { sn.pitches.insert(Pitch::new(DiatonicStep::A, Some(4), Alter::No)); }
+        sn
+    }
+
+    fn tuplet_ratios(gnotes: &[Gnote]) -> Vec<Option<(u16, u16)>> {
+        gnotes
+            .iter()
+            .map(|gn| {
+                match gn {
+                    Gnote::Tuplet(tup) => Some((tup.actual_number, tup.normal_number)),
+                    Gnote::SimpleNote(_) => None
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn simplest_brackets() {
+        assert_eq!([3, 5, 6, 7, 9, 15].iter().map(|actual| { normal_number_of(*actual) }).collect::<Vec<_>>(), [2, 4, 4, 4, 8, 8]);
+    }
+
+    #[test]
+    fn groups_off_grid_notes_per_beat() {
+        let mut part = Part::new("Flat".to_string(), 0, Clef::treble(), TimeSig::new_raw(4, 4));
+        // quarter | three triplet eighths | quintuplet: 2/5 + rest 1/5 + 2/5 | half tied over from a triplet
+        for (length, is_rest) in [
+            (Duration::from_integer(1), false),
+            (Duration::new(1, 3), false), (Duration::new(1, 3), false), (Duration::new(1, 3), false),
+            (Duration::new(2, 5), false), (Duration::new(1, 5), true), (Duration::new(2, 5), false),
+            (Duration::new(2, 3), false), (Duration::new(4, 3), false),
+        ].iter() {
+            part.append_simple_note(plain_note(*length, *is_rest));
+        }
+        let detected = part.detect_tuplets().unwrap();
+        assert_eq!(
+            tuplet_ratios(&detected.gnotes),
+            [None, Some((3, 2)), Some((5, 4)), Some((3, 2)), None]
+        );
+        assert_eq!(detected.simple_note_iter().map(|sn| { sn.interval.length }).sum::<Duration>(), Duration::from_integer(5));
+        // the last 4/3 is cut at the beat: a triplet eighth tied to a plain whole beat
+        match &detected.gnotes[3] {
+            Gnote::Tuplet(tup) => {
+                let tied = tup.simple_note_iter().last().unwrap();
+                assert_eq!(tied.interval.length, Duration::new(1, 3));
+                assert!(tied.tie_info.contains(TieInfo::TieStart));
+            },
+            _ => panic!("expected a triplet")
+        }
+        assert!(detected.simple_note_iter().filter(|sn| { sn.is_rest() }).all(|sn| { sn.tie_info == TieInfo::TieNeither }));
+
+        // detecting again changes nothing
+        assert_eq!(tuplet_ratios(&detected.detect_tuplets().unwrap().gnotes), tuplet_ratios(&detected.gnotes));
+
+        let measured = detected.to_measured().detect_tuplets().unwrap();
+        assert_eq!(measured.measures.len(), 2);
+        let mut score = Score::new("Flat");
+        score.parts.push(part.clone());
+        assert!(score_to_tag(&score).is_err());
+        score.parts[0] = detected;
+        assert!(score_to_tag(&score).is_ok());
+    }
+
+    #[test]
+    fn measured_part_detection() {
+        let mut part = Part::new("Measured".to_string(), 0, Clef::treble(), TimeSig::new_raw(2, 4));
+        for _ in 0..6 {
+            part.append_simple_note(plain_note(Duration::new(1, 3), false));
+        }
+        part.append_simple_note(plain_note(Duration::from_integer(2), false));
+        let measured = part.to_measured().detect_tuplets().unwrap();
+        assert_eq!(tuplet_ratios(&measured.measures[0].gnotes), [Some((3, 2)), Some((3, 2))]);
+        assert_eq!(tuplet_ratios(&measured.measures[1].gnotes), [None]);
+        assert!(measured.measures.iter().all(|mea| { mea.get_elements_acc_duration() == mea.interval.length }));
+    }
+
+    #[test]
+    fn beats_follow_the_time_signature() {
+        let detect = |time_sig: TimeSig, pickup: Option<Duration>, lengths: &[Duration]| {
+            let mut part = Part::new("Beats".to_string(), 0, Clef::treble(), time_sig);
+            part.implicit_measures.extend(pickup.map(|length| { (Offset::from_integer(0), length) }));
+            lengths.iter().for_each(|length| { part.append_simple_note(plain_note(*length, false)); });
+            part.detect_tuplets().unwrap().gnotes
+        };
+        let tuplet_starts = |gnotes: &[Gnote]| -> Vec<Offset> {
+            gnotes.iter().filter_map(|gn| { if let Gnote::Tuplet(tup) = gn { Some(tup.interval.start) } else { None } }).collect()
+        };
+
+        // a half note triplet is one bracket over the half note beat of 2/2
+        let cut_time = detect(TimeSig::new_raw(2, 2), None, &[Duration::new(2, 3), Duration::new(2, 3), Duration::new(2, 3), Duration::from_integer(2)]);
+        assert_eq!(tuplet_ratios(&cut_time), [Some((3, 2)), None]);
+
+        // nine notes in the dotted quarter beat of 6/8
+        let mut lengths = vec![Duration::new(1, 6); 9];
+        lengths.push(Duration::new(3, 2));
+        let compound = detect(TimeSig::new_raw(6, 8), None, &lengths);
+        assert_eq!(tuplet_ratios(&compound), [Some((3, 2)), None]);
+
+        // beats of 3/8 restart at the second barline instead of running on in quarter notes
+        let three_eight = detect(
+            TimeSig::new_raw(3, 8),
+            None,
+            &[Duration::new(3, 2), Duration::new(1, 6), Duration::new(1, 6), Duration::new(1, 6), Duration::from_integer(1)]
+        );
+        assert_eq!(tuplet_starts(&three_eight), [Offset::new(3, 2)]);
+
+        // the beats of a pickup of a beat and a half end on its barline
+        let pickup = detect(
+            TimeSig::new_raw(4, 4),
+            Some(Duration::new(3, 2)),
+            &[Duration::new(1, 2), Duration::new(1, 3), Duration::new(1, 3), Duration::new(1, 3), Duration::from_integer(4)]
+        );
+        assert_eq!(tuplet_starts(&pickup), [Offset::new(1, 2)]);
+    }
+}
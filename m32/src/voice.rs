@@ -0,0 +1,58 @@
+use std::collections::BTreeMap;
+use crate::attribs::{Duration, Offset};
+use crate::either_gnote;
+use crate::gnote::Gnote;
+use crate::simple_note::{SimpleNote, TieInfo};
+
+/// Voices are numbered like MusicXML's <voice>, staves like <staff>; both start at 1.
+pub type VoiceNumberType = u8;
+pub type StaffNumberType = u8;
+
+/// A voice is told apart by its staff and its number, both staves of a piano part may use voice 1
+pub type VoiceKey = (StaffNumberType, VoiceNumberType);
+
+pub const DEFAULT_VOICE: VoiceNumberType = 1;
+pub const DEFAULT_STAFF: StaffNumberType = 1;
+pub const DEFAULT_VOICE_KEY: VoiceKey = (DEFAULT_STAFF, DEFAULT_VOICE);
+
+/// Every voice other than the primary one, keyed by staff and voice number
+pub type VoiceMap = BTreeMap<VoiceKey, Vec<Gnote>>;
+
+pub fn rest_gnote(offset: Offset, duration: Duration) -> Gnote
+{
+    Gnote::SimpleNote(SimpleNote::new(offset, duration, Vec::new(), None, TieInfo::TieNeither))
+}
+
+/// Places `gnote` (whose offset is already set) after the stream, padding with a rest
+/// when it starts later than the stream ends, ie. what <forward> leaves behind.
+pub fn push_gnote_with_gap_rest(stream: &mut Vec<Gnote>, gnote: Gnote) -> anyhow::Result<()>
+{
+    let stream_end
+        = stream
+        .last()
+        .map(|g| { either_gnote!(g, gn => gn.interval.end) })
+        .unwrap_or(Offset::from_integer(0));
+    let gnote_start = either_gnote!(&gnote, gn => gn.interval.start);
+
+    if gnote_start < stream_end {
+        return Err(anyhow::anyhow!("Notes of one voice overlap at offset {}", gnote_start));
+    }
+    if gnote_start > stream_end {
+        stream.push(rest_gnote(stream_end, gnote_start - stream_end));
+    }
+    stream.push(gnote);
+    Ok(())
+}
+
+/// Pads the end of a measure-relative stream with a rest so that it lasts `length`
+pub fn pad_with_rest(stream: &mut Vec<Gnote>, length: Duration)
+{
+    let stream_end
+        = stream
+        .last()
+        .map(|g| { either_gnote!(g, gn => gn.interval.end) })
+        .unwrap_or(Offset::from_integer(0));
+    if stream_end < length {
+        stream.push(rest_gnote(stream_end, length - stream_end));
+    }
+}
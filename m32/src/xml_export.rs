@@ -0,0 +1,894 @@
+use anyhow::{anyhow, Context};
+use fraction::Integer;
+use smallvec::SmallVec;
+use adaxml::convert::ToXmlTag;
+use adaxml::iter::XmlVisit;
+use adaxml::tag::*;
+use crate::score::*;
+use crate::attribs::{BeatDivision, Duration, KeySignature, MeasureAttributes, Offset, TimeSig};
+use crate::config::config;
+use crate::diatonic_interval::DiatonicInterval;
+use crate::duration::DurationName;
+use crate::duration::duration_utils::{compute_dotted_length, decompose_duration_into_primitives};
+use crate::either_gnote;
+use crate::gnote::Gnote;
+use crate::lyric::Lyric;
+use crate::measure::Measure;
+use crate::mxl;
+use crate::part::MeasuredPart;
+use crate::pitch::{Alter, Pitch};
+use crate::simple_note::{SimpleNote, TieInfo};
+use crate::tuplet::{NormalNumType, Tuplet};
+use crate::voice::{DEFAULT_STAFF, StaffNumberType, VoiceKey};
+use crate::xml_import::{WrittenPitch, WrittenTime, WrittenTranspose};
+
+/// Position of a note inside (nested) tuplet brackets, used to emit <time-modification> and <tuplet>
+#[derive(Clone)]
+struct TupletContext {
+    levels: SmallVec<[(NormalNumType, NormalNumType); 2]>, // (actual, normal) of the brackets, outermost first
+    first_level: usize, // the note opens the brackets from this level inwards
+    last_level: usize, // and closes the ones from this level inwards
+}
+
+impl TupletContext {
+    // product of the ratios, what <time-modification> holds
+    fn time_modification(&self) -> (NormalNumType, NormalNumType) {
+        self.levels
+            .iter()
+            .fold((1, 1), |(actual, normal), level| { (actual * level.0, normal * level.1) })
+    }
+
+    fn notated_ratio(&self) -> Offset {
+        let (actual, normal) = self.time_modification();
+        Offset::new(actual as BeatDivision, normal as BeatDivision)
+    }
+}
+
+/// The version written in <score-partwise version=...> and in the DOCTYPE
+pub const MUSICXML_VERSION: &str = "3.1";
+
+/// Xml declaration and partwise DOCTYPE written before a <score-partwise>
+pub fn musicxml_meta_data() -> XmlMetaData
+{
+    XmlMetaData {
+        standalone: Some(false),
+        prolog: vec![
+            XmlPrologItem::Doctype(format!(
+                "<!DOCTYPE score-partwise PUBLIC \"-//Recordare//DTD MusicXML {} Partwise//EN\" \"http://www.musicxml.org/dtds/partwise.dtd\">",
+                MUSICXML_VERSION
+            ))
+        ],
+        ..Default::default()
+    }
+}
+
+/// Elements only about page layout and engraving, the notation does not depend on them
+pub const LAYOUT_ELEMENTS: [&str; 6] = ["print", "credit", "defaults", "system-layout", "staff-layout", "page-layout"];
+
+/// Attributes positioning things on the page
+pub const LAYOUT_ATTRIBUTES: [&str; 5] = ["default-x", "default-y", "relative-x", "relative-y", "width"];
+
+/// Removes the layout elements and attributes from a <score-partwise> tree, eg. before archiving it;
+/// returns how many elements went away
+pub fn strip_layout(score_tag: &mut XmlTag) -> usize
+{
+    let removed = score_tag.remove_desc_where(|tag| { LAYOUT_ELEMENTS.contains(&tag.name.as_str()) });
+    score_tag.visit_mut(|tag, _| {
+        tag.attribs.retain(|attrib| { !LAYOUT_ATTRIBUTES.contains(&attrib.name.as_str()) });
+        XmlVisit::Continue
+    });
+    removed
+}
+
+/// /////// Score //////// //
+
+pub fn score_to_path(score: &Score, path: &str) -> anyhow::Result<()>
+{
+    score_tag_to_path(&score_to_tag(score)?, path)
+}
+
+/// Writes the <score-partwise> tree uncompressed (.musicxml, .xml) or compressed (.mxl)
+pub fn score_tag_to_path(score_tag: &XmlTag, path_: &str) -> anyhow::Result<()>
+{
+    let path = std::path::Path::new(path_);
+    match path
+        .extension()
+        .and_then(|ext| { ext.to_str() })
+        .unwrap_or_default()
+    {
+        "mxl" => mxl::score_tag_to_mxl_path(score_tag, path_),
+        "musicxml" | "xml" => score_tag.to_path_with_meta(&musicxml_meta_data(), path_),
+        ext => Err(anyhow!("Unknown file extension {:?}", ext))
+    }
+}
+
+pub fn score_to_tag(score: &Score) -> anyhow::Result<XmlTag>
+{
+    measured_score_to_tag(&score.to_measured())
+}
+
+/// /////// Measured Score //////// //
+
+pub fn measured_score_to_path(mscore: &MeasuredScore, path: &str) -> anyhow::Result<()>
+{
+    score_tag_to_path(&measured_score_to_tag(mscore)?, path)
+}
+
+pub fn measured_score_to_tag(mscore: &MeasuredScore) -> anyhow::Result<XmlTag>
+{
+    let mut score_tag = XmlTag::new("score-partwise");
+    score_tag.add_attribute("version", MUSICXML_VERSION);
+
+    score_tag
+        .add_child("work")
+        .add_child_with_value("work-title", mscore.title.as_str());
+
+    {
+        let part_list_tag = score_tag.add_child("part-list");
+        for (idx, mpart) in mscore.measured_parts.iter().enumerate() {
+            let score_part_tag = part_list_tag.add_child("score-part");
+            score_part_tag.add_attribute("id", part_id(idx));
+            score_part_tag.add_child_with_value("part-name", mpart.name.as_str());
+        }
+    }
+
+    for (idx, mpart) in mscore.measured_parts.iter().enumerate() {
+        score_tag.children.push(
+            measured_part_to_tag(mpart, part_id(idx).as_str())
+            .with_context(|| format!("Can't export part {:?}", mpart.name))?
+        );
+    }
+    Ok(score_tag)
+}
+
+fn part_id(idx: usize) -> String {
+    format!("P{}", idx + 1)
+}
+
+/// /////// Measured Part //////// //
+
+pub fn measured_part_to_tag(mpart: &MeasuredPart, part_id: &str)
+    -> anyhow::Result<XmlTag>
+{
+    let mut part_tag = XmlTag::new("part");
+    part_tag.add_attribute("id", part_id);
+
+    let divisions = divisions_of_measured_part(mpart);
+    let mut previous_attributes = None;
+    for measure in mpart.measures.iter() {
+        let measure_tag = part_tag.add_child("measure");
+        measure_tag.add_attribute_with_type("number", measure.measure_number);
+        if measure.implicit {
+            measure_tag.add_attribute("implicit", "yes");
+        }
+
+        match previous_attributes {
+            None => {
+                let mut attributes_tag = part_attributes_to_tag(&measure.attributes, divisions);
+                if let Some(transposition) = mpart.transposition.as_ref() {
+                    attributes_tag.children.push(transposition_to_tag(transposition));
+                }
+                measure_tag.children.push(attributes_tag);
+            },
+            Some(previous) => {
+                if previous != measure.attributes {
+                    measure_tag.children.push(attribute_changes_to_tag(&previous, &measure.attributes));
+                }
+            }
+        }
+        previous_attributes = Some(measure.attributes.clone());
+
+        for (offset, bpm) in mpart.tempo_marks.range(measure.interval.start..measure.interval.end) {
+            measure_tag.children.push(tempo_to_tag(*bpm, *offset - measure.interval.start, divisions)?);
+        }
+
+        measure_tag.children.extend(
+            measure_to_tags(measure, divisions, mpart.primary_voice)
+            .with_context(|| format!("Can't export measure {}", measure.measure_number))?
+        );
+    }
+    Ok(part_tag)
+}
+
+/// <sound tempo>, with an <offset> from the start of the measure unless it is 0
+pub fn tempo_to_tag(quarter_bpm: f64, offset: Offset, divisions: BeatDivision) -> anyhow::Result<XmlTag>
+{
+    let mut sound_tag = XmlTag::new("sound");
+    sound_tag.add_attribute_with_type("tempo", quarter_bpm);
+    if offset != Offset::from_integer(0) {
+        sound_tag.add_child_with_value("offset", ticks_of(offset, divisions)?);
+    }
+    Ok(sound_tag)
+}
+
+/// Smallest number of ticks per quarter note so that every element has an integral <duration>
+/// and every tempo mark an integral <offset>
+pub fn divisions_of_measured_part(mpart: &MeasuredPart) -> BeatDivision
+{
+    let tempo_divisions
+        = mpart
+        .tempo_marks
+        .keys()
+        .fold(1, |divisions, offset| { divisions.lcm(offset.denom()) });
+    mpart
+    .measures
+    .iter()
+    .flat_map(|measure| {
+        measure.gnotes.iter().chain(measure.secondary_voices.values().flatten())
+    })
+    .flat_map(|gnote| {
+        let mut lengths = SmallVec::<[Duration; config::EXP_TUP_LEN]>::new();
+        match gnote {
+            Gnote::SimpleNote(sn) => lengths.push(sn.interval.length),
+            Gnote::Tuplet(tup) => {
+                lengths.push(tup.interval.length);
+                lengths.extend(tup.simple_note_iter().map(|sn| { sn.interval.length }));
+            }
+        }
+        lengths.into_iter()
+    })
+    .fold(tempo_divisions, |divisions, length| { divisions.lcm(length.denom()) })
+}
+
+pub fn part_attributes_to_tag(attributes: &MeasureAttributes, divisions: BeatDivision) -> XmlTag
+{
+    let mut attributes_tag = XmlTag::new("attributes");
+    attributes_tag.add_child_with_value("divisions", divisions);
+    attributes_tag.children.push(key_to_tag(attributes.key_sig));
+    attributes_tag.children.push(time_to_tag(attributes.time_sig));
+    if attributes.staves() > 1 {
+        attributes_tag.add_child_with_value("staves", attributes.staves());
+    }
+    attributes_tag.children.extend(
+        (1..=attributes.staves()).map(|staff| { clef_to_tag(attributes, staff) })
+    );
+    attributes_tag
+}
+
+/// <attributes> holding only what differs from `previous`
+pub fn attribute_changes_to_tag(previous: &MeasureAttributes, current: &MeasureAttributes) -> XmlTag
+{
+    let mut attributes_tag = XmlTag::new("attributes");
+    if previous.key_sig != current.key_sig {
+        attributes_tag.children.push(key_to_tag(current.key_sig));
+    }
+    if (previous.time_sig.numer(), previous.time_sig.denom()) != (current.time_sig.numer(), current.time_sig.denom()) {
+        attributes_tag.children.push(time_to_tag(current.time_sig));
+    }
+    if previous.staves() != current.staves() {
+        attributes_tag.add_child_with_value("staves", current.staves());
+    }
+    attributes_tag.children.extend(
+        (1..=current.staves())
+        .filter(|staff| { previous.clef_of_staff(*staff) != current.clef_of_staff(*staff) })
+        .map(|staff| { clef_to_tag(current, staff) })
+    );
+    attributes_tag
+}
+
+/// Written to sounding interval, goes last in <attributes>
+pub fn transposition_to_tag(transposition: &DiatonicInterval) -> XmlTag
+{
+    let octave_change = transposition.steps / 7;
+    WrittenTranspose {
+        diatonic: Some(transposition.steps - 7 * octave_change),
+        chromatic: transposition.semitones - 12 * octave_change,
+        octave_change: Some(octave_change).filter(|change| { *change != 0 })
+    }
+    .to_xml_tag("transpose")
+}
+
+fn key_to_tag(key_sig: KeySignature) -> XmlTag
+{
+    let mut key_tag = XmlTag::new("key");
+    key_tag.add_child_with_value("fifths", key_sig);
+    key_tag
+}
+
+fn time_to_tag(time_sig: TimeSig) -> XmlTag
+{
+    WrittenTime { beats: *time_sig.numer(), beat_type: *time_sig.denom() }.to_xml_tag("time")
+}
+
+// clefs are numbered by staff when there is more than one
+fn clef_to_tag(attributes: &MeasureAttributes, staff: StaffNumberType) -> XmlTag
+{
+    let mut clef_tag = attributes.clef_of_staff(staff).unwrap_or_default().to_xml_tag("clef");
+    if attributes.staves() > 1 {
+        clef_tag.add_attribute_with_type("number", staff);
+    }
+    clef_tag
+}
+
+/// Intra-measure translation !
+/// The primary voice comes first, each secondary voice follows a <backup> to the measure start.
+pub fn measure_to_tags(measure: &Measure, divisions: BeatDivision, primary_voice: VoiceKey)
+    -> anyhow::Result<Vec<XmlTag>>
+{
+    let mut note_tags = Vec::with_capacity(measure.gnotes.len());
+    let is_measure_rest
+        = measure.secondary_voices.is_empty()
+        && measure.gnotes.len() == 1
+        && measure.is_rest_only()
+        && either_gnote!(&measure.gnotes[0], gn => gn.interval.length) == measure.interval.length;
+
+    if is_measure_rest {
+        let mut rest_tag = XmlTag::new("note");
+        rest_tag.add_child("rest").add_attribute("measure", "yes");
+        rest_tag.add_child_with_value("duration", ticks_of(measure.interval.length, divisions)?);
+        return Ok(vec![rest_tag]);
+    }
+
+    for gnote in measure.gnotes.iter() {
+        note_tags.extend(gnote_to_tags(gnote, divisions, primary_voice)?);
+    }
+    for (voice, gnotes) in measure.secondary_voices.iter() {
+        let mut backup_tag = XmlTag::new("backup");
+        backup_tag.add_child_with_value("duration", ticks_of(measure.interval.length, divisions)?);
+        note_tags.push(backup_tag);
+        for gnote in gnotes.iter() {
+            note_tags.extend(gnote_to_tags(gnote, divisions, *voice)?);
+        }
+    }
+    Ok(note_tags)
+}
+
+pub fn gnote_to_tags(gnote: &Gnote, divisions: BeatDivision, voice: VoiceKey)
+    -> anyhow::Result<Vec<XmlTag>>
+{
+    match gnote {
+        Gnote::SimpleNote(sn) => simple_note_to_tags(sn, divisions, voice, None),
+        Gnote::Tuplet(tup) => tuplet_to_tags(tup, divisions, voice)
+    }
+}
+
+pub fn tuplet_to_tags(tup: &Tuplet, divisions: BeatDivision, voice: VoiceKey)
+    -> anyhow::Result<Vec<XmlTag>>
+{
+    let mut note_tags = Vec::new();
+    nested_tuplet_to_tags(tup, &SmallVec::new(), 0, 0, divisions, voice, &mut note_tags)?;
+    Ok(note_tags)
+}
+
+// The first member opens the brackets from `first_level` inwards, the last one closes them from `last_level`
+fn nested_tuplet_to_tags(
+    tup: &Tuplet,
+    outer_levels: &SmallVec<[(NormalNumType, NormalNumType); 2]>,
+    first_level: usize,
+    last_level: usize,
+    divisions: BeatDivision,
+    voice: VoiceKey,
+    note_tags: &mut Vec<XmlTag>
+)
+    -> anyhow::Result<()>
+{
+    if tup.members.is_empty() {
+        return Err(anyhow!("Can't notate an empty tuplet"));
+    }
+    let mut levels = outer_levels.clone();
+    levels.push((tup.actual_number, tup.normal_number));
+    // members other than the first and last open or close nothing out here
+    let inner_level = levels.len();
+    for (idx, member) in tup.members.iter().enumerate() {
+        let member_first_level = if idx == 0 { first_level } else { inner_level };
+        let member_last_level = if idx + 1 == tup.members.len() { last_level } else { inner_level };
+        match member {
+            Gnote::SimpleNote(sn) => {
+                let context = TupletContext {
+                    levels: levels.clone(),
+                    first_level: member_first_level,
+                    last_level: member_last_level
+                };
+                note_tags.extend(simple_note_to_tags(sn, divisions, voice, Some(context))?);
+            },
+            Gnote::Tuplet(inner) => nested_tuplet_to_tags(
+                inner, &levels, member_first_level, member_last_level, divisions, voice, note_tags
+            )?
+        }
+    }
+    Ok(())
+}
+
+/// A SimpleNote becomes several <note> when it is a chord (one per pitch) or
+/// when its length needs more than one notated value (tied together).
+fn simple_note_to_tags(
+    sn: &SimpleNote,
+    divisions: BeatDivision,
+    voice: VoiceKey,
+    tuplet: Option<TupletContext>
+)
+    -> anyhow::Result<Vec<XmlTag>>
+{
+    // length as it is notated, ie before the tuplet ratio is applied
+    let notated_ratio = tuplet
+        .as_ref()
+        .map_or(Offset::from_integer(1), |ctx| { ctx.notated_ratio() });
+    let notated_length = sn.interval.length * notated_ratio;
+    let components = decompose_duration_into_primitives(&notated_length)
+        .with_context(|| format!("Can't notate duration {}", sn.interval.length))?;
+    if components.is_empty() {
+        return Err(anyhow!("Can't notate empty duration"));
+    }
+
+    let mut note_tags = Vec::with_capacity(components.len() * sn.pitches.len().max(1));
+    for (idx, (primitive, dots)) in components.iter().enumerate() {
+        let is_first_piece = idx == 0;
+        let is_last_piece = idx + 1 == components.len();
+
+        let mut tie_info = TieInfo::TieNeither;
+        if !is_last_piece || sn.tie_info.contains(TieInfo::TieStart) { tie_info |= TieInfo::TieStart; }
+        if !is_first_piece || sn.tie_info.contains(TieInfo::TieEnd) { tie_info |= TieInfo::TieEnd; }
+
+        let piece_length = compute_dotted_length(*primitive, *dots);
+        let sounding_length = piece_length / notated_ratio;
+        let piece_tuplet = tuplet.as_ref().map(|ctx| {
+            TupletContext {
+                levels: ctx.levels.clone(),
+                first_level: if is_first_piece { ctx.first_level } else { ctx.levels.len() },
+                last_level: if is_last_piece { ctx.last_level } else { ctx.levels.len() }
+            }
+        });
+
+        let pitches: Vec<Option<&Pitch>> =
+            if sn.is_rest() { vec![None] }
+            else { sn.pitches.iter().map(Some).collect() };
+
+        for (pitch_idx, pitch) in pitches.into_iter().enumerate() {
+            let mut note_tag = XmlTag::new("note");
+            if let Some(color) = &sn.color {
+                note_tag.add_attribute("color", color.to_hex());
+            }
+
+            if pitch_idx > 0 { note_tag.add_child("chord"); }
+            match pitch {
+                Some(pt) => note_tag.children.push(pitch_to_tag(pt)),
+                None => { note_tag.add_child("rest"); }
+            }
+            note_tag.add_child_with_value("duration", ticks_of(sounding_length, divisions)?);
+            if tie_info.contains(TieInfo::TieEnd) {
+                note_tag.add_child("tie").add_attribute("type", "stop");
+            }
+            if tie_info.contains(TieInfo::TieStart) {
+                note_tag.add_child("tie").add_attribute("type", "start");
+            }
+            note_tag.add_child_with_value("voice", voice.1);
+            note_tag.add_child_with_value("type", <DurationName as Into<&str>>::into(DurationName::from(*primitive)));
+            (0..*dots).for_each(|_| { note_tag.add_child("dot"); });
+
+            if let Some(ctx) = &piece_tuplet {
+                let (actual_number, normal_number) = ctx.time_modification();
+                let time_mod_tag = note_tag.add_child("time-modification");
+                time_mod_tag.add_child_with_value("actual-notes", actual_number);
+                time_mod_tag.add_child_with_value("normal-notes", normal_number);
+            }
+            if voice.0 != DEFAULT_STAFF {
+                note_tag.add_child_with_value("staff", voice.0);
+            }
+
+            notations_to_tag(tie_info, piece_tuplet.as_ref(), pitch_idx == 0)
+                .map(|notations| { note_tag.children.push(notations) });
+
+            // lyrics belong to the first notehead of the first piece only
+            if is_first_piece && pitch_idx == 0 {
+                note_tag.children.extend(sn.lyrics.iter().map(lyric_to_tag));
+            }
+            note_tags.push(note_tag);
+        }
+    }
+    Ok(note_tags)
+}
+
+fn notations_to_tag(tie_info: TieInfo, tuplet: Option<&TupletContext>, is_chord_head: bool)
+    -> Option<XmlTag>
+{
+    let mut notations_tag = XmlTag::new("notations");
+    if tie_info.contains(TieInfo::TieEnd) {
+        notations_tag.add_child("tied").add_attribute("type", "stop");
+    }
+    if tie_info.contains(TieInfo::TieStart) {
+        notations_tag.add_child("tied").add_attribute("type", "start");
+    }
+    // Like the importer, only the first note of a chord carries the bracket
+    // Brackets are numbered from 1 outermost, nested ones spell out their own ratio
+    if let Some(ctx) = tuplet.filter(|_| { is_chord_head }) {
+        let is_nested = ctx.levels.len() > 1;
+        for level in ctx.first_level..ctx.levels.len() {
+            let tuplet_tag = notations_tag.add_child("tuplet");
+            tuplet_tag
+                .add_attribute("type", "start")
+                .add_attribute("bracket", "yes");
+            if is_nested {
+                let (actual_number, normal_number) = ctx.levels[level];
+                tuplet_tag.add_attribute_with_type("number", level + 1);
+                tuplet_tag.add_child("tuplet-actual").add_child_with_value("tuplet-number", actual_number);
+                tuplet_tag.add_child("tuplet-normal").add_child_with_value("tuplet-number", normal_number);
+            }
+        }
+        for level in (ctx.last_level..ctx.levels.len()).rev() {
+            let tuplet_tag = notations_tag.add_child("tuplet");
+            tuplet_tag.add_attribute("type", "stop");
+            if is_nested { tuplet_tag.add_attribute_with_type("number", level + 1); }
+        }
+    }
+
+    if notations_tag.children.is_empty() { None }
+    else { Some(notations_tag) }
+}
+
+fn ticks_of(duration: Duration, divisions: BeatDivision) -> anyhow::Result<BeatDivision>
+{
+    let ticks = duration * Offset::from_integer(divisions);
+    if !ticks.is_integer() {
+        return Err(anyhow!("Duration {} is not a multiple of 1/{} quarter", duration, divisions));
+    }
+    Ok(ticks.to_integer())
+}
+
+pub fn pitch_to_tag(pitch: &Pitch) -> XmlTag
+{
+    WrittenPitch {
+        step: pitch.step,
+        alter: match pitch.alter {
+            Alter::No => None,
+            alter => Some(alter.semitones())
+        },
+        octave: Some(pitch.octave.unwrap_or(4))
+    }
+    .to_xml_tag("pitch")
+}
+
+pub fn lyric_to_tag(lyric: &Lyric) -> XmlTag
+{
+    lyric.to_xml_tag("lyric")
+}
+
+#[cfg(test)]
+mod tests {
+    use adaxml::convert::FromXmlTag;
+    use adaxml::tag::XmlTag;
+    use crate::attribs::{Duration, MeasureAttributes, Offset, TimeSig};
+    use crate::clef::Clef;
+    use crate::either_gnote;
+    use crate::gnote::Gnote;
+    use crate::lyric::{Lyric, Syllabic};
+    use crate::part::{MeasuredPart, Part};
+    use crate::pitch::{Alter, DiatonicStep, Pitch};
+    use crate::score::{MeasuredScore, Score};
+    use crate::simple_note::{SimpleNote, TieInfo};
+    use crate::tuplet::Tuplet;
+    use crate::xml_export::{lyric_to_tag, measured_score_to_path, score_to_path, strip_layout};
+    use crate::xml_import::{measured_score_from_path, score_from_path};
+
+    fn assert_simple_note_eq(lhs: &SimpleNote, rhs: &SimpleNote) {
+        assert_eq!(lhs.interval.length, rhs.interval.length);
+        assert!(lhs.pitches.iter().eq_by(rhs.pitches.iter(), |x, y| { x.ps == y.ps }));
+        assert_eq!(lhs.tie_info, rhs.tie_info);
+        assert!(lhs.lyrics.iter().eq_by(rhs.lyrics.iter(), |x, y| { (x.syllabic, &x.text) == (y.syllabic, &y.text) }));
+        assert_eq!(
+            lhs.color.as_ref().map(|c| { c.to_hex() }),
+            rhs.color.as_ref().map(|c| { c.to_hex() })
+        );
+    }
+
+    fn assert_gnote_eq(lhs: &Gnote, rhs: &Gnote) {
+        match (lhs, rhs) {
+            (Gnote::SimpleNote(lsn), Gnote::SimpleNote(rsn)) => assert_simple_note_eq(lsn, rsn),
+            (Gnote::Tuplet(ltup), Gnote::Tuplet(rtup)) => {
+                assert_eq!(ltup.actual_number, rtup.actual_number);
+                assert_eq!(ltup.normal_number, rtup.normal_number);
+                assert_eq!(ltup.interval.length, rtup.interval.length);
+                assert_eq!(ltup.members.len(), rtup.members.len());
+                ltup.members.iter().zip(rtup.members.iter())
+                .for_each(|(lmember, rmember)| { assert_gnote_eq(lmember, rmember) });
+            }
+            _ => panic!("Gnote kinds differ after round trip")
+        }
+    }
+
+    fn assert_measured_part_eq(lhs: &MeasuredPart, rhs: &MeasuredPart) {
+        assert_eq!(lhs.name, rhs.name);
+        assert_eq!(lhs.key_sig, rhs.key_sig);
+        assert_eq!((lhs.time_sig.numer(), lhs.time_sig.denom()), (rhs.time_sig.numer(), rhs.time_sig.denom()));
+        assert!(lhs.clef_sign == rhs.clef_sign);
+        assert!(lhs.lower_staff_clefs == rhs.lower_staff_clefs);
+        assert_eq!(lhs.measures.len(), rhs.measures.len());
+        assert_eq!(lhs.primary_voice, rhs.primary_voice);
+        for (lmeasure, rmeasure) in lhs.measures.iter().zip(rhs.measures.iter()) {
+            assert_eq!(lmeasure.interval.length, rmeasure.interval.length);
+            assert_eq!(lmeasure.measure_number, rmeasure.measure_number);
+            assert_eq!(lmeasure.implicit, rmeasure.implicit);
+            assert_eq!(lmeasure.attributes, rmeasure.attributes);
+            assert!(lmeasure.secondary_voices.keys().eq(rmeasure.secondary_voices.keys()));
+            let lvoices = std::iter::once(&lmeasure.gnotes).chain(lmeasure.secondary_voices.values());
+            let rvoices = std::iter::once(&rmeasure.gnotes).chain(rmeasure.secondary_voices.values());
+            for (lgnotes, rgnotes) in lvoices.zip(rvoices) {
+                assert_eq!(lgnotes.len(), rgnotes.len());
+                for (lgnote, rgnote) in lgnotes.iter().zip(rgnotes.iter()) {
+                    assert_gnote_eq(lgnote, rgnote);
+                }
+            }
+        }
+    }
+
+    fn assert_measured_score_eq(lhs: &MeasuredScore, rhs: &MeasuredScore) {
+        assert_eq!(lhs.title, rhs.title);
+        assert_eq!(lhs.measured_parts.len(), rhs.measured_parts.len());
+        lhs.measured_parts.iter().zip(rhs.measured_parts.iter())
+        .for_each(|(lpart, rpart)| { assert_measured_part_eq(lpart, rpart) });
+    }
+
+    #[test]
+    fn round_trip_nested_tuplet_across_barline() {
+        let note = |length: Duration, step: Option<DiatonicStep>| {
+            let mut sn = SimpleNote::new(Offset::from_integer(0), length, Vec::new(), None, TieInfo::TieNeither);
+            sn.pitches.extend(step.map(|step| { Pitch::new(step, Some(5), Alter::No) }));
+            Gnote::SimpleNote(sn)
+        };
+        // quarter, eighth rest, quarter and a nested quintuplet under a triplet bracket
+        let quintuplet = Tuplet::new(
+            4, 5, Offset::from_integer(0),
+            (0..5).map(|_| { note(Duration::new(1, 15), Some(DiatonicStep::E)) }).collect()
+        );
+        let triplet = Tuplet::new(2, 3, Offset::from_integer(0), vec![
+            note(Duration::new(2, 3), Some(DiatonicStep::C)),
+            note(Duration::new(1, 3), None),
+            note(Duration::new(2, 3), Some(DiatonicStep::D)),
+            Gnote::Tuplet(quintuplet),
+        ]);
+
+        let mut part = Part::new("Tuplets".to_string(), 0, Clef::treble(), TimeSig::new_raw(2, 4));
+        part.append_gnote(note(Duration::new(3, 2), Some(DiatonicStep::G)));
+        part.append_gnote(Gnote::Tuplet(triplet));
+        part.append_gnote(note(Duration::new(1, 2), Some(DiatonicStep::G)));
+
+        // the first triplet quarter is cut by the barline, both halves stay 3:2
+        let mpart = part.to_measured();
+        match (mpart.measures[0].gnotes.last().unwrap(), mpart.measures[1].gnotes.first().unwrap()) {
+            (Gnote::Tuplet(left), Gnote::Tuplet(right)) => {
+                assert_eq!((left.actual_number, left.normal_number), (3, 2));
+                assert_eq!((right.actual_number, right.normal_number), (3, 2));
+                assert_eq!(left.interval.length, Duration::new(1, 2));
+                assert_eq!(right.interval.length, Duration::new(3, 2));
+                assert!(matches!(right.members.last(), Some(Gnote::Tuplet(inner)) if inner.actual_number == 5));
+            },
+            _ => panic!("expected the triplet on both sides of the barline")
+        }
+
+        let mut score = Score::new("Tuplets");
+        score.parts.push(part);
+        let mscore = score.to_measured();
+        let out_path = temp_path("m32_round_trip_tuplets.musicxml");
+        measured_score_to_path(&mscore, out_path.as_str()).unwrap();
+
+        let reimported = measured_score_from_path(out_path.as_str()).unwrap();
+        assert_measured_score_eq(&mscore, &reimported);
+    }
+
+    fn temp_path(name: &str) -> String {
+        std::env::temp_dir().join(name).to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn round_trip_measured_score() {
+        for path in ["test/template.musicxml", "test/longduongs_tied.musicxml", "test/longduongs_voice.musicxml"] {
+            let mscore = measured_score_from_path(path).unwrap();
+            let out_path = temp_path("m32_round_trip_measured.musicxml");
+            measured_score_to_path(&mscore, out_path.as_str()).unwrap();
+
+            let reimported = measured_score_from_path(out_path.as_str()).unwrap();
+            assert_measured_score_eq(&mscore, &reimported);
+        }
+    }
+
+    #[test]
+    fn round_trip_compressed() {
+        let mscore = measured_score_from_path("test/longduongs_tied.musicxml").unwrap();
+        let out_path = temp_path("m32_round_trip.mxl");
+        measured_score_to_path(&mscore, out_path.as_str()).unwrap();
+
+        let reimported = measured_score_from_path(out_path.as_str()).unwrap();
+        assert_measured_score_eq(&mscore, &reimported);
+    }
+
+    #[test]
+    fn round_trip_fused_score() {
+        let score = score_from_path("test/longduongs_tied.musicxml").unwrap();
+        let fused = score.fuse_tied_notes().unwrap();
+        let out_path = temp_path("m32_round_trip_fused.musicxml");
+        score_to_path(&fused, out_path.as_str()).unwrap();
+
+        let reimported = score_from_path(out_path.as_str()).unwrap();
+        assert_eq!(fused.parts.len(), reimported.parts.len());
+        for (fused_part, reimported_part) in fused.parts.iter().zip(reimported.parts.iter()) {
+            assert_eq!(
+                fused_part.hash_note_and_rests(),
+                reimported_part.fuse_tied_notes().unwrap().hash_note_and_rests()
+            );
+        }
+    }
+
+    #[test]
+    fn round_trip_attribute_changes() {
+        let mut part = Part::new("Meters".to_string(), 0, Clef::treble(), TimeSig::new_raw(4, 4));
+        for _ in 0..20 {
+            let mut sn = SimpleNote::new(
+                Offset::from_integer(0), Duration::from_integer(1), Vec::new(), None, TieInfo::TieNeither
+            );
+            sn.pitches.insert(Pitch::new(DiatonicStep::C, Some(4), Alter::No));
+            part.append_simple_note(sn);
+        }
+        let three_four = MeasureAttributes::new(2, Clef::treble(), TimeSig::new_raw(3, 4));
+        part.add_attribute_change(Offset::from_integer(8), three_four.clone());
+        part.add_attribute_change(Offset::from_integer(14), MeasureAttributes { clef_sign: Clef::bass(), ..three_four.clone() });
+
+        let mpart = part.to_measured();
+        assert!(
+            mpart.measures.iter()
+            .map(|mea| { mea.interval.length })
+            .eq([4, 4, 3, 3, 3, 3].iter().map(|length| { Duration::from_integer(*length) }))
+        );
+        assert_eq!(mpart.measures[2].attributes, three_four);
+        assert!(mpart.measures[4].attributes.clef_sign == Clef::bass());
+        assert_eq!(mpart.attribute_changes(), part.attribute_changes);
+
+        let mut score = Score::new("Meters");
+        score.parts.push(part);
+        let mscore = score.to_measured();
+        let out_path = temp_path("m32_round_trip_attribute_changes.musicxml");
+        measured_score_to_path(&mscore, out_path.as_str()).unwrap();
+
+        let reimported = measured_score_from_path(out_path.as_str()).unwrap();
+        assert_measured_score_eq(&mscore, &reimported);
+        assert_eq!(reimported.flatten().parts[0].attribute_changes, score.parts[0].attribute_changes);
+    }
+
+    #[test]
+    fn round_trip_pickup() {
+        let mut part = Part::new("Pickup".to_string(), 0, Clef::treble(), TimeSig::new_raw(3, 4));
+        part.implicit_measures.insert(Offset::from_integer(0), Duration::from_integer(1));
+        for _ in 0..7 {
+            let mut sn = SimpleNote::new(
+                Offset::from_integer(0), Duration::from_integer(1), Vec::new(), None, TieInfo::TieNeither
+            );
+            sn.pitches.insert(Pitch::new(DiatonicStep::G, Some(4), Alter::No));
+            part.append_simple_note(sn);
+        }
+
+        let mpart = part.to_measured();
+        assert!(
+            mpart.measures.iter()
+            .map(|mea| { (mea.measure_number, mea.interval.length, mea.implicit) })
+            .eq([(0, 1, true), (1, 3, false), (2, 3, false)].iter()
+                .map(|(number, length, implicit)| { (*number, Duration::from_integer(*length), *implicit) }))
+        );
+        assert_eq!(mpart.pickup_length(), part.pickup_length());
+
+        let mut score = Score::new("Pickup");
+        score.parts.push(part);
+        let mscore = score.to_measured();
+        let out_path = temp_path("m32_round_trip_pickup.musicxml");
+        measured_score_to_path(&mscore, out_path.as_str()).unwrap();
+
+        let reimported = measured_score_from_path(out_path.as_str()).unwrap();
+        assert_measured_score_eq(&mscore, &reimported);
+
+        // re-measuring the flattened part keeps the pickup
+        let flat = reimported.flatten();
+        assert_eq!(flat.parts[0].pickup_length(), Some(Duration::from_integer(1)));
+        assert_measured_score_eq(&mscore, &flat.to_measured());
+    }
+
+    #[test]
+    fn round_trip_grand_staff() {
+        let note = |length: i32, step: DiatonicStep, octave: i8| {
+            let mut sn = SimpleNote::new(
+                Offset::from_integer(0), Duration::from_integer(length), Vec::new(), None, TieInfo::TieNeither
+            );
+            sn.pitches.insert(Pitch::new(step, Some(octave), Alter::No));
+            Gnote::SimpleNote(sn)
+        };
+        let mut part = Part::new("Piano".to_string(), 0, Clef::treble(), TimeSig::new_raw(4, 4));
+        part.lower_staff_clefs.push(Clef::bass());
+        (0..8).for_each(|_| { part.append_gnote(note(1, DiatonicStep::E, 5)) });
+        // voice 1 of the lower staff is not voice 1 of the upper one
+        let mut lower_voice = Vec::new();
+        for (idx, step) in [DiatonicStep::C, DiatonicStep::G, DiatonicStep::C, DiatonicStep::E].iter().enumerate() {
+            let mut gnote = note(2, *step, 3);
+            either_gnote!(&mut gnote, gn => gn.interval.set_start_keep_length(Offset::from_integer(2 * idx as i32)));
+            lower_voice.push(gnote);
+        }
+        part.secondary_voices.insert((2, 1), lower_voice);
+        let treble_below = MeasureAttributes {
+            lower_staff_clefs: [Clef::treble()].iter().cloned().collect(),
+            ..part.initial_attributes()
+        };
+        part.add_attribute_change(Offset::from_integer(4), treble_below);
+
+        let mut score = Score::new("Grand staff");
+        score.parts.push(part);
+        let mscore = score.to_measured();
+        let out_path = temp_path("m32_round_trip_grand_staff.musicxml");
+        measured_score_to_path(&mscore, out_path.as_str()).unwrap();
+
+        let written = XmlTag::from_path(out_path.as_str()).unwrap();
+        assert_eq!(written.select("part-list/score-part").unwrap().count(), 1);
+        assert_eq!(written.select_values("part/measure/attributes/staves").unwrap().collect::<Vec<_>>(), ["2"]);
+        assert_eq!(written.select_values("part/measure/note/staff").unwrap().count(), 4);
+
+        let reimported = measured_score_from_path(out_path.as_str()).unwrap();
+        assert_measured_score_eq(&mscore, &reimported);
+        assert_eq!(reimported.measured_parts[0].voice_keys(), vec![(1, 1), (2, 1)]);
+        assert!(reimported.measured_parts[0].measures[1].attributes.clef_of_staff(2) == Some(Clef::treble()));
+        let flat = score_from_path(out_path.as_str()).unwrap();
+        assert_eq!(flat.parts.len(), 1);
+        assert_eq!(flat.parts[0].hash_note_and_rests(), score.parts[0].hash_note_and_rests());
+    }
+
+    #[test]
+    fn round_trip_transposition() {
+        let mut score = Score::new("Transposing");
+        // Bb clarinet and tenor saxophone, the latter sounding an octave lower still
+        for transposition in ["-M2", "-M9"].iter() {
+            let mut part = Part::new(transposition.to_string(), 0, Clef::treble(), TimeSig::new_raw(4, 4));
+            part.transposition = Some(transposition.parse().unwrap());
+            let mut sn = SimpleNote::new(
+                Offset::from_integer(0), Duration::from_integer(4), Vec::new(), None, TieInfo::TieNeither
+            );
+            sn.pitches.insert(Pitch::new(DiatonicStep::D, Some(5), Alter::No));
+            part.append_simple_note(sn);
+            score.parts.push(part);
+        }
+        let mscore = score.to_measured();
+        let out_path = temp_path("m32_round_trip_transposition.musicxml");
+        measured_score_to_path(&mscore, out_path.as_str()).unwrap();
+
+        let reimported = measured_score_from_path(out_path.as_str()).unwrap();
+        assert_measured_score_eq(&mscore, &reimported);
+        assert!(
+            reimported.measured_parts.iter().map(|mpart| { mpart.transposition })
+            .eq(score.parts.iter().map(|part| { part.transposition }))
+        );
+    }
+
+    #[test]
+    fn doctype_is_written() {
+        let (_, original_meta) = XmlTag::from_path_with_meta("test/longduongs_tied.musicxml").unwrap();
+        let mscore = measured_score_from_path("test/longduongs_tied.musicxml").unwrap();
+        let out_path = temp_path("m32_doctype.musicxml");
+        measured_score_to_path(&mscore, out_path.as_str()).unwrap();
+
+        let (_, meta) = XmlTag::from_path_with_meta(out_path.as_str()).unwrap();
+        assert!(original_meta.doctype().is_some());
+        assert_eq!(meta.doctype(), original_meta.doctype());
+        assert_eq!(meta.encoding, "UTF-8");
+    }
+
+    #[test]
+    fn stripped_layout_keeps_the_notes() {
+        let mut tag = XmlTag::from_path("test/longduongs_tied.musicxml").unwrap();
+        let before = measured_score_from_path("test/longduongs_tied.musicxml").unwrap();
+        assert!(strip_layout(&mut tag) > 0);
+        assert!(tag.get_desc_with_name("print").is_none());
+        assert!(tag.all_desc_with_name("note").all(|note| { note.get_attrib_value("default-x").is_none() }));
+
+        let out_path = temp_path("m32_stripped.musicxml");
+        tag.to_path(out_path.as_str()).unwrap();
+        let after = measured_score_from_path(out_path.as_str()).unwrap();
+        assert_measured_score_eq(&before, &after);
+    }
+
+    #[test]
+    fn lyrics_keep_their_syllabic() {
+        let lyric = Lyric { number: 1, syllabic: Some(Syllabic::Begin), text: "Hal".to_string() };
+        let lyric_tag = lyric_to_tag(&lyric);
+        assert!(lyric_tag.children.iter().map(|child| { child.name.as_str() }).eq(["syllabic", "text"].iter().cloned()));
+        assert_eq!(lyric_tag.get_child_value("syllabic").map(|value| { value.as_str() }), Some("begin"));
+        assert_eq!(Lyric::from_xml_tag(&lyric_tag).unwrap().syllabic, Some(Syllabic::Begin));
+
+        // without a known syllabic none is written
+        assert!(lyric_to_tag(&Lyric::new(1, "la".to_string())).get_child_with_name("syllabic").is_none());
+        let mut bad_tag = lyric_tag.clone();
+        bad_tag.children[0].set_value("start");
+        assert!(Lyric::from_xml_tag(&bad_tag).is_err());
+    }
+}
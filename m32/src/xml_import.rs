@@ -1,26 +1,35 @@
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
 use std::ffi::OsStr;
+use std::fs::File;
+use std::io::Read;
 use std::fmt::{Display, Formatter};
 use std::iter::{Peekable, zip};
 use std::path::Path;
 use anyhow::{anyhow, Context};
 use fraction::Ratio;
 use smallvec::SmallVec;
-use adaxml::{drill, drill_helper};
 use crate::score::*;
+use adaxml::convert::{FromXmlTag, FromXmlValue, ToXmlValue};
 use adaxml::tag::*;
 use adaxml::iter::*;
-use crate::attribs::{BeatDivision, ClefType, Duration, KeySignature, MPInterval, Offset, TimeSig, TimeSigComponent};
+use adaxml::stream::XmlSubtreeStream;
+use adaxml::xml_struct;
+use crate::attribs::{BeatDivision, ClefType, Duration, KeySignature, MeasureAttributes, Offset, TempoMarks, TimeSig, TimeSigComponent};
+use crate::clef::ClefSign;
 use crate::color::Color;
 use crate::config::config;
+use crate::diatonic_interval::DiatonicInterval;
 use crate::gnote::Gnote;
 use crate::gnote::Gnote::{SimpleNote, Tuplet};
-use crate::lyric::Lyric;
+use crate::lyric::{Lyric, Syllabic};
 use crate::measure::{Measure, measure_length_from_time_sig, MeasureNumberType};
 use crate::part::{MeasuredPart, Part};
 use crate::pitch::{Alter, DiatonicStep, Octave, Pitch};
-use crate::{either_gnote, simple_note, tuplet};
+use crate::{either_gnote, mxl, simple_note, tuplet};
 use crate::simple_note::{TieInfo};
 use crate::tuplet::NormalNumType;
+use crate::voice::{DEFAULT_STAFF, DEFAULT_VOICE, DEFAULT_VOICE_KEY, pad_with_rest, push_gnote_with_gap_rest, StaffNumberType, VoiceKey, VoiceNumberType};
 
 #[derive(Debug)]
 enum ImportErr {
@@ -29,51 +38,202 @@ enum ImportErr {
 
 impl Display for ImportErr {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        let fmt = match self {
-            Self::UnknownFileExt(s) => format_args!("UnknownFileExt: {}", s).to_string(),
-            _ => {unreachable!()}
-        };
-        f.write_str(fmt.as_str());
-        Ok(())
+        match self {
+            Self::UnknownFileExt(s) => write!(f, "UnknownFileExt: {}", s)
+        }
     }
 }
 
 impl std::error::Error for ImportErr {}
 
+/// /////// Element mappings //////// //
+
+impl FromXmlValue for DiatonicStep {
+    fn from_xml_value(value: &str) -> anyhow::Result<Self> {
+        value.trim().parse()
+    }
+}
+
+impl ToXmlValue for DiatonicStep {
+    fn to_xml_value(&self) -> XmlString {
+        <DiatonicStep as Into<&str>>::into(*self).to_string()
+    }
+}
+
+impl FromXmlValue for ClefSign {
+    fn from_xml_value(value: &str) -> anyhow::Result<Self> {
+        value.trim().parse()
+    }
+}
+
+impl ToXmlValue for ClefSign {
+    fn to_xml_value(&self) -> XmlString {
+        <ClefSign as Into<&str>>::into(*self).to_string()
+    }
+}
+
+impl FromXmlValue for Syllabic {
+    fn from_xml_value(value: &str) -> anyhow::Result<Self> {
+        value.trim().parse()
+    }
+}
+
+impl ToXmlValue for Syllabic {
+    fn to_xml_value(&self) -> XmlString {
+        <Syllabic as Into<&str>>::into(*self).to_string()
+    }
+}
+
+/// <pitch> as written, the alteration in semitones
+pub struct WrittenPitch {
+    pub step: DiatonicStep,
+    pub alter: Option<f32>,
+    pub octave: Option<Octave>
+}
+xml_struct!(WrittenPitch { step: child "step", alter: child "alter", octave: child "octave" });
+
+/// <time>, kept apart from TimeSig since 4/4 must not be reduced to 1/1
+pub struct WrittenTime {
+    pub beats: TimeSigComponent,
+    pub beat_type: TimeSigComponent
+}
+xml_struct!(WrittenTime { beats: child "beats", beat_type: child "beat-type" });
+
+/// <transpose>, the steps and semitones within an octave
+pub struct WrittenTranspose {
+    pub diatonic: Option<i8>,
+    pub chromatic: i8,
+    pub octave_change: Option<i8>
+}
+xml_struct!(WrittenTranspose { diatonic: child "diatonic", chromatic: child "chromatic", octave_change: child "octave-change" });
+
+xml_struct!(ClefType { sign: child "sign", line: child "line" });
+
+// <syllabic> goes before <text>
+xml_struct!(Lyric { number: attrib "number", syllabic: child "syllabic", text: child "text" });
+
 pub struct PartAttributes {
     division: BeatDivision,
     key_fifths: KeySignature,
     time_sig: TimeSig,
-    clef_signs: Vec<ClefType>,
-    staves: u8,
+    clef_signs: Vec<ClefType>, // one per staff
+    staves: StaffNumberType,
+    transposition: Option<DiatonicInterval>,
 
     pub measure_length: Duration //computed measure length since used alot
 }
+
+impl PartAttributes {
+    /// Attributes of every staff, as carried by a Measure
+    pub fn measure_attributes(&self) -> MeasureAttributes
+    {
+        MeasureAttributes {
+            lower_staff_clefs: self.clef_signs.iter().skip(1).cloned().collect(),
+            ..MeasureAttributes::new(
+                self.key_fifths,
+                self.clef_signs.first().cloned().unwrap_or_default(),
+                self.time_sig
+            )
+        }
+    }
+
+    /// Applies an <attributes> tag; elements it lacks keep their current value
+    pub fn update_from_tag(&mut self, tag: &XmlTag) -> anyhow::Result<()>
+    {
+        if let Some(division) = divisions_of_tag(tag)? {
+            self.division = division;
+        }
+
+        if let Some(fifths) = tag.get_child_with_name("key").and_then(|c| { c.get_child_value_as("fifths") }) {
+            self.key_fifths = fifths;
+        }
+
+        if let Some(time_tag) = tag.get_child_with_name("time") {
+            let time = WrittenTime::from_xml_tag(time_tag).context("Can't parse <time>")?;
+            self.time_sig = TimeSig::new_raw(time.beats, time.beat_type);
+            if *self.time_sig.numer() == 0 || *self.time_sig.denom() == 0 {
+                return Err(anyhow!("Time signature {}/{} is empty", self.time_sig.numer(), self.time_sig.denom()));
+            }
+            self.measure_length = measure_length_from_time_sig(self.time_sig);
+        }
+
+        if let Some(staves) = tag.get_child_value_as::<StaffNumberType>("staves") {
+            self.staves = staves;
+        }
+
+        if let Some(transpose_tag) = tag.get_child_with_name("transpose") {
+            self.transposition = Some(transposition_from_tag(transpose_tag)?);
+        }
+
+        // <clef number="n"> targets staff n, clefs without number go in order
+        for (idx, clef_tag) in tag.all_child_with_name("clef").enumerate() {
+            let staff_idx = clef_tag.get_attrib_value_as::<usize>("number").unwrap_or(idx + 1) - 1;
+            let clef = ClefType::from_xml_tag(clef_tag).context("Can't parse <clef>")?;
+            if self.clef_signs.len() <= staff_idx {
+                self.clef_signs.resize(staff_idx + 1, ClefType::default());
+            }
+            self.clef_signs[staff_idx] = clef;
+        }
+        // a staff without <clef> yet gets the default one
+        if self.clef_signs.len() < self.staves as usize {
+            self.clef_signs.resize(self.staves as usize, ClefType::default());
+        }
+        Ok(())
+    }
+}
+
+/// The <divisions> of an <attributes> tag, if it has one
+pub fn divisions_of_tag(attributes_tag: &XmlTag) -> anyhow::Result<Option<BeatDivision>>
+{
+    let divisions_tag = match attributes_tag.get_child_with_name("divisions") {
+        Some(divisions_tag) => divisions_tag,
+        None => return Ok(None)
+    };
+    let divisions: BeatDivision
+        = divisions_tag
+        .value.as_ref().context("<division> tag contains no value")?
+        .parse().context("Can't parse value in <division>")?;
+    if divisions <= 0 { return Err(anyhow!("<divisions> must be positive")) }
+    Ok(Some(divisions))
+}
+
+/// <diatonic> and <chromatic> within an octave, <octave-change> for the rest
+pub fn transposition_from_tag(transpose_tag: &XmlTag) -> anyhow::Result<DiatonicInterval>
+{
+    let transpose = WrittenTranspose::from_xml_tag(transpose_tag).context("Can't parse <transpose>")?;
+    let octave_change = transpose.octave_change.unwrap_or(0);
+    Ok(
+        DiatonicInterval::new(
+            transpose.diatonic.unwrap_or(0) + 7 * octave_change,
+            transpose.chromatic + 12 * octave_change
+        )
+    )
+}
+
 /// /////// Part //////// //
 
-pub fn score_from_path(path_: &str) -> anyhow::Result<Score>
+pub fn score_from_path(path: &str) -> anyhow::Result<Score>
+{
+    let score_tag = score_tag_from_path(path)?;
+    score_from_tag(&score_tag)
+}
+
+/// Loads the <score-partwise> tree of an uncompressed (.musicxml, .xml) or compressed (.mxl) file
+pub fn score_tag_from_path(path_: &str) -> anyhow::Result<XmlTag>
 {
     let path = std::path::Path::new(path_);
     match path
         .extension()
-        .ok_or(ImportErr::UnknownFileExt("".to_string()))?
-        .to_str()
-        .unwrap()
+        .and_then(|ext| { ext.to_str() })
+        .unwrap_or("")
     {
         "mxl" => {
-            unimplemented!()
+            mxl::score_tag_from_mxl_path(path_)
         },
-        "musicxml" => {
-            let score_tag = adaxml::io::XmlTag::from_path(path.to_str().unwrap_or_default())?;
-            score_from_tag(&score_tag)
-        }
-        _ => {
-            anyhow::Result::Err(anyhow::Error::from(ImportErr::UnknownFileExt(
-                path.extension().unwrap()
-                .to_str().unwrap()
-                .to_string()
-            )))
+        "musicxml" | "xml" => {
+            XmlTag::from_path(path_).context("Can't open xml from path")
         }
+        ext => Err(anyhow::Error::from(ImportErr::UnknownFileExt(ext.to_string())))
     }
 }
 
@@ -102,23 +262,6 @@ pub fn score_from_tag(score_tag: &XmlTag)
 
     // parse part-list
     let xml_part_headers = part_list_tag.all_child_with_name("score-part");
-    let number_of_parts = {
-        score_tag
-            .all_child_with_name("part")
-            .map(|part_tag| {
-                part_attributes_from_tag(
-                    part_tag
-                    .get_child_with_name("measure")
-                    .and_then(|m| { m.get_child_with_name("attributes")})
-                    .ok_or(anyhow!("Can't find <attributes> in <measure>"))
-                    .unwrap()
-                ).unwrap()
-            })
-            .map(|attr| { attr.staves })
-            .sum::<u8>() as usize
-    };
-    score.parts.reserve(number_of_parts);
-
     let xml_parts = score_tag.all_child_with_name("part");
     for (xml_part_header, xml_part) in zip(xml_part_headers, xml_parts) {
         score.parts.push(
@@ -134,54 +277,18 @@ pub fn score_from_tag(score_tag: &XmlTag)
     Ok(score)
 }
 
+/// Flat Part holding every staff and voice of the <part>
 pub fn part_from_tag(part_tag: &XmlTag, part_name: &str)
     -> anyhow::Result<Part>
 {
-    // look for <attributes>
-    let attrs
-        = part_tag
-        .get_desc_with_name("attributes")
-        .context("Error while parsing <attributes>")
-        .and_then(|tag| { part_attributes_from_tag(tag) })
-        .context("Can't parse <attributes> to attribute object")?;
-
-    assert_eq!(attrs.clef_signs.len(), 1);
-    let mut  part = Part::new(
-        part_name.to_string(),
-        attrs.key_fifths,
-        attrs.clef_signs[0],
-        attrs.time_sig
-    );
-
-    // parses each notes
-    let mut offset_so_far = Offset::from_integer(0);
-    for measure_tag in part_tag.all_child_with_name("measure") {
-        let mut note_tags = measure_tag.all_child_with_name("note");
-        let mut gnotes: Vec<_>
-            = xml_notes_to_gnotes(&mut note_tags.peekable(), attrs.division, &attrs)?
-            .into_iter()
-            .map(move |mut gn| {
-                either_gnote!(&mut gn, g => g.interval.displace_start_keep_length(offset_so_far));
-                gn
-            })
-            .collect();
-        offset_so_far +=
-            gnotes
-            .iter()
-            .map(|n| { either_gnote!(n, g => g.interval.length) })
-            .sum::<Duration>();
-        part.gnotes.append(&mut gnotes);
-    }
-
-    Ok(part)
+    Ok(measured_part_from_tag(part_tag, part_name)?.flatten())
 }
 
 /// /////// Measured Part //////// ///
 
 pub fn measured_score_from_path(path: &str) -> anyhow::Result<MeasuredScore>
 {
-    let tag = XmlTag::from_path(path).context("Can't open xml from path")?;
-    // println!("{:?}", tag);
+    let tag = score_tag_from_path(path)?;
     measured_score_from_tag(&tag)
 }
 
@@ -208,119 +315,355 @@ pub fn measured_score_from_tag(score_tag: &XmlTag) -> anyhow::Result<MeasuredSco
 
     // parse part-list
     let xml_part_headers = part_list_tag.all_child_with_name("score-part");
-    let number_of_parts = {
-        score_tag
-        .all_child_with_name("part")
-        .map(|part_tag| {
-            part_attributes_from_tag(
-                part_tag
-                .get_child_with_name("measure")
-                .and_then(|m| { m.get_child_with_name("attributes")})
-                .ok_or(anyhow!("Can't find <attributes> in <measure>"))
-                .unwrap()
-            ).unwrap()
-        })
-        .map(|attr| { attr.staves })
-        .sum::<u8>() as usize
-    };
-    mscore.measured_parts.reserve(number_of_parts);
-
     let xml_parts = score_tag.all_child_with_name("part");
     for (xml_part_header, xml_part) in zip(xml_part_headers, xml_parts) {
-        mscore.measured_parts.extend(
-            measured_parts_from_tag(
+        mscore.measured_parts.push(
+            measured_part_from_tag(
                 xml_part,
                 xml_part_header
                     .get_child_value("part-name")
                     .map(|c| { c.as_str()})
                     .unwrap_or("Untitled part")
             )?
-            .into_iter()
         )
     }
     Ok (mscore)
 }
 
-pub fn measured_parts_from_tag(part_tag: &XmlTag, part_name: &str)
-    -> anyhow::Result<Vec<MeasuredPart>> // returns Vec because eg: piano parts has 2 staves
+/// Same as `measured_score_from_path`, but the file is streamed a <measure> at a time
+/// instead of being loaded as a whole tree first
+pub fn measured_score_from_path_streaming(path_: &str) -> anyhow::Result<MeasuredScore>
 {
-    // look for <attributes> before parsing
-    let attrs
-        = part_tag
-        .get_desc_with_name("attributes")
-        .context("Error while parsing <attributes>")
-        .and_then(|tag| { part_attributes_from_tag(tag) })
-        .context("Can't parse <attributes> to attribute object")?;
-
-    let mut measured_parts: Vec<MeasuredPart> = Vec::new();
-    measured_parts.reserve(attrs.staves as usize);
-
-    let number_of_measures
-        = part_tag
-        .all_desc_with_name("measure")
-        .last()
-        .map(|t| {
-                t.get_attrib_value_as::<usize>("measure")
-                .unwrap_or(1)
+    let path = std::path::Path::new(path_);
+    match path
+        .extension()
+        .and_then(|ext| { ext.to_str() })
+        .unwrap_or("")
+    {
+        "mxl" => {
+            let f = File::open(path_).with_context(|| format!("Can't open {:?}", path_))?;
+            mxl::read_mxl_rootfile(f, |rootfile| { measured_score_from_read(rootfile) })
+        },
+        "musicxml" | "xml" => {
+            let f = File::open(path_).with_context(|| format!("Can't open {:?}", path_))?;
+            measured_score_from_read(f)
+        },
+        ext => Err(anyhow::Error::from(ImportErr::UnknownFileExt(ext.to_string())))
+    }
+}
+
+pub fn score_from_path_streaming(path: &str) -> anyhow::Result<Score>
+{
+    Ok(measured_score_from_path_streaming(path)?.flatten())
+}
+
+/// Reads a <score-partwise> document measure by measure.
+/// Only <work>, <part-list> and the current <measure> are built as trees.
+pub fn measured_score_from_read<R: Read>(source: R) -> anyhow::Result<MeasuredScore>
+{
+    let mut mscore = MeasuredScore::new("".to_string());
+    let mut part_names: BTreeMap<String, String> = BTreeMap::new();
+    let mut current_part: Option<(String, MeasuredPartBuilder)> = None;
+
+    for subtree in XmlSubtreeStream::from_read(source, &["work", "part-list", "measure"]) {
+        let subtree = subtree?;
+        if subtree.ancestors.first().map(|root| { root.name.as_str() }) != Some("score-partwise") {
+            return Err(anyhow!("<score-partwise> tag not found"));
+        }
+
+        match subtree.tag.name.as_str() {
+            "work" => {
+                if let Some(work_title) = subtree.tag.get_child_value("work-title") {
+                    mscore.title = work_title.clone();
+                }
+            },
+            "part-list" => {
+                for score_part_tag in subtree.tag.all_child_with_name("score-part") {
+                    if let Some(id) = score_part_tag.get_attrib_value("id") {
+                        part_names.insert(
+                            id.to_string(),
+                            score_part_tag
+                                .get_child_value("part-name")
+                                .map(|c| { c.to_string() })
+                                .unwrap_or("Untitled part".to_string())
+                        );
+                    }
+                }
+            },
+            _ => {
+                let part_id
+                    = subtree
+                    .ancestor_with_name("part")
+                    .and_then(|part_tag| { part_tag.get_attrib_value("id") })
+                    .context("<measure> outside of a <part id=...>")?;
+
+                // a new <part> begins, the previous one is complete
+                if current_part.as_ref().map(|(id, _)| { id.as_str() }) != Some(part_id) {
+                    if let Some((_, builder)) = current_part.take() {
+                        mscore.measured_parts.push(builder.finish()?);
+                    }
+                    let part_name = part_names
+                        .get(part_id)
+                        .map(|name| { name.as_str() })
+                        .unwrap_or("Untitled part");
+                    current_part = Some((part_id.to_string(), MeasuredPartBuilder::new(part_name)));
+                }
+
+                let (_, builder) = current_part.as_mut().unwrap();
+                builder.add_measure(&subtree.tag)
+                    .with_context(|| format!("Can't parse <measure> of part {:?}", part_id))?;
             }
-        )
-        .unwrap_or(1);
+        }
+    }
 
-    measured_parts
-    .extend(
-        attrs.clef_signs
-        .iter()
-        .map(|clef_sign| {
-            MeasuredPart::new(
-                part_name.to_string(),
-                attrs.key_fifths,
-                clef_sign.clone(),
-                attrs.time_sig
-            )
-        })
-        .map(|mut mpart| { mpart.measures.reserve(number_of_measures); mpart})
-    );
+    if let Some((_, builder)) = current_part {
+        mscore.measured_parts.push(builder.finish()?);
+    }
+    Ok(mscore)
+}
+
+pub fn measured_part_from_tag(part_tag: &XmlTag, part_name: &str)
+    -> anyhow::Result<MeasuredPart> // every staff of eg. a piano part is held in the one MeasuredPart
+{
+    let mut builder = MeasuredPartBuilder::new(part_name);
+    for measure_tag in part_tag.all_child_with_name("measure") {
+        builder.add_measure(measure_tag)?;
+    }
+    builder.finish()
+}
 
-    // split by <backup>
-    for measure_tag in part_tag.all_child_with_name("measure")
+/// Builds the MeasuredPart of one <part> a <measure> at a time, so the whole <part> never has to be held.
+/// Every voice is kept apart until `finish`, when the primary voice is known.
+pub struct MeasuredPartBuilder {
+    part_name: String,
+    attrs: Option<PartAttributes>,
+    measured_part: Option<MeasuredPart>,
+    pending_measures: Vec<XmlTag> // seen before the first <attributes>
+}
+
+impl MeasuredPartBuilder {
+    pub fn new(part_name: &str) -> Self
+    {
+        MeasuredPartBuilder {
+            part_name: part_name.to_string(),
+            attrs: None,
+            measured_part: None,
+            pending_measures: Vec::new()
+        }
+    }
+
+    pub fn add_measure(&mut self, measure_tag: &XmlTag) -> anyhow::Result<()>
     {
-        assert!(measured_parts.iter()
-            .all(|mea| {
-                mea.measures.len() == measured_parts[0].measures.len()
-            }));
-
-        let mut cur_stave_number = 1;
-        let layers: Vec<_>
-            = measure_tag.children.as_slice()
-            .split_inclusive(|t| { t.name == "backup"} )
-            .collect();
-
-        if layers.len() != attrs.staves as usize {
-            return Err(anyhow!("Expected {:?} staves but found {:?} instead", attrs.staves, layers.len()));
+        if self.attrs.is_none() {
+            // the initial <attributes> is the first one found, measures before it wait for it
+            let attributes_tag = match measure_tag.get_desc_with_name("attributes") {
+                Some(attributes_tag) => attributes_tag,
+                None => {
+                    self.pending_measures.push(measure_tag.clone());
+                    return Ok(());
+                }
+            };
+            let attrs
+                = part_attributes_from_tag(attributes_tag)
+                .context("Can't parse <attributes> to attribute object")?;
+            let initial_attributes = attrs.measure_attributes();
+            let mut mpart = MeasuredPart::new(
+                self.part_name.clone(),
+                initial_attributes.key_sig,
+                initial_attributes.clef_sign,
+                initial_attributes.time_sig
+            );
+            mpart.lower_staff_clefs = initial_attributes.lower_staff_clefs;
+            mpart.transposition = attrs.transposition;
+            self.measured_part = Some(mpart);
+            self.attrs = Some(attrs);
+            for pending_tag in std::mem::take(&mut self.pending_measures) {
+                self.add_measure(&pending_tag)?;
+            }
+        }
+        let attrs = self.attrs.as_mut().unwrap();
+        let mpart = self.measured_part.as_mut().unwrap();
+
+        // attribute changes apply from this measure on, but <divisions> only from where it stands
+        let divisions = attrs.division;
+        for attributes_tag in measure_tag.all_child_with_name("attributes") {
+            attrs.update_from_tag(attributes_tag)
+                .with_context(|| format!("Can't parse <attributes> of measure {:?}", measure_tag.get_attrib_value("number")))?;
+        }
+
+        let (voice_streams, tempo_marks) = voice_streams_from_measure_tag(measure_tag, divisions)?;
+        if let Some((staff, _)) = voice_streams.keys().find(|(staff, _)| { *staff == 0 || *staff > attrs.staves }) {
+            return Err(anyhow!("Expected {:?} staves but found staff {:?}", attrs.staves, staff));
         }
 
-        for (layer, mpart) in zip(&layers, &mut measured_parts) {
-            let gnote_stream = xml_notes_to_gnotes(
-                &mut layer.iter().peekable(),
-                attrs.division,
-                &attrs)?;
+        // an implicit measure (eg. a pickup) only lasts as long as its content, nothing when empty
+        let implicit_length
+            = if measure_tag.get_attrib_value("implicit") == Some("yes") {
+                Some(
+                    voice_streams
+                    .values()
+                    .filter_map(|gnotes| { gnotes.last() })
+                    .map(|gnote| { either_gnote!(gnote, gn => gn.interval.end) })
+                    .max()
+                    .unwrap_or_else(|| { Duration::from_integer(0) })
+                )
+            } else { None };
 
-            let cur_measure = mpart.append_empty_measure();
-            cur_measure
-                .gnotes
-                .extend(gnote_stream);
+        let cur_measure = match implicit_length {
+            Some(length) => mpart.append_implicit_measure(attrs.measure_attributes(), length),
+            None => mpart.append_empty_measure_with_attributes(attrs.measure_attributes())
+        };
+        if let Some(number) = measure_tag.get_attrib_value_as::<MeasureNumberType>("number") {
+            cur_measure.measure_number = number;
         }
+        let measure_start = cur_measure.interval.start;
+        cur_measure.secondary_voices.extend(voice_streams.into_iter());
+        mpart.tempo_marks.extend(
+            tempo_marks
+            .into_iter()
+            .map(|(offset, bpm)| { (measure_start + offset, bpm) })
+        );
+        Ok(())
     }
 
-    Ok(measured_parts)
+    /// The primary voice is the lowest voice used on the lowest staff
+    pub fn finish(self) -> anyhow::Result<MeasuredPart>
+    {
+        if !self.pending_measures.is_empty() {
+            return Err(anyhow!("Error while parsing <attributes>: part {:?} has none", self.part_name));
+        }
+        let mut mpart = self.measured_part.ok_or(anyhow!("Part {:?} has no <measure>", self.part_name))?;
+        let primary_voice
+            = mpart.measures
+            .iter()
+            .flat_map(|measure| { measure.secondary_voices.keys() })
+            .min()
+            .cloned()
+            .unwrap_or(DEFAULT_VOICE_KEY);
+        mpart.primary_voice = primary_voice;
+
+        for measure in mpart.measures.iter_mut() {
+            let measure_length = measure.interval.length;
+            measure.gnotes = measure.secondary_voices.remove(&primary_voice).unwrap_or_default();
+            pad_with_rest(&mut measure.gnotes, measure_length);
+            measure
+                .secondary_voices
+                .values_mut()
+                .for_each(|gnotes| { pad_with_rest(gnotes, measure_length) });
+        }
+        Ok(mpart)
+    }
+}
+
+pub fn staff_of_note_tag(note_tag: &XmlTag) -> StaffNumberType
+{
+    note_tag.get_child_value_as("staff").unwrap_or(DEFAULT_STAFF)
+}
+
+pub fn voice_of_note_tag(note_tag: &XmlTag) -> VoiceNumberType
+{
+    note_tag.get_child_value_as("voice").unwrap_or(DEFAULT_VOICE)
+}
+
+/// Splits the content of a <measure> into one gnote stream per (staff, voice).
+/// <backup> and <forward> move the cursor; holes left in a voice are filled with rests.
+/// `divisions` holds at the start of the measure, a <divisions> inside it holds from there on.
+/// Tempo marks are returned along, placed from the start of the measure.
+pub fn voice_streams_from_measure_tag(
+    measure_tag: &XmlTag,
+    divisions: BeatDivision
+) -> anyhow::Result<(BTreeMap<VoiceKey, Vec<Gnote>>, TempoMarks)>
+{
+    let mut voice_streams = BTreeMap::new();
+    let mut tempo_marks = TempoMarks::new();
+    let mut cursor = Offset::from_integer(0);
+    let mut divisions = divisions;
+
+    let mut child_tags = measure_tag.children.iter().peekable();
+    while let Some(child_tag) = child_tags.peek() {
+        match child_tag.name.as_str() {
+            "note" if child_tag.does_child_exists("grace") => { child_tags.next(); },
+            "note" => {
+                let key = (staff_of_note_tag(child_tag), voice_of_note_tag(child_tag));
+
+                // consecutive notes of one voice, so tuplets and chords are kept together
+                let mut run = Vec::new();
+                while let Some(tag) = child_tags.peek() {
+                    match tag.name.as_str() {
+                        "backup" | "forward" => break,
+                        "attributes" if tag.does_child_exists("divisions") => break,
+                        "sound" | "direction" if tempo_of_tag(tag)?.is_some() => break,
+                        "note" if tag.does_child_exists("grace") => {},
+                        "note" => {
+                            if (staff_of_note_tag(tag), voice_of_note_tag(tag)) != key { break; }
+                            run.push(*tag);
+                        },
+                        _ => {}
+                    }
+                    child_tags.next();
+                }
+
+                let stream = voice_streams.entry(key).or_insert_with(Vec::new);
+                let run_start = cursor;
+                for mut gnote in xml_notes_to_gnotes(&mut run.into_iter().peekable(), divisions)? {
+                    either_gnote!(&mut gnote, gn => gn.interval.displace_start_keep_length(run_start));
+                    cursor = either_gnote!(&gnote, gn => gn.interval.end);
+                    push_gnote_with_gap_rest(stream, gnote)?;
+                }
+            },
+            "backup" | "forward" => {
+                let duration = Duration::new(
+                    child_tag.get_child_value_as("duration")
+                        .with_context(|| format!("Can't parse <{}>::duration", child_tag.name))?,
+                    divisions
+                );
+                if child_tag.name == "backup" { cursor -= duration; } else { cursor += duration; }
+                if cursor < Offset::from_integer(0) {
+                    return Err(anyhow!("<backup> goes before the start of the measure"));
+                }
+                child_tags.next();
+            },
+            "attributes" => {
+                if let Some(new_divisions) = divisions_of_tag(child_tag)? {
+                    divisions = new_divisions;
+                }
+                child_tags.next();
+            },
+            "sound" | "direction" => {
+                if let Some(bpm) = tempo_of_tag(child_tag)? {
+                    let offset = Duration::new(child_tag.get_child_value_as("offset").unwrap_or(0), divisions);
+                    tempo_marks.insert(cursor + offset, bpm);
+                }
+                child_tags.next();
+            },
+            // <print>, <barline>, ... carry no notes
+            _ => { child_tags.next(); }
+        }
+    }
+    Ok((voice_streams, tempo_marks))
+}
+
+/// Quarter notes per minute of a <sound>, or of the <sound> of a <direction>
+fn tempo_of_tag(tag: &XmlTag) -> anyhow::Result<Option<f64>>
+{
+    let sound_tag = if tag.name == "sound" { Some(tag) } else { tag.get_child_with_name("sound") };
+    sound_tag
+    .and_then(|sound_tag| { sound_tag.get_attrib_value("tempo") })
+    .map(|tempo| {
+        tempo
+        .parse::<f64>()
+        .ok()
+        .filter(|bpm| { *bpm > 0.0 })
+        .ok_or_else(|| { anyhow!("Can't parse tempo {:?}", tempo) })
+    })
+    .transpose()
 }
 
-/// Intra-measure translation !
-// This fn stops when encountering <backup>, which should coincide with end of iterator
+/// Intra-measure translation of a run of notes of one voice, <backup> and <forward>
+/// are left to `voice_streams_from_measure_tag`
 fn xml_notes_to_gnotes<'a>(
     gn_tags: &mut Peekable<impl Iterator<Item=&'a XmlTag>>,
-    divisions: BeatDivision,
-    attrs: &PartAttributes
+    divisions: BeatDivision
 ) -> anyhow::Result<Vec<Gnote>>
 {
     let mut gnote_stream = Vec::new();
@@ -342,21 +685,8 @@ fn xml_notes_to_gnotes<'a>(
                     })?
                 );
             },
-            "backup" => {
-                let backup_duration = Duration::new(
-                    gn_tags.peek().unwrap().get_child_value_as("duration").unwrap(),
-                    divisions
-                );
-                if backup_duration !=
-                Ratio::<BeatDivision>::new(
-                    (*attrs.time_sig.numer() as BeatDivision) * BeatDivision::from(4),
-                    *attrs.time_sig.denom() as BeatDivision
-                )
-                {
-                    return Err(anyhow!("<backup> behaves in unexpected ways"));
-                }
-            },
-            _ => { return Err(anyhow!("Found tags other than <note>, <backup> in stream")) }
+            // <attributes>, <print>, <direction>, <barline>, ... carry no notes
+            _ => { gn_tags.next(); }
         }
     }
     Ok(gnote_stream)
@@ -369,47 +699,11 @@ pub fn gnote_from_tag<'a>(gn_tags: &mut Peekable<impl Iterator<Item=&'a XmlTag>>
     // Some doesn't do this and we must rely on the fact that <time-mod> is present
     if let Some(cur_gn_tag) = gn_tags.peek() {
         let is_tuplet_start
-            = cur_gn_tag.get_child_with_name("tuplet")
-                .and_then(|tup| { tup.get_attrib_value("type") })
-                .filter(|ty| { ty == &"start"})
-                .is_some()
+            = tuplet_tags_of_type(cur_gn_tag, "start").next().is_some()
             || cur_gn_tag.does_child_exists("time-modification");
 
         if is_tuplet_start {
-            let time_mod_tag
-                = cur_gn_tag.get_child_with_name("time-modification")
-                .expect("Apparently tuplet has no <time-modification>");
-
-            let mut simple_notes = Vec::new();
-            simple_notes.reserve(time_mod_tag.get_child_value_as::<NormalNumType>("actual-notes").unwrap() as usize);
-
-            let mut offset_so_far = Offset::from_integer(0);
-            loop {
-                // check endOfTuple before parsing it bc Chord only has one <tuple type=stop> for the first note
-                let reached_end_of_tuplet
-                    = gn_tags.peek().ok_or(anyhow!("Tag unexpected popped somewhere above"))?
-                    .get_child_with_name("tuplet")
-                    .and_then(|tup| { tup.get_attrib_value("type") })
-                    .filter(|ty| {ty == &"stop"})
-                    .is_some();
-
-                let mut tup_member = simple_note_from_tag(gn_tags, divisions)?;
-                tup_member.interval.displace_start_keep_length(offset_so_far);
-                offset_so_far += tup_member.interval.length;
-                simple_notes.push(tup_member);
-
-                if gn_tags.peek().is_none() || reached_end_of_tuplet {
-                    break;
-                }
-            }
-
-            let bare_tup = tuplet::Tuplet::new(
-                time_mod_tag.get_child_value_as("normal-notes").unwrap(),
-                time_mod_tag.get_child_value_as("actual-notes").unwrap(),
-                MPInterval::from_start_and_length(Offset::from_integer(0), offset_so_far),
-                simple_notes
-            );
-            Ok(Gnote::Tuplet(bare_tup))
+            tuplet_from_tags(gn_tags, divisions).map(Gnote::Tuplet)
         } else {
             simple_note_from_tag(gn_tags, divisions)
             .map(|sn| { Gnote::SimpleNote(sn) })
@@ -420,6 +714,102 @@ pub fn gnote_from_tag<'a>(gn_tags: &mut Peekable<impl Iterator<Item=&'a XmlTag>>
     }
 }
 
+fn tuplet_tags_of_type<'a>(note_tag: &'a XmlTag, tuplet_type: &'a str) -> impl Iterator<Item=&'a XmlTag>
+{
+    note_tag
+    .all_desc_with_name("tuplet")
+    .filter(move |tup| { tup.get_attrib_value("type") == Some(tuplet_type) })
+}
+
+fn time_modification_of_tag(note_tag: &XmlTag) -> anyhow::Result<(NormalNumType, NormalNumType)>
+{
+    let time_mod_tag
+        = note_tag.get_child_with_name("time-modification")
+        .context("Apparently tuplet has no <time-modification>")?;
+    let actual_number: NormalNumType = time_mod_tag
+        .get_child_value_as("actual-notes")
+        .context("Can't parse <time-modification>::actual-notes")?;
+    let normal_number: NormalNumType = time_mod_tag
+        .get_child_value_as("normal-notes")
+        .context("Can't parse <time-modification>::normal-notes")?;
+    Ok((actual_number, normal_number))
+}
+
+// <tuplet-actual> and <tuplet-normal> of a bracket, when it spells them out
+fn ratio_of_tuplet_tag(tuplet_tag: &XmlTag) -> Option<(NormalNumType, NormalNumType)>
+{
+    let number_of = |name: &str| {
+        tuplet_tag
+        .get_child_with_name(name)
+        .and_then(|tag| { tag.get_child_value_as::<NormalNumType>("tuplet-number") })
+    };
+    number_of("tuplet-actual").zip(number_of("tuplet-normal"))
+}
+
+/// Brackets read so far: (actual, normal, members)
+type OpenTuplets = Vec<(NormalNumType, NormalNumType, Vec<Gnote>)>;
+
+// what the enclosing brackets leave of a note's <time-modification>
+fn innermost_ratio(time_modification: (NormalNumType, NormalNumType), open_tuplets: &OpenTuplets)
+    -> anyhow::Result<(NormalNumType, NormalNumType)>
+{
+    let enclosing = open_tuplets
+        .iter()
+        .fold(Ratio::<BeatDivision>::from_integer(1), |ratio, (actual, normal, _)| {
+            ratio * Ratio::new(*actual as BeatDivision, *normal as BeatDivision)
+        });
+    if enclosing == Ratio::from_integer(1) { return Ok(time_modification); }
+    let ratio = Ratio::new(time_modification.0 as BeatDivision, time_modification.1 as BeatDivision) / enclosing;
+    Ok((
+        NormalNumType::try_from(*ratio.numer()).context("Nested <tuplet> ratio out of range")?,
+        NormalNumType::try_from(*ratio.denom()).context("Nested <tuplet> ratio out of range")?
+    ))
+}
+
+/// Notes up to the one closing the outermost bracket, nested brackets become nested tuplets.
+/// Without <tuplet> brackets the tuplet runs until the end of the notes.
+fn tuplet_from_tags<'a>(gn_tags: &mut Peekable<impl Iterator<Item=&'a XmlTag>>, divisions: BeatDivision)
+    -> anyhow::Result<tuplet::Tuplet>
+{
+    let mut open_tuplets: OpenTuplets = Vec::new();
+    loop {
+        let cur_tag = *gn_tags.peek().ok_or(anyhow!("Tag unexpected popped somewhere above"))?;
+        let starts: Vec<&XmlTag> = tuplet_tags_of_type(cur_tag, "start").collect();
+        // check endOfTuple before parsing it bc Chord only has one <tuple type=stop> for the first note
+        let stop_count = tuplet_tags_of_type(cur_tag, "stop").count();
+
+        if open_tuplets.is_empty() && starts.is_empty() {
+            let (actual_number, normal_number) = time_modification_of_tag(cur_tag)?;
+            open_tuplets.push((actual_number, normal_number, Vec::new()));
+        }
+        for (idx, start_tag) in starts.iter().enumerate() {
+            let (actual_number, normal_number) = match ratio_of_tuplet_tag(start_tag) {
+                Some(ratio) => ratio,
+                None if idx + 1 == starts.len() =>
+                    innermost_ratio(time_modification_of_tag(cur_tag)?, &open_tuplets)?,
+                None => return Err(anyhow!("Can't tell the ratio of a nested <tuplet> without <tuplet-actual>"))
+            };
+            if actual_number == 0 || normal_number == 0 {
+                return Err(anyhow!("Tuplet ratio {}:{} is not valid", actual_number, normal_number));
+            }
+            open_tuplets.push((actual_number, normal_number, Vec::new()));
+        }
+
+        let tup_member = simple_note_from_tag(gn_tags, divisions)?;
+        open_tuplets.last_mut().unwrap().2.push(Gnote::SimpleNote(tup_member));
+
+        let closing = if gn_tags.peek().is_none() { open_tuplets.len() } else { stop_count };
+        for _ in 0..closing.min(open_tuplets.len()) {
+            let (actual_number, normal_number, members) = open_tuplets.pop().unwrap();
+            let tup = tuplet::Tuplet::new(normal_number, actual_number, Offset::from_integer(0), members);
+            match open_tuplets.last_mut() {
+                Some(enclosing) => enclosing.2.push(Gnote::Tuplet(tup)),
+                None => return Ok(tup)
+            }
+        }
+    }
+}
+
 pub fn simple_note_from_tag<'a>(sn_tag: &mut Peekable<impl Iterator<Item=&'a XmlTag>>, divisions: BeatDivision)
     -> anyhow::Result<simple_note::SimpleNote>
 {
@@ -446,7 +836,8 @@ pub fn simple_note_from_tag<'a>(sn_tag: &mut Peekable<impl Iterator<Item=&'a Xml
             = cur_tag.get_attrib_value("color")
             .or(cur_tag.get_desc_with_name("notehead")
             .and_then(|nh| {nh.get_attrib_value("color")}))
-            .map(|s| { Color::from_hex_rgb(s)} );
+            .map(|s| { Color::from_hex_rgb(s) })
+            .transpose()?;
 
         // encode tie info
         let tie_info
@@ -461,18 +852,18 @@ pub fn simple_note_from_tag<'a>(sn_tag: &mut Peekable<impl Iterator<Item=&'a Xml
         );
 
         if cur_tag.does_child_exists("rest") {
+            sn_tag.next();
             return Ok(simple_note);
         }
 
         simple_note
     };
 
-    loop {
-        let cur_xml_tag = sn_tag.peek().unwrap();
+    while let Some(cur_xml_tag) = sn_tag.peek() {
         let pitch_tag
             = cur_xml_tag
             .get_child_with_name("pitch")
-            .expect("Not rest yet no <pitch> found");
+            .context("Not rest yet no <pitch> found")?;
         let pitch = pitch_from_tag(pitch_tag)?;
         simple_note.pitches.insert(pitch);
 
@@ -482,8 +873,7 @@ pub fn simple_note_from_tag<'a>(sn_tag: &mut Peekable<impl Iterator<Item=&'a Xml
         simple_note.tie_info |= potential_tie_info;
 
         sn_tag.next();
-        if sn_tag.peek().is_none()
-        || !sn_tag.peek().unwrap().does_child_exists("chord") { break; }
+        if !sn_tag.peek().map_or(false, |tag| { tag.does_child_exists("chord") }) { break; }
     }
 
     Ok(simple_note)
@@ -497,10 +887,10 @@ pub fn tie_info_from_tag<'a>(tie_tag: impl Iterator<Item=&'a XmlTag>)
     else {
         let mut tie_info = TieInfo::TieNeither;
         for xml_tie in xml_ties {
-            match xml_tie.get_attrib_value("type").unwrap() {
-                "start" => { tie_info |= TieInfo::TieStart },
-                "stop" => { tie_info |= TieInfo::TieEnd},
-                _ => { panic!("Unknown <tie>'s type")}
+            match xml_tie.get_attrib_value("type") {
+                Some("start") => { tie_info |= TieInfo::TieStart },
+                Some("stop") => { tie_info |= TieInfo::TieEnd},
+                other => { return Err(anyhow!("Unknown <tie>'s type {:?}", other)) }
             }
         }
         Ok(tie_info)
@@ -510,22 +900,8 @@ pub fn tie_info_from_tag<'a>(tie_tag: impl Iterator<Item=&'a XmlTag>)
 pub fn pitch_from_tag(pitch_tag: &XmlTag)
     -> anyhow::Result<Pitch>
 {
-    let alter: Alter
-        = pitch_tag.get_child_value_as::<i32>("alter")
-        .unwrap_or(0)
-        .into();
-
-    Ok (
-        Pitch::new(
-            DiatonicStep::from(
-                pitch_tag.get_child_value("step")
-                .context("Can't parse <step> in <pitch>")?
-                .as_str()
-            ),
-            pitch_tag.get_child_value_as("octave"),
-            alter
-        )
-    )
+    let pitch = WrittenPitch::from_xml_tag(pitch_tag)?;
+    Ok(Pitch::new(pitch.step, pitch.octave, Alter::from_semitones(pitch.alter.unwrap_or(0.0))))
 }
 
 pub fn lyrics_from_tags<'a>(lyric_tags: impl Iterator<Item=&'a XmlTag>)
@@ -534,15 +910,7 @@ pub fn lyrics_from_tags<'a>(lyric_tags: impl Iterator<Item=&'a XmlTag>)
     let mut lyrics = Vec::new();
     lyrics.reserve(config::EXP_LYRIC_NUM);
     for lyric_tag in lyric_tags {
-        lyrics.push(
-            Lyric::new(
-                lyric_tag.get_attrib_value_as("number")
-                    .ok_or(anyhow!("Can't get lyric number"))?,
-                lyric_tag.get_child_value("text")
-                    .ok_or(anyhow!("Can't extract lyric text"))?
-                    .clone()
-            )
-        )
+        lyrics.push(Lyric::from_xml_tag(lyric_tag)?)
     }
     Ok(lyrics)
 }
@@ -555,54 +923,16 @@ pub fn part_attributes_from_tag(tag: &XmlTag)
         key_fifths: 0,
         time_sig: TimeSig::from_integer(0),
         clef_signs: vec![],
-        staves: 0,
+        staves: 1,
+        transposition: None,
         measure_length: Duration::from(0)
     };
-    part_attrs.division
-        = tag
-            .get_child_with_name("divisions").context("Cannot find <division> tag")?
-            .value.as_ref().context("<division> tag contains no value")?
-            .parse().context("Can't parse value in <division>")?;
-
-    {
-        trace_macros!(true);
-        // TODO: USE DRILL! macro for easier to read code
-        let fifths_tag = drill!(tag; ["key", "fifths", "fs"]);
-        part_attrs.key_fifths = tag
-            .get_child_with_name("key")
-            .and_then( |c| { c.get_child_with_name("fifths") })
-            .and_then(|c| { c.value.clone() })
-            .map(|s| { s.parse().unwrap_or(0) })
-            .unwrap_or(0);
-    }
-
-    {
-        let time_tag = tag
-            .get_child_with_name("time").context("Can't find <time>")?;
-        part_attrs.time_sig = TimeSig::from((
-            time_tag.get_child_value_as("beats").context("Can't parse <beats>")?,
-            time_tag.get_child_value_as("beat-type").context("Can't parse <beat-type>")?
-        ));
-    }
-
-    {
-        let staves = tag.get_child_value_as::<u8>("staves");
-        part_attrs.staves = staves.unwrap_or(1);
-    }
+    part_attrs.update_from_tag(tag)?;
 
-    {
-        let clefs: Vec<_> = tag.all_child_with_name("clef").collect();
-        if clefs.is_empty() { return Err(anyhow!("<clef> not found")) };
-        part_attrs
-        .clef_signs
-        .extend(
-            clefs
-            .iter()
-            .map(|tag| {
-                tag.get_child_value_as::<ClefType>("sign").context("Can;t parse <clef>::sign").unwrap()
-            })
-        );
-    }
+    // the first <attributes> must be complete
+    if part_attrs.division == 0 { return Err(anyhow!("Cannot find <division> tag")) };
+    if tag.get_child_with_name("time").is_none() { return Err(anyhow!("Can't find <time>")) };
+    if part_attrs.clef_signs.is_empty() { return Err(anyhow!("<clef> not found")) };
 
     part_attrs.measure_length = measure_length_from_time_sig(part_attrs.time_sig);
 
@@ -613,11 +943,200 @@ pub fn part_attributes_from_tag(tag: &XmlTag)
 
 #[cfg(test)]
 mod tests {
-    use crate::xml_import::measured_score_from_path;
+    use std::fs::File;
+    use adaxml::tag::XmlTag;
+    use crate::attribs::{Duration, Offset};
+    use crate::either_gnote;
+    use crate::gnote::Gnote;
+    use adaxml::error::{XmlParseError, XmlParseErrorKind};
+    use crate::xml_export::measured_part_to_tag;
+    use crate::xml_import::{measured_part_from_tag, measured_score_from_path, measured_score_from_path_streaming, score_from_path};
 
     #[test]
     fn test () {
         let m = measured_score_from_path("test/template.musicxml").unwrap();
         println!("{:?}", m)
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn compressed_matches_uncompressed() {
+        measured_score_from_path("test/melBank/longduong/longduongs.mxl").unwrap();
+
+        // unpack the rootfile by hand and compare with what the container reader yields
+        let mut archive = zip::ZipArchive::new(File::open("test/longduongs.mxl").unwrap()).unwrap();
+        let unpacked_path = std::env::temp_dir().join("m32_unpacked_score.musicxml");
+        std::io::copy(
+            &mut archive.by_name("score.xml").unwrap(),
+            &mut File::create(&unpacked_path).unwrap()
+        ).unwrap();
+
+        let from_mxl = score_from_path("test/longduongs.mxl").unwrap();
+        let uncompressed = score_from_path(unpacked_path.to_str().unwrap()).unwrap();
+        assert_eq!(from_mxl.title, uncompressed.title);
+        assert_eq!(from_mxl.parts.len(), uncompressed.parts.len());
+        for (lhs, rhs) in from_mxl.parts.iter().zip(uncompressed.parts.iter()) {
+            assert_eq!(lhs.name, rhs.name);
+            assert_eq!(lhs.hash_note_and_rests(), rhs.hash_note_and_rests());
+        }
+    }
+
+    #[test]
+    fn voices_are_split_by_backup() {
+        let mscore = measured_score_from_path("test/longduongs_voice.musicxml").unwrap();
+        let soprano = &mscore.measured_parts[0];
+        assert_eq!(soprano.primary_voice, (1, 1));
+        assert_eq!(soprano.voice_keys(), vec![(1, 1), (1, 2), (1, 3)]);
+
+        let first_measure = &soprano.measures[0];
+        assert_eq!(first_measure.secondary_voices.len(), 2);
+        for voice in soprano.voice_keys() {
+            let gnotes = first_measure.voice_gnotes(voice, soprano.primary_voice).unwrap();
+            let voice_length = gnotes.iter().fold(
+                Offset::from_integer(0),
+                |acc, gn| { acc + either_gnote!(gn, g => g.interval.length) }
+            );
+            assert_eq!(voice_length, first_measure.interval.length);
+        }
+
+        // voices missing from a measure are rests in the flattened voice
+        let voice2 = soprano.flatten_voice((1, 2));
+        assert_eq!(voice2.primary_voice, (1, 2));
+        let end_of = |gnotes: &Vec<Gnote>| { either_gnote!(gnotes.last().unwrap(), g => g.interval.end) };
+        assert_eq!(end_of(&voice2.gnotes), end_of(&soprano.flatten().gnotes));
+        assert!(voice2.voice_simple_note_iter((1, 2)).skip(8).all(|sn| { sn.is_rest() }));
+
+        let score = score_from_path("test/longduongs_voice.musicxml").unwrap();
+        assert_eq!(score.parts[0].voice_keys(), vec![(1, 1), (1, 2), (1, 3)]);
+        assert_ne!(
+            score.parts[0].hash_voice_note_and_rests((1, 1)),
+            score.parts[0].hash_voice_note_and_rests((1, 2))
+        );
+        assert!(score.parts[0].hash_voice_note_and_rests((1, 4)).is_none());
+    }
+
+    #[test]
+    fn divisions_hold_from_where_they_change() {
+        let note = |duration: i32, note_type: &str| {
+            format!(
+                "<note><pitch><step>C</step><octave>4</octave></pitch><duration>{}</duration>\
+                <voice>1</voice><type>{}</type></note>",
+                duration, note_type
+            )
+        };
+        let part_xml = format!(
+            "<part id=\"P1\"><measure number=\"1\">\
+            <attributes><divisions>1</divisions><key><fifths>0</fifths></key>\
+            <time><beats>4</beats><beat-type>4</beat-type></time><clef><sign>G</sign><line>2</line></clef></attributes>\
+            {}<attributes><divisions>4</divisions></attributes>{}{}{}</measure>\
+            <measure number=\"2\"><attributes><time><beats>3</beats><beat-type>4</beat-type></time></attributes>\
+            <note><rest measure=\"yes\"/><duration>12</duration><voice>1</voice></note></measure></part>",
+            note(2, "half"), note(4, "quarter"), note(2, "eighth"), note(2, "eighth")
+        );
+        let part_tag = XmlTag::from_read(part_xml.as_bytes()).unwrap();
+        let mpart = measured_part_from_tag(&part_tag, "Divisions").unwrap();
+        assert!(
+            mpart.measures[0].gnotes.iter().map(|gn| { either_gnote!(gn, g => g.interval.length) })
+            .eq([Duration::from_integer(2), Duration::from_integer(1), Duration::new(1, 2), Duration::new(1, 2)].iter().cloned())
+        );
+        // every measure is as long as its own time signature says
+        assert_eq!(mpart.measures[1].interval.length, Duration::from_integer(3));
+        assert_eq!(mpart.measures[1].interval.length, mpart.measures[1].attributes.measure_length());
+    }
+
+    #[test]
+    fn tempo_marks_are_placed_where_they_stand() {
+        let note = |duration: i32, note_type: &str| {
+            format!(
+                "<note><pitch><step>C</step><octave>4</octave></pitch><duration>{}</duration>\
+                <voice>1</voice><type>{}</type></note>",
+                duration, note_type
+            )
+        };
+        let part_xml = format!(
+            "<part id=\"P1\"><measure number=\"1\">\
+            <attributes><divisions>2</divisions><key><fifths>0</fifths></key>\
+            <time><beats>4</beats><beat-type>4</beat-type></time><clef><sign>G</sign><line>2</line></clef></attributes>\
+            <direction><direction-type><words>Lento</words></direction-type><sound tempo=\"60\"/></direction>\
+            {}<sound tempo=\"90\"/>{}</measure>\
+            <measure number=\"2\"><sound tempo=\"120.5\"><offset>2</offset></sound>{}</measure></part>",
+            note(4, "half"), note(4, "half"), note(8, "whole")
+        );
+        let part_tag = XmlTag::from_read(part_xml.as_bytes()).unwrap();
+        let mpart = measured_part_from_tag(&part_tag, "Tempo").unwrap();
+        assert!(
+            mpart.tempo_marks.iter().map(|(offset, bpm)| { (*offset, *bpm) })
+            .eq([(Offset::from_integer(0), 60.0), (Offset::from_integer(2), 90.0), (Offset::from_integer(5), 120.5)].iter().cloned())
+        );
+        assert_eq!(mpart.measures[0].gnotes.len(), 2);
+
+        let exported = measured_part_to_tag(&mpart, "P1").unwrap();
+        let reimported = measured_part_from_tag(&exported, "Tempo").unwrap();
+        assert_eq!(reimported.tempo_marks, mpart.tempo_marks);
+
+        let bad_tempo = part_xml.replace("tempo=\"90\"", "tempo=\"fast\"");
+        assert!(measured_part_from_tag(&XmlTag::from_read(bad_tempo.as_bytes()).unwrap(), "Tempo").is_err());
+    }
+
+    #[test]
+    fn initial_attributes_are_searched_forward() {
+        let note = "<note><pitch><step>C</step><octave>4</octave></pitch><duration>4</duration><voice>1</voice><type>whole</type></note>";
+        let part_xml = format!(
+            "<part id=\"P1\"><measure number=\"1\">{}</measure><measure number=\"2\">\
+            <attributes><divisions>1</divisions><key><fifths>2</fifths></key>\
+            <time><beats>4</beats><beat-type>4</beat-type></time><clef><sign>G</sign><line>2</line></clef></attributes>\
+            {}</measure></part>",
+            note, note
+        );
+        let part_tag = XmlTag::from_read(part_xml.as_bytes()).unwrap();
+        let mpart = measured_part_from_tag(&part_tag, "Late attributes").unwrap();
+        assert_eq!(mpart.key_sig, 2);
+        assert!(mpart.measures.iter().map(|mea| { mea.measure_number }).eq(1..=2));
+        assert!(mpart.measures.iter().all(|mea| { mea.interval.length == Duration::from_integer(4) }));
+
+        let no_attributes = XmlTag::from_read(format!("<part id=\"P1\"><measure>{}</measure></part>", note).as_bytes()).unwrap();
+        assert!(measured_part_from_tag(&no_attributes, "No attributes").is_err());
+    }
+
+    #[test]
+    fn streaming_matches_tree_import() {
+        for path in ["test/melBank/beethoven/symp/no5.musicxml", "test/longduongs_voice.musicxml", "test/longduongs.mxl"].iter() {
+            let streamed = measured_score_from_path_streaming(path).unwrap();
+            let loaded = measured_score_from_path(path).unwrap();
+            assert_eq!(streamed.title, loaded.title);
+            assert_eq!(streamed.measured_parts.len(), loaded.measured_parts.len());
+            for (lhs, rhs) in streamed.measured_parts.iter().zip(loaded.measured_parts.iter()) {
+                assert_eq!(lhs.name, rhs.name);
+                assert_eq!(lhs.measures.len(), rhs.measures.len());
+                assert_eq!(lhs.primary_voice, rhs.primary_voice);
+                assert_eq!(lhs.flatten().hash_note_and_rests(), rhs.flatten().hash_note_and_rests());
+            }
+        }
+    }
+
+    #[test]
+    fn malformed_files_are_errors() {
+        let source = std::fs::read_to_string("test/longduongs_tied.musicxml").unwrap();
+        let corruptions = [
+            source.replacen("<step>B</step>", "<step>H</step>", 1),
+            source.replacen("<divisions>120</divisions>", "<divisions>0</divisions>", 1),
+            source.replacen("<tie type=\"start\"", "<tie type=\"begin\"", 1),
+            source.replacen("<duration>90</duration>", "<duration>ninety</duration>", 1),
+            source.replacen("</pitch>", "</pitsch>", 1),
+            source[..source.len() / 2].to_string(),
+        ];
+        for (idx, corrupted) in corruptions.iter().enumerate() {
+            assert_ne!(corrupted, &source);
+            let path = std::env::temp_dir().join(format!("m32_malformed_{}.musicxml", idx));
+            std::fs::write(&path, corrupted).unwrap();
+            assert!(score_from_path(path.to_str().unwrap()).is_err());
+            assert!(measured_score_from_path_streaming(path.to_str().unwrap()).is_err());
+        }
+
+        // xml errors tell where they are
+        let path = std::env::temp_dir().join("m32_malformed_4.musicxml");
+        let err = score_from_path(path.to_str().unwrap()).unwrap_err();
+        let parse_err = err.downcast_ref::<XmlParseError>().unwrap();
+        assert_eq!(parse_err.kind, XmlParseErrorKind::MismatchedEndTag { expected: "pitch".to_string(), found: "pitsch".to_string() });
+        assert_eq!(parse_err.path, "score-partwise/part[1]/measure[1]/note[1]/pitch[1]");
+    }
+}